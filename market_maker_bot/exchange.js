@@ -50,7 +50,7 @@ class Exchange {
     }
   }
 
-  // this function is needed only while the Exchange canister is not automatically using the heartbeat function
+  // the exchange runs on its own heartbeat; this only speeds up rounds and needs the owner identity
   async heartbeat() {
     await this.client.exec('canister', 'call enoki_exchange triggerRun');
   }
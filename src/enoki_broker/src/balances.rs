@@ -0,0 +1,158 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_rejected_deposits;
+use enoki_exchange_shared::has_sharded_users::get_user_shard;
+use enoki_exchange_shared::has_token_info;
+use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
+use enoki_exchange_shared::types::*;
+
+use crate::orders::reject_notification;
+use crate::payoffs::charge_deposit_fee;
+
+thread_local! {
+    static STATE: RefCell<BalancesState> = RefCell::new(BalancesState::default());
+}
+
+/// Tokens held by the broker on behalf of its users. Users deposit once, trade against their
+/// balance, get credited when their fills settle, and only move tokens out with `withdraw`.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct BalancesState {
+    balances: HashMap<Principal, LiquidityAmount>,
+}
+
+pub fn credit(user: Principal, amount: &LiquidityAmount) {
+    if !amount.token_a.is_nonzero() && !amount.token_b.is_nonzero() {
+        return;
+    }
+    ic_cdk::println!("[broker] crediting user {} with {:?}", user, amount);
+    STATE.with(|s| {
+        s.borrow_mut()
+            .balances
+            .entry(user)
+            .or_default()
+            .add_assign(amount.clone())
+    });
+}
+
+/// The users with tokens left in their balance.
+pub fn users_with_funds() -> Vec<Principal> {
+    STATE.with(|s| {
+        s.borrow()
+            .balances
+            .iter()
+            .filter(|(_, balance)| balance.is_nonzero())
+            .map(|(&user, _)| user)
+            .collect()
+    })
+}
+
+pub fn credit_token(user: Principal, token: &EnokiToken, amount: Nat) {
+    let mut credit_amount = LiquidityAmount::default();
+    credit_amount.get_mut(token).add_assign(amount.into());
+    credit(user, &credit_amount);
+}
+
+pub fn debit(user: Principal, token: &EnokiToken, amount: StableNat) -> Result<()> {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let balance = s.balances.entry(user).or_default().get_mut(token);
+        if *balance < amount {
+            return Err(TxError::InsufficientFunds {
+                token: token.clone(),
+                funds: balance.clone().to_nat().to_string(),
+                needed: amount.to_nat().to_string(),
+            }
+            .into());
+        }
+        balance.safe_sub_assign(amount)
+    })
+}
+
+/// Credits tokens sent with `shardTransferAndCall` to the sender's balance, minus the deposit fee.
+/// A rejected deposit is refunded.
+#[update(name = "deposit")]
+#[candid_method(update)]
+async fn deposit(notification: ShardedTransferNotification) -> String {
+    let token = match has_rejected_deposits::parse_notification(&notification) {
+        Ok(token) => token,
+        Err(error) => return has_rejected_deposits::reject_without_refund("deposit", error.into()),
+    };
+    let quantity = match charge_deposit_fee(notification.from, &token, notification.value.clone()) {
+        Ok(quantity) => quantity,
+        Err(error) => {
+            let refundable = notification.value.clone();
+            return reject_notification(notification, token, "deposit", error.into(), refundable)
+                .await;
+        }
+    };
+    credit_token(notification.from, &token, quantity.clone());
+    quantity.to_string()
+}
+
+#[query(name = "getBalance")]
+#[candid_method(query, rename = "getBalance")]
+pub(crate) fn get_balance(user: Principal) -> LiquidityAmountNat {
+    STATE
+        .with(|s| s.borrow().balances.get(&user).cloned())
+        .unwrap_or_default()
+        .into()
+}
+
+/// Sends tokens from the caller's balance to their wallet. Returns the amount sent.
+#[update(name = "withdraw")]
+#[candid_method(update, rename = "withdraw")]
+async fn withdraw(token: EnokiToken, amount: Nat) -> TxResult<Nat> {
+    let user = ic_cdk::caller();
+    debit(user, &token, amount.clone().into())?;
+    let result: Result<()> = async {
+        let user_shard = get_user_shard(user, has_token_info::get_token_address(&token))?;
+        ic_cdk::call(
+            has_token_info::get_assigned_shard(&token),
+            "shardTransfer",
+            (user_shard, user, amount.clone()),
+        )
+        .await
+        .map_err(|e| e.into_tx_error())
+    }
+    .await;
+    if let Err(error) = result {
+        ic_cdk::print(format!("[broker] failed to withdraw: {:?}", error));
+        credit_token(user, &token, amount);
+        return Err(error.into());
+    }
+    Ok(amount)
+}
+
+pub fn export_stable_storage() -> BalancesState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: BalancesState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod balances_tests {
+    use super::*;
+
+    #[test]
+    fn test_trade_against_balance() {
+        let user = Principal::from_slice(&[1]);
+        credit_token(user, &EnokiToken::TokenA, Nat::from(100u32));
+        credit_token(user, &EnokiToken::TokenB, Nat::from(0u32));
+        assert_eq!(users_with_funds(), vec![user]);
+
+        debit(user, &EnokiToken::TokenA, StableNat::from(60)).unwrap();
+        assert!(debit(user, &EnokiToken::TokenA, StableNat::from(41)).is_err());
+        assert!(debit(user, &EnokiToken::TokenB, StableNat::from(1)).is_err());
+        assert_eq!(get_balance(user).token_a, Nat::from(40u32));
+
+        debit(user, &EnokiToken::TokenA, StableNat::from(40)).unwrap();
+        assert!(users_with_funds().is_empty());
+    }
+}
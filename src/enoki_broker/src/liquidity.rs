@@ -11,7 +11,7 @@ use enoki_exchange_shared::has_token_info::{
     get_number_of_price_decimals, quantity_a_to_b, quantity_b_to_a, QuantityTranslator,
 };
 use enoki_exchange_shared::has_trading_fees::{
-    get_deposit_fee, get_swap_fee, get_swap_market_maker_reward,
+    get_deposit_fee, get_market_maker_pool_share, get_swap_fee, get_swap_market_maker_reward,
 };
 use enoki_exchange_shared::liquidity::{
     RequestForNewLiquidityTarget, ResponseAboutLiquidityChanges,
@@ -19,7 +19,7 @@ use enoki_exchange_shared::liquidity::{
 use enoki_exchange_shared::types::*;
 use enoki_exchange_shared::utils::{nat_to_u64, nat_x_float};
 
-use crate::payoffs;
+use crate::{balances, payoffs, referrals};
 
 thread_local! {
     static STATE: RefCell<LiquidityState> = RefCell::new(LiquidityState::default());
@@ -30,6 +30,16 @@ pub struct LiquidityState {
     bid_ask: AggregateBidAsk,
     available_liquidity: LiquidityAmount,
     liquidity_traded: LiquidityTrades,
+    fees_earned: Option<LiquidityAmount>,
+    rounding_error: Option<LiquidityAmount>,
+    // published by the exchange every round
+    swap_fee: Option<f64>,
+}
+
+fn get_current_swap_fee() -> f64 {
+    STATE
+        .with(|s| s.borrow().swap_fee)
+        .unwrap_or_else(get_swap_fee)
 }
 
 pub fn update_liquidity_target(
@@ -39,6 +49,7 @@ pub fn update_liquidity_target(
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.bid_ask = bid_ask;
+        s.swap_fee = Some(target.swap_fee);
 
         ic_cdk::println!(
             "[broker] new liquidity target: {:?}. Existing available: {:?}",
@@ -67,32 +78,34 @@ pub fn update_liquidity_target(
             added,
             removed,
             traded: std::mem::take(&mut s.liquidity_traded),
+            fees_earned: s.fees_earned.take().unwrap_or_default(),
+            rounding_error: s.rounding_error.take().unwrap_or_default(),
         }
     })
 }
 
 #[query(name = "getExpectedSwapPrice")]
 #[candid_method(query, rename = "getExpectedSwapPrice")]
-fn get_expected_swap_price(side: Side, quantity: Nat) -> f64 {
+fn get_expected_swap_price(side: Side, quantity: Nat) -> TxResult<f64> {
     let token = match side {
         Side::Buy => EnokiToken::TokenB,
         Side::Sell => EnokiToken::TokenA,
     };
-    let deposit_fee = get_deposit_fee(&token);
-    let mut quantity = quantity - deposit_fee;
-    if let Some(transfer_fee) = payoffs::try_get_fee_for_transfer(&token) {
-        quantity -= transfer_fee;
-    };
-    let swap_fee = get_swap_fee();
-    quantity = nat_x_float(quantity, 1.0 - swap_fee).unwrap();
-    let price_int = STATE
-        .with(|s| s.borrow().bid_ask.get_avg_price_for(side, quantity))
-        .unwrap();
-    (price_int as f64) / 10f64.pow(get_number_of_price_decimals() as f64)
+    let fees = get_deposit_fee(&token)
+        + payoffs::try_get_fee_for_transfer(&token).unwrap_or_default();
+    if quantity <= fees {
+        return Err(TxError::QuantityTooLow);
+    }
+    let swap_fee = get_current_swap_fee();
+    let quantity = nat_x_float(quantity - fees, 1.0 - swap_fee)?;
+    let price_int = STATE.with(|s| s.borrow().bid_ask.get_avg_price_for(side, quantity))?;
+    Ok((price_int as f64) / 10f64.pow(get_number_of_price_decimals() as f64))
 }
 
-pub async fn swap(mut order: ProcessedOrderInput) {
-    let swap_fee = get_swap_fee();
+/// Returns the quantity of the other token that was sent to the user, or credited to their
+/// balance if `to_balance` is set.
+pub async fn swap(mut order: ProcessedOrderInput, to_balance: bool) -> Result<Nat> {
+    let swap_fee = get_current_swap_fee();
     let original_quantity = order.quantity.clone();
     order.quantity = nat_x_float(order.quantity, 1.0 - swap_fee).unwrap();
     let mut lp_credit = original_quantity - order.quantity.clone();
@@ -119,11 +132,12 @@ pub async fn swap(mut order: ProcessedOrderInput) {
         Ok(s.bid_ask
             .execute_swap(order.side.clone(), order.quantity.clone()))
     });
-    let swap = swap.unwrap();
+    let swap = swap?;
     let (token_supplier, token_user) = match &order.side {
         Side::Buy => (EnokiToken::TokenA, EnokiToken::TokenB),
         Side::Sell => (EnokiToken::TokenB, EnokiToken::TokenA),
     };
+    let mut rounding_error = LiquidityAmount::default();
     let traded = STATE.with(|s| {
         let mut s = s.borrow_mut();
         let mut traded = LiquidityTrades::default();
@@ -133,10 +147,23 @@ pub async fn swap(mut order: ProcessedOrderInput) {
             .flat_map(|(_p, val)| val.iter().map(|info| info.quantity.clone()))
             .sum();
         let quantity_user = order.quantity.clone();
+        // the counterparties' prices may not cover the whole quantity because of rounding
+        let quantity_user_at_price = swap
+            .get_map_of_complement_token_by_broker(&token_user)
+            .into_values()
+            .fold(Nat::default(), |sum, next| sum + next);
+        let residue = if quantity_user > quantity_user_at_price {
+            quantity_user.clone() - quantity_user_at_price
+        } else {
+            Nat::default()
+        };
         traded
             .increased
             .get_mut(&token_user)
-            .add_assign((quantity_user + lp_credit).into());
+            .add_assign((quantity_user - residue.clone()).into());
+        rounding_error
+            .get_mut(&token_user)
+            .add_assign(residue.into());
         traded
             .decreased
             .get_mut(&token_supplier)
@@ -146,10 +173,12 @@ pub async fn swap(mut order: ProcessedOrderInput) {
             .unwrap();
         traded
     });
+    let quantity_received: Nat = traded.decreased.get(&token_supplier).clone().into();
     if let Err(error) = payoffs::send_swap_tokens(
         order.user,
         &token_supplier,
-        traded.decreased.get(&token_supplier).clone().into(),
+        quantity_received.clone(),
+        to_balance,
     )
     .await
     {
@@ -158,12 +187,28 @@ pub async fn swap(mut order: ProcessedOrderInput) {
                 .available_liquidity
                 .add_assign(traded.decreased)
         });
-        panic!("[broker] error with swap: {:?}", error);
+        ic_cdk::print(format!("[broker] error with swap: {:?}", error));
+        return Err(error);
+    }
+    if to_balance {
+        balances::credit_token(order.user, &token_supplier, quantity_received.clone());
     }
+    let mut fees_earned = LiquidityAmount::default();
+    fees_earned
+        .get_mut(&token_user)
+        .add_assign(referrals::share_fee(order.user, &token_user, lp_credit).into());
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.available_liquidity.add_assign(traded.increased.clone());
+        s.available_liquidity.add_assign(fees_earned.clone());
+        s.available_liquidity.add_assign(rounding_error.clone());
         s.liquidity_traded.add_assign(traded);
+        s.fees_earned
+            .get_or_insert_with(Default::default)
+            .add_assign(fees_earned);
+        s.rounding_error
+            .get_or_insert_with(Default::default)
+            .add_assign(rounding_error);
     });
     if market_maker_reward != 0u32 {
         pay_rewards_to_market_makers(
@@ -175,13 +220,21 @@ pub async fn swap(mut order: ProcessedOrderInput) {
             swap,
         );
     }
+    Ok(quantity_received)
 }
 
+/// Keeps the designated market makers' share of the reward in their pool, and splits the rest
+/// among the market makers whose liquidity was used.
 fn pay_rewards_to_market_makers(
     reward: Nat,
     reward_token: &EnokiToken,
     reference: LiquidityReference,
 ) {
+    let pool_share = nat_x_float(reward.clone(), get_market_maker_pool_share())
+        .unwrap_or_default()
+        .min(reward.clone());
+    payoffs::add_to_designated_pool(reward_token, pool_share.clone());
+    let reward = reward - pool_share;
     let amount_by_user = reference.get_map_of_complement_token_by_broker(reward_token);
     let total = amount_by_user
         .values()
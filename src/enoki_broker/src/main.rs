@@ -1,14 +1,19 @@
 extern crate core;
 
+#[allow(unused_imports)]
+use std::collections::HashMap;
+
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
 #[allow(unused_imports)]
 use candid::Nat;
 #[allow(unused_imports)]
+use enoki_exchange_shared::has_rejected_deposits::RejectedDeposit;
+#[allow(unused_imports)]
 use enoki_exchange_shared::has_token_info::{self, AssignedShards, TokenPairInfo};
 #[allow(unused_imports)]
-use enoki_exchange_shared::has_trading_fees::TradingFees;
+use enoki_exchange_shared::has_trading_fees::{FeeSweep, TradingFees};
 #[allow(unused_imports)]
 use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
 use enoki_exchange_shared::is_managed::{self, ManagementData};
@@ -18,7 +23,9 @@ use enoki_exchange_shared::liquidity::*;
 #[allow(unused_imports)]
 use enoki_exchange_shared::types::*;
 #[allow(unused_imports)]
-use payoffs::FirstTransfer;
+use orders::FailedOrder;
+#[allow(unused_imports)]
+use payoffs::{DelayedPayout, ExchangeBatch, LegacyTransferPair, Settlement};
 
 mod token_liquidity_params;
 mod orders;
@@ -28,6 +35,9 @@ mod other_brokers;
 mod upgrade;
 mod shared_candid_methods;
 mod users;
+mod balances;
+mod trading_volume;
+mod referrals;
 
 #[init]
 #[candid_method(init)]
@@ -40,10 +50,13 @@ fn init() {
 
 #[update(name = "finishInit")]
 #[candid_method(update, rename = "finishInit")]
-fn finish_init(exchange: Principal) {
-    assert_is_owner().unwrap();
-    assert_eq!(is_managed::get_manager(), Principal::anonymous(), "already init");
+fn finish_init(exchange: Principal) -> TxResult<()> {
+    assert_is_owner()?;
+    if is_managed::get_manager() != Principal::anonymous() {
+        return Err(TxError::AlreadyInitialized);
+    }
     is_managed::init_manager(ManagementData { manager: exchange });
+    Ok(())
 }
 
 #[cfg(any(target_arch = "wasm32", test))]
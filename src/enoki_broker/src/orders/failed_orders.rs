@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+
+use enoki_exchange_shared::types::*;
+
+/// A completed order whose fills could not be turned into exchanges. It is retried every round
+/// until it goes through or the owner cancels or resolves it.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct FailedOrder {
+    pub order: Order,
+    pub reason: String,
+    pub first_failed_at: u64,
+    pub last_attempt_at: u64,
+    pub attempts: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FailedOrders {
+    orders: BTreeMap<u64, FailedOrder>,
+}
+
+impl FailedOrder {
+    /// What the user paid for the fills of the order, held by the broker until the exchange goes
+    /// through. The unfilled quantity was already refunded when the order completed.
+    pub fn locked_funds(&self) -> TokenAmount {
+        let order = &self.order;
+        TokenAmount {
+            token: match order.info.side {
+                Side::Buy => EnokiToken::TokenB,
+                Side::Sell => EnokiToken::TokenA,
+            },
+            amount: (order.info.quantity.clone() - order.state.quantity_remaining.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl FailedOrders {
+    pub fn all_orders(&self) -> Vec<Order> {
+        self.orders
+            .values()
+            .map(|failed| failed.order.clone())
+            .collect()
+    }
+    pub fn get(&self, id: u64) -> Option<&FailedOrder> {
+        self.orders.get(&id)
+    }
+    pub fn remove(&mut self, id: u64) -> Option<FailedOrder> {
+        self.orders.remove(&id)
+    }
+    /// Records the outcome of an attempt at the given orders: those in `failed` are (still)
+    /// failing, the others went through.
+    pub fn record_attempt(&mut self, attempted: &[u64], failed: Vec<(Order, String)>, now: u64) {
+        for id in attempted {
+            if !failed.iter().any(|(order, _)| order.info.id == *id) {
+                self.orders.remove(id);
+            }
+        }
+        for (order, reason) in failed {
+            let failed_order = self
+                .orders
+                .entry(order.info.id)
+                .or_insert_with(|| FailedOrder {
+                    order: order.clone(),
+                    reason: reason.clone(),
+                    first_failed_at: now,
+                    last_attempt_at: now,
+                    attempts: 0,
+                });
+            failed_order.order = order;
+            failed_order.reason = reason;
+            failed_order.last_attempt_at = now;
+            failed_order.attempts += 1;
+        }
+    }
+    pub fn list(&self, user: Option<Principal>) -> Vec<FailedOrder> {
+        self.orders
+            .values()
+            .filter(|failed| user.is_none() || user == Some(failed.order.info.user))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod failed_orders_tests {
+    use super::*;
+
+    fn order(id: u64) -> Order {
+        let mut order: Order = OrderInfo {
+            broker: Principal::anonymous(),
+            user: Principal::from_slice(&[id as u8]),
+            id,
+            side: Side::Buy,
+            maker_taker: MakerTaker::MakerOrTaker,
+            limit_price: 1,
+            quantity: Default::default(),
+            expiration_time: None,
+            fees: Default::default(),
+        }
+        .into();
+        order.state.status = OrderStatus::Completed;
+        order
+    }
+
+    #[test]
+    fn test_record_attempt() {
+        let mut failed_orders = FailedOrders::default();
+        failed_orders.record_attempt(&[1, 2], vec![(order(1), "first".to_string())], 10);
+        failed_orders.record_attempt(&[1], vec![(order(1), "second".to_string())], 20);
+        let failed = failed_orders.get(1).unwrap();
+        assert_eq!(failed.attempts, 2);
+        assert_eq!(failed.first_failed_at, 10);
+        assert_eq!(failed.last_attempt_at, 20);
+        assert_eq!(failed.reason, "second");
+        assert!(failed_orders.get(2).is_none());
+
+        failed_orders.record_attempt(&[1], vec![], 30);
+        assert!(failed_orders.list(None).is_empty());
+    }
+
+    #[test]
+    fn test_locked_funds() {
+        let mut failed_orders = FailedOrders::default();
+        let mut sell = order(1);
+        sell.info.side = Side::Sell;
+        sell.info.quantity = StableNat::from(100);
+        sell.state.quantity_remaining = StableNat::from(30);
+        failed_orders.record_attempt(&[1], vec![(sell, "failed".to_string())], 10);
+
+        let failed = failed_orders.remove(1).unwrap();
+        let locked = failed.locked_funds();
+        assert_eq!(locked.token, EnokiToken::TokenA);
+        assert_eq!(locked.amount, StableNat::from(70));
+        assert!(failed_orders.get(1).is_none());
+    }
+}
@@ -1,23 +1,27 @@
 use std::cell::RefCell;
 
-use candid::{candid_method, CandidType, Principal};
+use candid::{candid_method, CandidType, Nat, Principal};
 use ic_cdk_macros::*;
 
 use enoki_exchange_shared::has_sharded_users::register_user;
+use enoki_exchange_shared::has_trading_fees::{self, TradingFees};
 use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
 use enoki_exchange_shared::is_managed::assert_is_manager;
+use enoki_exchange_shared::is_owned::assert_is_owner;
 use enoki_exchange_shared::liquidity::{
     RequestForNewLiquidityTarget, ResponseAboutLiquidityChanges,
 };
 use enoki_exchange_shared::types::*;
-use enoki_exchange_shared::{has_sharded_users, has_token_info};
+use enoki_exchange_shared::{has_rejected_deposits, has_sharded_users, has_token_info};
 
+use crate::orders::failed_orders::FailedOrders;
 use crate::orders::order_book::OrderBook;
 use crate::orders::order_history::OrderHistory;
-use crate::payoffs::distribute_market_maker_rewards;
+use crate::payoffs::net_rewards_with_other_brokers;
 use crate::users::assert_is_user;
-use crate::{liquidity, payoffs};
+use crate::{balances, liquidity, payoffs, trading_volume};
 
+mod failed_orders;
 mod order_book;
 mod order_history;
 mod order_parser;
@@ -30,20 +34,95 @@ thread_local! {
 pub struct OrdersState {
     order_book: OrderBook,
     order_history: OrderHistory,
-    failed_orders: Vec<Order>,
+    /// orders that failed before they were tracked with their reason, moved into
+    /// `failed_order_records` on upgrade
+    failed_orders: Option<Vec<Order>>,
+    failed_order_records: Option<FailedOrders>,
 }
 
+fn with_failed_orders<F: FnOnce(&mut FailedOrders) -> R, R>(f: F) -> R {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        f(s.failed_order_records.get_or_insert_with(Default::default))
+    })
+}
+
+pub use failed_orders::FailedOrder;
+
+/// Called by the exchange at the start of each round. A fee change due this round is applied in
+/// the same call, so every broker switches between the same two rounds; orders already placed
+/// keep the fees they were placed with.
 #[update(name = "retrieveOrders")]
 #[candid_method(update, rename = "retrieveOrders")]
-fn retrieve_orders() -> (Vec<OrderInfo>, Vec<OrderInfo>) {
-    assert_is_manager().unwrap();
-    STATE.with(|s| s.borrow_mut().order_book.lock_pending_orders())
+fn retrieve_orders(
+    fee_change: Option<TradingFees>,
+) -> TxResult<(Vec<OrderInfo>, Vec<OrderInfo>)> {
+    assert_is_manager()?;
+    if let Some(fees) = fee_change {
+        has_trading_fees::init_fee_info(fees)?;
+    }
+    Ok(STATE.with(|s| s.borrow_mut().order_book.lock_pending_orders()))
+}
+
+pub fn count_failed_orders() -> u64 {
+    with_failed_orders(|failed| failed.list(None).len() as u64)
 }
 
 #[query(name = "getFailedOrders")]
 #[candid_method(query, rename = "getFailedOrders")]
-fn get_failed_orders() -> Vec<Order> {
-    STATE.with(|s| s.borrow().failed_orders.clone())
+fn get_failed_orders() -> Vec<FailedOrder> {
+    with_failed_orders(|failed| failed.list(None))
+}
+
+/// Orders of `user` whose payout is delayed because their exchange failed.
+#[query(name = "getUserFailedOrders")]
+#[candid_method(query, rename = "getUserFailedOrders")]
+fn get_user_failed_orders(user: Principal) -> Vec<FailedOrder> {
+    with_failed_orders(|failed| failed.list(Some(user)))
+}
+
+/// Retries the exchange of a failed order right away. Returns whether it went through.
+#[update(name = "retryFailedOrder")]
+#[candid_method(update, rename = "retryFailedOrder")]
+fn retry_failed_order(id: u64) -> TxResult<bool> {
+    assert_is_owner()?;
+    let order = with_failed_orders(|failed| failed.get(id).map(|failed| failed.order.clone()))
+        .ok_or(TxError::OrderNotFound { id })?;
+    let failed = payoffs::exchange_tokens(vec![order]);
+    let succeeded = failed.is_empty();
+    with_failed_orders(|failed_orders| {
+        failed_orders.record_attempt(&[id], failed, ic_cdk::api::time())
+    });
+    Ok(succeeded)
+}
+
+/// Gives up on a failed order and returns what the user paid for its fills to their balance.
+/// The counterparties of the fills are not paid.
+#[update(name = "cancelFailedOrder")]
+#[candid_method(update, rename = "cancelFailedOrder")]
+fn cancel_failed_order(id: u64) -> TxResult<()> {
+    assert_is_owner()?;
+    let failed =
+        with_failed_orders(|failed| failed.remove(id)).ok_or(TxError::OrderNotFound { id })?;
+    let locked = failed.locked_funds();
+    ic_cdk::println!(
+        "[broker] cancelling failed order {}, refunding {:?}",
+        id,
+        locked
+    );
+    balances::credit_token(failed.order.info.user, &locked.token, locked.amount.into());
+    Ok(())
+}
+
+/// Forgets a failed order that was settled by other means, with both the user and the
+/// counterparties of its fills paid.
+#[update(name = "resolveFailedOrder")]
+#[candid_method(update, rename = "resolveFailedOrder")]
+fn resolve_failed_order(id: u64) -> TxResult<()> {
+    assert_is_owner()?;
+    with_failed_orders(|failed| failed.remove(id)).ok_or(TxError::OrderNotFound { id })?;
+    ic_cdk::println!("[broker] failed order {} was resolved manually", id);
+    Ok(())
 }
 
 #[update(name = "submitCompletedOrders")]
@@ -52,8 +131,8 @@ fn submit_completed_orders(
     completed: Vec<Order>,
     aggregate_bid_ask: AggregateBidAsk,
     request: RequestForNewLiquidityTarget,
-) -> ResponseAboutLiquidityChanges {
-    assert_is_manager().unwrap();
+) -> TxResult<ResponseAboutLiquidityChanges> {
+    assert_is_manager()?;
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         for order in completed.iter() {
@@ -62,23 +141,61 @@ fn submit_completed_orders(
         }
     });
     let response = liquidity::update_liquidity_target(aggregate_bid_ask, request);
+    refund_unfilled_quantities(&completed);
     resolve_completed_orders(completed);
-    ic_cdk::spawn(distribute_market_maker_rewards());
-    response
+    ic_cdk::spawn(net_rewards_with_other_brokers());
+    ic_cdk::spawn(payoffs::process_settlements());
+    Ok(response)
+}
+
+/// Returns what is left of cancelled, expired or rejected orders to the users' balances.
+fn refund_unfilled_quantities(orders: &[Order]) {
+    for order in orders {
+        if !order.state.quantity_remaining.is_nonzero() {
+            continue;
+        }
+        let token = match order.info.side {
+            Side::Buy => EnokiToken::TokenB,
+            Side::Sell => EnokiToken::TokenA,
+        };
+        balances::credit_token(
+            order.info.user,
+            &token,
+            order.state.quantity_remaining.clone().into(),
+        );
+    }
 }
 
 fn resolve_completed_orders(mut orders: Vec<Order>) {
-    let mut older_orders = STATE.with(|s| std::mem::take(&mut s.borrow_mut().failed_orders));
+    trading_volume::record_completed_orders(&orders);
+    let mut older_orders = with_failed_orders(|failed| failed.all_orders());
     orders.append(&mut older_orders);
+    let attempted: Vec<u64> = orders.iter().map(|order| order.info.id).collect();
     let failed = payoffs::exchange_tokens(orders);
-    STATE.with(|s| s.borrow_mut().failed_orders = failed);
+    with_failed_orders(|failed_orders| {
+        failed_orders.record_attempt(&attempted, failed, ic_cdk::api::time())
+    });
 }
 
+/// Called by the token shards through `shardTransferAndCall`, which expect text back. A rejected
+/// order is refunded and answered with text starting with `REJECTED_PREFIX`.
 #[update(name = "limitOrder")]
 #[candid_method(update, rename = "limitOrder")]
-fn submit_limit_order(notification: ShardedTransferNotification) -> String {
-    let input = order_parser::validate_order_input(notification, false).unwrap();
-    assert_is_user(input.user).unwrap();
+async fn submit_limit_order(notification: ShardedTransferNotification) -> String {
+    let token = match has_rejected_deposits::parse_notification(&notification) {
+        Ok(token) => token,
+        Err(error) => {
+            return has_rejected_deposits::reject_without_refund("limitOrder", error.into())
+        }
+    };
+    let input = match order_parser::validate_order_input(&token, &notification, false) {
+        Ok(input) => input,
+        Err(error) => {
+            let refundable = notification.value.clone();
+            return reject_notification(notification, token, "limitOrder", error.into(), refundable)
+                .await;
+        }
+    };
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         let (user, id) = s.order_book.create_limit_order(input);
@@ -87,32 +204,116 @@ fn submit_limit_order(notification: ShardedTransferNotification) -> String {
     })
 }
 
+/// If the swap itself fails, what is left after the deposit fee is refunded.
 #[update(name = "swap")]
 #[candid_method(update)]
 async fn swap(notification: ShardedTransferNotification) -> String {
-    let input = order_parser::validate_order_input(notification, true).unwrap();
-    liquidity::swap(input).await;
-    "OK".to_string()
+    let token = match has_rejected_deposits::parse_notification(&notification) {
+        Ok(token) => token,
+        Err(error) => return has_rejected_deposits::reject_without_refund("swap", error.into()),
+    };
+    let input = match order_parser::validate_order_input(&token, &notification, true) {
+        Ok(input) => input,
+        Err(error) => {
+            let refundable = notification.value.clone();
+            return reject_notification(notification, token, "swap", error.into(), refundable).await;
+        }
+    };
+    let quantity = input.quantity.clone();
+    match liquidity::swap(input, false).await {
+        Ok(received) => received.to_string(),
+        Err(error) => reject_notification(notification, token, "swap", error.into(), quantity).await,
+    }
+}
+
+pub(crate) async fn reject_notification(
+    notification: ShardedTransferNotification,
+    token: EnokiToken,
+    method: &str,
+    error: TxError,
+    refundable: Nat,
+) -> String {
+    let transfer_fee = payoffs::get_fee_for_transfer(&token).await;
+    has_rejected_deposits::refund_rejected_deposit(
+        notification,
+        token,
+        method,
+        error,
+        refundable,
+        transfer_fee,
+    )
+    .await
+}
+
+/// Places a limit order paid for from the caller's balance.
+#[update(name = "limitOrderFromBalance")]
+#[candid_method(update, rename = "limitOrderFromBalance")]
+fn submit_limit_order_from_balance(
+    token: EnokiToken,
+    quantity: Nat,
+    order: OrderInput,
+) -> TxResult<u64> {
+    let user = ic_cdk::caller();
+    let input =
+        order_parser::validate_balance_order_input(user, &token, quantity.clone(), order, false)?;
+    assert_is_user(user)?;
+    balances::debit(user, &token, quantity.into())?;
+    Ok(STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let (user, id) = s.order_book.create_limit_order(input);
+        s.order_history.add_new_order(user, id);
+        id
+    }))
+}
+
+/// Swaps tokens from the caller's balance and credits what is received to it.
+/// If the swap fails, the balance is restored.
+#[update(name = "swapFromBalance")]
+#[candid_method(update, rename = "swapFromBalance")]
+async fn swap_from_balance(
+    token: EnokiToken,
+    quantity: Nat,
+    limit_price_in_b: f64,
+) -> TxResult<Nat> {
+    let user = ic_cdk::caller();
+    let order = OrderInput {
+        allow_taker: true,
+        limit_price_in_b,
+        expiration_time: None,
+    };
+    let input =
+        order_parser::validate_balance_order_input(user, &token, quantity.clone(), order, true)?;
+    balances::debit(user, &token, quantity.clone().into())?;
+    match liquidity::swap(input, true).await {
+        Ok(received) => Ok(received),
+        Err(error) => {
+            balances::credit_token(user, &token, quantity);
+            Err(error.into())
+        }
+    }
 }
 
 #[update(name = "cancelOrder")]
 #[candid_method(update, rename = "cancelOrder")]
-fn cancel_order(order_id: u64) {
+fn cancel_order(order_id: u64) -> TxResult<()> {
     let from = ic_cdk::caller();
-    STATE.with(|s| s.borrow_mut().order_book.try_cancel_order(order_id, from));
+    STATE.with(|s| s.borrow_mut().order_book.try_cancel_order(order_id, from))
 }
 
 #[update(name = "cancelAllOpenOrders")]
 #[candid_method(update, rename = "cancelAllOpenOrders")]
-fn cancel_all_open_orders() {
+fn cancel_all_open_orders() -> TxResult<()> {
     let from = ic_cdk::caller();
+    assert_is_user(from)?;
     let orders = get_open_orders(from);
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         for order in orders.open_orders {
-            s.order_book.try_cancel_order(order.id, from);
+            // orders not yet sent to the exchange cannot be cancelled
+            let _ = s.order_book.try_cancel_order(order.id, from);
         }
     });
+    Ok(())
 }
 
 #[query(name = "getOpenOrders")]
@@ -180,14 +381,24 @@ pub fn is_user_registered(user: Principal) -> bool {
 
 #[update(name = "register")]
 #[candid_method(update)]
-async fn register(user: Principal) {
-    register_user(user).await.unwrap();
+async fn register(user: Principal) -> TxResult<()> {
+    Ok(register_user(user).await?)
 }
 
 pub fn export_stable_storage() -> OrdersState {
     STATE.with(|s| s.take())
 }
 
-pub fn import_stable_storage(data: OrdersState) {
+pub fn import_stable_storage(mut data: OrdersState) {
+    let legacy_failed: Vec<(Order, String)> = data
+        .failed_orders
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|order| (order, "failed before upgrade".to_string()))
+        .collect();
+    data.failed_order_records
+        .get_or_insert_with(Default::default)
+        .record_attempt(&[], legacy_failed, ic_cdk::api::time());
     STATE.with(|s| s.replace(data));
 }
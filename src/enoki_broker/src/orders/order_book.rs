@@ -4,6 +4,8 @@ use candid::{CandidType, Principal};
 
 use enoki_exchange_shared::types::*;
 
+use crate::trading_volume;
+
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct OrderBook {
     last_id: u64,
@@ -53,6 +55,7 @@ impl OrderBook {
             limit_price: input.limit_price_in_b,
             quantity: input.quantity.into(),
             expiration_time: input.expiration_time,
+            fees: Some(trading_volume::get_user_fees(input.user)),
         };
         self.pending_orders.insert(id, order);
         (input.user, id)
@@ -73,9 +76,16 @@ impl OrderBook {
         self.orders_to_cancel.remove(&id);
         self.pending_orders_to_cancel.remove(&id);
     }
-    pub fn try_cancel_order(&mut self, id: u64, user: Principal) {
-        let info = self.orders.get(&id).expect("order not found").clone();
-        assert_eq!(user, info.user, "invalid order id");
+    pub fn try_cancel_order(&mut self, id: u64, user: Principal) -> TxResult<()> {
+        let info = self
+            .orders
+            .get(&id)
+            .ok_or(TxError::OrderNotFound { id })?
+            .clone();
+        if user != info.user {
+            return Err(TxError::NotOrderOwner { id });
+        }
         self.pending_orders_to_cancel.insert(id, info);
+        Ok(())
     }
 }
@@ -1,21 +1,54 @@
-use enoki_exchange_shared::has_token_info;
+use candid::{Nat, Principal};
+
 use enoki_exchange_shared::has_token_info::price_in_b_float_to_u64;
 use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
 use enoki_exchange_shared::types::*;
 use crate::orders::is_user_registered;
 
-use crate::payoffs::charge_deposit_fee;
+use crate::payoffs::{charge_deposit_fee, quantity_after_deposit_fee};
+use crate::users::{assert_accepts_orders, assert_is_user};
 
+/// Validates an order sent with `shardTransferAndCall`. The deposit fee is only charged once the
+/// order is accepted, so a rejected notification can be refunded in full.
 pub fn validate_order_input(
-    notification: ShardedTransferNotification,
+    token: &EnokiToken,
+    notification: &ShardedTransferNotification,
     is_swap: bool,
 ) -> Result<ProcessedOrderInput> {
-    let token = has_token_info::parse_from()?;
+    assert_accepts_orders()?;
     let user = notification.from;
     let order: OrderInput = serde_json::from_str(&notification.data)
         .map_err(|e| TxError::ParsingError(e.to_string()))?;
-    let quantity = charge_deposit_fee(&token, notification.value)?;
-    let side = match &token {
+    let quantity = quantity_after_deposit_fee(token, notification.value.clone())?;
+    assert_is_user(user)?;
+    let input = process_order_input(user, token, quantity, order, is_swap)?;
+    charge_deposit_fee(user, token, notification.value.clone())?;
+    Ok(input)
+}
+
+/// Validates an order paid for from the user's balance, so no deposit fee is charged.
+pub fn validate_balance_order_input(
+    user: Principal,
+    token: &EnokiToken,
+    quantity: Nat,
+    order: OrderInput,
+    is_swap: bool,
+) -> Result<ProcessedOrderInput> {
+    assert_accepts_orders()?;
+    if quantity == 0u32 {
+        return Err(TxError::QuantityTooLow.into());
+    }
+    process_order_input(user, token, quantity, order, is_swap)
+}
+
+fn process_order_input(
+    user: Principal,
+    token: &EnokiToken,
+    quantity: Nat,
+    order: OrderInput,
+    is_swap: bool,
+) -> Result<ProcessedOrderInput> {
+    let side = match token {
         EnokiToken::TokenA => Side::Sell,
         EnokiToken::TokenB => Side::Buy,
     };
@@ -21,9 +21,10 @@ pub fn assert_is_broker(principal: Principal) -> Result<()> {
 
 #[update(name = "addBroker")]
 #[candid_method(update, rename = "addBroker")]
-fn add_broker(principal: Principal) {
-    assert_is_manager().unwrap();
+fn add_broker(principal: Principal) -> TxResult<()> {
+    assert_is_manager()?;
     STATE.with(|s| s.borrow_mut().other_brokers.insert(principal));
+    Ok(())
 }
 
 pub fn init_brokers(brokers: Vec<Principal>) {
@@ -1,67 +1,17 @@
-use candid::{Nat, Principal};
-use futures::FutureExt;
+use candid::Nat;
 
-use enoki_exchange_shared::has_sharded_users::get_user_shard;
 use enoki_exchange_shared::has_token_info;
-use enoki_exchange_shared::has_token_info::get_token_address;
-use enoki_exchange_shared::has_trading_fees::get_limit_order_taker_fee;
 use enoki_exchange_shared::types::*;
-use enoki_exchange_shared::utils::nat_div_float;
+use enoki_exchange_shared::utils::{nat_div_float, nat_x_float};
 
-use crate::payoffs::{
-    with_failed_exchanges_mut, with_pending_transfers_mut, FirstTransfer, TokenExchangeInfo,
-    TransferInfo, TransferPair,
-};
+use crate::balances;
+use crate::payoffs;
+use crate::payoffs::{fees, settlements, TokenExchangeInfo, TransferInfo};
 
-async fn send_funds_from(id: String, broker: Principal, info: FirstTransfer) -> Result<()> {
-    if broker == ic_cdk::id() {
-        let shard_id_to_retrieve = get_user_shard(
-            info.user_for_shard_id_to_retrieve,
-            get_token_address(&info.token.opposite()),
-        )?;
-        send_funds_internal(id, info, ic_cdk::id(), shard_id_to_retrieve).await
-    } else {
-        ic_cdk::println!("[broker] sending exchange id {} to broker {}", id, broker);
-        ic_cdk::call(broker, "sendFunds", (id, info))
-            .await
-            .map_err(|e| e.into_tx_error())
-    }
-}
-
-pub async fn send_funds_internal(
-    id: String,
-    info: FirstTransfer,
-    notify_principal: Principal,
-    shard_id_to_retrieve: Principal,
-) -> Result<()> {
-    let assigned_token_shard = has_token_info::get_assigned_shard(&info.token);
-    let to_shard = info.to_shard;
-    let message = format!("{}|{}", id, shard_id_to_retrieve.to_string());
-    ic_cdk::println!("[broker] executing first half of exchange id {}", id);
-    let result: Result<(String,)> = ic_cdk::call(
-        assigned_token_shard,
-        "shardTransferAndCall",
-        (
-            to_shard,
-            info.to,
-            info.amount,
-            notify_principal,
-            "fundsSent",
-            message,
-        ),
-    )
-    .await
-    .map_err(|e| e.into_tx_error());
-    result.map(|_| ())
-}
-
-pub fn exchange_tokens(orders: Vec<Order>) -> Vec<Order> {
-    let mut failed_orders: Vec<Order> = Vec::new();
-    let plus_fees = |val: Nat| -> Result<Nat> {
-        let taker_fee = get_limit_order_taker_fee();
-        nat_div_float(val, 1.0 - taker_fee)
-    };
-    let exchanges: Vec<TokenExchangeInfo> = orders
+/// Returns the orders whose exchanges could not be computed, with the reason.
+pub fn exchange_tokens(orders: Vec<Order>) -> Vec<(Order, String)> {
+    let mut failed_orders: Vec<(Order, String)> = Vec::new();
+    let exchanges: Vec<(TokenExchangeInfo, StableNat)> = orders
         .into_iter()
         .filter_map(|order| {
             let order_info = order.info.clone();
@@ -70,106 +20,125 @@ pub fn exchange_tokens(orders: Vec<Order>) -> Vec<Order> {
                 .state
                 .marker_makers
                 .into_iter()
-                .map(move |market_maker| match &order_info.side {
-                    Side::Buy => Ok(TokenExchangeInfo {
-                        local_user: TransferInfo {
-                            broker: market_maker.broker, // should be paid by
-                            token: EnokiToken::TokenA,
-                            to: order_info.user,
-                            amount: market_maker.quantity.clone(),
-                        },
-                        other_user: TransferInfo {
-                            broker: ic_cdk::id(), // should be paid by
-                            token: EnokiToken::TokenB,
-                            to: market_maker.user,
-                            amount: plus_fees(has_token_info::quantity_a_to_b(
-                                market_maker.quantity.clone().into(),
-                                market_maker.price,
-                            )?)?
-                            .into(),
-                        },
-                    }),
-                    Side::Sell => Ok(TokenExchangeInfo {
-                        other_user: TransferInfo {
-                            broker: ic_cdk::id(), // should be paid by
-                            token: EnokiToken::TokenA,
-                            to: market_maker.user,
-                            amount: plus_fees(has_token_info::quantity_b_to_a(
-                                market_maker.quantity.clone().into(),
-                                market_maker.price,
-                            )?)?
-                            .into(),
-                        },
-                        local_user: TransferInfo {
-                            broker: market_maker.broker, // should be paid by
-                            token: EnokiToken::TokenB,
-                            to: order_info.user,
-                            amount: market_maker.quantity.clone(),
-                        },
-                    }),
-                })
-                .collect::<Result<Vec<TokenExchangeInfo>>>()
-                .map_err(|_| failed_orders.push(order))
+                .map(move |market_maker| get_exchange(&order_info, market_maker))
+                .collect::<Result<Vec<(TokenExchangeInfo, StableNat)>>>()
+                .map_err(|e| failed_orders.push((order, e.to_string())))
                 .ok()
         })
         .flat_map(|order| order)
         .collect();
+    let mut exchanges = exchanges;
+    exchanges.extend(
+        payoffs::take_legacy_exchanges()
+            .into_iter()
+            .map(|exchange| (exchange, StableNat::default())),
+    );
     ic_cdk::spawn(execute_exchanges(exchanges));
     failed_orders
 }
 
-async fn execute_exchanges(mut exchanges: Vec<TokenExchangeInfo>) {
-    let mut older_pending = with_failed_exchanges_mut(|failed| std::mem::take(failed));
-    exchanges.append(&mut older_pending);
+/// Returns the exchange between the taker and one of their makers, and the part of the taker's
+/// payment the broker keeps.
+fn get_exchange(
+    order_info: &OrderInfo,
+    market_maker: CounterpartyInfo,
+) -> Result<(TokenExchangeInfo, StableNat)> {
+    Ok(match &order_info.side {
+        Side::Buy => {
+            let (maker_amount, fee) = split_taker_payment(
+                has_token_info::quantity_a_to_b(
+                    market_maker.quantity.clone().into(),
+                    market_maker.price,
+                )?,
+                order_info.get_fees().taker_fee,
+                market_maker.get_maker_fee(),
+            )?;
+            let exchange = TokenExchangeInfo {
+                local_user: TransferInfo {
+                    broker: market_maker.broker, // should be paid by
+                    token: EnokiToken::TokenA,
+                    to: order_info.user,
+                    amount: market_maker.quantity.clone(),
+                },
+                other_user: TransferInfo {
+                    broker: ic_cdk::id(), // should be paid by
+                    token: EnokiToken::TokenB,
+                    to: market_maker.user,
+                    amount: maker_amount.into(),
+                },
+            };
+            (exchange, fee.into())
+        }
+        Side::Sell => {
+            let (maker_amount, fee) = split_taker_payment(
+                has_token_info::quantity_b_to_a(
+                    market_maker.quantity.clone().into(),
+                    market_maker.price,
+                )?,
+                order_info.get_fees().taker_fee,
+                market_maker.get_maker_fee(),
+            )?;
+            let exchange = TokenExchangeInfo {
+                other_user: TransferInfo {
+                    broker: ic_cdk::id(), // should be paid by
+                    token: EnokiToken::TokenA,
+                    to: market_maker.user,
+                    amount: maker_amount.into(),
+                },
+                local_user: TransferInfo {
+                    broker: market_maker.broker, // should be paid by
+                    token: EnokiToken::TokenB,
+                    to: order_info.user,
+                    amount: market_maker.quantity.clone(),
+                },
+            };
+            (exchange, fee.into())
+        }
+    })
+}
+
+/// Splits what the taker paid for `quantity` into what the maker receives after their fee (or
+/// rebate, capped by the payment) and what the broker keeps.
+fn split_taker_payment(quantity: Nat, taker_fee: f64, maker_fee: f64) -> Result<(Nat, Nat)> {
+    let taker_paid = nat_div_float(quantity.clone(), 1.0 - taker_fee)?;
+    let maker_amount = nat_x_float(quantity, 1.0 - maker_fee)?.min(taker_paid.clone());
+    Ok((maker_amount.clone(), taker_paid - maker_amount))
+}
 
-    let results: Vec<Option<TokenExchangeInfo>> =
-        futures::future::join_all(exchanges.into_iter().map(|exchange| {
-            execute_exchange(exchange.clone()).map(|res: Result<()>| {
-                if let Err(err) = res {
-                    ic_cdk::api::print(format!(
-                        "[broker] error exchanging tokens: {:?}. Input: {:?}",
-                        err, exchange
-                    ));
-                    Some(exchange)
-                } else {
-                    None
-                }
-            })
-        }))
-        .await;
-    let mut failed: Vec<_> = results.into_iter().filter_map(|r| r).collect();
-    if !failed.is_empty() {
-        with_failed_exchanges_mut(|f| f.append(&mut failed));
+/// Settles the exchanges of a round: exchanges between local users are credited right away, the
+/// others are netted into one settlement per broker.
+async fn execute_exchanges(exchanges: Vec<(TokenExchangeInfo, StableNat)>) {
+    let (local_credits, local_fees, batches) = settlements::net_exchanges(exchanges, ic_cdk::id());
+    for (user, credit) in local_credits {
+        balances::credit(user, &credit);
     }
+    fees::charge_trading_fees(&local_fees);
+    futures::future::join_all(
+        batches
+            .into_iter()
+            .map(|(broker, batch)| settlements::settle_batch(broker, batch)),
+    )
+    .await;
 }
 
-async fn execute_exchange(exchange: TokenExchangeInfo) -> Result<()> {
-    ic_cdk::api::print(format!("[broker] executing token exchange: {:?}", exchange));
+#[cfg(test)]
+mod exchange_tokens_tests {
+    use super::*;
 
-    let TokenExchangeInfo {
-        local_user,
-        other_user,
-    } = exchange;
+    #[test]
+    fn test_split_taker_payment() {
+        let (maker, fee) = split_taker_payment(Nat::from(1_000_000u32), 0.002, 0.001).unwrap();
+        assert_eq!(maker, Nat::from(999_000u32));
+        assert_eq!(
+            maker + fee,
+            nat_div_float(Nat::from(1_000_000u32), 0.998).unwrap()
+        );
 
-    let user_for_shard_id_to_retrieve = other_user.to;
-    let to_shard = get_user_shard(local_user.to, get_token_address(&local_user.token))?;
-    let id = with_pending_transfers_mut(|pending_transfers| {
-        pending_transfers.create_new(TransferPair {
-            waiting_on: local_user.clone(),
-            next_transfer: other_user,
-        })
-    });
-
-    send_funds_from(
-        id.to_string(),
-        local_user.broker,
-        FirstTransfer {
-            to: local_user.to,
-            to_shard,
-            token: local_user.token,
-            amount: local_user.amount.into(),
-            user_for_shard_id_to_retrieve,
-        },
-    )
-    .await
+        let (maker, fee) = split_taker_payment(Nat::from(1_000_000u32), 0.002, -0.01).unwrap();
+        assert_eq!(
+            maker,
+            nat_div_float(Nat::from(1_000_000u32), 0.998).unwrap()
+        );
+        assert_eq!(fee, Nat::from(0u32));
+    }
 }
@@ -1,17 +1,25 @@
 use std::cell::RefCell;
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::ops::AddAssign;
 
-use candid::{candid_method, CandidType, Nat};
+use candid::{candid_method, CandidType, Nat, Principal};
 use ic_cdk_macros::*;
 
+use enoki_exchange_shared::has_sharded_users::{get_user_shard, register_user};
 use enoki_exchange_shared::has_token_info;
 use enoki_exchange_shared::has_trading_fees;
-use enoki_exchange_shared::has_trading_fees::{get_deposit_fee, TradingFees};
-use enoki_exchange_shared::is_managed::assert_is_manager;
+use enoki_exchange_shared::has_trading_fees::{get_deposit_fee, FeeSweep, TradingFees};
+use enoki_exchange_shared::is_managed::{assert_is_manager, assert_is_owner_or_manager};
 use enoki_exchange_shared::is_owned::assert_is_owner;
 use enoki_exchange_shared::types::*;
 
+use crate::referrals;
+
+// deposit fees kept back to pay for the transfers of future payouts, as a multiple of the
+// transfer fee
+const RESERVED_TRANSFER_FEES: u32 = 100;
+
 thread_local! {
     static STATE: RefCell<AccruedFees> = RefCell::new(AccruedFees::default());
 }
@@ -19,8 +27,11 @@ thread_local! {
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct AccruedFees {
     deposit_fees: LiquidityAmount,
+    trading_fees: Option<LiquidityAmount>,
     token_a_transfer_fee: Option<StableNat>,
     token_b_transfer_fee: Option<StableNat>,
+    fee_recipient: Option<Principal>,
+    sweeps: Option<Vec<FeeSweep>>,
 }
 
 impl AccruedFees {
@@ -36,14 +47,36 @@ impl AccruedFees {
             EnokiToken::TokenB => &mut self.token_b_transfer_fee,
         }
     }
+    fn trading_fees_mut(&mut self) -> &mut LiquidityAmount {
+        self.trading_fees.get_or_insert_with(Default::default)
+    }
+    /// Takes the trading fees and the deposit fees above the reserve, returned in that order.
+    fn take_sweepable(&mut self, token: &EnokiToken, transfer_fee: &Nat) -> (StableNat, StableNat) {
+        let reserve: StableNat = (transfer_fee.clone() * RESERVED_TRANSFER_FEES).into();
+        let trading = std::mem::take(self.trading_fees_mut().get_mut(token));
+        let deposits = self.deposit_fees.get_mut(token);
+        let from_deposits = (deposits.clone() - reserve).unwrap_or_default();
+        deposits.safe_sub_assign(from_deposits.clone()).unwrap();
+        (trading, from_deposits)
+    }
+    fn restore_sweepable(&mut self, token: &EnokiToken, trading: StableNat, deposits: StableNat) {
+        self.trading_fees_mut().get_mut(token).add_assign(trading);
+        self.deposit_fees.get_mut(token).add_assign(deposits);
+    }
 }
 
-pub fn charge_deposit_fee(token: &EnokiToken, deposit_amount: Nat) -> Result<Nat> {
+/// Returns what is left of a deposit after the deposit fee, without charging it.
+pub fn quantity_after_deposit_fee(token: &EnokiToken, deposit_amount: Nat) -> Result<Nat> {
     let fee = get_deposit_fee(token);
     if deposit_amount <= fee {
         return Err(TxError::QuantityTooLow.into());
     }
-    let remaining = deposit_amount - fee.clone();
+    Ok(deposit_amount - fee)
+}
+
+pub fn charge_deposit_fee(user: Principal, token: &EnokiToken, deposit_amount: Nat) -> Result<Nat> {
+    let remaining = quantity_after_deposit_fee(token, deposit_amount.clone())?;
+    let fee = referrals::share_fee(user, token, deposit_amount - remaining.clone());
     STATE.with(|s| {
         s.borrow_mut()
             .deposit_fees
@@ -53,6 +86,22 @@ pub fn charge_deposit_fee(token: &EnokiToken, deposit_amount: Nat) -> Result<Nat
     Ok(remaining)
 }
 
+/// Keeps the part of the takers' payments that their makers did not receive, once the exchanges
+/// went through, less the share of the takers' referrers.
+pub fn charge_trading_fees(taker_fees: &HashMap<Principal, LiquidityAmount>) {
+    let mut kept = LiquidityAmount::default();
+    for (&user, fees) in taker_fees.iter() {
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            let fee = fees.get(&token).clone().to_nat();
+            if fee > 0u32 {
+                let fee = referrals::share_fee(user, &token, fee);
+                kept.get_mut(&token).add_assign(fee.into());
+            }
+        }
+    }
+    STATE.with(|s| s.borrow_mut().trading_fees_mut().add_assign(kept));
+}
+
 pub fn try_get_fee_for_transfer(token: &EnokiToken) -> Option<Nat> {
     STATE.with(|s| s.borrow().get_token_fee(token))
 }
@@ -95,21 +144,19 @@ pub async fn use_fee_for_transfer(token: &EnokiToken) -> Result<Nat> {
 
 #[update(name = "setFees")]
 #[candid_method(update, rename = "setFees")]
-fn set_fees(data: TradingFees) {
-    assert_is_manager().unwrap();
-    has_trading_fees::init_fee_info(data);
+fn set_fees(data: TradingFees) -> TxResult<()> {
+    assert_is_manager()?;
+    has_trading_fees::init_fee_info(data)?;
+    Ok(())
 }
 
 #[update(name = "updateUpstreamFees")]
 #[candid_method(update, rename = "updateUpstreamFees")]
-async fn update_upstream_fees() {
-    assert_is_owner().unwrap();
-    update_upstream_token_fee(&EnokiToken::TokenA)
-        .await
-        .unwrap();
-    update_upstream_token_fee(&EnokiToken::TokenB)
-        .await
-        .unwrap();
+async fn update_upstream_fees() -> TxResult<()> {
+    assert_is_owner()?;
+    update_upstream_token_fee(&EnokiToken::TokenA).await?;
+    update_upstream_token_fee(&EnokiToken::TokenB).await?;
+    Ok(())
 }
 
 async fn update_upstream_token_fee(token: &EnokiToken) -> Result<()> {
@@ -128,6 +175,89 @@ fn get_accrued_fees() -> LiquidityAmount {
     STATE.with(|s| s.borrow().deposit_fees.clone())
 }
 
+/// Sets where the accrued fees are sent, registering it with the token shards.
+#[update(name = "setFeeRecipient")]
+#[candid_method(update, rename = "setFeeRecipient")]
+async fn set_fee_recipient(recipient: Principal) -> TxResult<()> {
+    assert_is_owner_or_manager()?;
+    register_user(recipient).await?;
+    STATE.with(|s| s.borrow_mut().fee_recipient = Some(recipient));
+    Ok(())
+}
+
+#[query(name = "getFeeRecipient")]
+#[candid_method(query, rename = "getFeeRecipient")]
+fn get_fee_recipient() -> Option<Principal> {
+    STATE.with(|s| s.borrow().fee_recipient)
+}
+
+/// Sends the accrued trading fees, and the deposit fees not needed for future transfer fees, to
+/// the fee recipient. Returns the sweeps made, which are also added to the fee ledger.
+#[update(name = "withdrawAccruedFees")]
+#[candid_method(update, rename = "withdrawAccruedFees")]
+async fn withdraw_accrued_fees() -> TxResult<Vec<FeeSweep>> {
+    assert_is_owner_or_manager()?;
+    let recipient = STATE
+        .with(|s| s.borrow().fee_recipient)
+        .ok_or_else(|| TxError::NotInitialized("fee recipient".to_string()))?;
+    let mut sweeps = Vec::new();
+    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+        if let Some(sweep) = sweep_token(recipient, token).await? {
+            sweeps.push(sweep);
+        }
+    }
+    Ok(sweeps)
+}
+
+async fn sweep_token(recipient: Principal, token: EnokiToken) -> Result<Option<FeeSweep>> {
+    let transfer_fee = get_fee_for_transfer(&token).await?;
+    let recipient_shard = get_user_shard(recipient, has_token_info::get_token_address(&token))?;
+    let (trading, deposits) = STATE.with(|s| s.borrow_mut().take_sweepable(&token, &transfer_fee));
+    let amount = (trading.clone() + deposits.clone()).to_nat();
+    if amount <= transfer_fee {
+        STATE.with(|s| s.borrow_mut().restore_sweepable(&token, trading, deposits));
+        return Ok(None);
+    }
+    let response: Result<()> = ic_cdk::call(
+        has_token_info::get_assigned_shard(&token),
+        "shardTransfer",
+        (recipient_shard, recipient, amount.clone()),
+    )
+    .await
+    .map_err(|e| e.into_tx_error());
+    if let Err(error) = response {
+        STATE.with(|s| s.borrow_mut().restore_sweepable(&token, trading, deposits));
+        return Err(error);
+    }
+    let sweep = FeeSweep {
+        time: ic_cdk::api::time(),
+        recipient,
+        token,
+        amount: amount.into(),
+        transfer_fee: transfer_fee.into(),
+    };
+    ic_cdk::println!("[broker] swept accrued fees: {:?}", sweep);
+    STATE.with(|s| {
+        s.borrow_mut()
+            .sweeps
+            .get_or_insert_with(Default::default)
+            .push(sweep.clone())
+    });
+    Ok(Some(sweep))
+}
+
+#[query(name = "getFeeSweeps")]
+#[candid_method(query, rename = "getFeeSweeps")]
+fn get_fee_sweeps() -> Vec<FeeSweep> {
+    STATE.with(|s| s.borrow().sweeps.clone().unwrap_or_default())
+}
+
+#[query(name = "getAccruedTradingFees")]
+#[candid_method(query, rename = "getAccruedTradingFees")]
+fn get_accrued_trading_fees() -> LiquidityAmount {
+    STATE.with(|s| s.borrow().trading_fees.clone().unwrap_or_default())
+}
+
 pub fn export_stable_storage() -> AccruedFees {
     let data = STATE.with(|s| s.take());
     data
@@ -136,3 +266,31 @@ pub fn export_stable_storage() -> AccruedFees {
 pub fn import_stable_storage(data: AccruedFees) {
     STATE.with(|s| s.replace(data));
 }
+
+#[cfg(test)]
+mod fees_tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_keeps_transfer_fee_reserve() {
+        let mut fees = AccruedFees::default();
+        fees.deposit_fees.token_a = StableNat::from(1_500);
+        fees.trading_fees_mut().token_a = StableNat::from(300);
+        fees.trading_fees_mut().token_b = StableNat::from(40);
+
+        let (trading, deposits) = fees.take_sweepable(&EnokiToken::TokenA, &Nat::from(10u32));
+        assert_eq!(trading, StableNat::from(300));
+        assert_eq!(deposits, StableNat::from(500));
+        assert_eq!(fees.deposit_fees.token_a, StableNat::from(1_000));
+        assert!(!fees.trading_fees_mut().token_a.is_nonzero());
+        assert_eq!(fees.trading_fees_mut().token_b, StableNat::from(40));
+
+        fees.restore_sweepable(&EnokiToken::TokenA, trading, deposits);
+        assert_eq!(fees.deposit_fees.token_a, StableNat::from(1_500));
+        assert_eq!(fees.trading_fees_mut().token_a, StableNat::from(300));
+
+        // deposit fees below the reserve are all kept
+        let (_, deposits) = fees.take_sweepable(&EnokiToken::TokenB, &Nat::from(10u32));
+        assert!(!deposits.is_nonzero());
+    }
+}
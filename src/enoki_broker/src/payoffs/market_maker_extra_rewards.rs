@@ -6,8 +6,9 @@ use ic_cdk_macros::*;
 
 use enoki_exchange_shared::has_sharded_users::get_user_shard;
 use enoki_exchange_shared::has_token_info;
-use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
+use enoki_exchange_shared::is_managed::assert_is_manager;
 use enoki_exchange_shared::types::*;
+use enoki_exchange_shared::utils::nat_x_float;
 
 use crate::orders::add_accrued_extra_reward;
 use crate::other_brokers::assert_is_broker;
@@ -16,14 +17,78 @@ use crate::payoffs::fees::use_fee_for_transfer;
 use crate::payoffs::{fees, with_pending_market_maker_rewards};
 
 const MIN_AMOUNT_TO_SEND_WITH_RESPECT_TO_FEE: u64 = 10;
+const NETTING_INTERVAL: u64 = 3600 * 1_000_000_000;
 
+/// Rewards are claimed by the market makers rather than sent to them. Rewards owed to the users
+/// of other brokers are netted against what those brokers owe to local users, and only the net
+/// amount is transferred.
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct MarketMakerAccruedExtraRewards {
     local_rewards: HashMap<Principal, LiquidityAmount>,
     other_broker_rewards: HashMap<Principal, HashMap<Principal, LiquidityAmount>>,
+    owed_to_brokers: Option<HashMap<Principal, LiquidityAmount>>,
+    last_netting: Option<u64>,
+    designated_pool: Option<LiquidityAmount>,
+}
+
+fn total(rewards: &HashMap<Principal, LiquidityAmount>) -> LiquidityAmount {
+    rewards
+        .values()
+        .fold(LiquidityAmount::default(), |mut sum, next| {
+            sum.add_assign(next.clone());
+            sum
+        })
+}
+
+fn saturating_sub(lhs: &LiquidityAmount, rhs: &LiquidityAmount) -> LiquidityAmount {
+    LiquidityAmount {
+        token_a: (lhs.token_a.clone() - rhs.token_a.clone()).unwrap_or_default(),
+        token_b: (lhs.token_b.clone() - rhs.token_b.clone()).unwrap_or_default(),
+    }
+}
+
+impl MarketMakerAccruedExtraRewards {
+    fn owed_to_brokers_mut(&mut self) -> &mut HashMap<Principal, LiquidityAmount> {
+        self.owed_to_brokers.get_or_insert_with(Default::default)
+    }
+    fn designated_pool_mut(&mut self) -> &mut LiquidityAmount {
+        self.designated_pool.get_or_insert_with(Default::default)
+    }
+    fn add_other_broker_rewards(
+        &mut self,
+        broker: Principal,
+        rewards: HashMap<Principal, LiquidityAmount>,
+    ) {
+        let broker_rewards = self.other_broker_rewards.entry(broker).or_default();
+        for (user, reward) in rewards {
+            broker_rewards.entry(user).or_default().add_assign(reward);
+        }
+    }
+    /// Credits the local users what `broker` owes them, and adds what this broker owes on net to
+    /// its debt to `broker`. The local users can claim before the debts are paid.
+    fn record_netting(
+        &mut self,
+        broker: Principal,
+        owed_out: &HashMap<Principal, LiquidityAmount>,
+        owed_in: HashMap<Principal, LiquidityAmount>,
+    ) {
+        self.owed_to_brokers_mut()
+            .entry(broker)
+            .or_default()
+            .add_assign(saturating_sub(&total(owed_out), &total(&owed_in)));
+        for (user, reward) in owed_in {
+            self.local_rewards
+                .entry(user)
+                .or_default()
+                .add_assign(reward);
+        }
+    }
 }
 
 pub fn add_reward(broker: Principal, user: Principal, token: &EnokiToken, amount: Nat) {
+    if broker == ic_cdk::id() {
+        add_accrued_extra_reward(user, amount.clone().into(), token);
+    }
     with_pending_market_maker_rewards(|rewards| {
         if broker == ic_cdk::id() {
             rewards
@@ -45,201 +110,296 @@ pub fn add_reward(broker: Principal, user: Principal, token: &EnokiToken, amount
     })
 }
 
-#[update(name = "receiveMarketMakerRewards")]
-#[candid_method(update, rename = "receiveMarketMakerRewards")]
-fn receive_market_maker_rewards(notification: ShardedTransferNotification) -> String {
-    let token = has_token_info::parse_from().unwrap();
-    let broker = notification.from;
-    assert_is_broker(broker).unwrap();
-    let user_rewards: UserRewards = serde_json::from_str(&notification.data).unwrap();
-    assert_eq!(
-        user_rewards.0.values().cloned().sum::<StableNat>().to_nat(),
-        notification.value
-    );
-    for (user, reward) in user_rewards.0 {
-        add_reward(ic_cdk::id(), user, &token, reward.into());
+/// Keeps part of a swap market maker reward for the designated market makers.
+pub fn add_to_designated_pool(token: &EnokiToken, amount: Nat) {
+    with_pending_market_maker_rewards(|rewards| {
+        rewards
+            .designated_pool_mut()
+            .get_mut(token)
+            .add_assign(amount.into())
+    })
+}
+
+/// Splits the designated market maker pool among the makers in proportion to their score. What
+/// is lost to rounding stays in the pool.
+#[update(name = "distributeMarketMakerPool")]
+#[candid_method(update, rename = "distributeMarketMakerPool")]
+fn distribute_market_maker_pool(scores: Vec<MarketMakerScore>) -> TxResult<()> {
+    assert_is_manager()?;
+    let total_score: f64 = scores.iter().map(|score| score.score).sum();
+    if total_score <= 0.0 {
+        return Ok(());
     }
-    "OK".to_string()
+    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+        let pool: Nat = with_pending_market_maker_rewards(|rewards| {
+            rewards.designated_pool_mut().get(&token).clone().to_nat()
+        });
+        let mut remaining = pool.clone();
+        let mut user_rewards = vec![];
+        for score in scores.iter().filter(|score| score.score > 0.0) {
+            let reward =
+                nat_x_float(pool.clone(), score.score / total_score)?.min(remaining.clone());
+            remaining -= reward.clone();
+            user_rewards.push((score, reward));
+        }
+        with_pending_market_maker_rewards(|rewards| {
+            *rewards.designated_pool_mut().get_mut(&token) = remaining.into();
+        });
+        for (score, reward) in user_rewards {
+            add_reward(score.broker, score.user, &token, reward);
+        }
+    }
+    Ok(())
 }
 
-#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
-pub struct UserRewards(HashMap<Principal, StableNat>);
+#[query(name = "getMarketMakerPool")]
+#[candid_method(query, rename = "getMarketMakerPool")]
+fn get_market_maker_pool() -> LiquidityAmountNat {
+    with_pending_market_maker_rewards(|rewards| rewards.designated_pool.clone())
+        .unwrap_or_default()
+        .into()
+}
 
-impl UserRewards {
-    pub fn new(rewards: &HashMap<Principal, LiquidityAmount>, token: &EnokiToken) -> Self {
-        Self(
+/// Sends the caller's rewards in `token` to their wallet; the token takes its transfer fee out of
+/// them. Returns the amount sent.
+#[update(name = "claimRewards")]
+#[candid_method(update, rename = "claimRewards")]
+async fn claim_rewards(token: EnokiToken) -> TxResult<Nat> {
+    let user = ic_cdk::caller();
+    let transfer_fee = fees::get_fee_for_transfer(&token).await?;
+    let user_shard = get_user_shard(user, has_token_info::get_token_address(&token))?;
+    let amount: Nat = with_pending_market_maker_rewards(|rewards| {
+        let reward = rewards
+            .local_rewards
+            .entry(user)
+            .or_default()
+            .get_mut(&token);
+        if reward.compare_with(&transfer_fee) != std::cmp::Ordering::Greater {
+            return Err(TxError::QuantityTooLow);
+        }
+        Ok(std::mem::take(reward).to_nat())
+    })?;
+    ic_cdk::println!(
+        "[broker] market maker {} claimed {} {:?}",
+        user,
+        amount,
+        token
+    );
+    let result: Result<()> = ic_cdk::call(
+        has_token_info::get_assigned_shard(&token),
+        "shardTransfer",
+        (user_shard, user, amount.clone()),
+    )
+    .await
+    .map_err(|e| e.into_tx_error());
+    if let Err(error) = result {
+        with_pending_market_maker_rewards(|rewards| {
             rewards
-                .iter()
-                .map(|(&user, reward)| (user, reward.get(token).clone()))
-                .collect(),
-        )
+                .local_rewards
+                .entry(user)
+                .or_default()
+                .get_mut(&token)
+                .add_assign(amount.into())
+        });
+        return Err(error.into());
     }
+    Ok(amount)
 }
 
-pub async fn distribute_market_maker_rewards() {
-    let transfer_fee_a = if let Some(fee) = fees::try_get_fee_for_transfer(&EnokiToken::TokenA) {
-        fee
-    } else {
-        fees::get_fee_for_transfer(&EnokiToken::TokenA)
-            .await
-            .unwrap()
-    };
-    let transfer_fee_b = if let Some(fee) = fees::try_get_fee_for_transfer(&EnokiToken::TokenB) {
-        fee
-    } else {
-        fees::get_fee_for_transfer(&EnokiToken::TokenB)
-            .await
-            .unwrap()
-    };
-    distribute_local_rewards(transfer_fee_a.clone(), transfer_fee_b.clone()).await;
-    distribute_other_broker_rewards(transfer_fee_a, transfer_fee_b).await;
+/// The market makers with rewards left to claim.
+pub fn users_with_rewards() -> Vec<Principal> {
+    with_pending_market_maker_rewards(|rewards| {
+        rewards
+            .local_rewards
+            .iter()
+            .filter(|(_, rewards)| rewards.is_nonzero())
+            .map(|(&user, _)| user)
+            .collect()
+    })
 }
 
-async fn distribute_other_broker_rewards(transfer_fee_a: Nat, transfer_fee_b: Nat) {
-    let rewards = with_pending_market_maker_rewards(|rewards| {
-        std::mem::take(&mut rewards.other_broker_rewards)
-    });
-    let mut failed: HashMap<Principal, HashMap<Principal, LiquidityAmount>> = HashMap::new();
+#[query(name = "getClaimableRewards")]
+#[candid_method(query, rename = "getClaimableRewards")]
+fn get_claimable_rewards(user: Principal) -> LiquidityAmountNat {
+    with_pending_market_maker_rewards(|rewards| rewards.local_rewards.get(&user).cloned())
+        .unwrap_or_default()
+        .into()
+}
 
-    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
-        let shard_address = has_token_info::get_assigned_shard(&token);
-        let transfer_fee = match token {
-            EnokiToken::TokenA => transfer_fee_a.clone(),
-            EnokiToken::TokenB => transfer_fee_b.clone(),
-        };
-        for (&broker, reward) in rewards.iter() {
-            async fn transfer_to_broker(
-                shard_address: Principal,
-                broker: Principal,
-                token: &EnokiToken,
-                reward: &HashMap<Principal, LiquidityAmount>,
-                transfer_fee: &Nat,
-            ) -> Result<()> {
-                let user_rewards = UserRewards::new(reward, &token);
-                let rewards_total: StableNat = user_rewards.0.values().cloned().sum();
-                let mut value: Nat = rewards_total.into();
-                if transfer_fee.clone() * MIN_AMOUNT_TO_SEND_WITH_RESPECT_TO_FEE > value {
-                    return Err(TxError::QuantityTooLow.into());
-                }
-                let fee = use_fee_for_transfer(&token).await?;
-                value += fee;
-                let user_rewards_str = serde_json::to_string(&user_rewards)
-                    .map_err(|e| TxError::ParsingError(format!("{:?}", e)))?;
-                let broker_shard = payoffs::get_broker_assigned_shard(broker, token.clone()).await?;
-                let result: Result<(String,)> = ic_cdk::call(
-                    shard_address,
-                    "shardTransferAndCall",
-                    (
-                        broker_shard,
-                        broker,
-                        value,
-                        broker,
-                        "receiveMarketMakerRewards",
-                        user_rewards_str,
-                    ),
-                )
-                .await
-                .map_err(|e| e.into_tx_error());
+/// Rewards owed on net to other brokers that have not been transferred yet.
+#[query(name = "getRewardsOwedToBrokers")]
+#[candid_method(query, rename = "getRewardsOwedToBrokers")]
+fn get_rewards_owed_to_brokers() -> Vec<(Principal, LiquidityAmount)> {
+    with_pending_market_maker_rewards(|rewards| {
+        rewards
+            .owed_to_brokers_mut()
+            .iter()
+            .map(|(&broker, owed)| (broker, owed.clone()))
+            .collect()
+    })
+}
 
-                result.map(|_| ())
-            }
-            if let Err(error) =
-                transfer_to_broker(shard_address, broker, &token, reward, &transfer_fee).await
-            {
-                ic_cdk::api::print(format!(
-                    "could not transfer market maker rewards to other broker: {:?}",
-                    error
-                ));
-                for (&user, reward) in reward {
-                    failed
-                        .entry(broker)
-                        .or_default()
-                        .entry(user)
-                        .or_default()
-                        .get_mut(&token)
-                        .add_assign(reward.get(&token).clone());
-                }
-            }
+/// Called by another broker netting its rewards with this one. `owed_in` are the rewards it owes
+/// to the users of this broker; the rewards this broker owes to its users are returned.
+#[update(name = "netMarketMakerRewards")]
+#[candid_method(update, rename = "netMarketMakerRewards")]
+fn net_market_maker_rewards(
+    owed_in: HashMap<Principal, LiquidityAmount>,
+) -> TxResult<HashMap<Principal, LiquidityAmount>> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    Ok(with_pending_market_maker_rewards(|rewards| {
+        let owed_out = rewards
+            .other_broker_rewards
+            .remove(&broker)
+            .unwrap_or_default();
+        rewards.record_netting(broker, &owed_out, owed_in);
+        owed_out
+    }))
+}
+
+/// Nets the rewards owed to and by every other broker, then pays what is owed on net. Runs at
+/// most once per `NETTING_INTERVAL`.
+pub async fn net_rewards_with_other_brokers() {
+    let now = ic_cdk::api::time();
+    let brokers: Vec<Principal> = match with_pending_market_maker_rewards(|rewards| {
+        if rewards.last_netting.unwrap_or_default() + NETTING_INTERVAL > now {
+            return None;
         }
-    }
+        rewards.last_netting = Some(now);
+        let mut brokers: Vec<Principal> = rewards.other_broker_rewards.keys().copied().collect();
+        brokers.extend(
+            rewards
+                .owed_to_brokers
+                .iter()
+                .flat_map(|owed| owed.keys())
+                .filter(|broker| !rewards.other_broker_rewards.contains_key(broker)),
+        );
+        Some(brokers)
+    }) {
+        Some(brokers) => brokers,
+        None => return,
+    };
+    futures::future::join_all(brokers.into_iter().map(net_rewards_with)).await;
+}
 
-    with_pending_market_maker_rewards(|rewards| {
-        for (broker, broker_rewards) in failed {
-            let rewards = rewards.other_broker_rewards.entry(broker).or_default();
-            for (user, user_reward) in broker_rewards {
-                rewards.entry(user).or_default().add_assign(user_reward);
-            }
+async fn net_rewards_with(broker: Principal) {
+    let owed_out =
+        with_pending_market_maker_rewards(|rewards| rewards.other_broker_rewards.remove(&broker))
+            .unwrap_or_default();
+    let result: Result<HashMap<Principal, LiquidityAmount>> =
+        flatten_response(ic_cdk::call(broker, "netMarketMakerRewards", (owed_out.clone(),)).await);
+    match result {
+        Ok(owed_in) => with_pending_market_maker_rewards(|rewards| {
+            rewards.record_netting(broker, &owed_out, owed_in)
+        }),
+        Err(error) => {
+            ic_cdk::print(format!(
+                "[broker] could not net market maker rewards with broker {}: {:?}",
+                broker, error
+            ));
+            with_pending_market_maker_rewards(|rewards| {
+                rewards.add_other_broker_rewards(broker, owed_out)
+            });
+            return;
         }
-    })
+    }
+    pay_broker(broker).await;
 }
 
-async fn distribute_local_rewards(transfer_fee_a: Nat, transfer_fee_b: Nat) {
-    let local_rewards =
-        with_pending_market_maker_rewards(|rewards| std::mem::take(&mut rewards.local_rewards));
-    let mut failed: HashMap<Principal, LiquidityAmount> = HashMap::new();
+/// Transfers what is owed on net to `broker`, once it is worth the transfer fee.
+async fn pay_broker(broker: Principal) {
     for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
-        let transfer_fee = match token {
-            EnokiToken::TokenA => transfer_fee_a.clone(),
-            EnokiToken::TokenB => transfer_fee_b.clone(),
-        };
-        let token_address = has_token_info::get_token_address(&token);
-        let shard_address = has_token_info::get_assigned_shard(&token);
-        for (&user, reward) in local_rewards.iter() {
-            let token_reward: Nat = reward.get(&token).clone().into();
-            if transfer_fee.clone() * MIN_AMOUNT_TO_SEND_WITH_RESPECT_TO_FEE > token_reward {
-                failed
-                    .entry(user)
-                    .or_default()
-                    .get_mut(&token)
-                    .add_assign(token_reward.into());
-                continue;
+        let result: Result<()> = async {
+            let transfer_fee = fees::get_fee_for_transfer(&token).await?;
+            let amount: Nat = with_pending_market_maker_rewards(|rewards| {
+                rewards
+                    .owed_to_brokers_mut()
+                    .get(&broker)
+                    .map(|owed| owed.get(&token).clone().to_nat())
+            })
+            .unwrap_or_default();
+            if amount < transfer_fee * MIN_AMOUNT_TO_SEND_WITH_RESPECT_TO_FEE {
+                return Ok(());
             }
-            match get_user_shard(user, token_address) {
-                Ok(user_shard) => {
-                    ic_cdk::api::print(format!(
-                        "[broker] rewarding market maker {} with {:?} {:?}",
-                        user,
-                        token,
-                        reward.get(&token)
-                    ));
-                    let result: Result<()> = ic_cdk::call(
-                        shard_address,
-                        "shardTransfer",
-                        (user_shard, user, token_reward.clone()),
-                    )
-                    .await
-                    .map_err(|e| e.into_tx_error());
-                    if let Err(err) = result {
-                        ic_cdk::api::print(format!(
-                            "[broker] error distributing extra reward: {:?}",
-                            err
-                        ));
-                        failed
-                            .entry(user)
-                            .or_default()
-                            .get_mut(&token)
-                            .add_assign(token_reward.into());
-                    } else {
-                        add_accrued_extra_reward(user, token_reward.clone().into(), &token);
-                    }
-                }
-                Err(_) => {
-                    failed
-                        .entry(user)
+            let broker_shard = payoffs::get_broker_assigned_shard(broker, token.clone()).await?;
+            let fee = use_fee_for_transfer(&token).await?;
+            let owed = with_pending_market_maker_rewards(|rewards| {
+                std::mem::take(
+                    rewards
+                        .owed_to_brokers_mut()
+                        .entry(broker)
+                        .or_default()
+                        .get_mut(&token),
+                )
+            });
+            ic_cdk::println!(
+                "[broker] sending {:?} {:?} of market maker rewards to broker {}",
+                owed,
+                token,
+                broker
+            );
+            let result: Result<()> = ic_cdk::call(
+                has_token_info::get_assigned_shard(&token),
+                "shardTransfer",
+                (broker_shard, broker, owed.clone().to_nat() + fee),
+            )
+            .await
+            .map_err(|e| e.into_tx_error());
+            if result.is_err() {
+                with_pending_market_maker_rewards(|rewards| {
+                    rewards
+                        .owed_to_brokers_mut()
+                        .entry(broker)
                         .or_default()
                         .get_mut(&token)
-                        .add_assign(token_reward.into());
-                }
+                        .add_assign(owed)
+                });
             }
+            result
         }
-    }
-    with_pending_market_maker_rewards(|rewards| {
-        for (user, amount) in failed {
-            rewards
-                .local_rewards
-                .entry(user)
-                .or_default()
-                .add_assign(amount);
+        .await;
+        if let Err(error) = result {
+            ic_cdk::print(format!(
+                "[broker] could not send market maker rewards to broker {}: {:?}",
+                broker, error
+            ));
         }
-    });
+    }
+}
+
+#[cfg(test)]
+mod market_maker_extra_rewards_tests {
+    use super::*;
+
+    #[test]
+    fn test_record_netting() {
+        let broker = Principal::from_slice(&[1]);
+        let local_user = Principal::from_slice(&[10]);
+        let remote_user = Principal::from_slice(&[20]);
+        let mut rewards = MarketMakerAccruedExtraRewards::default();
+        let owed_out = vec![
+            (remote_user, LiquidityAmount::from((60, 5))),
+            (local_user, LiquidityAmount::from((40, 0))),
+        ]
+        .into_iter()
+        .collect();
+
+        rewards.record_netting(
+            broker,
+            &owed_out,
+            vec![(local_user, LiquidityAmount::from((30, 50)))]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(
+            rewards.local_rewards[&local_user].token_b,
+            LiquidityAmount::from((0, 50)).token_b
+        );
+        assert_eq!(
+            rewards.owed_to_brokers_mut()[&broker].token_a,
+            LiquidityAmount::from((70, 0)).token_a
+        );
+        assert!(!rewards.owed_to_brokers_mut()[&broker].token_b.is_nonzero());
+    }
 }
@@ -2,32 +2,38 @@ use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 
-use candid::{candid_method, CandidType, Deserialize, Nat, Principal};
+use candid::{candid_method, CandidType, Principal};
 use ic_cdk_macros::*;
 
-use enoki_exchange_shared::has_sharded_users::get_user_shard;
 use enoki_exchange_shared::has_token_info;
 use enoki_exchange_shared::has_token_info::AssignedShards;
-use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
+use enoki_exchange_shared::is_owned::assert_is_owner;
 use enoki_exchange_shared::types::*;
 pub use exchange_tokens::exchange_tokens;
-pub use fees::{charge_deposit_fee, try_get_fee_for_transfer, get_fee_for_transfer};
+pub use fees::{
+    charge_deposit_fee, get_fee_for_transfer, quantity_after_deposit_fee, try_get_fee_for_transfer,
+};
 pub use fees::{
     export_stable_storage as export_stable_storage_fees,
     import_stable_storage as import_stable_storage_fees, AccruedFees,
 };
-pub use market_maker_extra_rewards::{add_reward, distribute_market_maker_rewards};
+pub use market_maker_extra_rewards::{
+    add_reward, add_to_designated_pool, net_rewards_with_other_brokers,
+    users_with_rewards as users_with_market_maker_rewards,
+};
+pub use settlements::{process_settlements, DelayedPayout, ExchangeBatch, Settlement};
 pub use swap_tokens::send_swap_tokens;
 
+use crate::balances;
 use crate::other_brokers::assert_is_broker;
 use crate::payoffs::market_maker_extra_rewards::MarketMakerAccruedExtraRewards;
-use crate::payoffs::token_shard_validation::is_valid_token_shard;
+use crate::payoffs::settlements::Settlements;
 
 mod exchange_tokens;
 mod fees;
 mod market_maker_extra_rewards;
+mod settlements;
 mod swap_tokens;
-mod token_shard_validation;
 
 thread_local! {
     static STATE: RefCell<PayoffsState> = RefCell::new(PayoffsState::default());
@@ -35,46 +41,27 @@ thread_local! {
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct PayoffsState {
-    pending_transfers: PendingTransfers,
-    failed_exchanges: Vec<TokenExchangeInfo>,
+    settlements: Option<Settlements>,
     broker_assigned_shards: HashMap<(Principal, EnokiToken), Principal>,
-    valid_token_shards_a: HashSet<Principal>,
-    valid_token_shards_b: HashSet<Principal>,
     market_maker_pending_rewards: MarketMakerAccruedExtraRewards,
+    /// settlements of other brokers for which users were credited
+    netted_credited: Option<HashSet<(Principal, u64)>>,
+    /// exchanges that failed before settlements, executed again with the next round
+    failed_exchanges: Option<Vec<TokenExchangeInfo>>,
+    /// transfers waiting on another broker before settlements, resolved by the owner
+    pending_transfers: Option<LegacyPendingTransfers>,
 }
 
+/// The transfers of exchanges in flight when the broker was upgraded to settlements. Whether the
+/// other broker sent its part cannot be known here anymore.
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
-pub struct PendingTransfers {
+pub struct LegacyPendingTransfers {
     last_id: u64,
-    pending: HashMap<u64, TransferPair>,
-}
-
-impl PendingTransfers {
-    pub fn create_new(&mut self, pair: TransferPair) -> u64 {
-        self.last_id += 1;
-        let id = self.last_id;
-        self.pending.insert(id, pair);
-        id
-    }
-    pub fn remove(&mut self, id: u64) -> Option<TransferPair> {
-        self.pending.remove(&id)
-    }
-    pub fn get_token_for_first_part(&self, id: u64) -> Option<EnokiToken> {
-        self.pending.get(&id).map(|val| val.waiting_on.token.clone())
-    }
-}
-
-#[derive(Deserialize, CandidType, Clone, Debug)]
-pub struct FirstTransfer {
-    to: Principal,
-    to_shard: Principal,
-    token: EnokiToken,
-    amount: Nat,
-    user_for_shard_id_to_retrieve: Principal,
+    pending: HashMap<u64, LegacyTransferPair>,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
-pub struct TransferPair {
+pub struct LegacyTransferPair {
     waiting_on: TransferInfo,
     next_transfer: TransferInfo,
 }
@@ -109,31 +96,24 @@ pub struct TokenExchangeInfo {
     other_user: TransferInfo,
 }
 
-fn with_failed_exchanges_mut<F: FnOnce(&mut Vec<TokenExchangeInfo>) -> R, R>(f: F) -> R {
+fn with_settlements_mut<F: FnOnce(&mut Settlements) -> R, R>(f: F) -> R {
     STATE.with(|s| {
         let mut s = s.borrow_mut();
-        f(&mut s.failed_exchanges)
+        f(s.settlements.get_or_insert_with(Default::default))
     })
 }
 
-fn with_pending_transfers_mut<F: FnOnce(&mut PendingTransfers) -> R, R>(f: F) -> R {
+fn with_settlements<F: FnOnce(&Settlements) -> R, R>(f: F) -> R {
     STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        f(&mut s.pending_transfers)
+        f(s.borrow()
+            .settlements
+            .as_ref()
+            .unwrap_or(&Default::default()))
     })
 }
 
-fn with_valid_token_shards<F: FnOnce(&mut HashSet<Principal>) -> R, R>(
-    token: &EnokiToken,
-    f: F,
-) -> R {
-    STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        match token {
-            EnokiToken::TokenA => f(&mut s.valid_token_shards_a),
-            EnokiToken::TokenB => f(&mut s.valid_token_shards_b),
-        }
-    })
+fn take_legacy_exchanges() -> Vec<TokenExchangeInfo> {
+    STATE.with(|s| s.borrow_mut().failed_exchanges.take().unwrap_or_default())
 }
 
 fn with_pending_market_maker_rewards<F: FnOnce(&mut MarketMakerAccruedExtraRewards) -> R, R>(
@@ -174,85 +154,217 @@ async fn get_broker_assigned_shard(broker: Principal, token: EnokiToken) -> Resu
     Ok(shard)
 }
 
-#[update(name = "sendFunds")]
-#[candid_method(update, rename = "sendFunds")]
-async fn send_funds(id: String, info: FirstTransfer) {
-    assert_is_broker(ic_cdk::caller()).unwrap();
-    ic_cdk::println!(
-        "[broker] received exchange id {} from broker {}",
-        id,
-        ic_cdk::caller()
-    );
-    let shard_id_to_retrieve = get_user_shard(
-        info.user_for_shard_id_to_retrieve,
-        has_token_info::get_token_address(&info.token.opposite()),
-    )
-    .unwrap();
-    exchange_tokens::send_funds_internal(id, info, ic_cdk::caller(), shard_id_to_retrieve)
+/// Another broker starts a netted settlement with this one, including the batches this broker
+/// forwarded to it. What this broker owes is computed here, from its own copies of those batches.
+#[update(name = "openNettedSettlement")]
+#[candid_method(update, rename = "openNettedSettlement")]
+fn open_netted_settlement(id: u64, forwarded_ids: Vec<u64>, batch: ExchangeBatch) -> TxResult<()> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    with_settlements_mut(|s| s.open_remote(broker, id, forwarded_ids, batch))?;
+    Ok(())
+}
+
+/// First leg of a netted settlement opened by another broker: sends it what this broker owes on
+/// net. The broker must ask for exactly that, and each token is only ever sent once per
+/// settlement, so the other broker can retry.
+#[update(name = "sendNettedFunds")]
+#[candid_method(update, rename = "sendNettedFunds")]
+async fn send_netted_funds(id: u64, amounts: LiquidityAmountNat) -> TxResult<()> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    let amounts: LiquidityAmount = amounts.into();
+    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+        let requested = amounts.get(&token);
+        if !requested.is_nonzero() {
+            continue;
+        }
+        let amount = with_settlements(|s| s.netted_amount_to_send(broker, id, &token, requested))?;
+        if !amount.is_nonzero() {
+            continue;
+        }
+        ic_cdk::println!(
+            "[broker] sending {:?} {:?} to broker {} for settlement {}",
+            amount,
+            token,
+            broker,
+            id
+        );
+        let broker_shard = get_broker_assigned_shard(broker, token.clone()).await?;
+        let result: Result<()> = ic_cdk::call(
+            has_token_info::get_assigned_shard(&token),
+            "shardTransfer",
+            (broker_shard, broker, amount.clone().to_nat()),
+        )
         .await
-        .unwrap()
+        .map_err(|e| e.into_tx_error());
+        result?;
+        with_settlements_mut(|s| s.mark_netted_sent(broker, id, &token, amount));
+    }
+    Ok(())
+}
+
+/// Second leg of a netted settlement: the other broker has sent what it owed on net, and the
+/// users of this broker are credited what they are owed. The fees of the takers of this broker
+/// are only kept now.
+#[update(name = "creditNettedFunds")]
+#[candid_method(update, rename = "creditNettedFunds")]
+fn credit_netted_funds(
+    id: u64,
+    credits: HashMap<Principal, LiquidityAmount>,
+    taker_fees: Option<HashMap<Principal, LiquidityAmount>>,
+) -> TxResult<()> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    if !mark_netted_credited(broker, id) {
+        return Ok(());
+    }
+    for (user, credit) in credits {
+        balances::credit(user, &credit);
+    }
+    fees::charge_trading_fees(&taker_fees.unwrap_or_default());
+    Ok(())
 }
 
-#[update(name = "fundsSent")]
-#[candid_method(update, rename = "fundsSent")]
-async fn funds_sent(notification: ShardedTransferNotification) -> String {
-    let mut data = notification.data.split('|');
-    let id: u64 = data
-        .next()
-        .expect("invalid message body fundsSent")
-        .parse()
-        .expect("cannot parse id");
-    let user_shard_to_retrieve: Principal = data
-        .next()
-        .expect("invalid message body fundsSent")
-        .parse()
-        .expect("cannot parse user_shard_to_retrieve");
-    if !is_valid_token_shard(
-        &STATE
-            .with(|s| s.borrow().pending_transfers.get_token_for_first_part(id))
-            .expect("id not found"),
-        ic_cdk::caller(),
-    )
-    .await
-    {
-        panic!("Unauthorized notification from {}", ic_cdk::caller());
+/// A netted settlement settled by the other broker failed: the takers of this broker whose
+/// exchanges it included get back what they paid.
+#[update(name = "refundNettedFunds")]
+#[candid_method(update, rename = "refundNettedFunds")]
+fn refund_netted_funds(id: u64, refunds: HashMap<Principal, LiquidityAmount>) -> TxResult<()> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    if !mark_netted_credited(broker, id) {
+        return Ok(());
     }
-    let TransferPair {
-        waiting_on,
-        next_transfer,
-    } = STATE
-        .with(|s| s.borrow_mut().pending_transfers.remove(id))
-        .expect("cannot find id");
-    assert_eq!(
-        waiting_on.to, notification.to,
-        "recipient not the same as expected"
-    );
-    assert_eq!(
-        waiting_on.amount.to_nat(),
-        notification.value + notification.fee_charged,
-        "amount received not the same as expected"
-    );
-
-    let assigned_token_shard = has_token_info::get_assigned_shard(&next_transfer.token);
-
-    ic_cdk::println!(
-        "[broker] executing second half of exchange id {}",
-        notification.data
-    );
-
-    let response: Result<()> = ic_cdk::call(
-        assigned_token_shard,
-        "shardTransfer",
-        (
-            user_shard_to_retrieve,
-            next_transfer.to,
-            next_transfer.amount.to_nat(),
-        ),
-    )
-    .await
-    .map_err(|e| e.into_tx_error());
-    response.unwrap();
-    "OK".to_string()
+    for (user, refund) in refunds {
+        balances::credit(user, &refund);
+    }
+    Ok(())
+}
+
+/// Whether the users were not credited for this settlement yet, either way.
+fn mark_netted_credited(broker: Principal, id: u64) -> bool {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .netted_credited
+            .get_or_insert_with(Default::default)
+            .insert((broker, id))
+    })
+}
+
+/// The exchanges of a round of another broker with the users of this one, for this broker to
+/// settle along with its own.
+#[update(name = "forwardExchangeBatch")]
+#[candid_method(update, rename = "forwardExchangeBatch")]
+fn forward_exchange_batch(batch_id: u64, batch: ExchangeBatch) -> TxResult<()> {
+    let broker = ic_cdk::caller();
+    assert_is_broker(broker)?;
+    with_settlements_mut(|s| s.add_forwarded(broker, batch_id, batch));
+    Ok(())
+}
+
+#[query(name = "getInFlightSettlements")]
+#[candid_method(query, rename = "getInFlightSettlements")]
+fn get_in_flight_settlements() -> Vec<Settlement> {
+    with_settlements(|s| s.list(true))
+}
+
+#[query(name = "getFailedSettlements")]
+#[candid_method(query, rename = "getFailedSettlements")]
+fn get_failed_settlements() -> Vec<Settlement> {
+    with_settlements(|s| s.list(false))
+}
+
+/// Payouts to `user` that are waiting on a settlement with another broker.
+#[query(name = "getDelayedPayouts")]
+#[candid_method(query, rename = "getDelayedPayouts")]
+fn get_delayed_payouts(user: Principal) -> Vec<DelayedPayout> {
+    with_settlements(|s| s.delayed_payouts(user))
+}
+
+#[update(name = "retrySettlement")]
+#[candid_method(update, rename = "retrySettlement")]
+async fn retry_settlement(id: u64) -> TxResult<()> {
+    assert_is_owner()?;
+    settlements::retry_settlement(id).await;
+    Ok(())
+}
+
+/// Refunds the local users of a settlement whose first leg has not been confirmed.
+#[update(name = "cancelSettlement")]
+#[candid_method(update, rename = "cancelSettlement")]
+fn cancel_settlement(id: u64) -> TxResult<()> {
+    assert_is_owner()?;
+    Ok(with_settlements_mut(|s| s.cancel(id, ic_cdk::api::time()))?)
+}
+
+/// Forgets a settlement that was settled by other means.
+#[update(name = "resolveSettlement")]
+#[candid_method(update, rename = "resolveSettlement")]
+fn resolve_settlement(id: u64) -> TxResult<Settlement> {
+    assert_is_owner()?;
+    let settlement = with_settlements_mut(|s| s.remove(id))?;
+    ic_cdk::println!("[broker] settlement {} was resolved manually", id);
+    Ok(settlement)
+}
+
+/// Settlements and legacy transfers that still have to go through for the users of this broker.
+pub fn count_open_settlements() -> u64 {
+    let settlements = with_settlements(|s| s.count_open());
+    let legacy = STATE.with(|s| {
+        let s = s.borrow();
+        s.pending_transfers
+            .as_ref()
+            .map_or(0, |transfers| transfers.pending.len())
+            + s.failed_exchanges.as_ref().map_or(0, Vec::len)
+    });
+    (settlements + legacy) as u64
+}
+
+#[query(name = "getLegacyPendingTransfers")]
+#[candid_method(query, rename = "getLegacyPendingTransfers")]
+fn get_legacy_pending_transfers() -> Vec<(u64, LegacyTransferPair)> {
+    STATE.with(|s| {
+        s.borrow()
+            .pending_transfers
+            .iter()
+            .flat_map(|transfers| transfers.pending.iter())
+            .map(|(id, pair)| (*id, pair.clone()))
+            .collect()
+    })
+}
+
+/// Executes a legacy pending transfer again with the next round of exchanges, or forgets it when
+/// the other broker is known to have sent its part and the exchange was settled by other means.
+#[update(name = "resolveLegacyTransfer")]
+#[candid_method(update, rename = "resolveLegacyTransfer")]
+fn resolve_legacy_transfer(id: u64, execute_again: bool) -> TxResult<()> {
+    assert_is_owner()?;
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let LegacyTransferPair {
+            waiting_on,
+            next_transfer,
+        } = s
+            .pending_transfers
+            .as_mut()
+            .and_then(|transfers| transfers.pending.remove(&id))
+            .ok_or(TxError::InvalidInput(format!("no legacy transfer {}", id)))?;
+        if execute_again {
+            s.failed_exchanges
+                .get_or_insert_with(Default::default)
+                .push(TokenExchangeInfo {
+                    local_user: waiting_on,
+                    other_user: next_transfer,
+                });
+        }
+        ic_cdk::println!(
+            "[broker] legacy transfer {} was resolved (executed again: {})",
+            id,
+            execute_again
+        );
+        Ok(())
+    })
 }
 
 pub fn export_stable_storage() -> PayoffsState {
@@ -0,0 +1,984 @@
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+use candid::{CandidType, Principal};
+
+use enoki_exchange_shared::has_token_info;
+use enoki_exchange_shared::types::*;
+
+use crate::balances;
+use crate::payoffs::{fees, get_broker_assigned_shard, with_settlements_mut, TokenExchangeInfo};
+
+/// Settlements whose first leg is not confirmed within this time are compensated.
+const SETTLEMENT_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, PartialEq)]
+pub enum SettlementStatus {
+    Initiated,
+    FirstLegSent,
+    /// part of the first leg was received, so the settlement can only go forward
+    FirstLegPartiallyReceived,
+    FirstLegConfirmed,
+    Completed,
+    Compensating,
+    Failed,
+}
+
+/// The exchanges between local users and the users of another broker, in both directions, netted
+/// per token. The other broker first sends what it owes on net (`net_in`), then this broker sends
+/// what it owes on net (`net_out`), and each broker credits its own users internally.
+/// If none of the first leg goes through, the users of both brokers are refunded what they were
+/// going to pay, fees included. The fees are only kept once the first leg is confirmed.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct Settlement {
+    pub id: u64,
+    pub status: SettlementStatus,
+    pub broker: Principal,
+    pub net_in: LiquidityAmount,
+    pub net_out: LiquidityAmount,
+    net_in_received: LiquidityAmount,
+    net_out_sent: LiquidityAmount,
+    local_credits: HashMap<Principal, LiquidityAmount>,
+    local_refunds: HashMap<Principal, LiquidityAmount>,
+    taker_fees: HashMap<Principal, LiquidityAmount>,
+    remote_credits: HashMap<Principal, LiquidityAmount>,
+    /// what the takers of the other broker get back, fees included, if the settlement fails
+    remote_refunds: HashMap<Principal, LiquidityAmount>,
+    remote_fees: HashMap<Principal, LiquidityAmount>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// set while a call for this settlement is awaited, so it is never made twice at once
+    transfer_in_progress: bool,
+    /// the batch of this broker, before merging, for the other broker to compute what it owes
+    own_batch: Option<ExchangeBatch>,
+    /// the ids of the batches the other broker forwarded that this settlement includes
+    forwarded_ids: Option<Vec<u64>>,
+}
+
+/// What a local user is owed from a settlement that has not gone through yet.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct DelayedPayout {
+    pub settlement_id: u64,
+    pub broker: Principal,
+    pub status: SettlementStatus,
+    pub amount: LiquidityAmount,
+    pub since: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+/// What a round of exchanges with one other broker amounts to, before netting. The broker of
+/// the pair with the lower principal settles both directions: the other one forwards its batch.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ExchangeBatch {
+    owed_in: LiquidityAmount,
+    owed_out: LiquidityAmount,
+    local_credits: HashMap<Principal, LiquidityAmount>,
+    local_refunds: HashMap<Principal, LiquidityAmount>,
+    taker_fees: HashMap<Principal, LiquidityAmount>,
+    remote_credits: HashMap<Principal, LiquidityAmount>,
+    remote_refunds: HashMap<Principal, LiquidityAmount>,
+    remote_fees: HashMap<Principal, LiquidityAmount>,
+}
+
+/// A settlement of another broker with this one, with what this broker owes on net as computed
+/// from its own batches.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct OpenedSettlement {
+    owed: LiquidityAmount,
+    sent: LiquidityAmount,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct Settlements {
+    last_id: u64,
+    settlements: HashMap<u64, Settlement>,
+    /// batches forwarded by other brokers, seen from this broker, waiting for the next settlement
+    forwarded: HashMap<Principal, ExchangeBatch>,
+    /// the ids of the batches in `forwarded`, per broker
+    forwarded_ids: Option<HashMap<Principal, Vec<u64>>>,
+    /// batches this broker forwarded, by id, until the broker they went to opens their settlement
+    forwarded_out: Option<HashMap<u64, (Principal, ExchangeBatch)>>,
+    /// settlements opened by other brokers, by broker and id of the settlement there
+    opened: Option<HashMap<(Principal, u64), OpenedSettlement>>,
+}
+
+fn add_credit(
+    credits: &mut HashMap<Principal, LiquidityAmount>,
+    user: Principal,
+    token: &EnokiToken,
+    amount: StableNat,
+) {
+    credits
+        .entry(user)
+        .or_default()
+        .get_mut(token)
+        .add_assign(amount);
+}
+
+fn add_credits(
+    credits: &mut HashMap<Principal, LiquidityAmount>,
+    other: HashMap<Principal, LiquidityAmount>,
+) {
+    for (user, amount) in other {
+        credits.entry(user).or_default().add_assign(amount);
+    }
+}
+
+fn saturating_sub(lhs: &LiquidityAmount, rhs: &LiquidityAmount) -> LiquidityAmount {
+    LiquidityAmount {
+        token_a: (lhs.token_a.clone() - rhs.token_a.clone()).unwrap_or_default(),
+        token_b: (lhs.token_b.clone() - rhs.token_b.clone()).unwrap_or_default(),
+    }
+}
+
+/// Groups exchanges, with the fee their taker paid on top, by the broker of the other user.
+/// Exchanges with users of this broker need no transfer at all, and are returned as credits and
+/// fees to apply right away.
+pub fn net_exchanges(
+    exchanges: Vec<(TokenExchangeInfo, StableNat)>,
+    this_broker: Principal,
+) -> (
+    HashMap<Principal, LiquidityAmount>,
+    HashMap<Principal, LiquidityAmount>,
+    HashMap<Principal, ExchangeBatch>,
+) {
+    let mut local_credits = HashMap::new();
+    let mut local_fees = HashMap::new();
+    let mut batches: HashMap<Principal, ExchangeBatch> = HashMap::new();
+    for (
+        TokenExchangeInfo {
+            local_user,
+            other_user,
+        },
+        fee,
+    ) in exchanges
+    {
+        if local_user.broker == this_broker {
+            add_credit(&mut local_fees, local_user.to, &other_user.token, fee);
+            add_credit(
+                &mut local_credits,
+                local_user.to,
+                &local_user.token,
+                local_user.amount,
+            );
+            add_credit(
+                &mut local_credits,
+                other_user.to,
+                &other_user.token,
+                other_user.amount,
+            );
+            continue;
+        }
+        let batch = batches.entry(local_user.broker).or_default();
+        batch
+            .owed_in
+            .get_mut(&local_user.token)
+            .add_assign(local_user.amount.clone());
+        batch
+            .owed_out
+            .get_mut(&other_user.token)
+            .add_assign(other_user.amount.clone());
+        add_credit(
+            &mut batch.local_credits,
+            local_user.to,
+            &local_user.token,
+            local_user.amount,
+        );
+        add_credit(
+            &mut batch.local_refunds,
+            local_user.to,
+            &other_user.token,
+            other_user.amount.clone(),
+        );
+        add_credit(&mut batch.taker_fees, local_user.to, &other_user.token, fee);
+        add_credit(
+            &mut batch.remote_credits,
+            other_user.to,
+            &other_user.token,
+            other_user.amount,
+        );
+    }
+    (local_credits, local_fees, batches)
+}
+
+impl ExchangeBatch {
+    fn merge(&mut self, other: ExchangeBatch) {
+        self.owed_in.add_assign(other.owed_in);
+        self.owed_out.add_assign(other.owed_out);
+        add_credits(&mut self.local_credits, other.local_credits);
+        add_credits(&mut self.local_refunds, other.local_refunds);
+        add_credits(&mut self.taker_fees, other.taker_fees);
+        add_credits(&mut self.remote_credits, other.remote_credits);
+        add_credits(&mut self.remote_refunds, other.remote_refunds);
+        add_credits(&mut self.remote_fees, other.remote_fees);
+    }
+    /// The batch of the other broker, as seen from this one: its takers are the remote users, and
+    /// their refunds include the fees they paid.
+    fn inverted(self) -> ExchangeBatch {
+        let mut remote_refunds = self.local_refunds;
+        add_credits(&mut remote_refunds, self.taker_fees.clone());
+        ExchangeBatch {
+            owed_in: self.owed_out,
+            owed_out: self.owed_in,
+            local_credits: self.remote_credits,
+            local_refunds: self.remote_refunds,
+            taker_fees: self.remote_fees,
+            remote_credits: self.local_credits,
+            remote_refunds,
+            remote_fees: self.taker_fees,
+        }
+    }
+}
+
+impl Settlement {
+    fn set_status(&mut self, status: SettlementStatus, now: u64) {
+        self.status = status;
+        self.updated_at = now;
+    }
+    pub fn is_in_flight(&self) -> bool {
+        !matches!(
+            self.status,
+            SettlementStatus::Completed | SettlementStatus::Failed
+        )
+    }
+    fn first_leg_confirmed(&mut self, now: u64) {
+        for (user, credit) in self.local_credits.iter() {
+            balances::credit(*user, credit);
+        }
+        fees::charge_trading_fees(&self.taker_fees);
+        self.set_status(SettlementStatus::FirstLegConfirmed, now);
+    }
+    fn compensate(&mut self, now: u64) {
+        self.set_status(SettlementStatus::Compensating, now);
+        for (user, refund) in self.local_refunds.iter() {
+            balances::credit(*user, refund);
+        }
+        for (user, fee) in self.taker_fees.iter() {
+            balances::credit(*user, fee);
+        }
+        if self.remote_refunds.is_empty() {
+            self.set_status(SettlementStatus::Failed, now);
+        }
+    }
+    /// Whether the first leg was not confirmed in time and no call for it is still running.
+    fn has_timed_out(&self, now: u64) -> bool {
+        matches!(
+            self.status,
+            SettlementStatus::Initiated | SettlementStatus::FirstLegSent
+        ) && !self.transfer_in_progress
+            && self.created_at + SETTLEMENT_TIMEOUT <= now
+    }
+}
+
+impl Settlements {
+    /// Keeps the batch of another broker to settle along with the next batch with that broker.
+    pub fn add_forwarded(&mut self, broker: Principal, batch_id: u64, batch: ExchangeBatch) {
+        self.forwarded
+            .entry(broker)
+            .or_default()
+            .merge(batch.inverted());
+        self.forwarded_ids
+            .get_or_insert_with(Default::default)
+            .entry(broker)
+            .or_default()
+            .push(batch_id);
+    }
+    /// Keeps a batch forwarded to another broker, to check what that broker asks for later.
+    pub fn add_forwarded_out(&mut self, broker: Principal, batch: ExchangeBatch) -> u64 {
+        self.last_id += 1;
+        self.forwarded_out
+            .get_or_insert_with(Default::default)
+            .insert(self.last_id, (broker, batch));
+        self.last_id
+    }
+    /// The broker did not take the batch, so it is settled here instead.
+    pub fn remove_forwarded_out(&mut self, batch_id: u64) -> Option<ExchangeBatch> {
+        self.forwarded_out
+            .as_mut()?
+            .remove(&batch_id)
+            .map(|(_, batch)| batch)
+    }
+    /// Records a settlement the broker started with this one, computing what this broker owes
+    /// from the batches it forwarded there and the batch of that broker.
+    pub fn open_remote(
+        &mut self,
+        broker: Principal,
+        id: u64,
+        forwarded_ids: Vec<u64>,
+        batch: ExchangeBatch,
+    ) -> Result<()> {
+        let opened = self.opened.get_or_insert_with(Default::default);
+        if opened.contains_key(&(broker, id)) {
+            return Ok(());
+        }
+        let forwarded_out = self.forwarded_out.get_or_insert_with(Default::default);
+        for batch_id in forwarded_ids.iter() {
+            if !matches!(forwarded_out.get(batch_id), Some((to, _)) if *to == broker) {
+                return Err(TxError::InvalidInput(format!(
+                    "batch {} was not forwarded to broker {}",
+                    batch_id, broker
+                ))
+                .into());
+            }
+        }
+        let mut merged = batch.inverted();
+        for batch_id in forwarded_ids {
+            if let Some((_, batch)) = forwarded_out.remove(&batch_id) {
+                merged.merge(batch);
+            }
+        }
+        opened.insert(
+            (broker, id),
+            OpenedSettlement {
+                owed: saturating_sub(&merged.owed_out, &merged.owed_in),
+                sent: Default::default(),
+            },
+        );
+        Ok(())
+    }
+    /// What to send for a token of a settlement opened by the broker, which must ask for exactly
+    /// what this broker owes. Nothing is left to send once it was sent, so the broker can retry.
+    pub fn netted_amount_to_send(
+        &self,
+        broker: Principal,
+        id: u64,
+        token: &EnokiToken,
+        requested: &StableNat,
+    ) -> Result<StableNat> {
+        let opened = self
+            .opened
+            .as_ref()
+            .and_then(|opened| opened.get(&(broker, id)))
+            .ok_or_else(|| {
+                TxError::InvalidInput(format!("no settlement {} opened by broker {}", id, broker))
+            })?;
+        let owed = opened.owed.get(token);
+        if requested != owed {
+            return Err(TxError::InvalidInput(format!(
+                "settlement {} owes {:?} {:?}, not {:?}",
+                id, owed, token, requested
+            ))
+            .into());
+        }
+        Ok(saturating_sub(&opened.owed, &opened.sent)
+            .get(token)
+            .clone())
+    }
+    pub fn mark_netted_sent(
+        &mut self,
+        broker: Principal,
+        id: u64,
+        token: &EnokiToken,
+        amount: StableNat,
+    ) {
+        if let Some(opened) = self
+            .opened
+            .as_mut()
+            .and_then(|opened| opened.get_mut(&(broker, id)))
+        {
+            opened.sent.get_mut(token).add_assign(amount);
+        }
+    }
+    /// Starts a settlement for the batch, along with whatever the broker forwarded so far.
+    pub fn create_new(&mut self, broker: Principal, batch: ExchangeBatch, now: u64) -> u64 {
+        let own_batch = batch.clone();
+        let mut batch = batch;
+        if let Some(forwarded) = self.forwarded.remove(&broker) {
+            batch.merge(forwarded);
+        }
+        let forwarded_ids = self
+            .forwarded_ids
+            .as_mut()
+            .and_then(|ids| ids.remove(&broker))
+            .unwrap_or_default();
+        self.last_id += 1;
+        let id = self.last_id;
+        self.settlements.insert(
+            id,
+            Settlement {
+                id,
+                status: SettlementStatus::Initiated,
+                broker,
+                net_in: saturating_sub(&batch.owed_in, &batch.owed_out),
+                net_out: saturating_sub(&batch.owed_out, &batch.owed_in),
+                net_in_received: Default::default(),
+                net_out_sent: Default::default(),
+                local_credits: batch.local_credits,
+                local_refunds: batch.local_refunds,
+                taker_fees: batch.taker_fees,
+                remote_credits: batch.remote_credits,
+                remote_refunds: batch.remote_refunds,
+                remote_fees: batch.remote_fees,
+                created_at: now,
+                updated_at: now,
+                attempts: 0,
+                last_error: None,
+                transfer_in_progress: false,
+                own_batch: Some(own_batch),
+                forwarded_ids: Some(forwarded_ids),
+            },
+        );
+        id
+    }
+    pub fn get_mut(&mut self, id: u64) -> Option<&mut Settlement> {
+        self.settlements.get_mut(&id)
+    }
+    /// Marks the settlement as being processed if it is in `status` and no call is running.
+    fn start_transfer(&mut self, id: u64, status: SettlementStatus) -> Option<Settlement> {
+        let settlement = self.get_mut(id)?;
+        if settlement.status != status || settlement.transfer_in_progress {
+            return None;
+        }
+        settlement.transfer_in_progress = true;
+        Some(settlement.clone())
+    }
+    /// The settlements in flight, and the brokers whose forwarded batches are not settled yet.
+    pub fn count_open(&self) -> usize {
+        self.settlements
+            .values()
+            .filter(|settlement| settlement.is_in_flight())
+            .count()
+            + self.forwarded.len()
+    }
+    pub fn list(&self, in_flight: bool) -> Vec<Settlement> {
+        let mut list: Vec<Settlement> = self
+            .settlements
+            .values()
+            .filter(|settlement| settlement.is_in_flight() == in_flight)
+            .cloned()
+            .collect();
+        list.sort_by_key(|settlement| settlement.id);
+        list
+    }
+    pub fn delayed_payouts(&self, user: Principal) -> Vec<DelayedPayout> {
+        let mut payouts: Vec<DelayedPayout> = self
+            .settlements
+            .values()
+            .filter(|settlement| settlement.is_in_flight())
+            .filter_map(|settlement| {
+                Some(DelayedPayout {
+                    settlement_id: settlement.id,
+                    broker: settlement.broker,
+                    status: settlement.status.clone(),
+                    amount: settlement.local_credits.get(&user)?.clone(),
+                    since: settlement.created_at,
+                    attempts: settlement.attempts,
+                    last_error: settlement.last_error.clone(),
+                })
+            })
+            .collect();
+        payouts.sort_by_key(|payout| payout.settlement_id);
+        payouts
+    }
+    /// Refunds the users of a settlement whose first leg is not confirmed yet.
+    /// The users of the other broker are refunded by the next attempt.
+    pub fn cancel(&mut self, id: u64, now: u64) -> Result<()> {
+        let settlement = self
+            .get_mut(id)
+            .ok_or_else(|| TxError::NotFound(format!("settlement {}", id)))?;
+        if !matches!(
+            settlement.status,
+            SettlementStatus::Initiated | SettlementStatus::FirstLegSent
+        ) || settlement.transfer_in_progress
+        {
+            return Err(
+                TxError::InvalidState("settlement can no longer be cancelled".to_string()).into(),
+            );
+        }
+        settlement.compensate(now);
+        Ok(())
+    }
+    /// Forgets a settlement that was settled by other means.
+    pub fn remove(&mut self, id: u64) -> Result<Settlement> {
+        match self.settlements.get(&id) {
+            None => Err(TxError::NotFound(format!("settlement {}", id)).into()),
+            Some(settlement) if settlement.transfer_in_progress => Err(TxError::InvalidState(
+                "settlement has a transfer in progress".to_string(),
+            )
+            .into()),
+            Some(_) => Ok(self.settlements.remove(&id).unwrap()),
+        }
+    }
+    fn remove_completed(&mut self) {
+        self.settlements
+            .retain(|_, settlement| settlement.status != SettlementStatus::Completed);
+    }
+}
+
+/// Records the outcome of a call made for a settlement, moving it to `next_status` on success.
+fn finish_attempt(id: u64, result: Result<()>, next_status: SettlementStatus) {
+    let now = ic_cdk::api::time();
+    with_settlements_mut(|s| {
+        if let Some(settlement) = s.get_mut(id) {
+            settlement.transfer_in_progress = false;
+            settlement.attempts += 1;
+            match result {
+                Ok(_) => {
+                    ic_cdk::println!("[broker] settlement {} is now {:?}", id, next_status);
+                    if next_status == SettlementStatus::FirstLegConfirmed {
+                        settlement.first_leg_confirmed(now);
+                    } else {
+                        settlement.set_status(next_status, now);
+                    }
+                }
+                Err(error) => {
+                    ic_cdk::print(format!("[broker] settlement {} failed: {:?}", id, error));
+                    settlement.last_error = Some(format!("{:?}", error));
+                }
+            }
+        }
+        s.remove_completed();
+    });
+}
+
+/// Opens the settlement with the other broker, then asks it to send what it owes on net, one
+/// token at a time, so that whatever was received is known. The other broker computes what it
+/// owes itself and ignores repeated requests for the same settlement and token, so this is safe
+/// to retry.
+async fn send_first_leg(id: u64) {
+    let settlement = match with_settlements_mut(|s| {
+        s.start_transfer(id, SettlementStatus::Initiated)
+            .or_else(|| s.start_transfer(id, SettlementStatus::FirstLegSent))
+            .or_else(|| s.start_transfer(id, SettlementStatus::FirstLegPartiallyReceived))
+    }) {
+        Some(settlement) => settlement,
+        None => return,
+    };
+    let result: Result<()> = async {
+        if let (Some(batch), Some(forwarded_ids)) = (
+            settlement.own_batch.clone(),
+            settlement.forwarded_ids.clone(),
+        ) {
+            let result: Result<()> = flatten_response(
+                ic_cdk::call(
+                    settlement.broker,
+                    "openNettedSettlement",
+                    (id, forwarded_ids, batch),
+                )
+                .await,
+            );
+            result?;
+        }
+        let remaining = saturating_sub(&settlement.net_in, &settlement.net_in_received);
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            let amount = remaining.get(&token).clone();
+            if !amount.is_nonzero() {
+                continue;
+            }
+            with_settlements_mut(|s| {
+                if let Some(settlement) = s.get_mut(id) {
+                    if settlement.status == SettlementStatus::Initiated {
+                        settlement.set_status(SettlementStatus::FirstLegSent, ic_cdk::api::time());
+                    }
+                }
+            });
+            ic_cdk::println!(
+                "[broker] requesting {:?} {:?} from broker {} for settlement {}",
+                amount,
+                token,
+                settlement.broker,
+                id
+            );
+            let mut requested = LiquidityAmount::default();
+            *requested.get_mut(&token) = amount.clone();
+            let result: Result<()> = flatten_response(
+                ic_cdk::call(
+                    settlement.broker,
+                    "sendNettedFunds",
+                    (id, LiquidityAmountNat::from(requested)),
+                )
+                .await,
+            );
+            result?;
+            with_settlements_mut(|s| {
+                if let Some(settlement) = s.get_mut(id) {
+                    settlement
+                        .net_in_received
+                        .get_mut(&token)
+                        .add_assign(amount);
+                    settlement.set_status(
+                        SettlementStatus::FirstLegPartiallyReceived,
+                        ic_cdk::api::time(),
+                    );
+                }
+            });
+        }
+        Ok(())
+    }
+    .await;
+    finish_attempt(id, result, SettlementStatus::FirstLegConfirmed);
+}
+
+/// Sends what this broker owes on net, then has the other broker credit its users.
+async fn send_second_leg(id: u64) {
+    let settlement =
+        match with_settlements_mut(|s| s.start_transfer(id, SettlementStatus::FirstLegConfirmed)) {
+            Some(settlement) => settlement,
+            None => return,
+        };
+    let result: Result<()> = async {
+        let remaining = saturating_sub(&settlement.net_out, &settlement.net_out_sent);
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            let amount = remaining.get(&token).clone();
+            if !amount.is_nonzero() {
+                continue;
+            }
+            let broker_shard = get_broker_assigned_shard(settlement.broker, token.clone()).await?;
+            let result: Result<()> = ic_cdk::call(
+                has_token_info::get_assigned_shard(&token),
+                "shardTransfer",
+                (broker_shard, settlement.broker, amount.clone().to_nat()),
+            )
+            .await
+            .map_err(|e| e.into_tx_error());
+            result?;
+            with_settlements_mut(|s| {
+                if let Some(settlement) = s.get_mut(id) {
+                    settlement.net_out_sent.get_mut(&token).add_assign(amount);
+                }
+            });
+        }
+        flatten_response(
+            ic_cdk::call(
+                settlement.broker,
+                "creditNettedFunds",
+                (
+                    id,
+                    settlement.remote_credits.clone(),
+                    Some(settlement.remote_fees.clone()),
+                ),
+            )
+            .await,
+        )
+    }
+    .await;
+    finish_attempt(id, result, SettlementStatus::Completed);
+}
+
+/// Has the other broker refund its takers of a settlement that failed.
+async fn send_remote_refunds(id: u64) {
+    let settlement =
+        match with_settlements_mut(|s| s.start_transfer(id, SettlementStatus::Compensating)) {
+            Some(settlement) => settlement,
+            None => return,
+        };
+    let result: Result<()> = flatten_response(
+        ic_cdk::call(
+            settlement.broker,
+            "refundNettedFunds",
+            (id, settlement.remote_refunds.clone()),
+        )
+        .await,
+    );
+    finish_attempt(id, result, SettlementStatus::Failed);
+}
+
+/// Starts settling a round of exchanges with another broker, or forwards them to the other broker
+/// if it is the one settling the pair. A batch the other broker does not take is settled here.
+pub async fn settle_batch(broker: Principal, batch: ExchangeBatch) {
+    if broker < ic_cdk::id() {
+        let batch_id = with_settlements_mut(|s| s.add_forwarded_out(broker, batch.clone()));
+        let result: Result<()> = flatten_response(
+            ic_cdk::call(broker, "forwardExchangeBatch", (batch_id, batch.clone())).await,
+        );
+        match result {
+            Ok(()) => return,
+            Err(error) => {
+                ic_cdk::print(format!(
+                    "[broker] could not forward exchanges to broker {}: {:?}",
+                    broker, error
+                ));
+                with_settlements_mut(|s| s.remove_forwarded_out(batch_id));
+            }
+        }
+    }
+    let id = with_settlements_mut(|s| s.create_new(broker, batch, ic_cdk::api::time()));
+    retry_settlement(id).await;
+}
+
+/// Runs whichever legs of a settlement are due.
+pub async fn retry_settlement(id: u64) {
+    send_first_leg(id).await;
+    send_second_leg(id).await;
+    send_remote_refunds(id).await;
+}
+
+/// Settles the batches forwarded by brokers this broker had no exchanges with, compensates
+/// settlements that timed out, then retries the legs that are still due.
+pub async fn process_settlements() {
+    let now = ic_cdk::api::time();
+    let (first_legs, second_legs, refunds) = with_settlements_mut(|s| {
+        let brokers: Vec<Principal> = s.forwarded.keys().copied().collect();
+        for broker in brokers {
+            s.create_new(broker, Default::default(), now);
+        }
+        let mut first_legs = vec![];
+        let mut second_legs = vec![];
+        let mut refunds = vec![];
+        for settlement in s.settlements.values_mut() {
+            if settlement.has_timed_out(now) {
+                ic_cdk::println!(
+                    "[broker] settlement {} timed out, refunding local users",
+                    settlement.id
+                );
+                settlement.compensate(now);
+            }
+            match settlement.status {
+                SettlementStatus::Initiated
+                | SettlementStatus::FirstLegSent
+                | SettlementStatus::FirstLegPartiallyReceived => first_legs.push(settlement.id),
+                SettlementStatus::FirstLegConfirmed => second_legs.push(settlement.id),
+                SettlementStatus::Compensating => refunds.push(settlement.id),
+                _ => {}
+            }
+        }
+        (first_legs, second_legs, refunds)
+    });
+    futures::future::join_all(first_legs.into_iter().map(send_first_leg)).await;
+    futures::future::join_all(second_legs.into_iter().map(send_second_leg)).await;
+    futures::future::join_all(refunds.into_iter().map(send_remote_refunds)).await;
+}
+
+#[cfg(test)]
+mod settlements_tests {
+    use candid::Nat;
+
+    use crate::payoffs::TransferInfo;
+
+    use super::*;
+
+    fn exchange(
+        broker: u8,
+        taker: u8,
+        maker: u8,
+        a: u64,
+        b: u64,
+    ) -> (TokenExchangeInfo, StableNat) {
+        let exchange = TokenExchangeInfo {
+            local_user: TransferInfo {
+                broker: Principal::from_slice(&[broker]),
+                token: EnokiToken::TokenA,
+                to: Principal::from_slice(&[taker]),
+                amount: StableNat::from(a),
+            },
+            other_user: TransferInfo {
+                broker: Principal::from_slice(&[0]),
+                token: EnokiToken::TokenB,
+                to: Principal::from_slice(&[maker]),
+                amount: StableNat::from(b),
+            },
+        };
+        (exchange, StableNat::from(1))
+    }
+
+    #[test]
+    fn test_net_exchanges_per_broker() {
+        let this_broker = Principal::from_slice(&[0]);
+        let other_broker = Principal::from_slice(&[1]);
+        let mut sell = exchange(1, 10, 21, 0, 0);
+        sell.0.local_user.token = EnokiToken::TokenB;
+        sell.0.local_user.amount = StableNat::from(150);
+        sell.0.other_user.token = EnokiToken::TokenA;
+        sell.0.other_user.amount = StableNat::from(30);
+        let (local, local_fees, batches) = net_exchanges(
+            vec![
+                exchange(1, 10, 20, 100, 200),
+                exchange(1, 11, 20, 50, 100),
+                sell,
+                exchange(0, 12, 22, 5, 10),
+            ],
+            this_broker,
+        );
+        assert_eq!(local.len(), 2);
+        assert_eq!(
+            local[&Principal::from_slice(&[22])].token_b,
+            StableNat::from(10)
+        );
+        assert_eq!(
+            local_fees[&Principal::from_slice(&[12])].token_b,
+            StableNat::from(1)
+        );
+        assert_eq!(batches.len(), 1);
+
+        let mut settlements = Settlements::default();
+        let id = settlements.create_new(other_broker, batches[&other_broker].clone(), 0);
+        let settlement = settlements.get_mut(id).unwrap();
+        assert_eq!(settlement.net_in.token_a, StableNat::from(120));
+        assert!(!settlement.net_in.token_b.is_nonzero());
+        assert!(!settlement.net_out.token_a.is_nonzero());
+        assert_eq!(settlement.net_out.token_b, StableNat::from(150));
+        assert_eq!(settlement.remote_credits.len(), 2);
+        assert_eq!(
+            settlement.remote_credits[&Principal::from_slice(&[20])].token_b,
+            StableNat::from(300)
+        );
+        assert_eq!(
+            settlement.local_refunds[&Principal::from_slice(&[10])].token_b,
+            StableNat::from(200)
+        );
+        assert_eq!(
+            settlement.taker_fees[&Principal::from_slice(&[10])].token_b,
+            StableNat::from(1)
+        );
+        assert_eq!(
+            settlement.taker_fees[&Principal::from_slice(&[10])].token_a,
+            StableNat::from(1)
+        );
+    }
+
+    #[test]
+    fn test_compensate_refunds_taker_fee() {
+        let other_broker = Principal::from_slice(&[1]);
+        let taker = Principal::from_slice(&[10]);
+        let (_, _, batches) = net_exchanges(
+            vec![exchange(1, 10, 20, 100, 200)],
+            Principal::from_slice(&[0]),
+        );
+        let mut settlements = Settlements::default();
+        let id = settlements.create_new(other_broker, batches[&other_broker].clone(), 0);
+        settlements.cancel(id, 1).unwrap();
+        assert_eq!(
+            settlements.get_mut(id).unwrap().status,
+            SettlementStatus::Failed
+        );
+        let balance = balances::get_balance(taker);
+        assert_eq!(balance.token_b, Nat::from(201u32));
+        assert_eq!(balance.token_a, Nat::from(0u32));
+    }
+
+    #[test]
+    fn test_forwarded_batch_is_netted_both_ways() {
+        let this_broker = Principal::from_slice(&[0]);
+        let other_broker = Principal::from_slice(&[1]);
+        // the other broker's taker 30 bought 80 A from maker 13 here for 150 B, fee 1 B
+        let (_, _, forwarded) = net_exchanges(vec![exchange(0, 30, 13, 80, 150)], other_broker);
+        let (_, _, batches) = net_exchanges(vec![exchange(1, 10, 20, 100, 200)], this_broker);
+        let mut settlements = Settlements::default();
+        settlements.add_forwarded(other_broker, 1, forwarded[&this_broker].clone());
+        let id = settlements.create_new(other_broker, batches[&other_broker].clone(), 0);
+        assert!(settlements.forwarded.is_empty());
+        let settlement = settlements.get_mut(id).unwrap();
+        assert_eq!(settlement.net_in.token_a, StableNat::from(20));
+        assert!(!settlement.net_in.token_b.is_nonzero());
+        assert!(!settlement.net_out.token_a.is_nonzero());
+        assert_eq!(settlement.net_out.token_b, StableNat::from(50));
+        let maker = Principal::from_slice(&[13]);
+        let remote_taker = Principal::from_slice(&[30]);
+        assert_eq!(
+            settlement.local_credits[&maker].token_b,
+            StableNat::from(150)
+        );
+        assert_eq!(
+            settlement.remote_credits[&remote_taker].token_a,
+            StableNat::from(80)
+        );
+        assert_eq!(
+            settlement.remote_refunds[&remote_taker].token_b,
+            StableNat::from(151)
+        );
+        assert_eq!(
+            settlement.remote_fees[&remote_taker].token_b,
+            StableNat::from(1)
+        );
+
+        settlements.cancel(id, 1).unwrap();
+        let settlement = settlements.get_mut(id).unwrap();
+        assert_eq!(settlement.status, SettlementStatus::Compensating);
+        assert_eq!(balances::get_balance(maker).token_b, Nat::from(0u32));
+    }
+
+    #[test]
+    fn test_netted_funds_are_checked_against_own_batches() {
+        let this_broker = Principal::from_slice(&[0]);
+        let other_broker = Principal::from_slice(&[1]);
+        let (_, _, forwarded) = net_exchanges(vec![exchange(0, 30, 13, 80, 150)], other_broker);
+        let (_, _, batches) = net_exchanges(vec![exchange(1, 10, 20, 100, 200)], this_broker);
+        let mut remote = Settlements::default();
+        let batch_id = remote.add_forwarded_out(this_broker, forwarded[&this_broker].clone());
+        let mut settlements = Settlements::default();
+        settlements.add_forwarded(other_broker, batch_id, forwarded[&this_broker].clone());
+        let id = settlements.create_new(other_broker, batches[&other_broker].clone(), 0);
+        let settlement = settlements.get_mut(id).unwrap().clone();
+        let own_batch = settlement.own_batch.unwrap();
+        let forwarded_ids = settlement.forwarded_ids.unwrap();
+
+        assert!(remote
+            .open_remote(
+                Principal::from_slice(&[2]),
+                id,
+                forwarded_ids.clone(),
+                Default::default()
+            )
+            .is_err());
+        remote
+            .open_remote(this_broker, id, forwarded_ids, own_batch)
+            .unwrap();
+        let token = EnokiToken::TokenA;
+        let owed = &settlement.net_in.token_a;
+        let is_invalid = |result: Result<StableNat>| {
+            matches!(result.map_err(TxError::from), Err(TxError::InvalidInput(_)))
+        };
+        assert!(is_invalid(remote.netted_amount_to_send(
+            this_broker,
+            id,
+            &token,
+            &StableNat::from(1000)
+        )));
+        assert!(is_invalid(remote.netted_amount_to_send(
+            Principal::from_slice(&[2]),
+            id,
+            &token,
+            owed
+        )));
+        assert!(is_invalid(remote.netted_amount_to_send(
+            this_broker,
+            id + 1,
+            &token,
+            owed
+        )));
+        assert!(is_invalid(remote.netted_amount_to_send(
+            this_broker,
+            id,
+            &EnokiToken::TokenB,
+            owed
+        )));
+        assert_eq!(
+            &remote
+                .netted_amount_to_send(this_broker, id, &token, owed)
+                .unwrap(),
+            owed
+        );
+        remote.mark_netted_sent(this_broker, id, &token, owed.clone());
+        assert!(!remote
+            .netted_amount_to_send(this_broker, id, &token, owed)
+            .unwrap()
+            .is_nonzero());
+    }
+
+    #[test]
+    fn test_partially_received_is_not_compensable() {
+        let mut settlements = Settlements::default();
+        let id = settlements.create_new(Principal::from_slice(&[1]), Default::default(), 0);
+        let settlement = settlements.get_mut(id).unwrap();
+        settlement.set_status(SettlementStatus::FirstLegPartiallyReceived, 0);
+        assert!(!settlement.has_timed_out(2 * SETTLEMENT_TIMEOUT));
+        assert!(settlements.cancel(id, 1).is_err());
+    }
+
+    #[test]
+    fn test_settlement_timeout() {
+        let mut settlements = Settlements::default();
+        let id = settlements.create_new(Principal::from_slice(&[1]), Default::default(), 0);
+        let settlement = settlements.get_mut(id).unwrap();
+        assert!(!settlement.has_timed_out(SETTLEMENT_TIMEOUT - 1));
+        settlement.transfer_in_progress = true;
+        assert!(!settlement.has_timed_out(SETTLEMENT_TIMEOUT));
+        settlement.transfer_in_progress = false;
+        assert!(settlement.has_timed_out(SETTLEMENT_TIMEOUT));
+        settlement.set_status(SettlementStatus::FirstLegConfirmed, SETTLEMENT_TIMEOUT);
+        assert!(!settlement.has_timed_out(2 * SETTLEMENT_TIMEOUT));
+    }
+}
@@ -10,6 +10,7 @@ pub async fn send_swap_tokens(
     user: Principal,
     token: &EnokiToken,
     amount_to_send: Nat,
+    to_balance: bool,
 ) -> Result<()> {
     ic_cdk::api::print(format!(
         "[broker] swap -> sending user {} {:?} {:?}",
@@ -17,11 +18,18 @@ pub async fn send_swap_tokens(
     ));
     let lp_location = get_lp_worker_location();
     let lp_shard = get_lp_worker_assigned_shard(token);
-    let user_shard = get_user_shard(user, has_token_info::get_token_address(token))?;
+    let (recipient, recipient_shard) = if to_balance {
+        (ic_cdk::id(), has_token_info::get_assigned_shard(token))
+    } else {
+        (
+            user,
+            get_user_shard(user, has_token_info::get_token_address(token))?,
+        )
+    };
     let result: Result<()> = ic_cdk::call(
         lp_shard,
         "shardSpend",
-        (lp_location, user_shard, user, amount_to_send),
+        (lp_location, recipient_shard, recipient, amount_to_send),
     )
     .await
     .map_err(|e| e.into_tx_error());
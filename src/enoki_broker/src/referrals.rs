@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::AddAssign;
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_sharded_users::{get_user_shard, register_user};
+use enoki_exchange_shared::has_token_info;
+use enoki_exchange_shared::has_trading_fees::get_referral_share;
+use enoki_exchange_shared::types::*;
+use enoki_exchange_shared::utils::nat_x_float;
+
+use crate::payoffs::get_fee_for_transfer;
+
+thread_local! {
+    static STATE: RefCell<ReferralsState> = RefCell::new(ReferralsState::default());
+}
+
+/// Who referred each user, and the share of their fees owed to each referrer.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ReferralsState {
+    referrers: HashMap<Principal, Principal>,
+    rewards: HashMap<Principal, LiquidityAmount>,
+}
+
+pub fn set_referrer(user: Principal, referrer: Principal) {
+    STATE.with(|s| s.borrow_mut().referrers.insert(user, referrer));
+}
+
+/// Moves the referrer's share of a fee paid by `user` to their rewards. Returns what is left of
+/// the fee.
+pub fn share_fee(user: Principal, token: &EnokiToken, fee: Nat) -> Nat {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let referrer = match s.referrers.get(&user) {
+            Some(&referrer) => referrer,
+            None => return fee,
+        };
+        let share = nat_x_float(fee.clone(), get_referral_share())
+            .unwrap_or_default()
+            .min(fee.clone());
+        s.rewards
+            .entry(referrer)
+            .or_default()
+            .get_mut(token)
+            .add_assign(share.clone().into());
+        fee - share
+    })
+}
+
+/// The referrers with rewards left to claim.
+pub fn users_with_rewards() -> Vec<Principal> {
+    STATE.with(|s| {
+        s.borrow()
+            .rewards
+            .iter()
+            .filter(|(_, rewards)| rewards.is_nonzero())
+            .map(|(&referrer, _)| referrer)
+            .collect()
+    })
+}
+
+#[query(name = "getReferrer")]
+#[candid_method(query, rename = "getReferrer")]
+fn get_referrer(user: Principal) -> Option<Principal> {
+    STATE.with(|s| s.borrow().referrers.get(&user).copied())
+}
+
+#[query(name = "getReferralRewards")]
+#[candid_method(query, rename = "getReferralRewards")]
+fn get_referral_rewards(referrer: Principal) -> LiquidityAmountNat {
+    STATE
+        .with(|s| s.borrow().rewards.get(&referrer).cloned())
+        .unwrap_or_default()
+        .into()
+}
+
+/// Sends the caller's referral rewards to their wallet; the token takes its transfer fee out of
+/// them. Rewards too small to cover the fee are kept. Returns the amounts sent.
+#[update(name = "claimReferralRewards")]
+#[candid_method(update, rename = "claimReferralRewards")]
+async fn claim_referral_rewards() -> TxResult<LiquidityAmountNat> {
+    let referrer = ic_cdk::caller();
+    let transfer_fees = (
+        get_fee_for_transfer(&EnokiToken::TokenA).await?,
+        get_fee_for_transfer(&EnokiToken::TokenB).await?,
+    );
+    if get_user_shard(
+        referrer,
+        has_token_info::get_token_address(&EnokiToken::TokenA),
+    )
+    .is_err()
+    {
+        register_user(referrer).await?;
+    }
+    let mut claimed = LiquidityAmount::default();
+    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+        let transfer_fee = match token {
+            EnokiToken::TokenA => transfer_fees.0.clone(),
+            EnokiToken::TokenB => transfer_fees.1.clone(),
+        };
+        let amount: Nat = STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            let reward = s.rewards.entry(referrer).or_default().get_mut(&token);
+            if reward.compare_with(&transfer_fee) != std::cmp::Ordering::Greater {
+                return Nat::default();
+            }
+            std::mem::take(reward).to_nat()
+        });
+        if amount == 0u32 {
+            continue;
+        }
+        let user_shard = get_user_shard(referrer, has_token_info::get_token_address(&token))?;
+        let response: Result<()> = ic_cdk::call(
+            has_token_info::get_assigned_shard(&token),
+            "shardTransfer",
+            (user_shard, referrer, amount.clone()),
+        )
+        .await
+        .map_err(|e| e.into_tx_error());
+        if let Err(error) = response {
+            STATE.with(|s| {
+                s.borrow_mut()
+                    .rewards
+                    .entry(referrer)
+                    .or_default()
+                    .get_mut(&token)
+                    .add_assign(amount.into())
+            });
+            return Err(error.into());
+        }
+        claimed.get_mut(&token).add_assign(amount.into());
+    }
+    Ok(claimed.into())
+}
+
+pub fn export_stable_storage() -> ReferralsState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: ReferralsState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod referrals_tests {
+    use enoki_exchange_shared::has_trading_fees::{init_fee_info, TradingFees};
+
+    use super::*;
+
+    #[test]
+    fn test_share_fee_with_referrer() {
+        init_fee_info(TradingFees {
+            referral_share: Some(0.2),
+            ..Default::default()
+        })
+        .unwrap();
+        let user = Principal::from_slice(&[1]);
+        let referred = Principal::from_slice(&[2]);
+        let referrer = Principal::from_slice(&[3]);
+        set_referrer(referred, referrer);
+
+        assert_eq!(
+            share_fee(user, &EnokiToken::TokenA, Nat::from(100u32)),
+            100u32
+        );
+        assert_eq!(
+            share_fee(referred, &EnokiToken::TokenA, Nat::from(100u32)),
+            80u32
+        );
+        assert_eq!(
+            share_fee(referred, &EnokiToken::TokenB, Nat::from(4u32)),
+            4u32
+        );
+        let rewards = get_referral_rewards(referrer);
+        assert_eq!(rewards.token_a, 20u32);
+        assert_eq!(rewards.token_b, 0u32);
+        assert_eq!(users_with_rewards(), vec![referrer]);
+    }
+}
@@ -1,9 +1,13 @@
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
-use enoki_exchange_shared::{has_token_info, has_trading_fees, is_managed, is_owned};
+use enoki_exchange_shared::{
+    has_rejected_deposits, has_token_info, has_trading_fees, is_managed, is_owned,
+};
+use enoki_exchange_shared::has_rejected_deposits::RejectedDeposit;
 use enoki_exchange_shared::has_token_info::{AssignedShards, TokenPairInfo};
 use enoki_exchange_shared::has_trading_fees::TradingFees;
+use enoki_exchange_shared::types::TxResult;
 
 #[query(name = "getOwner")]
 #[candid_method(query, rename = "getOwner")]
@@ -13,8 +17,8 @@ fn get_owner() -> Principal {
 
 #[update(name = "setOwner")]
 #[candid_method(update, rename = "setOwner")]
-fn set_owner(new_owner: Principal) {
-    is_owned::set_owner(new_owner).unwrap()
+fn set_owner(new_owner: Principal) -> TxResult<()> {
+    Ok(is_owned::set_owner(new_owner)?)
 }
 
 #[query(name = "getTokenInfo")]
@@ -55,6 +59,12 @@ fn get_manager() -> Principal {
 
 #[update(name = "setManager")]
 #[candid_method(update, rename = "setManager")]
-fn set_manager(new_manager: Principal) {
-    is_managed::set_manager(new_manager).unwrap()
+fn set_manager(new_manager: Principal) -> TxResult<()> {
+    Ok(is_managed::set_manager(new_manager)?)
+}
+
+#[query(name = "getRejectedDeposits")]
+#[candid_method(query, rename = "getRejectedDeposits")]
+fn get_rejected_deposits(user: Principal) -> Vec<RejectedDeposit> {
+    has_rejected_deposits::get_rejected_deposits(user)
 }
@@ -42,31 +42,32 @@ pub fn get_lp_worker_assigned_shard(token: &EnokiToken) -> Principal {
 
 #[update(name = "initBroker")]
 #[candid_method(update, rename = "initBroker")]
-async fn init_broker(params: InitBrokerParams) -> AssignedShards {
+async fn init_broker(params: InitBrokerParams) -> TxResult<AssignedShards> {
     let InitBrokerParams {
         other_brokers,
         supply_token_info,
         liquidity_location,
         trading_fees,
     } = params;
-    is_managed::assert_is_manager().unwrap();
+    is_managed::assert_is_manager()?;
+    has_trading_fees::validate_trading_fees(&trading_fees)?;
     init_brokers(other_brokers);
-    has_token_info::init_token_info(supply_token_info).await.unwrap();
+    has_token_info::init_token_info(supply_token_info).await?;
     let assigned = has_token_info::get_assigned_shards();
 
     let worker_assigned_shards: Result<(AssignedShards, )> =
         ic_cdk::call(liquidity_location, "getAssignedShards", ())
             .await
             .map_err(|e| e.into_tx_error());
-    let worker_assigned_shards = worker_assigned_shards.unwrap().0;
+    let worker_assigned_shards = worker_assigned_shards?.0;
 
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.liquidity_location = liquidity_location;
         s.assigned_shards_for_worker = worker_assigned_shards;
     });
-    has_trading_fees::init_fee_info(trading_fees);
-    assigned
+    has_trading_fees::init_fee_info(trading_fees)?;
+    Ok(assigned)
 }
 
 pub fn export_stable_storage() -> TokenLiquidityData {
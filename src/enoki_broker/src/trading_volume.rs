@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::AddAssign;
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_token_info;
+use enoki_exchange_shared::has_trading_fees;
+use enoki_exchange_shared::types::*;
+
+const DAY_NANOS: u64 = 24 * 3600 * 1_000_000_000;
+const VOLUME_WINDOW_DAYS: u64 = 30;
+
+thread_local! {
+    static STATE: RefCell<TradingVolumeState> = RefCell::new(TradingVolumeState::default());
+}
+
+/// Daily traded volume of each user, in token B, over the last 30 days. It decides the fee tier
+/// of their new orders.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct TradingVolumeState {
+    volumes: HashMap<Principal, BTreeMap<u64, StableNat>>,
+}
+
+impl TradingVolumeState {
+    fn add(&mut self, user: Principal, volume: StableNat, now: u64) {
+        let today = now / DAY_NANOS;
+        let days = self.volumes.entry(user).or_default();
+        days.entry(today).or_default().add_assign(volume);
+        let first_day = (today + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        *days = days.split_off(&first_day);
+    }
+    fn get(&self, user: Principal, now: u64) -> StableNat {
+        let first_day = (now / DAY_NANOS + 1).saturating_sub(VOLUME_WINDOW_DAYS);
+        self.volumes
+            .get(&user)
+            .map(|days| {
+                days.range(first_day..)
+                    .map(|(_, volume)| volume.clone())
+                    .sum()
+            })
+            .unwrap_or_default()
+    }
+}
+
+fn get_filled_quantity_in_b(order: &Order) -> Result<Nat> {
+    let filled = (order.info.quantity.clone() - order.state.quantity_remaining.clone())?.to_nat();
+    match order.info.side {
+        Side::Buy => Ok(filled),
+        Side::Sell => has_token_info::quantity_a_to_b(filled, order.info.limit_price),
+    }
+}
+
+/// Adds what was filled of the completed orders to their users' volumes.
+pub fn record_completed_orders(orders: &[Order]) {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for order in orders {
+            match get_filled_quantity_in_b(order) {
+                Ok(volume) => s.add(order.info.user, volume.into(), now),
+                Err(error) => ic_cdk::print(format!(
+                    "[broker] cannot compute volume of order {}: {:?}",
+                    order.info.id, error
+                )),
+            }
+        }
+    });
+}
+
+pub fn get_user_fees(user: Principal) -> OrderFees {
+    let volume = STATE.with(|s| s.borrow().get(user, ic_cdk::api::time()));
+    has_trading_fees::get_limit_order_fees(&volume)
+}
+
+#[query(name = "getTradingVolume")]
+#[candid_method(query, rename = "getTradingVolume")]
+fn get_trading_volume(user: Principal) -> Nat {
+    STATE
+        .with(|s| s.borrow().get(user, ic_cdk::api::time()))
+        .to_nat()
+}
+
+#[query(name = "getUserFees")]
+#[candid_method(query, rename = "getUserFees")]
+fn get_user_fees_query(user: Principal) -> OrderFees {
+    get_user_fees(user)
+}
+
+pub fn export_stable_storage() -> TradingVolumeState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: TradingVolumeState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod trading_volume_tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_volume() {
+        let user = Principal::anonymous();
+        let mut state = TradingVolumeState::default();
+        state.add(user, StableNat::from(100), 0);
+        state.add(user, StableNat::from(50), 10 * DAY_NANOS);
+        assert_eq!(state.get(user, 10 * DAY_NANOS), StableNat::from(150));
+        assert_eq!(state.get(user, 35 * DAY_NANOS), StableNat::from(50));
+
+        state.add(user, StableNat::from(1), 45 * DAY_NANOS);
+        assert_eq!(state.volumes[&user].len(), 1);
+        assert_eq!(state.get(user, 45 * DAY_NANOS), StableNat::from(1));
+    }
+}
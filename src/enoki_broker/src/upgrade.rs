@@ -1,22 +1,30 @@
 use candid::{CandidType, Deserialize};
 use ic_cdk_macros::*;
 
+use enoki_exchange_shared::has_rejected_deposits::RejectedDepositsState;
 use enoki_exchange_shared::has_sharded_users::ShardedUserState;
 use enoki_exchange_shared::has_token_info::TokenInfoState;
 use enoki_exchange_shared::has_trading_fees::TradingFees;
 use enoki_exchange_shared::is_managed::ManagementData;
 use enoki_exchange_shared::is_owned::OwnershipData;
 use enoki_exchange_shared::{
-    has_sharded_users, has_token_info, has_trading_fees, is_managed, is_owned,
+    has_rejected_deposits, has_sharded_users, has_token_info, has_trading_fees, is_managed,
+    is_owned,
 };
 
+use crate::balances::BalancesState;
 use crate::liquidity::LiquidityState;
 use crate::orders::OrdersState;
 use crate::other_brokers::BrokersState;
 use crate::payoffs::{AccruedFees, PayoffsState};
+use crate::referrals::ReferralsState;
 use crate::token_liquidity_params::TokenLiquidityData;
+use crate::trading_volume::TradingVolumeState;
 use crate::users::UsersState;
-use crate::{liquidity, orders, other_brokers, payoffs, token_liquidity_params, users};
+use crate::{
+    balances, liquidity, orders, other_brokers, payoffs, referrals, token_liquidity_params,
+    trading_volume, users,
+};
 
 #[derive(Deserialize, CandidType)]
 struct UpgradePayload {
@@ -32,6 +40,10 @@ struct UpgradePayload {
     manager: ManagementData,
     owner: OwnershipData,
     users: UsersState,
+    balances: Option<BalancesState>,
+    rejected_deposits: Option<RejectedDepositsState>,
+    trading_volume: Option<TradingVolumeState>,
+    referrals: Option<ReferralsState>,
 }
 
 #[pre_upgrade]
@@ -48,6 +60,10 @@ fn pre_upgrade() {
     let manager = is_managed::export_stable_storage();
     let owner = is_owned::export_stable_storage();
     let users = users::export_stable_storage();
+    let balances = balances::export_stable_storage();
+    let rejected_deposits = has_rejected_deposits::export_stable_storage();
+    let trading_volume = trading_volume::export_stable_storage();
+    let referrals = referrals::export_stable_storage();
     let payload = UpgradePayload {
         liquidity,
         brokers,
@@ -61,6 +77,10 @@ fn pre_upgrade() {
         manager,
         owner,
         users,
+        balances: Some(balances),
+        rejected_deposits: Some(rejected_deposits),
+        trading_volume: Some(trading_volume),
+        referrals: Some(referrals),
     };
     ic_cdk::storage::stable_save((payload,)).expect("failed to save to stable storage");
 }
@@ -83,6 +103,10 @@ fn post_upgrade() {
         manager,
         owner,
         users,
+        balances,
+        rejected_deposits,
+        trading_volume,
+        referrals,
     } = payload;
 
     liquidity::import_stable_storage(liquidity);
@@ -97,4 +121,16 @@ fn post_upgrade() {
     is_managed::import_stable_storage(manager);
     is_owned::import_stable_storage(owner);
     users::import_stable_storage(users);
+    if let Some(balances) = balances {
+        balances::import_stable_storage(balances);
+    }
+    if let Some(rejected_deposits) = rejected_deposits {
+        has_rejected_deposits::import_stable_storage(rejected_deposits);
+    }
+    if let Some(trading_volume) = trading_volume {
+        trading_volume::import_stable_storage(trading_volume);
+    }
+    if let Some(referrals) = referrals {
+        referrals::import_stable_storage(referrals);
+    }
 }
@@ -7,9 +7,12 @@ use ic_cdk_macros::*;
 use enoki_exchange_shared::is_managed::assert_is_manager;
 use enoki_exchange_shared::types::*;
 
+use crate::{balances, orders, payoffs, referrals};
+
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct UsersState {
     users: HashSet<Principal>,
+    draining: Option<bool>,
 }
 
 thread_local! {
@@ -28,11 +31,48 @@ pub fn assert_is_user(principal: Principal) -> Result<()> {
     }
 }
 
+/// A broker being retired by the exchange no longer takes new orders.
+pub fn assert_accepts_orders() -> Result<()> {
+    if STATE.with(|s| s.borrow().draining.unwrap_or_default()) {
+        Err(TxError::InvalidState("broker is being retired".to_string()).into())
+    } else {
+        Ok(())
+    }
+}
+
 #[update(name = "addUser")]
 #[candid_method(update, rename = "addUser")]
-fn add_user(principal: Principal) {
-    assert_is_manager().unwrap();
+fn add_user(principal: Principal, referrer: Option<Principal>) -> TxResult<()> {
+    assert_is_manager()?;
     STATE.with(|s| s.borrow_mut().users.insert(principal));
+    if let Some(referrer) = referrer {
+        referrals::set_referrer(principal, referrer);
+    }
+    Ok(())
+}
+
+#[update(name = "setDraining")]
+#[candid_method(update, rename = "setDraining")]
+fn set_draining() -> TxResult<()> {
+    assert_is_manager()?;
+    STATE.with(|s| s.borrow_mut().draining = Some(true));
+    Ok(())
+}
+
+/// What keeps this broker from being dropped by the exchange once it is draining: users can still
+/// withdraw and claim from it, so it has to hold nothing for them anymore.
+#[query(name = "getDrainStatus")]
+#[candid_method(query, rename = "getDrainStatus")]
+fn get_drain_status() -> DrainStatus {
+    let mut users_with_funds: HashSet<Principal> =
+        balances::users_with_funds().into_iter().collect();
+    users_with_funds.extend(referrals::users_with_rewards());
+    users_with_funds.extend(payoffs::users_with_market_maker_rewards());
+    DrainStatus {
+        users_with_funds: users_with_funds.len() as u64,
+        open_settlements: payoffs::count_open_settlements(),
+        failed_orders: orders::count_failed_orders(),
+    }
 }
 
 pub fn export_stable_storage() -> UsersState {
@@ -1,18 +1,19 @@
 use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 
-use candid::utils::{ArgumentDecoder, ArgumentEncoder};
-use candid::{candid_method, CandidType, Nat, Principal};
+use candid::utils::ArgumentEncoder;
+use candid::{candid_method, CandidType, Principal};
+use serde::de::DeserializeOwned;
 use ic_cdk_macros::*;
 
 use enoki_exchange_shared::has_sharded_users::{get_user_shard, register_user_with};
 use enoki_exchange_shared::has_token_info::AssignedShards;
-use enoki_exchange_shared::has_trading_fees::TradingFees;
 use enoki_exchange_shared::is_owned;
 use enoki_exchange_shared::types::*;
 use enoki_exchange_shared::{has_token_info, has_trading_fees};
 
 use crate::liquidity::{self, get_liquidity_location, init_broker_lp};
+use crate::{orders, protocol_fees, referrals};
 
 #[allow(unused)]
 pub fn assert_is_broker_contract() -> Result<()> {
@@ -26,8 +27,28 @@ pub fn assert_is_broker_contract() -> Result<()> {
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct BrokerState {
     pub brokers: HashSet<Principal>,
-    pub current_broker_index: usize,
     pub users: HashMap<Principal, Principal>,
+    /// brokers being removed: they get no new users and are dropped once they hold no orders,
+    /// liquidity or user funds
+    pub draining: Option<HashSet<Principal>>,
+    /// brokers users were moved away from, which can still hold their funds until withdrawn
+    pub former_brokers: Option<HashMap<Principal, Vec<Principal>>>,
+}
+
+/// How busy a broker is when a user has to be assigned to one.
+#[derive(Clone, Debug, PartialEq)]
+struct BrokerLoad {
+    broker: Principal,
+    open_orders: usize,
+    users: usize,
+}
+
+/// The broker with the fewest open orders, then the fewest users.
+fn least_loaded(loads: &[BrokerLoad]) -> Option<Principal> {
+    loads
+        .iter()
+        .min_by_key(|load| (load.open_orders, load.users, load.broker))
+        .map(|load| load.broker)
 }
 
 thread_local! {
@@ -36,27 +57,24 @@ thread_local! {
 
 pub async fn foreach_broker<
     T: ArgumentEncoder,
-    R: for<'a> ArgumentDecoder<'a>,
+    R: CandidType + DeserializeOwned,
     TF: FnMut(Principal) -> T,
 >(
     method: &str,
     mut args_getter: TF,
 ) -> Result<Vec<R>> {
     let ids = get_broker_ids();
-    let responses: Vec<std::result::Result<R, _>> = futures::future::join_all(
+    let responses: Vec<std::result::Result<(TxResult<R>,), _>> = futures::future::join_all(
         ids.into_iter()
             .map(|id| ic_cdk::call(id, method, args_getter(id))),
     )
     .await;
-    responses
-        .into_iter()
-        .collect::<std::result::Result<Vec<R>, _>>()
-        .map_err(|err| err.into_tx_error())
+    responses.into_iter().map(flatten_response).collect()
 }
 
 pub async fn foreach_broker_map<
     T: ArgumentEncoder,
-    R: for<'a> ArgumentDecoder<'a>,
+    R: CandidType + DeserializeOwned,
     TF: FnMut(Principal) -> T,
     FR,
     RM: Fn(R) -> FR,
@@ -74,41 +92,80 @@ pub async fn foreach_broker_map<
         .collect())
 }
 
+/// Assigns `user` to a broker. A referral code is passed on to the broker, which shares the fees
+/// of the user with the code's owner.
 #[update(name = "register")]
 #[candid_method(update, rename = "register")]
-pub async fn register(user: Principal) -> Principal {
+pub async fn register(user: Principal, referral_code: Option<String>) -> TxResult<Principal> {
     if let Some(broker) = STATE.with(|s| s.borrow().users.get(&user).copied()) {
-        return broker;
+        return Ok(broker);
     }
+    let referrer = match &referral_code {
+        Some(code) => Some(referrals::get_referrer(user, code)?),
+        None => None,
+    };
 
-    let assigned_broker = STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        let i = s.current_broker_index;
-        s.current_broker_index += 1;
-        let mut brokers: Vec<_> = s.brokers.iter().copied().collect();
-        brokers.sort();
-        brokers[i % brokers.len()]
-    });
-    let result: Result<()> = ic_cdk::call(assigned_broker, "addUser", (user,))
-        .await
-        .map_err(|e| e.into_tx_error().into());
-    result.unwrap();
+    let loads = get_broker_loads().await;
+    let assigned_broker =
+        least_loaded(&loads).ok_or_else(|| TxError::NotInitialized("no brokers".to_string()))?;
+    let result: Result<()> =
+        flatten_response(ic_cdk::call(assigned_broker, "addUser", (user, referrer)).await);
+    result?;
 
     STATE.with(|s| s.borrow_mut().users.insert(user, assigned_broker));
+    if let Some(code) = referral_code {
+        referrals::record_user_code(user, code);
+    }
 
-    assigned_broker
+    Ok(assigned_broker)
 }
 
 #[query(name = "getAssignedBroker")]
 #[candid_method(query, rename = "getAssignedBroker")]
-pub fn get_assigned_broker(user: Principal) -> Principal {
+pub fn get_assigned_broker(user: Principal) -> TxResult<Principal> {
     STATE
         .with(|s| s.borrow().users.get(&user).copied())
         .ok_or(TxError::UserNotRegistered {
             user: user.to_string(),
             registry: ic_cdk::id().to_string(),
         })
-        .unwrap()
+}
+
+/// The brokers `user` was moved away from that are not dropped yet: whatever the user still had
+/// there can be withdrawn or claimed from them.
+#[query(name = "getFormerBrokers")]
+#[candid_method(query, rename = "getFormerBrokers")]
+pub fn get_former_brokers(user: Principal) -> Vec<Principal> {
+    STATE.with(|s| {
+        s.borrow()
+            .former_brokers
+            .as_ref()
+            .and_then(|former| former.get(&user).cloned())
+            .unwrap_or_default()
+    })
+}
+
+/// The load of every broker that takes new users. Brokers that cannot be reached are left out.
+async fn get_broker_loads() -> Vec<BrokerLoad> {
+    let ids = get_active_broker_ids();
+    let responses: Vec<std::result::Result<(usize,), _>> = futures::future::join_all(
+        ids.iter()
+            .map(|&id| ic_cdk::call(id, "getOpenOrdersCount", ())),
+    )
+    .await;
+    STATE.with(|s| {
+        let s = s.borrow();
+        ids.into_iter()
+            .zip(responses)
+            .filter_map(|(broker, response)| {
+                Some(BrokerLoad {
+                    broker,
+                    open_orders: response.ok()?.0,
+                    users: s.users.values().filter(|&&b| b == broker).count(),
+                })
+            })
+            .collect()
+    })
 }
 
 #[query(name = "getBrokerIds")]
@@ -119,39 +176,204 @@ pub fn get_broker_ids() -> Vec<Principal> {
     ids
 }
 
+/// The brokers that take new users.
+pub fn get_active_broker_ids() -> Vec<Principal> {
+    let mut ids: Vec<Principal> = STATE.with(|s| {
+        let s = s.borrow();
+        s.brokers
+            .iter()
+            .filter(|broker| !s.draining.iter().any(|draining| draining.contains(broker)))
+            .copied()
+            .collect()
+    });
+    ids.sort();
+    ids
+}
+
+#[query(name = "getDrainingBrokers")]
+#[candid_method(query, rename = "getDrainingBrokers")]
+pub fn get_draining_brokers() -> Vec<Principal> {
+    let mut ids: Vec<Principal> = STATE.with(|s| {
+        s.borrow()
+            .draining
+            .iter()
+            .flat_map(|draining| draining.iter().copied())
+            .collect()
+    });
+    ids.sort();
+    ids
+}
+
+/// Starts retiring a broker: it stops taking orders and new users, its users are moved to the
+/// least loaded brokers, and its resting orders are cancelled and its liquidity taken back over
+/// the next rounds. It is dropped once it holds neither, and its former users have withdrawn or
+/// claimed what they had left there (see `getFormerBrokers`). Returns the number of users that
+/// could not be moved yet; calling again retries them.
+#[update(name = "removeBroker")]
+#[candid_method(update, rename = "removeBroker")]
+async fn remove_broker(broker: Principal) -> TxResult<u64> {
+    is_owned::assert_is_owner()?;
+    if !STATE.with(|s| s.borrow().brokers.contains(&broker)) {
+        return Err(TxError::NotFound(format!("broker {}", broker)));
+    }
+    if get_active_broker_ids() == vec![broker] {
+        return Err(TxError::InvalidInput(
+            "cannot remove the last active broker".to_string(),
+        ));
+    }
+    let newly_draining = STATE.with(|s| {
+        s.borrow_mut()
+            .draining
+            .get_or_insert_with(Default::default)
+            .insert(broker)
+    });
+    if newly_draining {
+        ic_cdk::println!("[exchange] draining broker {}", broker);
+    }
+    let result: Result<()> = flatten_response(ic_cdk::call(broker, "setDraining", ()).await);
+    result?;
+    Ok(migrate_users(broker).await)
+}
+
+/// Moves the users of a draining broker to the least loaded active brokers. Returns how many are
+/// left.
+async fn migrate_users(broker: Principal) -> u64 {
+    let mut loads = get_broker_loads().await;
+    let users: Vec<Principal> = STATE.with(|s| {
+        s.borrow()
+            .users
+            .iter()
+            .filter(|(_, &b)| b == broker)
+            .map(|(&user, _)| user)
+            .collect()
+    });
+    let mut left = 0;
+    for user in users {
+        let target = match least_loaded(&loads) {
+            Some(target) => target,
+            None => return left + 1,
+        };
+        match migrate_user(user, target).await {
+            Ok(()) => {
+                STATE.with(|s| {
+                    let mut s = s.borrow_mut();
+                    s.users.insert(user, target);
+                    s.former_brokers
+                        .get_or_insert_with(Default::default)
+                        .entry(user)
+                        .or_default()
+                        .push(broker);
+                });
+                if let Some(load) = loads.iter_mut().find(|load| load.broker == target) {
+                    load.users += 1;
+                }
+            }
+            Err(error) => {
+                ic_cdk::print(format!(
+                    "[exchange] could not move user {} to broker {}: {:?}",
+                    user, target, error
+                ));
+                left += 1;
+            }
+        }
+    }
+    left
+}
+
+async fn migrate_user(user: Principal, target: Principal) -> Result<()> {
+    let referrer = referrals::get_registered_referrer(user);
+    let result: Result<()> =
+        flatten_response(ic_cdk::call(target, "addUser", (user, referrer)).await);
+    result?;
+    let result: Result<()> = flatten_response(ic_cdk::call(target, "register", (user,)).await);
+    result
+}
+
+/// Drops the draining brokers that hold no more orders or liquidity, nor anything for their
+/// former users. They stay known to the other brokers, so that what is still being settled with
+/// them can complete.
+pub async fn remove_drained_brokers() {
+    for broker in get_draining_brokers() {
+        let users_left = STATE.with(|s| s.borrow().users.values().any(|&b| b == broker));
+        if users_left
+            || orders::count_open_orders(broker) > 0
+            || !liquidity::is_broker_liquidity_empty(broker)
+        {
+            continue;
+        }
+        let status: std::result::Result<(DrainStatus,), _> =
+            ic_cdk::call(broker, "getDrainStatus", ()).await;
+        match status {
+            Ok((status,)) if status.is_drained() => {}
+            Ok((status,)) => {
+                ic_cdk::println!(
+                    "[exchange] draining broker {} still holds funds: {:?}",
+                    broker,
+                    status
+                );
+                continue;
+            }
+            Err(error) => {
+                ic_cdk::print(format!(
+                    "[exchange] could not get the drain status of broker {}: {:?}",
+                    broker, error
+                ));
+                continue;
+            }
+        }
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            s.brokers.remove(&broker);
+            if let Some(draining) = s.draining.as_mut() {
+                draining.remove(&broker);
+            }
+            if let Some(former) = s.former_brokers.as_mut() {
+                for brokers in former.values_mut() {
+                    brokers.retain(|&b| b != broker);
+                }
+                former.retain(|_, brokers| !brokers.is_empty());
+            }
+        });
+        liquidity::remove_broker_lp(broker);
+        ic_cdk::println!("[exchange] removed drained broker {}", broker);
+    }
+}
+
 #[update(name = "addBroker")]
 #[candid_method(update, rename = "addBroker")]
-async fn add_broker(broker: Principal) {
-    is_owned::assert_is_owner().unwrap();
+async fn add_broker(broker: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
     if STATE.with(|s| s.borrow().brokers.contains(&broker)) {
-        panic!("broker already added");
+        return Err(TxError::InvalidInput("broker already added".to_string()));
     }
     let token_info = has_token_info::get_token_info();
     let token_a = token_info.token_a.principal;
     let token_b = token_info.token_b.principal;
-    let response: Result<(AssignedShards,)> = ic_cdk::call(
-        broker,
-        "initBroker",
-        (InitBrokerParams {
-            other_brokers: get_broker_ids(),
-            supply_token_info: token_info,
-            liquidity_location: get_liquidity_location(),
-            trading_fees: has_trading_fees::get_trading_fees(),
-        },),
-    )
-    .await
-    .map_err(|e| e.into_tx_error());
-    let assigned = response.unwrap().0;
-    let _result: Vec<()> = foreach_broker("addBroker", |_| (broker,)).await.unwrap();
+    let response: Result<AssignedShards> = flatten_response(
+        ic_cdk::call(
+            broker,
+            "initBroker",
+            (InitBrokerParams {
+                other_brokers: get_broker_ids(),
+                supply_token_info: token_info,
+                liquidity_location: get_liquidity_location()?,
+                trading_fees: has_trading_fees::get_trading_fees(),
+            },),
+        )
+        .await,
+    );
+    let assigned = response?;
+    protocol_fees::init_broker_fee_recipient(broker).await?;
+    let _result: Vec<()> = foreach_broker("addBroker", |_| (broker,)).await?;
+    let result: Result<()> = flatten_response(
+        ic_cdk::call(liquidity::get_pool_contract(), "addBroker", (broker,)).await,
+    );
+    result?;
     STATE.with(|s| s.borrow_mut().brokers.insert(broker));
     register_user_with(broker, token_a, assigned.token_a);
     register_user_with(broker, token_b, assigned.token_b);
     init_broker_lp(broker);
-
-    let result: Result<()> = ic_cdk::call(liquidity::get_pool_contract(), "addBroker", (broker,))
-        .await
-        .map_err(|e| e.into_tx_error());
-    result.unwrap();
+    Ok(())
 }
 
 #[allow(unused)]
@@ -167,25 +389,25 @@ pub fn import_stable_storage(data: BrokerState) {
     STATE.with(|b| b.replace(data));
 }
 
-#[update(name = "setFees")]
-#[candid_method(update, rename = "setFees")]
-async fn set_fees(
-    token_a_deposit_fee: Nat,
-    token_b_deposit_fee: Nat,
-    limit_order_taker_fee: f64,
-    swap_fee: f64,
-    swap_market_maker_reward: f64,
-) {
-    is_owned::assert_is_owner().unwrap();
-    has_trading_fees::init_fee_info(TradingFees {
-        token_a_deposit_fee: token_a_deposit_fee.into(),
-        token_b_deposit_fee: token_b_deposit_fee.into(),
-        limit_order_taker_fee,
-        swap_fee,
-        swap_market_maker_reward,
-    });
-    let data = has_trading_fees::get_trading_fees();
-    let _result: Vec<()> = foreach_broker("setFees", |_| (data.clone(),))
-        .await
-        .unwrap();
+#[cfg(test)]
+mod brokers_tests {
+    use super::*;
+
+    #[test]
+    fn test_least_loaded() {
+        let load = |id: u8, open_orders, users| BrokerLoad {
+            broker: Principal::from_slice(&[id]),
+            open_orders,
+            users,
+        };
+        assert_eq!(least_loaded(&[]), None);
+        assert_eq!(
+            least_loaded(&[load(1, 5, 0), load(2, 3, 10), load(3, 3, 2)]),
+            Some(Principal::from_slice(&[3]))
+        );
+        assert_eq!(
+            least_loaded(&[load(2, 0, 1), load(1, 0, 1)]),
+            Some(Principal::from_slice(&[1]))
+        );
+    }
 }
@@ -0,0 +1,263 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Nat};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_trading_fees;
+use enoki_exchange_shared::has_trading_fees::{DynamicSwapFee, FeeTier, TradingFees};
+use enoki_exchange_shared::is_owned;
+use enoki_exchange_shared::types::*;
+
+use crate::brokers::get_broker_ids;
+
+// how long before it takes effect a fee change has to be announced
+const MIN_FEE_CHANGE_NOTICE: u64 = 24 * 3600 * 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<FeeScheduleState> = RefCell::new(FeeScheduleState::default());
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct PendingFeeChange {
+    pub fees: TradingFees,
+    pub effective_at: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FeeScheduleState {
+    pending: Option<PendingFeeChange>,
+}
+
+/// Schedules `update` on top of the pending change, if any, replacing it.
+fn schedule_fee_change<F: FnOnce(&mut TradingFees)>(effective_at: u64, update: F) -> Result<()> {
+    schedule_fee_change_at(ic_cdk::api::time(), effective_at, update)
+}
+
+fn schedule_fee_change_at<F: FnOnce(&mut TradingFees)>(
+    now: u64,
+    effective_at: u64,
+    update: F,
+) -> Result<()> {
+    if effective_at < now + MIN_FEE_CHANGE_NOTICE {
+        return Err(TxError::InvalidInput(
+            "fee changes must be scheduled at least 24 hours ahead".to_string(),
+        )
+        .into());
+    }
+    let mut fees = STATE
+        .with(|s| s.borrow().pending.clone())
+        .map(|pending| pending.fees)
+        .unwrap_or_else(has_trading_fees::get_trading_fees);
+    update(&mut fees);
+    has_trading_fees::validate_trading_fees(&fees)?;
+    ic_cdk::println!(
+        "[exchange] fee change scheduled at {}: {:?}",
+        effective_at,
+        fees
+    );
+    STATE.with(|s| s.borrow_mut().pending = Some(PendingFeeChange { fees, effective_at }));
+    Ok(())
+}
+
+/// Sets the fees right away, which is only allowed before the first broker is added: brokers get
+/// the fees when they are added, and trading only starts then.
+#[update(name = "initFees")]
+#[candid_method(update, rename = "initFees")]
+fn init_fees(
+    token_a_deposit_fee: Nat,
+    token_b_deposit_fee: Nat,
+    limit_order_taker_fee: f64,
+    swap_fee: f64,
+    swap_market_maker_reward: f64,
+) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    if !get_broker_ids().is_empty() {
+        return Err(TxError::InvalidState(
+            "fees can only be changed with notice once brokers are added".to_string(),
+        ));
+    }
+    let mut fees = has_trading_fees::get_trading_fees();
+    fees.token_a_deposit_fee = token_a_deposit_fee.into();
+    fees.token_b_deposit_fee = token_b_deposit_fee.into();
+    fees.limit_order_taker_fee = limit_order_taker_fee;
+    fees.swap_fee = swap_fee;
+    fees.swap_market_maker_reward = swap_market_maker_reward;
+    has_trading_fees::init_fee_info(fees)?;
+    Ok(())
+}
+
+#[update(name = "setFees")]
+#[candid_method(update, rename = "setFees")]
+fn set_fees(
+    token_a_deposit_fee: Nat,
+    token_b_deposit_fee: Nat,
+    limit_order_taker_fee: f64,
+    swap_fee: f64,
+    swap_market_maker_reward: f64,
+    effective_at: u64,
+) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    schedule_fee_change(effective_at, |fees| {
+        fees.token_a_deposit_fee = token_a_deposit_fee.into();
+        fees.token_b_deposit_fee = token_b_deposit_fee.into();
+        fees.limit_order_taker_fee = limit_order_taker_fee;
+        fees.swap_fee = swap_fee;
+        fees.swap_market_maker_reward = swap_market_maker_reward;
+    })?;
+    Ok(())
+}
+
+/// Replaces the volume based limit order fee tiers.
+#[update(name = "setFeeTiers")]
+#[candid_method(update, rename = "setFeeTiers")]
+fn set_fee_tiers(fee_tiers: Vec<FeeTier>, effective_at: u64) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    schedule_fee_change(effective_at, |fees| fees.fee_tiers = Some(fee_tiers))?;
+    Ok(())
+}
+
+/// Sets the share of the fees paid by referred users that goes to their referrer.
+#[update(name = "setReferralShare")]
+#[candid_method(update, rename = "setReferralShare")]
+fn set_referral_share(referral_share: f64, effective_at: u64) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    schedule_fee_change(effective_at, |fees| {
+        fees.referral_share = Some(referral_share)
+    })?;
+    Ok(())
+}
+
+/// Sets the share of the swap market maker reward that goes to the designated market maker pool.
+#[update(name = "setMarketMakerPoolShare")]
+#[candid_method(update, rename = "setMarketMakerPoolShare")]
+fn set_market_maker_pool_share(market_maker_pool_share: f64, effective_at: u64) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    schedule_fee_change(effective_at, |fees| {
+        fees.market_maker_pool_share = Some(market_maker_pool_share)
+    })?;
+    Ok(())
+}
+
+/// Turns the volatility based swap fee on, or off with `None`.
+#[update(name = "setDynamicSwapFee")]
+#[candid_method(update, rename = "setDynamicSwapFee")]
+fn set_dynamic_swap_fee(
+    dynamic_swap_fee: Option<DynamicSwapFee>,
+    effective_at: u64,
+) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    schedule_fee_change(effective_at, |fees| {
+        fees.dynamic_swap_fee = dynamic_swap_fee
+    })?;
+    Ok(())
+}
+
+#[update(name = "cancelPendingFeeChange")]
+#[candid_method(update, rename = "cancelPendingFeeChange")]
+fn cancel_pending_fee_change() -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    STATE
+        .with(|s| s.borrow_mut().pending.take())
+        .map(|_| ())
+        .ok_or_else(|| TxError::NotFound("pending fee change".to_string()))
+}
+
+#[query(name = "getPendingFeeChange")]
+#[candid_method(query, rename = "getPendingFeeChange")]
+fn get_pending_fee_change() -> Option<PendingFeeChange> {
+    STATE.with(|s| s.borrow().pending.clone())
+}
+
+/// The pending change, if it is due. It is handed to the brokers with the orders of the round.
+pub fn get_due_fee_change() -> Option<PendingFeeChange> {
+    get_due_fee_change_at(ic_cdk::api::time())
+}
+
+/// A due change that is not valid is dropped, so that it does not hold up every round.
+fn get_due_fee_change_at(now: u64) -> Option<PendingFeeChange> {
+    let change = STATE
+        .with(|s| s.borrow().pending.clone())
+        .filter(|pending| pending.effective_at <= now)?;
+    if let Err(error) = has_trading_fees::validate_trading_fees(&change.fees) {
+        ic_cdk::println!(
+            "[exchange] dropping invalid fee change {:?}: {:?}",
+            change,
+            error
+        );
+        STATE.with(|s| s.borrow_mut().pending = None);
+        return None;
+    }
+    Some(change)
+}
+
+/// Applies a change once every broker has, unless it was replaced in the meantime.
+pub fn apply_fee_change(change: PendingFeeChange) -> Result<()> {
+    let effective_at = change.effective_at;
+    has_trading_fees::init_fee_info(change.fees)?;
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.pending.as_ref().map(|pending| pending.effective_at) == Some(effective_at) {
+            s.pending = None;
+        }
+    });
+    Ok(())
+}
+
+pub fn export_stable_storage() -> FeeScheduleState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: FeeScheduleState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod fee_schedule_tests {
+    use super::*;
+
+    #[test]
+    fn test_fee_changes_need_notice_and_layer() {
+        has_trading_fees::init_fee_info(TradingFees {
+            swap_fee: 0.002,
+            ..Default::default()
+        })
+        .unwrap();
+        let now = 1_000;
+        let due = now + MIN_FEE_CHANGE_NOTICE;
+        assert!(schedule_fee_change_at(now, due - 1, |fees| fees.swap_fee = 0.003).is_err());
+        schedule_fee_change_at(now, due, |fees| fees.swap_fee = 0.003).unwrap();
+        schedule_fee_change_at(now, due + 1, |fees| fees.referral_share = Some(0.2)).unwrap();
+        assert!(schedule_fee_change_at(now, due, |fees| fees.swap_fee = 2.0).is_err());
+
+        let pending = get_pending_fee_change().unwrap();
+        assert_eq!(pending.effective_at, due + 1);
+        assert_eq!(pending.fees.swap_fee, 0.003);
+        assert_eq!(pending.fees.referral_share, Some(0.2));
+        assert_eq!(has_trading_fees::get_trading_fees().swap_fee, 0.002);
+
+        // a change replaced after it was handed to the brokers stays pending
+        let mut outdated = pending.clone();
+        outdated.effective_at = due;
+        apply_fee_change(outdated).unwrap();
+        assert!(get_pending_fee_change().is_some());
+        apply_fee_change(pending).unwrap();
+        assert!(get_pending_fee_change().is_none());
+        assert_eq!(has_trading_fees::get_trading_fees().swap_fee, 0.003);
+    }
+
+    #[test]
+    fn test_invalid_due_fee_change_is_dropped() {
+        let mut fees = has_trading_fees::get_trading_fees();
+        fees.swap_fee = 2.0;
+        STATE.with(|s| {
+            s.borrow_mut().pending = Some(PendingFeeChange {
+                fees,
+                effective_at: 1_000,
+            })
+        });
+        assert!(get_due_fee_change_at(999).is_none());
+        assert!(get_pending_fee_change().is_some());
+        assert!(get_due_fee_change_at(1_000).is_none());
+        assert!(get_pending_fee_change().is_none());
+    }
+}
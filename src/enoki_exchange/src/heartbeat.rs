@@ -1,56 +1,46 @@
-use std::cell::RefCell;
-
 use candid::candid_method;
 use ic_cdk_macros::*;
 
-use crate::synchronize::do_run;
+use enoki_exchange_shared::has_scheduler;
+use enoki_exchange_shared::is_owned;
+use enoki_exchange_shared::types::*;
 
-// #[heartbeat]
-// fn tick() {
-//     ic_cdk::spawn(run())
-// }
+use crate::synchronize::do_run;
 
-thread_local! {
-    static STATE: RefCell<AntiSpam> = RefCell::new(Default::default());
+#[heartbeat]
+fn tick() {
+    if has_scheduler::try_start_scheduled_run() {
+        ic_cdk::spawn(scheduled_run())
+    }
 }
 
-#[derive(Default)]
-struct AntiSpam {
-    last_update: u64,
-    locked: bool,
+async fn scheduled_run() {
+    let result = do_run().await;
+    if let Err(error) = &result {
+        ic_cdk::print(format!(
+            "[exchange] error during scheduled run: {:?}",
+            error
+        ));
+    }
+    let _ = finish_run(result);
 }
 
-impl AntiSpam {
-    const UPDATE_INTERVAL: u64 = 1 * 1_000_000_000;
-    pub fn try_lock(&mut self) -> bool {
-        let now = ic_cdk::api::time();
-        if self.last_update < now - Self::UPDATE_INTERVAL {
-            self.locked = true;
-            self.last_update = now;
-            true
-        } else {
-            false
-        }
-    }
-    pub fn unlock(&mut self) {
-        self.locked = false;
-    }
+fn finish_run(result: Result<bool>) -> Result<()> {
+    let work_pending = matches!(result, Ok(true));
+    let result = result.map(|_| ());
+    has_scheduler::finish_run(&result, work_pending);
+    result
 }
 
+/// Runs a round right away for the owner, unless one ran within the active interval of the
+/// scheduler, in which case the start of the last one is returned.
 #[update(name = "triggerRun")]
 #[candid_method(update, rename = "triggerRun")]
-async fn trigger_run() -> Option<u64> {
-    if let Some(too_soon) = STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        if s.try_lock() {
-            None
-        } else {
-            Some(s.last_update)
-        }
-    }) {
-        return Some(too_soon);
+async fn trigger_run() -> TxResult<Option<u64>> {
+    is_owned::assert_is_owner()?;
+    if let Err(last_run) = has_scheduler::try_start_manual_run() {
+        return Ok(Some(last_run));
     }
-    do_run().await.unwrap();
-    STATE.with(|s| s.borrow_mut().unlock());
-    None
+    finish_run(do_run().await)?;
+    Ok(None)
 }
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ops::AddAssign;
 
-use candid::{candid_method, CandidType, Principal};
+use candid::{candid_method, CandidType, Nat, Principal};
 use ic_cdk_macros::*;
 
 use enoki_exchange_shared::has_token_info::get_token_info;
@@ -11,6 +11,12 @@ use enoki_exchange_shared::liquidity::{
     RequestForLiquidityChanges, RequestForNewLiquidityTarget, ResponseAboutLiquidityChanges,
 };
 use enoki_exchange_shared::types::*;
+use enoki_exchange_shared::utils::{nat_to_f64, nat_x_float};
+
+use crate::brokers::{get_active_broker_ids, get_broker_ids};
+
+// shares of the liquidity targets are rounded down to millionths
+const SHARE_PRECISION: u64 = 1_000_000;
 
 thread_local! {
     static STATE: RefCell<LiquidityState> = RefCell::new(LiquidityState::default());
@@ -23,6 +29,30 @@ pub struct LiquidityState {
     broker_liquidity: HashMap<Principal, LiquidityAmount>,
     excess_liquidity: LiquidityAmount,
     lp_proposed_changes: RequestForLiquidityChanges,
+    swap_volume: Option<HashMap<Principal, LiquidityAmount>>,
+    allocation: Option<LiquidityAllocation>,
+}
+
+/// How the liquidity targets are split between the active brokers: by their recent swap volume,
+/// within limits around an even split.
+#[derive(serde::Deserialize, serde::Serialize, CandidType, Clone, Debug)]
+pub struct LiquidityAllocation {
+    // share of the tracked swap volume forgotten every round (ex: 0.01)
+    pub volume_decay: f64,
+    // smallest target of a broker, as a multiple of an even split (ex: 0.25)
+    pub floor: f64,
+    // largest target of a broker, as a multiple of an even split (ex: 4.0)
+    pub ceiling: f64,
+}
+
+impl Default for LiquidityAllocation {
+    fn default() -> Self {
+        Self {
+            volume_decay: 0.01,
+            floor: 0.25,
+            ceiling: 4.0,
+        }
+    }
 }
 
 impl Default for LiquidityState {
@@ -33,33 +63,28 @@ impl Default for LiquidityState {
             broker_liquidity: Default::default(),
             excess_liquidity: Default::default(),
             lp_proposed_changes: Default::default(),
+            swap_volume: Default::default(),
+            allocation: Default::default(),
         }
     }
 }
 
-#[derive(serde::Deserialize, serde::Serialize, CandidType, Clone, Debug, Default)]
-pub struct ProposedLiquidityChanges {
-    to_add: LiquidityAmount,
-    to_remove: LiquidityAmount,
-}
-
 #[update(name = "initPool")]
 #[candid_method(update, rename = "initPool")]
-async fn init_pool(pool: Principal) {
-    assert_is_owner().unwrap();
+async fn init_pool(pool: Principal) -> TxResult<()> {
+    assert_is_owner()?;
     if STATE.with(|s| s.borrow().pool_address != Principal::anonymous()) {
-        panic!("pool already init");
+        return Err(TxError::AlreadyInitialized);
     }
-    let response: Result<(Principal,)> =
-        ic_cdk::call(pool, "initLiquidityPool", (get_token_info(),))
-            .await
-            .map_err(|e| e.into_tx_error());
-    let worker = response.unwrap().0;
+    let response: Result<Principal> =
+        flatten_response(ic_cdk::call(pool, "initLiquidityPool", (get_token_info(),)).await);
+    let worker = response?;
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.pool_address = pool;
         s.worker_pool_address = worker;
     });
+    Ok(())
 }
 
 pub fn init_broker_lp(broker: Principal) {
@@ -70,27 +95,74 @@ pub fn init_broker_lp(broker: Principal) {
     });
 }
 
+pub fn remove_broker_lp(broker: Principal) {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.broker_liquidity.remove(&broker);
+        if let Some(swap_volume) = s.swap_volume.as_mut() {
+            swap_volume.remove(&broker);
+        }
+    });
+}
+
+pub fn is_broker_liquidity_empty(broker: Principal) -> bool {
+    STATE.with(|s| {
+        s.borrow()
+            .broker_liquidity
+            .get(&broker)
+            .map(|liquidity| !liquidity.token_a.is_nonzero() && !liquidity.token_b.is_nonzero())
+            .unwrap_or(true)
+    })
+}
+
 pub fn get_pool_contract() -> Principal {
     STATE.with(|s| s.borrow().pool_address)
 }
 
 #[query(name = "getLiquidityLocation")]
 #[candid_method(update, rename = "getLiquidityLocation")]
-pub fn get_liquidity_location() -> Principal {
+pub fn get_liquidity_location() -> TxResult<Principal> {
     let location = STATE.with(|s| s.borrow().worker_pool_address);
-    assert_ne!(
-        location,
-        Principal::anonymous(),
-        "LP has not yet been initialized"
-    );
-    location
+    if location == Principal::anonymous() {
+        return Err(TxError::PoolNotInitialized);
+    }
+    Ok(location)
+}
+
+#[update(name = "setLiquidityAllocation")]
+#[candid_method(update, rename = "setLiquidityAllocation")]
+fn set_liquidity_allocation(allocation: LiquidityAllocation) -> TxResult<()> {
+    assert_is_owner()?;
+    if !(0.0..=1.0).contains(&allocation.volume_decay)
+        || !(0.0..=1.0).contains(&allocation.floor)
+        || allocation.ceiling < 1.0
+    {
+        return Err(TxError::InvalidInput(
+            "volume decay and floor must be between 0 and 1, and ceiling at least 1".to_string(),
+        ));
+    }
+    STATE.with(|s| s.borrow_mut().allocation = Some(allocation));
+    Ok(())
 }
 
-pub async fn get_updated_liquidity_from_pool() -> Result<RequestForNewLiquidityTarget> {
+#[query(name = "getLiquidityAllocation")]
+#[candid_method(query, rename = "getLiquidityAllocation")]
+fn get_liquidity_allocation() -> LiquidityAllocation {
+    STATE.with(|s| s.borrow().allocation.clone().unwrap_or_default())
+}
+
+/// The decayed swap volume of each broker that the liquidity targets are weighted by.
+#[query(name = "getBrokerSwapVolume")]
+#[candid_method(query, rename = "getBrokerSwapVolume")]
+fn get_broker_swap_volume() -> HashMap<Principal, LiquidityAmount> {
+    STATE.with(|s| s.borrow().swap_volume.clone().unwrap_or_default())
+}
+
+/// The liquidity target of every broker for this round. Draining brokers get none.
+pub async fn get_updated_liquidity_from_pool(
+) -> Result<HashMap<Principal, RequestForNewLiquidityTarget>> {
     let result: Result<(LiquidityAmount, LiquidityAmount)> =
-        ic_cdk::call(get_pool_contract(), "getUpdatedLiquidity", ())
-            .await
-            .map_err(|e| e.into_tx_error());
+        flatten_response(ic_cdk::call(get_pool_contract(), "getUpdatedLiquidity", ()).await);
     let (to_add, to_remove) = result?;
     let request_from_pool = RequestForLiquidityChanges { to_add, to_remove };
     let proposed_target_for_brokers =
@@ -101,13 +173,11 @@ pub async fn get_updated_liquidity_from_pool() -> Result<RequestForNewLiquidityT
 
 fn create_requests_for_broker_liquidity_targets(
     request_from_pool: &RequestForLiquidityChanges,
-) -> RequestForNewLiquidityTarget {
+) -> HashMap<Principal, RequestForNewLiquidityTarget> {
     let current_liquidity = STATE.with(|s| s.borrow().broker_liquidity.clone());
-    let broker_count = current_liquidity.len();
 
     let mut liquidity_reserves = request_from_pool.to_add.clone();
     liquidity_reserves.add_assign(STATE.with(|s| s.borrow().excess_liquidity.clone()));
-    let max_currently_available_per_broker = liquidity_reserves.clone().div_int(broker_count);
 
     let mut total_liquidity_target: LiquidityAmount = current_liquidity
         .into_iter()
@@ -116,31 +186,146 @@ fn create_requests_for_broker_liquidity_targets(
             sum.add_assign(next);
             sum
         });
-    total_liquidity_target.add_assign(liquidity_reserves);
+    total_liquidity_target.add_assign(liquidity_reserves.clone());
     total_liquidity_target.sub_assign_or_zero(request_from_pool.to_remove.clone());
 
-    let target_liquidity_per_broker = total_liquidity_target.clone().div_int(broker_count);
+    // the liquidity of draining brokers goes to the others
+    let active_brokers = get_active_broker_ids();
+    let (shares_a, shares_b) = STATE.with(|s| {
+        let s = s.borrow();
+        let allocation = s.allocation.clone().unwrap_or_default();
+        let volumes = |token: EnokiToken| -> Vec<f64> {
+            active_brokers
+                .iter()
+                .map(|broker| {
+                    s.swap_volume
+                        .as_ref()
+                        .and_then(|swap_volume| swap_volume.get(broker))
+                        .map(|volume| nat_to_f64(volume.get(&token).clone().to_nat()))
+                        .unwrap_or_default()
+                })
+                .collect()
+        };
+        (
+            allocate_shares(&volumes(EnokiToken::TokenA), &allocation),
+            allocate_shares(&volumes(EnokiToken::TokenB), &allocation),
+        )
+    });
+
+    let mut targets: HashMap<Principal, RequestForNewLiquidityTarget> = get_broker_ids()
+        .into_iter()
+        .map(|broker| (broker, Default::default()))
+        .collect();
+    let split = |amount: &LiquidityAmount| -> Vec<LiquidityAmount> {
+        split_amount(&amount.token_a, &shares_a)
+            .into_iter()
+            .zip(split_amount(&amount.token_b, &shares_b))
+            .map(|(token_a, token_b)| LiquidityAmount { token_a, token_b })
+            .collect()
+    };
+    let brokers_targets = active_brokers
+        .into_iter()
+        .zip(split(&total_liquidity_target))
+        .zip(split(&liquidity_reserves));
+    for ((broker, target), extra_liquidity_available) in brokers_targets {
+        targets.insert(
+            broker,
+            RequestForNewLiquidityTarget {
+                target,
+                extra_liquidity_available,
+                ..Default::default()
+            },
+        );
+    }
+    targets
+}
+
+fn share_of(amount: &StableNat, share: f64) -> StableNat {
+    let parts = (share * SHARE_PRECISION as f64).floor() as u64;
+    (amount.clone().to_nat() * Nat::from(parts) / Nat::from(SHARE_PRECISION)).into()
+}
 
-    RequestForNewLiquidityTarget {
-        target: target_liquidity_per_broker,
-        extra_liquidity_available: max_currently_available_per_broker,
+/// Splits the amount by `shares`. What rounding down leaves over goes to the largest allocation,
+/// so that the allocations add up to the amount.
+fn split_amount(amount: &StableNat, shares: &[f64]) -> Vec<StableNat> {
+    let mut allocations: Vec<Nat> = shares
+        .iter()
+        .map(|&share| share_of(amount, share).to_nat())
+        .collect();
+    let total = amount.clone().to_nat();
+    let allocated = allocations
+        .iter()
+        .fold(Nat::from(0u32), |sum, next| sum + next.clone());
+    if allocated < total {
+        if let Some(largest) = allocations.iter_mut().max() {
+            *largest = largest.clone() + (total - allocated);
+        }
     }
+    allocations.into_iter().map(StableNat::from).collect()
+}
+
+/// Splits the liquidity in proportion to `volumes`, keeping every share between the floor and the
+/// ceiling around an even split. Without any volume, the split is even.
+fn allocate_shares(volumes: &[f64], allocation: &LiquidityAllocation) -> Vec<f64> {
+    let even = 1.0 / volumes.len() as f64;
+    let floor = even * allocation.floor.min(1.0);
+    let ceiling = even * allocation.ceiling.max(1.0);
+    let mut shares: Vec<Option<f64>> = vec![None; volumes.len()];
+    loop {
+        let remaining = 1.0 - shares.iter().flatten().sum::<f64>();
+        let free: Vec<usize> = (0..volumes.len())
+            .filter(|&i| shares[i].is_none())
+            .collect();
+        if free.is_empty() {
+            break;
+        }
+        let free_volume: f64 = free.iter().map(|&i| volumes[i]).sum();
+        let free_count = free.len() as f64;
+        let proposed = |i: usize| {
+            if free_volume > 0.0 {
+                remaining * volumes[i] / free_volume
+            } else {
+                remaining / free_count
+            }
+        };
+        // the side that is exceeded the most is bound in the final split as well, while fixing
+        // the other one first could leave too much or too little for the rest
+        let over: f64 = free.iter().map(|&i| (proposed(i) - ceiling).max(0.0)).sum();
+        let under: f64 = free.iter().map(|&i| (floor - proposed(i)).max(0.0)).sum();
+        if over > 0.0 || under > 0.0 {
+            let (bound, out_of_bounds): (f64, Vec<usize>) = if over >= under {
+                let above = free.iter().copied().filter(|&i| proposed(i) > ceiling);
+                (ceiling, above.collect())
+            } else {
+                let below = free.iter().copied().filter(|&i| proposed(i) < floor);
+                (floor, below.collect())
+            };
+            for i in out_of_bounds {
+                shares[i] = Some(bound);
+            }
+            continue;
+        }
+        for i in free {
+            shares[i] = Some(proposed(i));
+        }
+    }
+    shares.into_iter().flatten().collect()
 }
 
 pub async fn update_committed_broker_liquidity(
     response: HashMap<Principal, ResponseAboutLiquidityChanges>,
+    last_price: Option<u64>,
 ) -> Result<()> {
     apply_changes(&response);
-    let (mut added, mut removed, traded): (LiquidityAmount, LiquidityAmount, LiquidityTrades) =
-        response.into_iter().fold(
-            (Default::default(), Default::default(), Default::default()),
-            |(mut added, mut removed, mut traded), (_, changes)| {
-                added.add_assign(changes.added);
-                removed.add_assign(changes.removed);
-                traded.add_assign(changes.traded);
-                (added, removed, traded)
-            },
-        );
+    let mut total_changes: ResponseAboutLiquidityChanges =
+        response
+            .into_iter()
+            .fold(Default::default(), |mut sum, (_, changes)| {
+                sum.add_assign(changes);
+                sum
+            });
+    let added = &mut total_changes.added;
+    let removed = &mut total_changes.removed;
 
     let proposed_by_lp = STATE.with(|s| std::mem::take(&mut s.borrow_mut().lp_proposed_changes));
     let excess_added = added.sub_or_zero(&proposed_by_lp.to_add);
@@ -153,30 +338,35 @@ pub async fn update_committed_broker_liquidity(
         s.excess_liquidity.safe_sub_assign(excess_added).unwrap();
     });
 
-    let result: Result<()> = ic_cdk::call(
-        get_pool_contract(),
-        "resolveLiquidity",
-        (added, removed, traded),
+    flatten_response(
+        ic_cdk::call(
+            get_pool_contract(),
+            "resolveLiquidity",
+            (total_changes, last_price),
+        )
+        .await,
     )
-    .await
-    .map_err(|e| e.into_tx_error());
-    result
 }
 
 fn apply_changes(changes: &HashMap<Principal, ResponseAboutLiquidityChanges>) {
+    record_swap_volume(changes);
     STATE.with(|s| {
         for (broker_id, liquidity) in s.borrow_mut().broker_liquidity.iter_mut() {
             if let Some(changes) = changes.get(broker_id) {
                 ic_cdk::println!(
-                    "[exchange] updating broker liquidity for {}. Currently available: {:?}. added: {:?}. removed: {:?}. traded: {:?}",
+                    "[exchange] updating broker liquidity for {}. Currently available: {:?}. added: {:?}. removed: {:?}. traded: {:?}. fees: {:?}. rounding: {:?}",
                     broker_id,
                     liquidity,
                     changes.added,
                     changes.removed,
-                    changes.traded
+                    changes.traded,
+                    changes.fees_earned,
+                    changes.rounding_error
                 );
                 liquidity.add_assign(changes.added.clone());
                 liquidity.add_assign(changes.traded.increased.clone());
+                liquidity.add_assign(changes.fees_earned.clone());
+                liquidity.add_assign(changes.rounding_error.clone());
                 liquidity.safe_sub_assign(changes.traded.decreased.clone()).unwrap();
                 liquidity.safe_sub_assign(changes.removed.clone()).unwrap();
                 ic_cdk::println!(
@@ -189,6 +379,28 @@ fn apply_changes(changes: &HashMap<Principal, ResponseAboutLiquidityChanges>) {
     })
 }
 
+/// Decays the swap volume of every broker and adds what they traded with the LPs this round.
+fn record_swap_volume(changes: &HashMap<Principal, ResponseAboutLiquidityChanges>) {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let s = &mut *s;
+        let retained = 1.0 - s.allocation.clone().unwrap_or_default().volume_decay;
+        let swap_volume = s.swap_volume.get_or_insert_with(Default::default);
+        for broker_id in s.broker_liquidity.keys() {
+            let volume = swap_volume.entry(*broker_id).or_default();
+            for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+                let decayed =
+                    nat_x_float(volume.get(&token).clone().to_nat(), retained).unwrap_or_default();
+                *volume.get_mut(&token) = decayed.into();
+            }
+            if let Some(changes) = changes.get(broker_id) {
+                volume.add_assign(changes.traded.increased.clone());
+                volume.add_assign(changes.traded.decreased.clone());
+            }
+        }
+    })
+}
+
 pub fn export_stable_storage() -> LiquidityState {
     STATE.with(|s| s.take())
 }
@@ -196,3 +408,59 @@ pub fn export_stable_storage() -> LiquidityState {
 pub fn import_stable_storage(data: LiquidityState) {
     STATE.with(|s| s.replace(data));
 }
+
+#[cfg(test)]
+mod liquidity_tests {
+    use super::*;
+
+    fn assert_shares(shares: Vec<f64>, expected: &[f64]) {
+        assert_eq!(shares.len(), expected.len());
+        for (share, expected) in shares.into_iter().zip(expected) {
+            assert!((share - expected).abs() < 1e-9, "{} != {}", share, expected);
+        }
+    }
+
+    #[test]
+    fn test_allocate_shares() {
+        let allocation = LiquidityAllocation {
+            volume_decay: 0.0,
+            floor: 0.5,
+            ceiling: 1.5,
+        };
+        assert_shares(allocate_shares(&[0.0, 0.0], &allocation), &[0.5, 0.5]);
+        assert_shares(allocate_shares(&[3.0, 1.0], &allocation), &[0.75, 0.25]);
+        assert_shares(allocate_shares(&[100.0, 0.0], &allocation), &[0.75, 0.25]);
+        assert_shares(
+            allocate_shares(&[10.0, 1.0, 1.0, 0.0], &allocation),
+            &[0.375, 0.25, 0.25, 0.125],
+        );
+
+        let allocation = LiquidityAllocation {
+            volume_decay: 0.0,
+            floor: 0.9,
+            ceiling: 2.0,
+        };
+        let shares = allocate_shares(&[100.0, 0.0, 0.0], &allocation);
+        assert_shares(shares.clone(), &[0.4, 0.3, 0.3]);
+        assert!((shares.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        let allocation = LiquidityAllocation {
+            volume_decay: 0.0,
+            floor: 0.5,
+            ceiling: 1.2,
+        };
+        assert_shares(
+            allocate_shares(&[100.0, 0.0, 0.0], &allocation),
+            &[0.4, 0.3, 0.3],
+        );
+
+        assert_eq!(share_of(&StableNat::from(999), 0.5), StableNat::from(499));
+        let split = split_amount(&StableNat::from(999), &shares);
+        assert_eq!(
+            split
+                .iter()
+                .fold(Nat::from(0u32), |sum, next| sum + next.clone().to_nat()),
+            Nat::from(999u32)
+        );
+        assert_eq!(split[0], StableNat::from(401));
+    }
+}
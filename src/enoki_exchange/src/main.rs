@@ -1,30 +1,47 @@
+#[allow(unused_imports)]
+use std::collections::HashMap;
+
 #[allow(unused_imports)]
 use candid::Nat;
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
+#[allow(unused_imports)]
+use enoki_exchange_shared::has_scheduler::{SchedulerSettings, SchedulerStatus};
 #[allow(unused_imports)]
 use enoki_exchange_shared::has_token_info::AssignedShards;
 use enoki_exchange_shared::has_token_info::{self, TokenInfo, TokenPairInfo};
 #[allow(unused_imports)]
-use enoki_exchange_shared::has_trading_fees::TradingFees;
+use enoki_exchange_shared::has_trading_fees::{DynamicSwapFee, FeeSweep, FeeTier, TradingFees};
 use enoki_exchange_shared::is_owned::{self, assert_is_owner, OwnershipData};
 #[allow(unused_imports)]
-use enoki_exchange_shared::types::{Result, BidAskCurve};
+use enoki_exchange_shared::types::{
+    Result, BidAskCurve, LiquidityAmount, MarketMakerScore, TxError, TxResult,
+};
 #[allow(unused_imports)]
 use price_history::LastPricePoint;
 
 #[allow(unused_imports)]
 use crate::brokers::BrokerState;
+#[allow(unused_imports)]
+use crate::fee_schedule::PendingFeeChange;
+#[allow(unused_imports)]
+use crate::liquidity::LiquidityAllocation;
+#[allow(unused_imports)]
+use crate::market_makers::{DesignatedMarketMaker, QuotingObligations};
 
 mod brokers;
+mod fee_schedule;
 mod heartbeat;
 mod liquidity;
+mod market_makers;
 mod orders;
 mod shared_candid_methods;
 mod synchronize;
 mod upgrade;
 mod price_history;
+mod protocol_fees;
+mod referrals;
 
 #[init]
 #[candid_method(init)]
@@ -37,15 +54,21 @@ fn init() {
 
 #[update(name = "finishInit")]
 #[candid_method(update, rename = "finishInit")]
-async fn finish_init(token_a: Principal, token_b: Principal, price_number_of_decimals: u64) {
-    assert_is_owner().unwrap();
-    assert_eq!(has_token_info::get_assigned_shards(), AssignedShards::default(), "already init");
+async fn finish_init(
+    token_a: Principal,
+    token_b: Principal,
+    price_number_of_decimals: u64,
+) -> TxResult<()> {
+    assert_is_owner()?;
+    if has_token_info::get_assigned_shards() != AssignedShards::default() {
+        return Err(TxError::AlreadyInitialized);
+    }
     let token_info = TokenPairInfo {
         token_a: TokenInfo { principal: token_a },
         token_b: TokenInfo { principal: token_b },
         price_number_of_decimals,
     };
-    has_token_info::init_token_info(token_info).await.unwrap();
+    Ok(has_token_info::init_token_info(token_info).await?)
 }
 
 #[query(name = "whoami")]
@@ -0,0 +1,289 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_token_info::quantity_b_to_a;
+use enoki_exchange_shared::is_owned;
+use enoki_exchange_shared::types::*;
+
+use crate::brokers::{foreach_broker, get_assigned_broker};
+
+// designated market makers are scored and the pool is distributed once per period
+const SCORING_PERIOD: u64 = 24 * 3600 * 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<MarketMakersState> = RefCell::new(MarketMakersState::default());
+}
+
+/// What a designated market maker commits to quote every round.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct QuotingObligations {
+    // largest spread between their best bid and ask, as percentage of the mid price (ex: 0.01)
+    pub max_spread: f64,
+    // smallest quantity of token A on each side
+    pub min_size: StableNat,
+    // smallest share of the rounds in which the obligations were met (ex: 0.9)
+    pub min_uptime: f64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct DesignatedMarketMaker {
+    pub user: Principal,
+    pub obligations: QuotingObligations,
+    pub registered_at: u64,
+    pub rounds_sampled: u64,
+    pub rounds_compliant: u64,
+}
+
+impl DesignatedMarketMaker {
+    fn uptime(&self) -> f64 {
+        if self.rounds_sampled == 0 {
+            0.0
+        } else {
+            self.rounds_compliant as f64 / self.rounds_sampled as f64
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct MarketMakersState {
+    makers: HashMap<Principal, DesignatedMarketMaker>,
+    period_start: u64,
+    last_scores: Vec<MarketMakerScore>,
+}
+
+/// The best price of `user` on one side of the book, with their total quantity at that price.
+fn best_quote<'a, I: Iterator<Item = (&'a u64, &'a Vec<CounterpartyInfo>)>>(
+    levels: I,
+    user: Principal,
+) -> Option<(u64, Nat)> {
+    levels
+        .map(|(&price, infos)| {
+            let quantity = infos
+                .iter()
+                .filter(|info| info.user == user)
+                .fold(Nat::default(), |sum, info| {
+                    sum + info.quantity.clone().to_nat()
+                });
+            (price, quantity)
+        })
+        .find(|(_, quantity)| *quantity > 0u32)
+}
+
+/// Whether `user` quoted both sides of the book within their obligations.
+fn meets_obligations(
+    bid_ask: &AggregateBidAsk,
+    user: Principal,
+    obligations: &QuotingObligations,
+) -> bool {
+    let (bid, bid_size) = match best_quote(bid_ask.bids.iter().rev(), user) {
+        Some(quote) => quote,
+        None => return false,
+    };
+    let (ask, ask_size) = match best_quote(bid_ask.asks.iter(), user) {
+        Some(quote) => quote,
+        None => return false,
+    };
+    let bid_size = match quantity_b_to_a(bid_size, bid) {
+        Ok(size) => size,
+        Err(_) => return false,
+    };
+    let mid = (bid as f64 + ask as f64) / 2.0;
+    let spread = (ask as f64 - bid as f64) / mid;
+    spread <= obligations.max_spread
+        && obligations.min_size.compare_with(&bid_size) != std::cmp::Ordering::Greater
+        && obligations.min_size.compare_with(&ask_size) != std::cmp::Ordering::Greater
+}
+
+impl MarketMakersState {
+    fn sample(&mut self, bid_ask: &AggregateBidAsk) {
+        for maker in self.makers.values_mut() {
+            maker.rounds_sampled += 1;
+            if meets_obligations(bid_ask, maker.user, &maker.obligations) {
+                maker.rounds_compliant += 1;
+            }
+        }
+    }
+    /// Scores the makers over the period that just ended and starts a new one. Makers below their
+    /// minimum uptime score zero; the others score their uptime.
+    fn end_period<F: Fn(Principal) -> Option<Principal>>(
+        &mut self,
+        now: u64,
+        get_broker: F,
+    ) -> Vec<MarketMakerScore> {
+        let scores: Vec<MarketMakerScore> = self
+            .makers
+            .values_mut()
+            .filter_map(|maker| {
+                let uptime = maker.uptime();
+                maker.rounds_sampled = 0;
+                maker.rounds_compliant = 0;
+                Some(MarketMakerScore {
+                    broker: get_broker(maker.user)?,
+                    user: maker.user,
+                    uptime,
+                    score: if uptime >= maker.obligations.min_uptime {
+                        uptime
+                    } else {
+                        0.0
+                    },
+                })
+            })
+            .collect();
+        self.period_start = now;
+        self.last_scores = scores.clone();
+        scores
+    }
+}
+
+/// Checks the quotes of every designated market maker against the book of this round. Once a
+/// period is over, the brokers distribute their market maker pools by score.
+pub fn sample_quotes(bid_ask: &AggregateBidAsk) {
+    let now = ic_cdk::api::time();
+    let scores = STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.sample(bid_ask);
+        if s.period_start == 0 {
+            s.period_start = now;
+        }
+        if s.period_start + SCORING_PERIOD > now {
+            return None;
+        }
+        Some(s.end_period(now, |user| get_assigned_broker(user).ok()))
+    });
+    if let Some(scores) = scores {
+        if scores.iter().any(|score| score.score > 0.0) {
+            ic_cdk::spawn(distribute_market_maker_pools(scores));
+        }
+    }
+}
+
+async fn distribute_market_maker_pools(scores: Vec<MarketMakerScore>) {
+    ic_cdk::println!("[exchange] distributing market maker pools: {:?}", scores);
+    let result: Result<Vec<()>> =
+        foreach_broker("distributeMarketMakerPool", |_| (scores.clone(),)).await;
+    if let Err(error) = result {
+        ic_cdk::print(format!(
+            "[exchange] error distributing market maker pools: {:?}",
+            error
+        ));
+    }
+}
+
+#[update(name = "registerMarketMaker")]
+#[candid_method(update, rename = "registerMarketMaker")]
+fn register_market_maker(user: Principal, obligations: QuotingObligations) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    get_assigned_broker(user)?;
+    if !(0.0..=1.0).contains(&obligations.max_spread)
+        || !(0.0..=1.0).contains(&obligations.min_uptime)
+    {
+        return Err(TxError::InvalidInput(
+            "max spread and min uptime must be between 0 and 1".to_string(),
+        ));
+    }
+    STATE.with(|s| {
+        s.borrow_mut().makers.insert(
+            user,
+            DesignatedMarketMaker {
+                user,
+                obligations,
+                registered_at: ic_cdk::api::time(),
+                rounds_sampled: 0,
+                rounds_compliant: 0,
+            },
+        )
+    });
+    Ok(())
+}
+
+#[update(name = "removeMarketMaker")]
+#[candid_method(update, rename = "removeMarketMaker")]
+fn remove_market_maker(user: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    STATE
+        .with(|s| s.borrow_mut().makers.remove(&user))
+        .map(|_| ())
+        .ok_or_else(|| TxError::NotFound(format!("market maker {}", user)))
+}
+
+#[query(name = "getMarketMakers")]
+#[candid_method(query, rename = "getMarketMakers")]
+fn get_market_makers() -> Vec<DesignatedMarketMaker> {
+    STATE.with(|s| s.borrow().makers.values().cloned().collect())
+}
+
+/// The scores of the last period that ended.
+#[query(name = "getMarketMakerScores")]
+#[candid_method(query, rename = "getMarketMakerScores")]
+fn get_market_maker_scores() -> Vec<MarketMakerScore> {
+    STATE.with(|s| s.borrow().last_scores.clone())
+}
+
+pub fn export_stable_storage() -> MarketMakersState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: MarketMakersState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod market_makers_tests {
+    use super::*;
+
+    fn quote(user: Principal, quantity: u64) -> Vec<CounterpartyInfo> {
+        vec![CounterpartyInfo {
+            broker: Principal::anonymous(),
+            user,
+            quantity: StableNat::from(quantity),
+            price: 0,
+            maker_fee: Some(0.0),
+        }]
+    }
+
+    #[test]
+    fn test_scoring() {
+        let user = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let obligations = QuotingObligations {
+            max_spread: 0.05,
+            min_size: StableNat::from(10),
+            min_uptime: 0.5,
+        };
+        let mut state = MarketMakersState::default();
+        for maker in [user, other] {
+            state.makers.insert(
+                maker,
+                DesignatedMarketMaker {
+                    user: maker,
+                    obligations: obligations.clone(),
+                    registered_at: 0,
+                    rounds_sampled: 0,
+                    rounds_compliant: 0,
+                },
+            );
+        }
+
+        let mut book = AggregateBidAsk::default();
+        book.bids.insert(100, quote(user, 1000));
+        book.asks.insert(104, quote(user, 10));
+        book.asks.insert(110, quote(other, 10));
+        state.sample(&book);
+        book.asks.insert(104, quote(user, 9));
+        state.sample(&book);
+        book.asks.insert(104, quote(user, 10));
+        book.bids.insert(99, quote(other, 10000));
+        state.sample(&book);
+
+        let scores = state.end_period(10, Some);
+        let score = |maker| scores.iter().find(|s| s.user == maker).unwrap().clone();
+        assert_eq!(score(user).score, 2.0 / 3.0);
+        assert_eq!(score(other).uptime, 0.0);
+        assert_eq!(score(other).score, 0.0);
+        assert_eq!(state.makers[&user].rounds_sampled, 0);
+        assert_eq!(state.period_start, 10);
+    }
+}
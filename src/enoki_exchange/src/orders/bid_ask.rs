@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 
-use candid::CandidType;
+use candid::{CandidType, Principal};
 
 use enoki_exchange_shared::types::*;
 
@@ -24,6 +24,7 @@ impl BidAsk {
                             user: order.info.user,
                             quantity: order.state.quantity_remaining.clone(),
                             price: order.info.limit_price,
+                            maker_fee: Some(order.info.get_fees().maker_fee),
                         })
                         .collect(),
                 )
@@ -96,6 +97,25 @@ impl BidAsk {
         }
         last_price
     }
+    pub fn cancel_broker_orders(&mut self, broker: Principal) {
+        for order in self
+            .0
+            .iter_mut()
+            .flat_map(|(_, orders)| orders)
+            .filter(|order| order.info.broker == broker)
+        {
+            if let OrderStatus::Pending = order.state.status {
+                order.state.status = OrderStatus::Cancelled;
+            }
+        }
+    }
+    pub fn count_broker_orders(&self, broker: Principal) -> usize {
+        self.0
+            .values()
+            .flatten()
+            .filter(|order| order.info.broker == broker)
+            .count()
+    }
     pub fn cancel_expired(&mut self) {
         let now = ic_cdk::api::time();
         for order in self
@@ -1,7 +1,6 @@
 use std::ops::Sub;
 
 use enoki_exchange_shared::has_token_info::QuantityTranslator;
-use enoki_exchange_shared::has_trading_fees::get_limit_order_taker_fee;
 use enoki_exchange_shared::types::*;
 use enoki_exchange_shared::utils::{nat_div_float, nat_x_float};
 
@@ -33,7 +32,7 @@ impl OrderMatching for Order {
         if let OrderStatus::Pending = self.state.status {
             if let OrderStatus::Pending = executor.state.status {
                 last_price = Some(executor.info.limit_price);
-                let taker_fee = get_limit_order_taker_fee();
+                let taker_fee = self.info.get_fees().taker_fee;
                 let mut quantity_remaining = self.state.quantity_remaining.take_as_nat();
                 quantity_remaining = nat_x_float(quantity_remaining, 1.0 - taker_fee).unwrap();
                 let market_maker_original_quantity = executor.state.quantity_remaining.clone();
@@ -78,6 +77,7 @@ impl OrderMatching for Order {
                         .sub(executor.state.quantity_remaining.clone())
                         .unwrap(),
                     price: executor.info.limit_price,
+                    maker_fee: Some(executor.info.get_fees().maker_fee),
                 });
             }
         }
@@ -20,17 +20,23 @@ pub struct OrdersState {
     orders: OrderMatcher,
 }
 
+/// Matches the orders of the round. The resting orders of draining brokers are cancelled.
 pub fn match_orders(
     new_orders: Vec<OrderInfo>,
     orders_to_cancel: Vec<OrderInfo>,
+    draining_brokers: &[Principal],
 ) -> (HashMap<Principal, Vec<Order>>, AggregateBidAsk) {
     STATE.with(|s| {
         s.borrow_mut()
             .orders
-            .match_orders(new_orders, orders_to_cancel)
+            .match_orders(new_orders, orders_to_cancel, draining_brokers)
     })
 }
 
+pub fn count_open_orders(broker: Principal) -> usize {
+    STATE.with(|s| s.borrow().orders.count_broker_orders(broker))
+}
+
 pub fn export_stable_storage() -> OrdersState {
     STATE.with(|s| s.take())
 }
@@ -45,10 +45,14 @@ impl OrderMatcher {
         }
         None
     }
+    pub fn count_broker_orders(&self, broker: Principal) -> usize {
+        self.bids.count_broker_orders(broker) + self.asks.count_broker_orders(broker)
+    }
     pub fn match_orders(
         &mut self,
         new_orders: Vec<OrderInfo>,
         orders_to_cancel: Vec<OrderInfo>,
+        draining_brokers: &[Principal],
     ) -> (HashMap<Principal, Vec<Order>>, AggregateBidAsk) {
         let mut completed_orders: CompletedOrders = Default::default();
 
@@ -140,6 +144,11 @@ impl OrderMatcher {
         self.bids.cancel_expired();
         self.asks.cancel_expired();
 
+        for &broker in draining_brokers {
+            self.bids.cancel_broker_orders(broker);
+            self.asks.cancel_broker_orders(broker);
+        }
+
         for completed in self
             .asks
             .take_completed()
@@ -6,8 +6,10 @@ use candid::{candid_method, CandidType};
 use ic_cdk_macros::*;
 
 use enoki_exchange_shared::has_token_info::price_in_b_u64_to_float;
+use enoki_exchange_shared::has_trading_fees;
 
 const MAX_HISTORY_SIZE: usize = 3600;
+const VOLATILITY_WINDOW: u64 = 3600 * 1_000_000_000;
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct PriceHistory {
@@ -61,6 +63,43 @@ pub fn save_last_price_value(last_price: u64) {
     })
 }
 
+/// Square root of the sum of the squared log returns between consecutive prices.
+fn realized_volatility<I: Iterator<Item = u64>>(prices: I) -> f64 {
+    let mut previous: Option<u64> = None;
+    let mut sum = 0.0;
+    for price in prices.filter(|&price| price > 0) {
+        if let Some(previous) = previous {
+            sum += (price as f64 / previous as f64).ln().powi(2);
+        }
+        previous = Some(price);
+    }
+    sum.sqrt()
+}
+
+pub fn get_realized_volatility() -> f64 {
+    let since = ic_cdk::api::time().saturating_sub(VOLATILITY_WINDOW);
+    STATE.with(|s| {
+        realized_volatility(
+            s.borrow()
+                .last_prices_by_timestamp
+                .iter()
+                .filter(|last| last.time >= since)
+                .map(|last| last.price),
+        )
+    })
+}
+
+/// The swap fee brokers charge this round.
+#[query(name = "getEffectiveSwapFee")]
+#[candid_method(query, rename = "getEffectiveSwapFee")]
+pub fn get_effective_swap_fee() -> f64 {
+    has_trading_fees::get_effective_swap_fee(get_realized_volatility())
+}
+
+pub fn get_last_price() -> Option<u64> {
+    STATE.with(|s| s.borrow().last_prices_by_timestamp.back().map(|p| p.price))
+}
+
 pub fn get_last_price_time() -> u64 {
     STATE
         .with(|s| {
@@ -96,3 +135,17 @@ pub fn export_stable_storage() -> PriceHistory {
 pub fn import_stable_storage(data: PriceHistory) {
     STATE.with(|s| s.replace(data));
 }
+
+#[cfg(test)]
+mod price_history_tests {
+    use super::*;
+
+    #[test]
+    fn test_realized_volatility() {
+        assert_eq!(realized_volatility(vec![100, 100, 100].into_iter()), 0.0);
+        assert_eq!(realized_volatility(vec![100].into_iter()), 0.0);
+        let volatility = realized_volatility(vec![100, 110, 0, 100].into_iter());
+        let expected = ((1.1f64).ln().powi(2) * 2.0).sqrt();
+        assert!((volatility - expected).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_trading_fees::FeeSweep;
+use enoki_exchange_shared::is_owned;
+use enoki_exchange_shared::types::*;
+
+use crate::brokers::{foreach_broker, get_broker_ids};
+
+thread_local! {
+    static STATE: RefCell<ProtocolFeesState> = RefCell::new(ProtocolFeesState::default());
+}
+
+/// Where the brokers send their accrued fees, and every sweep collected through the exchange.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ProtocolFeesState {
+    fee_recipient: Option<Principal>,
+    ledger: Vec<(Principal, FeeSweep)>,
+}
+
+#[update(name = "setFeeRecipient")]
+#[candid_method(update, rename = "setFeeRecipient")]
+async fn set_fee_recipient(recipient: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    let _result: Vec<()> = foreach_broker("setFeeRecipient", |_| (recipient,)).await?;
+    STATE.with(|s| s.borrow_mut().fee_recipient = Some(recipient));
+    Ok(())
+}
+
+#[query(name = "getFeeRecipient")]
+#[candid_method(query, rename = "getFeeRecipient")]
+fn get_fee_recipient() -> Option<Principal> {
+    STATE.with(|s| s.borrow().fee_recipient)
+}
+
+/// Tells a new broker where to send its fees.
+pub async fn init_broker_fee_recipient(broker: Principal) -> Result<()> {
+    if let Some(recipient) = STATE.with(|s| s.borrow().fee_recipient) {
+        let result: Result<()> =
+            flatten_response(ic_cdk::call(broker, "setFeeRecipient", (recipient,)).await);
+        result?;
+    }
+    Ok(())
+}
+
+/// Has every broker send its accrued fees to the fee recipient. A broker that fails does not stop
+/// the others; the outcome is returned per broker.
+#[update(name = "collectProtocolFees")]
+#[candid_method(update, rename = "collectProtocolFees")]
+async fn collect_protocol_fees() -> TxResult<Vec<(Principal, TxResult<Vec<FeeSweep>>)>> {
+    is_owned::assert_is_owner()?;
+    let ids = get_broker_ids();
+    let responses = futures::future::join_all(
+        ids.iter()
+            .map(|&id| ic_cdk::call(id, "withdrawAccruedFees", ())),
+    )
+    .await;
+    let results: Vec<(Principal, TxResult<Vec<FeeSweep>>)> = ids
+        .into_iter()
+        .zip(responses)
+        .map(|(id, response)| (id, flatten_response(response).map_err(TxError::from)))
+        .collect();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for (id, result) in results.iter() {
+            if let Ok(sweeps) = result {
+                s.ledger
+                    .extend(sweeps.iter().map(|sweep| (*id, sweep.clone())));
+            }
+        }
+    });
+    Ok(results)
+}
+
+#[query(name = "getProtocolFeeLedger")]
+#[candid_method(query, rename = "getProtocolFeeLedger")]
+fn get_protocol_fee_ledger() -> Vec<(Principal, FeeSweep)> {
+    STATE.with(|s| s.borrow().ledger.clone())
+}
+
+pub fn export_stable_storage() -> ProtocolFeesState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: ProtocolFeesState) {
+    STATE.with(|s| s.replace(data));
+}
@@ -0,0 +1,88 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::types::*;
+
+const MAX_CODE_LENGTH: usize = 32;
+
+thread_local! {
+    static STATE: RefCell<ReferralsState> = RefCell::new(ReferralsState::default());
+}
+
+/// Referral codes of integrators, and the code each user registered with.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ReferralsState {
+    codes: HashMap<String, Principal>,
+    user_codes: HashMap<Principal, String>,
+}
+
+#[update(name = "createReferralCode")]
+#[candid_method(update, rename = "createReferralCode")]
+fn create_referral_code(code: String) -> TxResult<()> {
+    if code.is_empty() || code.len() > MAX_CODE_LENGTH {
+        return Err(TxError::InvalidInput(format!(
+            "referral codes must have between 1 and {} characters",
+            MAX_CODE_LENGTH
+        )));
+    }
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.codes.contains_key(&code) {
+            return Err(TxError::InvalidInput("referral code taken".to_string()));
+        }
+        s.codes.insert(code, ic_cdk::caller());
+        Ok(())
+    })
+}
+
+#[query(name = "getReferralCodeOwner")]
+#[candid_method(query, rename = "getReferralCodeOwner")]
+fn get_referral_code_owner(code: String) -> Option<Principal> {
+    STATE.with(|s| s.borrow().codes.get(&code).copied())
+}
+
+#[query(name = "getUserReferralCode")]
+#[candid_method(query, rename = "getUserReferralCode")]
+fn get_user_referral_code(user: Principal) -> Option<String> {
+    STATE.with(|s| s.borrow().user_codes.get(&user).cloned())
+}
+
+/// Returns who referred `user` with `code`. Only users registering themselves can use a code.
+pub fn get_referrer(user: Principal, code: &str) -> Result<Principal> {
+    if ic_cdk::caller() != user {
+        return Err(TxError::Unauthorized.into());
+    }
+    let referrer = STATE
+        .with(|s| s.borrow().codes.get(code).copied())
+        .ok_or_else(|| TxError::InvalidInput(format!("unknown referral code {}", code)))?;
+    if referrer == user {
+        return Err(TxError::InvalidInput("users cannot refer themselves".to_string()).into());
+    }
+    Ok(referrer)
+}
+
+pub fn record_user_code(user: Principal, code: String) {
+    STATE.with(|s| s.borrow_mut().user_codes.insert(user, code));
+}
+
+/// The owner of the code `user` registered with, if any.
+pub fn get_registered_referrer(user: Principal) -> Option<Principal> {
+    STATE.with(|s| {
+        let s = s.borrow();
+        s.user_codes
+            .get(&user)
+            .and_then(|code| s.codes.get(code))
+            .copied()
+    })
+}
+
+pub fn export_stable_storage() -> ReferralsState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: ReferralsState) {
+    STATE.with(|s| s.replace(data));
+}
@@ -1,9 +1,11 @@
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
-use enoki_exchange_shared::{has_token_info, has_trading_fees, is_owned};
+use enoki_exchange_shared::{has_scheduler, has_token_info, has_trading_fees, is_owned};
+use enoki_exchange_shared::has_scheduler::{SchedulerSettings, SchedulerStatus};
 use enoki_exchange_shared::has_token_info::{AssignedShards, TokenPairInfo};
 use enoki_exchange_shared::has_trading_fees::TradingFees;
+use enoki_exchange_shared::types::TxResult;
 
 #[query(name = "getOwner")]
 #[candid_method(query, rename = "getOwner")]
@@ -13,8 +15,8 @@ fn get_owner() -> Principal {
 
 #[update(name = "setOwner")]
 #[candid_method(update, rename = "setOwner")]
-fn set_owner(new_owner: Principal) {
-    is_owned::set_owner(new_owner).unwrap()
+fn set_owner(new_owner: Principal) -> TxResult<()> {
+    Ok(is_owned::set_owner(new_owner)?)
 }
 
 #[query(name = "getTokenInfo")]
@@ -46,3 +48,15 @@ fn get_assigned_shard_b() -> Principal {
 fn get_trading_fees() -> TradingFees {
     has_trading_fees::get_trading_fees()
 }
+
+#[query(name = "getSchedulerStatus")]
+#[candid_method(query, rename = "getSchedulerStatus")]
+fn get_scheduler_status() -> SchedulerStatus {
+    has_scheduler::get_status()
+}
+
+#[update(name = "setSchedulerSettings")]
+#[candid_method(update, rename = "setSchedulerSettings")]
+fn set_scheduler_settings(settings: SchedulerSettings) -> TxResult<()> {
+    Ok(has_scheduler::set_settings(settings)?)
+}
@@ -9,10 +9,16 @@ use enoki_exchange_shared::liquidity::ResponseAboutLiquidityChanges;
 use enoki_exchange_shared::types::*;
 use enoki_exchange_shared::utils::flat_map_vecs;
 
-use crate::brokers::{foreach_broker, foreach_broker_map, get_broker_ids};
+use crate::brokers::{
+    foreach_broker, foreach_broker_map, get_broker_ids, get_draining_brokers,
+    remove_drained_brokers,
+};
+use crate::fee_schedule;
 use crate::liquidity;
 use crate::liquidity::update_committed_broker_liquidity;
+use crate::market_makers;
 use crate::orders::match_orders;
+use crate::price_history::{get_effective_swap_fee, get_last_price};
 
 thread_local! {
     static STATE: RefCell<RunningState> = RefCell::new(RunningState::default());
@@ -20,61 +26,44 @@ thread_local! {
 
 #[derive(serde::Deserialize, serde::Serialize, CandidType, Clone, Debug, Default)]
 pub struct RunningState {
-    locked: bool,
     aggregate_bid_ask: AggregateBidAsk,
 }
 
-impl RunningState {
-    pub fn lock(&mut self) -> bool {
-        if self.locked {
-            false
-        } else {
-            self.locked = true;
-            true
-        }
-    }
-    pub fn unlock(&mut self) {
-        self.locked = false;
-    }
-}
-
-#[allow(unused)]
-pub async fn run() {
-    if !STATE.with(|s| s.borrow_mut().lock()) {
-        return;
-    }
-
-    let result = do_run().await;
-    if let Err(error) = result {
-        ic_cdk::api::print(format!("ERROR during exchange run: {:?}", error));
-    }
-
-    STATE.with(|s| s.borrow_mut().unlock());
-}
-
-pub async fn do_run() -> Result<()> {
+/// Runs a round of the exchange. Returns whether it had orders to process, in which case more
+/// are likely to follow soon.
+pub async fn do_run() -> Result<bool> {
     if get_broker_ids().is_empty() {
-        return Ok(());
+        return Ok(false);
     }
     ic_cdk::println!("[exchange] started exchange sync");
-    let proposed_liquidity_target_for_brokers =
-        liquidity::get_updated_liquidity_from_pool().await?;
+    let mut liquidity_targets = liquidity::get_updated_liquidity_from_pool().await?;
 
-    ic_cdk::api::print(format!(
-        "[exchange] got liquidity: {:?}",
-        proposed_liquidity_target_for_brokers
-    ));
+    ic_cdk::api::print(format!("[exchange] got liquidity: {:?}", liquidity_targets));
 
+    let fee_change = fee_schedule::get_due_fee_change();
+    let fee_change_fees = fee_change.as_ref().map(|change| change.fees.clone());
     let (new_orders, orders_to_cancel) =
-        flat_map_vecs(foreach_broker("retrieveOrders", |_| ()).await?);
+        flat_map_vecs(foreach_broker("retrieveOrders", |_| (fee_change_fees.clone(),)).await?);
+    if let Some(change) = fee_change {
+        match fee_schedule::apply_fee_change(change.clone()) {
+            Ok(()) => ic_cdk::println!("[exchange] applied fee change: {:?}", change),
+            Err(error) => ic_cdk::print(format!(
+                "[exchange] could not apply fee change {:?}: {:?}",
+                change, error
+            )),
+        }
+    }
 
     ic_cdk::println!(
         "[exchange] got {} new orders and {} to cancel",
         new_orders.len(),
         orders_to_cancel.len()
     );
+    let had_orders = !new_orders.is_empty() || !orders_to_cancel.is_empty();
 
-    let (mut completed_orders, aggregate_bid_ask) = match_orders(new_orders, orders_to_cancel);
+    let draining_brokers = get_draining_brokers();
+    let (mut completed_orders, aggregate_bid_ask) =
+        match_orders(new_orders, orders_to_cancel, &draining_brokers);
 
     ic_cdk::println!("[exchange] completed orders: {:?}", completed_orders);
 
@@ -83,6 +72,13 @@ pub async fn do_run() -> Result<()> {
             .aggregate_bid_ask
             .change_to_next(&aggregate_bid_ask)
     });
+    market_makers::sample_quotes(&aggregate_bid_ask);
+
+    // after the fee change of the round, if any, was applied
+    let swap_fee = get_effective_swap_fee();
+    for target in liquidity_targets.values_mut() {
+        target.swap_fee = swap_fee;
+    }
 
     ic_cdk::println!("[exchange] submitting orders to brokers...");
 
@@ -92,44 +88,45 @@ pub async fn do_run() -> Result<()> {
             (
                 completed_orders.remove(&id).unwrap_or_default(),
                 aggregate_bid_ask.clone(),
-                proposed_liquidity_target_for_brokers.clone(),
+                liquidity_targets.remove(&id).unwrap_or_default(),
             )
         },
-        |res: (ResponseAboutLiquidityChanges,)| res.0,
+        |res: ResponseAboutLiquidityChanges| res,
     )
     .await?;
 
     ic_cdk::println!("[exchange] updating changes in liquidity...");
 
-    update_committed_broker_liquidity(changes_in_liquidity_by_broker).await?;
+    update_committed_broker_liquidity(changes_in_liquidity_by_broker, get_last_price()).await?;
+    remove_drained_brokers().await;
 
     ic_cdk::println!("[exchange] end exchange sync");
 
-    Ok(())
+    Ok(had_orders)
 }
 
 #[query(name = "getBidAskCurve")]
 #[candid_method(query, rename = "getBidAskCurve")]
-pub fn get_bid_ask_curve() -> BidAskCurve {
+pub fn get_bid_ask_curve() -> TxResult<BidAskCurve> {
     let num_decimals = has_token_info::get_number_of_price_decimals();
     let bid_ask = STATE.with(|s| s.borrow().aggregate_bid_ask.clone());
     fn sum(info: Vec<CounterpartyInfo>) -> Nat {
         info.into_iter()
             .fold(Nat::from(0u32), |sum, next| sum + next.quantity.to_nat())
     }
-    BidAskCurve {
+    Ok(BidAskCurve {
         num_decimals,
         bids: bid_ask
             .bids
             .into_iter()
-            .map(|(price, bids)| (price, quantity_b_to_a(sum(bids), price).unwrap()))
-            .collect(),
+            .map(|(price, bids)| Ok((price, quantity_b_to_a(sum(bids), price)?)))
+            .collect::<Result<_>>()?,
         asks: bid_ask
             .asks
             .into_iter()
             .map(|(price, asks)| (price, sum(asks)))
             .collect(),
-    }
+    })
 }
 
 pub fn export_stable_storage() -> RunningState {
@@ -1,17 +1,27 @@
 use candid::{CandidType, Deserialize};
 use ic_cdk_macros::*;
 
+use enoki_exchange_shared::has_scheduler::SchedulerState;
 use enoki_exchange_shared::has_sharded_users::ShardedUserState;
 use enoki_exchange_shared::has_token_info::TokenInfoState;
 use enoki_exchange_shared::has_trading_fees::TradingFees;
 use enoki_exchange_shared::is_owned::OwnershipData;
-use enoki_exchange_shared::{has_sharded_users, has_token_info, has_trading_fees, is_owned};
+use enoki_exchange_shared::{
+    has_scheduler, has_sharded_users, has_token_info, has_trading_fees, is_owned,
+};
 
+use crate::fee_schedule::FeeScheduleState;
 use crate::liquidity::LiquidityState;
+use crate::market_makers::MarketMakersState;
 use crate::orders::OrdersState;
 use crate::price_history::PriceHistory;
+use crate::protocol_fees::ProtocolFeesState;
+use crate::referrals::ReferralsState;
 use crate::synchronize::RunningState;
-use crate::{brokers, liquidity, orders, price_history, synchronize, BrokerState};
+use crate::{
+    brokers, fee_schedule, liquidity, market_makers, orders, price_history, protocol_fees,
+    referrals, synchronize, BrokerState,
+};
 
 #[derive(Deserialize, CandidType)]
 struct UpgradePayload {
@@ -24,6 +34,11 @@ struct UpgradePayload {
     liquidity: LiquidityState,
     run_state: RunningState,
     price_history: Option<PriceHistory>,
+    protocol_fees: Option<ProtocolFeesState>,
+    fee_schedule: Option<FeeScheduleState>,
+    referrals: Option<ReferralsState>,
+    market_makers: Option<MarketMakersState>,
+    scheduler: Option<SchedulerState>,
 }
 
 #[pre_upgrade]
@@ -37,6 +52,11 @@ fn pre_upgrade() {
     let liquidity = liquidity::export_stable_storage();
     let run_state = synchronize::export_stable_storage();
     let price_history = price_history::export_stable_storage();
+    let protocol_fees = protocol_fees::export_stable_storage();
+    let fee_schedule = fee_schedule::export_stable_storage();
+    let referrals = referrals::export_stable_storage();
+    let market_makers = market_makers::export_stable_storage();
+    let scheduler = has_scheduler::export_stable_storage();
     let payload = UpgradePayload {
         sharded_users,
         token_info,
@@ -47,6 +67,11 @@ fn pre_upgrade() {
         liquidity,
         run_state,
         price_history: Some(price_history),
+        protocol_fees: Some(protocol_fees),
+        fee_schedule: Some(fee_schedule),
+        referrals: Some(referrals),
+        market_makers: Some(market_makers),
+        scheduler: Some(scheduler),
     };
     ic_cdk::storage::stable_save((payload,)).expect("failed to save to stable storage");
 }
@@ -66,6 +91,11 @@ fn post_upgrade() {
         liquidity,
         run_state,
         price_history,
+        protocol_fees,
+        fee_schedule,
+        referrals,
+        market_makers,
+        scheduler,
     } = payload;
 
     has_sharded_users::import_stable_storage(sharded_users);
@@ -79,4 +109,19 @@ fn post_upgrade() {
     if let Some(price_history) = price_history {
         price_history::import_stable_storage(price_history);
     }
+    if let Some(protocol_fees) = protocol_fees {
+        protocol_fees::import_stable_storage(protocol_fees);
+    }
+    if let Some(fee_schedule) = fee_schedule {
+        fee_schedule::import_stable_storage(fee_schedule);
+    }
+    if let Some(referrals) = referrals {
+        referrals::import_stable_storage(referrals);
+    }
+    if let Some(market_makers) = market_makers {
+        market_makers::import_stable_storage(market_makers);
+    }
+    if let Some(scheduler) = scheduler {
+        has_scheduler::import_stable_storage(scheduler);
+    }
 }
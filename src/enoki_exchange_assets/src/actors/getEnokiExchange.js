@@ -3,6 +3,7 @@ import {idlFactory, canisterId} from "../../../declarations/enoki_exchange";
 import getTokenShard from "./getTokenShard";
 import getMainToken from "./getMainToken";
 import getEnokiBroker from "./getEnokiBroker";
+import {unwrapResult} from "../utils/utils";
 
 /**
  *
@@ -36,9 +37,13 @@ export default getEnokiExchange;
 export const getAssignedBroker = async (identity) => {
   let assigned_broker;
   try {
-    assigned_broker = await getEnokiExchange(identity).getAssignedBroker(identity.getPrincipal());
+    assigned_broker = unwrapResult(await getEnokiExchange(identity).getAssignedBroker(identity.getPrincipal()));
   } catch (err) {
-    assigned_broker = await getEnokiExchange(identity).register(identity.getPrincipal());
+    const referralCode = new URLSearchParams(window.location.search).get('ref');
+    assigned_broker = unwrapResult(await getEnokiExchange(identity).register(
+      identity.getPrincipal(),
+      referralCode ? [referralCode] : []
+    ));
   }
   return getEnokiBroker(identity, assigned_broker);
 }
@@ -6,7 +6,7 @@ import {canisterId as canisterIdA} from "../../../../declarations/enoki_wrapped_
 import {canisterId as canisterIdB} from "../../../../declarations/enoki_wrapped_token_b";
 import {useDispatch, useSelector} from "react-redux";
 import getPoolWorker from "../../actors/getPoolWorker";
-import {bigIntToStr, floatToBigInt} from "../../utils/utils";
+import {assertNotRejected, bigIntToStr, floatToBigInt} from "../../utils/utils";
 import {setTradeOccurred} from "../../state/lastTradeSlice";
 import {getAssignedTokenShard} from "../../actors/getMainToken";
 import {Actor} from "@dfinity/agent";
@@ -54,6 +54,7 @@ const supplyToken = async (identity, isTokenA, quantity) => {
         "addLiquidity",
         ""
     );
+    assertNotRejected(message);
     console.log("liquidity added: ", message);
 }
 
@@ -7,12 +7,9 @@ import {enoki_liquidity_pool_worker} from "../../../../declarations/enoki_liquid
 import {bigIntToFloat} from "../../utils/utils";
 import ChangePool from "./ChangePool";
 import useLogo from "../../hooks/useLogo";
-import useHeartbeat from "../../hooks/useHeartbeat";
 
 const Pool = ({setShowWalletButtons}) => {
   const {isLoggedIn, getIdentity} = useLogin();
-  // noinspection JSUnusedLocalSymbols
-  const lastExchangeUpdate = useHeartbeat();
   const logoA = useLogo({canisterId: canisterIdA});
   const logoB = useLogo({canisterId: canisterIdB});
   const lastTradeTime = useSelector(state => state.lastTrade.lastTradeTime);
@@ -33,10 +30,6 @@ const Pool = ({setShowWalletButtons}) => {
     setIsLoadingBalances(true);
     setIsLoadingNetWithdrawals(true);
 
-    const triggerHeartbeat = async () => {
-      await enoki_liquidity_pool_worker.triggerHeartbeat();
-    }
-
     const updateLiquidity = async () => {
       let liquidity = await enoki_liquidity_pool_worker.getLiquidity(getIdentity().getPrincipal());
       if (stop) return false;
@@ -62,8 +55,7 @@ const Pool = ({setShowWalletButtons}) => {
       let liquidityOriginal = await enoki_liquidity_pool_worker.getLiquidity(getIdentity().getPrincipal());
 
       while (!stop) {
-        await wait(200);
-        await enoki_liquidity_pool_worker.triggerHeartbeat();
+        await wait(1000);
         let liquidity = await enoki_liquidity_pool_worker.getLiquidity(getIdentity().getPrincipal());
         if (stop) return false;
         if (liquidity.token_a !== liquidityOriginal.token_a || liquidity.token_b !== liquidityOriginal.token_b) {
@@ -81,7 +73,7 @@ const Pool = ({setShowWalletButtons}) => {
     }
 
     if (waitingForBalanceChange) {
-      triggerHeartbeat().then(() => Promise.all([updateLiquidityWhileItDoesntChange(), updateNetDeposits()]))
+      Promise.all([updateLiquidityWhileItDoesntChange(), updateNetDeposits()])
         .then(([updatedBalances, updatedDeposits]) => {
           if (updatedBalances && updatedDeposits) {
             setIsLoadingBalances(false);
@@ -91,7 +83,7 @@ const Pool = ({setShowWalletButtons}) => {
         })
         .catch(err => console.error("error updating pool values: ", err));
     } else {
-      triggerHeartbeat().then(() => Promise.all([updateLiquidity(), updateNetDeposits()]))
+      Promise.all([updateLiquidity(), updateNetDeposits()])
         .then(([updatedBalances, updatedDeposits]) => {
           if (updatedBalances) {
             setIsLoadingBalances(false);
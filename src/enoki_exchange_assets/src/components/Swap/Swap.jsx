@@ -7,13 +7,12 @@ import useLogin from "../../hooks/useLogin";
 import {canisterId as canisterIdA} from "../../../../declarations/enoki_wrapped_token";
 import {canisterId as canisterIdB} from "../../../../declarations/enoki_wrapped_token_b";
 import useTokenBalance from "../../hooks/useTokenBalance";
-import {bigIntToStr, floatToBigInt} from "../../utils/utils";
+import {assertNotRejected, bigIntToStr, floatToBigInt, unwrapResult} from "../../utils/utils";
 import LoadingText from "../shared/LoadingText";
 import {getAssignedTokenShard} from "../../actors/getMainToken";
 import {Actor} from "@dfinity/agent";
 import {setTradeOccurred} from "../../state/lastTradeSlice";
 import useLogo from "../../hooks/useLogo";
-import useHeartbeat from "../../hooks/useHeartbeat";
 import LoadingButton from "../shared/LoadingButton";
 
 const NUM_DECIMALS_QUANTITY = {
@@ -62,13 +61,12 @@ const execute_swap = async (identity, canisterId, sellingTokenA, quantity, price
     "swap",
     JSON.stringify({allow_taker: true, limit_price_in_b: price})
   );
+  assertNotRejected(message);
   console.log("swap success: ", message);
 }
 
 const Swap = ({setShowWalletButtons}) => {
   const {isLoggedIn, getIdentity} = useLogin();
-  // noinspection JSUnusedLocalSymbols
-  const lastExchangeUpdate = useHeartbeat();
   const [pair, setPair] = React.useState(['eICP', 'eXTC']);
   const [leftSwapValue, setLeftSwapValue] = React.useState("");
   const [rightSwapValue, setRightSwapValue] = React.useState("");
@@ -170,6 +168,7 @@ const Swap = ({setShowWalletButtons}) => {
         pair[0] === 'eICP' ? {'Sell': null} : {'Buy': null},
         quantity
       ))
+      .then(unwrapResult)
       .then(price => {
         if (stop) return;
         console.log(`expected price for ${quantity} is ${price}`);
@@ -177,7 +176,7 @@ const Swap = ({setShowWalletButtons}) => {
       })
       .catch(err => {
         console.error(err);
-        if (/Insufficient Liquidity/i.test(err.message)) {
+        if (/Insufficient ?Liquidity/i.test(err.message)) {
           setErrorDetails("Insufficient liquidity. Try a lower value.");
         } else {
           setErrorDetails("An error occurred - try again in a bit.");
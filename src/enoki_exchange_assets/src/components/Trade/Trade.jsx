@@ -4,7 +4,7 @@ import {useSelector, useDispatch} from 'react-redux'
 import {setAllowTaker, setOnlyMaker} from "../../state/tradeSlice";
 import {canisterId as canisterIdA} from "../../../../declarations/enoki_wrapped_token";
 import {canisterId as canisterIdB} from "../../../../declarations/enoki_wrapped_token_b";
-import {bigIntToStr, floatToBigInt} from "../../utils/utils";
+import {assertNotRejected, bigIntToStr, floatToBigInt, unwrapResult} from "../../utils/utils";
 import {getAssignedTokenShard} from "../../actors/getMainToken";
 import getEnokiExchange, {getAssignedBroker} from "../../actors/getEnokiExchange";
 import {enoki_liquidity_pool_worker} from "../../../../declarations/enoki_liquidity_pool_worker";
@@ -17,7 +17,6 @@ import {setTradeOccurred} from "../../state/lastTradeSlice";
 import {Actor} from "@dfinity/agent";
 import Orders from "./Orders";
 import OrderBook from "./OrderBook";
-import useHeartbeat from "../../hooks/useHeartbeat";
 import PriceHistory from "./PriceHistory";
 import useOrderBook from "../../hooks/useOrderBook";
 import LoadingButton from "../shared/LoadingButton";
@@ -44,6 +43,7 @@ const executeOrder = async (identity, canisterId, sellingTokenA, quantity, price
     "limitOrder",
     JSON.stringify({allow_taker: allowTaker, limit_price_in_b: price})
   );
+  assertNotRejected(message);
   console.log("trade id: ", message);
 }
 
@@ -51,8 +51,6 @@ const Trade = ({setShowWalletButtons}) => {
   const dispatch = useDispatch();
   const allowTaker = useSelector(state => state.trade.allowTaker);
   const {isLoggedIn, getIdentity} = useLogin();
-  // noinspection JSUnusedLocalSymbols
-  const lastExchangeUpdate = useHeartbeat();
   const logoA = useLogo({canisterId: canisterIdA});
   const logoB = useLogo({canisterId: canisterIdB});
   const [side, setSide] = React.useState('buy');
@@ -64,6 +62,8 @@ const Trade = ({setShowWalletButtons}) => {
   const [isError, setIsError] = React.useState(null);
   const [errorDetails, setErrorDetails] = React.useState(undefined);
   const [extraRewards, setExtraRewards] = React.useState([null, null]);
+  const [claimableRewards, setClaimableRewards] = React.useState([null, null]);
+  const [claiming, setClaiming] = React.useState(false);
   const [executing, setExecuting] = React.useState(false);
   const [lastPrices, setLastPrices] = React.useState([]);
   const lastPrice = (lastPrices && lastPrices[lastPrices.length - 1]) || null;
@@ -148,17 +148,30 @@ const Trade = ({setShowWalletButtons}) => {
     let stop = false;
 
     getAssignedBroker(getIdentity())
-      .then(broker => broker.getAccruedExtraRewards(getIdentity().getPrincipal()))
-      .then(rewards => {
+      .then(broker => Promise.all([
+        broker.getAccruedExtraRewards(getIdentity().getPrincipal()),
+        broker.getClaimableRewards(getIdentity().getPrincipal()),
+      ]))
+      .then(([rewards, claimable]) => {
         if (stop) return;
         setExtraRewards([bigIntToStr(rewards.token_a, 'eICP', 2), bigIntToStr(rewards.token_b, 'eXTC', 2)])
+        setClaimableRewards([bigIntToStr(claimable.token_a, 'eICP', 2), bigIntToStr(claimable.token_b, 'eXTC', 2)])
       })
       .catch(err => console.error("error retrieving extra rewards: ", err));
 
     return () => {
       stop = true;
     }
-  }, [isLoggedIn]);
+  }, [isLoggedIn, claiming]);
+
+  const claimRewards = token => {
+    setClaiming(true);
+    getAssignedBroker(getIdentity())
+      .then(broker => broker.claimRewards(token === 'eICP' ? {TokenA: null} : {TokenB: null}))
+      .then(unwrapResult)
+      .catch(err => console.error("error claiming rewards: ", err))
+      .then(() => setClaiming(false));
+  }
 
   React.useEffect(() => {
     let stop = false;
@@ -289,7 +302,11 @@ const Trade = ({setShowWalletButtons}) => {
             <div className="price_line">
               <strong>Current Balance:</strong>{balancesStr['eICP']} eICP / {balancesStr['eXTC']} eXTC<br/><br/>
               <strong>Accrued Market Maker Rewards:</strong>{extraRewards[0] || '--'} eICP
-              / {extraRewards[1] || '--'} eXTC
+              / {extraRewards[1] || '--'} eXTC<br/><br/>
+              <strong>Claimable Rewards:</strong>{claimableRewards[0] || '--'} eICP
+              <button className="btn btn-link" disabled={claiming} onClick={() => claimRewards('eICP')}>Claim</button>
+              / {claimableRewards[1] || '--'} eXTC
+              <button className="btn btn-link" disabled={claiming} onClick={() => claimRewards('eXTC')}>Claim</button>
             </div>
             <div className="cal">
               <ul className="nav nav-tabs" id="myTab" role="tablist">
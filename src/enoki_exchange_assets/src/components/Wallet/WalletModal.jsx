@@ -4,13 +4,14 @@ import useLogin from "../../hooks/useLogin";
 import {canisterId as canisterIdA} from "../../../../declarations/enoki_wrapped_token";
 import {canisterId as canisterIdB} from "../../../../declarations/enoki_wrapped_token_b";
 import useTokenBalance from "../../hooks/useTokenBalance";
-import {bigIntToStr} from "../../utils/utils";
+import {bigIntToStr, unwrapResult} from "../../utils/utils";
 import ComingSoon from "../shared/ComingSoon";
 import {getAssignedTokenShard} from "../../actors/getMainToken";
 import getTokenShard from "../../actors/getTokenShard";
 import {setTradeOccurred} from "../../state/lastTradeSlice";
 import LoadingText from "../shared/LoadingText";
 import useLogo from "../../hooks/useLogo";
+import useBrokerBalance from "../../hooks/useBrokerBalance";
 
 const WalletModal = ({toggleShowWallet}) => {
   let {logout, getIdentity} = useLogin();
@@ -21,9 +22,14 @@ const WalletModal = ({toggleShowWallet}) => {
   const balanceEXtc = useTokenBalance({principal: canisterIdB});
   const balanceEIcpStr = balanceEIcp !== null && bigIntToStr(balanceEIcp, 'eICP', 6, null);
   const balanceEXtcStr = balanceEXtc !== null && bigIntToStr(balanceEXtc, 'eXTC', 4, null);
+  const brokerBalance = useBrokerBalance();
+  const brokerBalanceAStr = brokerBalance !== null && bigIntToStr(brokerBalance.token_a, 'eICP', 6, null);
+  const brokerBalanceBStr = brokerBalance !== null && bigIntToStr(brokerBalance.token_b, 'eXTC', 4, null);
 
   const [mintingA, setMintingA] = React.useState(false);
   const [mintingB, setMintingB] = React.useState(false);
+  const [withdrawingA, setWithdrawingA] = React.useState(false);
+  const [withdrawingB, setWithdrawingB] = React.useState(false);
 
   const mintA = () => {
     setMintingA(true);
@@ -45,6 +51,30 @@ const WalletModal = ({toggleShowWallet}) => {
       })
   }
 
+  const withdrawA = () => {
+    setWithdrawingA(true);
+    withdraw({'TokenA': null}, 'token_a', () => setWithdrawingA(false));
+  }
+
+  const withdrawB = () => {
+    setWithdrawingB(true);
+    withdraw({'TokenB': null}, 'token_b', () => setWithdrawingB(false));
+  }
+
+  // sends the whole balance held by every broker to the wallet
+  const withdraw = (token, key, cb) => {
+    Promise.all(
+      brokerBalance.brokers
+        .filter(({balance}) => balance[key])
+        .map(({broker, balance}) => broker.withdraw(token, balance[key]).then(unwrapResult))
+    )
+      .catch(e => console.error('withdraw error: ', e))
+      .then(() => {
+        cb();
+        dispatch(setTradeOccurred());
+      })
+  }
+
   const clickLogout = () => {
     toggleShowWallet();
     logout();
@@ -97,6 +127,43 @@ const WalletModal = ({toggleShowWallet}) => {
                 </div>
               </div>
             </div>
+            <div className="box">
+              <h5>Exchange Balance</h5>
+              <div className="icon_box">
+                <img className="icon" src={logoA} alt=""/>
+                <div className="content">
+                  <p><b>{brokerBalanceAStr !== null ? brokerBalanceAStr : "--"}</b> eICP</p>
+                  {
+                    withdrawingA ? (
+                      <div>
+                        <img style={{width: 20, margin: 8}} src="img/spinner.svg"/>
+                        <LoadingText text="Withdrawing" speed={200}/>
+                      </div>
+                    ) : (
+                      <button onClick={() => withdrawA()} className="btn btn-small btn-black"
+                              disabled={!(brokerBalance && brokerBalance.token_a)}>WITHDRAW</button>
+                    )
+                  }
+                </div>
+              </div>
+              <div className="icon_box">
+                <img className="icon" src={logoB} alt=""/>
+                <div className="content">
+                  <p><b>{brokerBalanceBStr !== null ? brokerBalanceBStr : "--"}</b> eXTC</p>
+                  {
+                    withdrawingB ? (
+                      <div>
+                        <img style={{width: 20, margin: 8}} src="img/spinner.svg"/>
+                        <LoadingText text="Withdrawing" speed={200}/>
+                      </div>
+                    ) : (
+                      <button onClick={() => withdrawB()} className="btn btn-small btn-black"
+                              disabled={!(brokerBalance && brokerBalance.token_b)}>WITHDRAW</button>
+                    )
+                  }
+                </div>
+              </div>
+            </div>
             <div className="box">
               <h5>Tokens</h5>
               <div className="icon_box">
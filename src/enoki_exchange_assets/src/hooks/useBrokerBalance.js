@@ -0,0 +1,58 @@
+import React from "react";
+import {useSelector} from "react-redux";
+import useLogin from "./useLogin";
+import getEnokiExchange, {getAssignedBroker} from "../actors/getEnokiExchange";
+import getEnokiBroker from "../actors/getEnokiBroker";
+
+/**
+ * Tokens the brokers hold for the user: fills are credited there until withdrawn. Brokers retired
+ * by the exchange keep the balances of their former users, so those are included per broker.
+ *
+ * @return {{token_a: BigInt, token_b: BigInt, brokers: Array<{broker, balance}>} | null}
+ */
+const useBrokerBalance = () => {
+  const {
+    isLoggedIn, getIdentity
+  } = useLogin();
+  const lastTradeTime = useSelector(state => state.lastTrade.lastTradeTime);
+  const [balance, setBalance] = React.useState(null);
+
+  React.useEffect(() => {
+    if (!isLoggedIn) {
+      setBalance(null);
+      return;
+    }
+
+    let stop = false;
+    const wait = time => new Promise(resolve => setTimeout(resolve, time));
+    const run = async () => {
+      const identity = getIdentity();
+      const assigned = await getAssignedBroker(identity);
+      while (!stop) {
+        const former = await getEnokiExchange(identity).getFormerBrokers(identity.getPrincipal());
+        const brokers = [assigned, ...former.map(id => getEnokiBroker(identity, id))];
+        const balances = await Promise.all(
+          brokers.map(broker => broker.getBalance(identity.getPrincipal()))
+        );
+        if (stop) return;
+        setBalance({
+          token_a: balances.reduce((sum, balance) => sum + balance.token_a, BigInt(0)),
+          token_b: balances.reduce((sum, balance) => sum + balance.token_b, BigInt(0)),
+          brokers: brokers.map((broker, i) => ({broker, balance: balances[i]})),
+        });
+        await wait(10000);
+      }
+    }
+
+    run()
+      .catch(err => console.error('error updating broker balance:', err));
+
+    return () => {
+      stop = true;
+    }
+  }, [isLoggedIn, lastTradeTime])
+
+  return balance;
+}
+
+export default useBrokerBalance;
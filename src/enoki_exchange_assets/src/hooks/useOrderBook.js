@@ -1,6 +1,6 @@
 import React from "react";
 import getEnokiExchange from "../actors/getEnokiExchange";
-import {bigIntToFloat} from "../utils/utils";
+import {bigIntToFloat, unwrapResult} from "../utils/utils";
 
 const ORDER_BOOK_LENGTH = 7;
 
@@ -15,6 +15,7 @@ const useOrderBook = () => {
   React.useEffect(() => {
     let stop = false;
     const fetch = () => getEnokiExchange(undefined).getBidAskCurve()
+      .then(unwrapResult)
       .then(bidAsk => {
         if (stop) return;
 
@@ -32,3 +32,20 @@ export const floatToBigInt = (value, token, significantDigits = 8) => {
     return BigInt(value.toFixed(significantDigits).replace(/\./g, '') + '0'.repeat(tokenDecimal - significantDigits));
   }
 }
+
+// endpoints return a candid Result: {Ok: value} or {Err: {Variant: details}}
+export const unwrapResult = result => {
+  if ('Ok' in result) {
+    return result.Ok;
+  }
+  const [kind, details] = Object.entries(result.Err)[0];
+  throw new Error(details === null ? kind : `${kind}: ${details}`);
+}
+
+// canisters answer a rejected shardTransferAndCall with this prefix after refunding the tokens
+export const assertNotRejected = message => {
+  if (message.startsWith('rejected: ')) {
+    throw new Error(message.slice('rejected: '.length));
+  }
+  return message;
+}
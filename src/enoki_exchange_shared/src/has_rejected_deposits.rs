@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::{CandidType, Nat, Principal};
+
+use crate::has_token_info;
+use crate::interfaces::enoki_wrapped_token::ShardedTransferNotification;
+use crate::types::*;
+
+/// A `shardTransferAndCall` notification that was rejected after its tokens had already arrived.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct RejectedDeposit {
+    pub method: String,
+    pub token: EnokiToken,
+    pub value: StableNat,
+    pub refunded: StableNat,
+    pub reason: String,
+    pub refund_error: Option<String>,
+    pub time: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct RejectedDepositsState {
+    deposits: HashMap<Principal, Vec<RejectedDeposit>>,
+}
+
+thread_local! {
+    static STATE: RefCell<RejectedDepositsState> = RefCell::new(Default::default());
+}
+
+pub const REJECTED_PREFIX: &str = "rejected: ";
+
+/// The transfer fee the token will take out of a refund of `refundable`, if it can be paid at all.
+fn refund_fee(refundable: &Nat, transfer_fee: Result<Nat>) -> std::result::Result<Nat, String> {
+    match transfer_fee {
+        Ok(fee) if *refundable > fee => Ok(fee),
+        Ok(_) => Err("deposit does not cover the transfer fee".to_string()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+/// Sends `refundable` back to the sender of the notification, minus the transfer fee, and records
+/// the rejection. Returns the text to reply to the token shard with.
+pub async fn refund_rejected_deposit(
+    notification: ShardedTransferNotification,
+    token: EnokiToken,
+    method: &str,
+    reason: TxError,
+    refundable: Nat,
+    transfer_fee: Result<Nat>,
+) -> String {
+    let reason = reason.to_string();
+    ic_cdk::print(format!(
+        "rejected {} from {}: {}",
+        method, notification.from, reason
+    ));
+    let (refunded, refund_error) = match refund_fee(&refundable, transfer_fee) {
+        Ok(fee) => {
+            let response: Result<()> = ic_cdk::call(
+                has_token_info::get_assigned_shard(&token),
+                "shardTransfer",
+                (notification.from_shard, notification.from, refundable.clone()),
+            )
+            .await
+            .map_err(|e| e.into_tx_error());
+            match response {
+                Ok(()) => (refundable - fee, None),
+                Err(error) => (Nat::default(), Some(error.to_string())),
+            }
+        }
+        Err(error) => (Nat::default(), Some(error)),
+    };
+    let rejected = RejectedDeposit {
+        method: method.to_string(),
+        token,
+        value: notification.value.into(),
+        refunded: refunded.into(),
+        reason: reason.clone(),
+        refund_error,
+        time: ic_cdk::api::time(),
+    };
+    STATE.with(|s| {
+        s.borrow_mut()
+            .deposits
+            .entry(notification.from)
+            .or_default()
+            .push(rejected)
+    });
+    format!("{}{}", REJECTED_PREFIX, reason)
+}
+
+/// The token of a notification sent to this canister by the shard assigned to it. Any other
+/// notification brought no tokens here, so it is rejected without a refund.
+pub fn parse_notification(notification: &ShardedTransferNotification) -> Result<EnokiToken> {
+    if notification.to != ic_cdk::id() {
+        return Err(
+            TxError::InvalidInput("tokens were sent to another canister".to_string()).into(),
+        );
+    }
+    has_token_info::parse_from()
+}
+
+/// Answers a notification that brought no tokens to this canister.
+pub fn reject_without_refund(method: &str, reason: TxError) -> String {
+    ic_cdk::print(format!("rejected {} from {}: {}", method, ic_cdk::caller(), reason));
+    format!("{}{}", REJECTED_PREFIX, reason)
+}
+
+pub fn get_rejected_deposits(user: Principal) -> Vec<RejectedDeposit> {
+    STATE.with(|s| s.borrow().deposits.get(&user).cloned().unwrap_or_default())
+}
+
+pub fn export_stable_storage() -> RejectedDepositsState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: RejectedDepositsState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod has_rejected_deposits_tests {
+    use super::*;
+
+    #[test]
+    fn test_refund_fee() {
+        let refundable = Nat::from(100u32);
+        assert_eq!(
+            refund_fee(&refundable, Ok(Nat::from(10u32))).unwrap(),
+            Nat::from(10u32)
+        );
+        assert!(refund_fee(&refundable, Ok(Nat::from(100u32))).is_err());
+        let unknown_fee = Err(TxError::NotInitialized("transfer fee".to_string()).into());
+        assert!(refund_fee(&refundable, unknown_fee).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+
+use candid::CandidType;
+
+use crate::is_owned::assert_is_owner;
+use crate::types::*;
+
+const SECOND: u64 = 1_000_000_000;
+// shortest interval between runs, which is also how often anyone can trigger one
+const MIN_INTERVAL: u64 = SECOND;
+// a run still marked as running after this long trapped, and is not waited for anymore
+const RUN_TIMEOUT: u64 = 300 * SECOND;
+
+/// How often the periodic work of a canister runs from its heartbeat: every `active_interval`
+/// while the last run left work pending, and every `idle_interval` otherwise.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct SchedulerSettings {
+    pub enabled: bool,
+    pub active_interval: u64,
+    pub idle_interval: u64,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            active_interval: 2 * SECOND,
+            idle_interval: 30 * SECOND,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct SchedulerStatus {
+    pub settings: SchedulerSettings,
+    pub running: bool,
+    pub work_pending: bool,
+    pub last_run_start: u64,
+    pub last_run_duration: u64,
+    pub last_error: Option<String>,
+    pub next_run: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct SchedulerState {
+    settings: SchedulerSettings,
+    running: bool,
+    work_pending: bool,
+    last_run_start: u64,
+    last_run_duration: u64,
+    last_error: Option<String>,
+}
+
+impl SchedulerState {
+    fn next_run(&self) -> u64 {
+        let interval = if self.work_pending {
+            self.settings.active_interval
+        } else {
+            self.settings.idle_interval
+        };
+        self.last_run_start + interval
+    }
+    fn try_start(&mut self, now: u64, manual: bool) -> bool {
+        if self.running && now < self.last_run_start + RUN_TIMEOUT {
+            return false;
+        }
+        let due = if manual {
+            self.last_run_start + MIN_INTERVAL.max(self.settings.active_interval)
+        } else {
+            self.next_run()
+        };
+        if (!manual && !self.settings.enabled) || now < due {
+            return false;
+        }
+        self.running = true;
+        self.last_run_start = now;
+        true
+    }
+    fn finish(&mut self, now: u64, error: Option<String>, work_pending: bool) {
+        self.running = false;
+        self.work_pending = work_pending;
+        self.last_run_duration = now.saturating_sub(self.last_run_start);
+        self.last_error = error;
+    }
+}
+
+thread_local! {
+    static STATE: RefCell<SchedulerState> = RefCell::new(Default::default());
+}
+
+/// Starts a run from the heartbeat if one is due. Every started run must be finished with
+/// `finish_run`.
+pub fn try_start_scheduled_run() -> bool {
+    STATE.with(|s| s.borrow_mut().try_start(ic_cdk::api::time(), false))
+}
+
+/// Starts a run triggered by a call, which cannot happen more often than the active interval.
+/// Returns the start of the last run otherwise.
+pub fn try_start_manual_run() -> std::result::Result<(), u64> {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.try_start(ic_cdk::api::time(), true) {
+            Ok(())
+        } else {
+            Err(s.last_run_start)
+        }
+    })
+}
+
+pub fn finish_run(result: &Result<()>, work_pending: bool) {
+    let error = result.as_ref().err().map(|error| format!("{:?}", error));
+    STATE.with(|s| {
+        s.borrow_mut()
+            .finish(ic_cdk::api::time(), error, work_pending)
+    });
+}
+
+pub fn set_settings(settings: SchedulerSettings) -> Result<()> {
+    assert_is_owner()?;
+    if settings.active_interval < MIN_INTERVAL || settings.idle_interval < settings.active_interval
+    {
+        return Err(TxError::InvalidInput(
+            "active interval must be at least 1s and idle interval at least the active one"
+                .to_string(),
+        )
+        .into());
+    }
+    STATE.with(|s| s.borrow_mut().settings = settings);
+    Ok(())
+}
+
+pub fn get_status() -> SchedulerStatus {
+    STATE.with(|s| {
+        let s = s.borrow();
+        SchedulerStatus {
+            settings: s.settings.clone(),
+            running: s.running,
+            work_pending: s.work_pending,
+            last_run_start: s.last_run_start,
+            last_run_duration: s.last_run_duration,
+            last_error: s.last_error.clone(),
+            next_run: s.next_run(),
+        }
+    })
+}
+
+pub fn export_stable_storage() -> SchedulerState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(mut data: SchedulerState) {
+    // no run survives an upgrade
+    data.running = false;
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod has_scheduler_tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_cadence() {
+        let mut state = SchedulerState::default();
+        assert!(state.try_start(100 * SECOND, false));
+        assert!(!state.try_start(200 * SECOND, false));
+        state.finish(101 * SECOND, None, false);
+        assert_eq!(state.last_run_duration, SECOND);
+
+        assert!(!state.try_start(120 * SECOND, false));
+        assert!(state.try_start(120 * SECOND, true));
+        state.finish(121 * SECOND, Some("error".to_string()), true);
+        assert!(!state.try_start(121 * SECOND, true));
+        assert!(state.try_start(122 * SECOND, false));
+        assert_eq!(state.last_error, Some("error".to_string()));
+
+        assert!(state.try_start(122 * SECOND + RUN_TIMEOUT, false));
+        state.settings.enabled = false;
+        state.finish(500 * SECOND, None, true);
+        assert!(!state.try_start(600 * SECOND, false));
+        assert!(state.try_start(600 * SECOND, true));
+    }
+}
@@ -1,8 +1,8 @@
 use std::cell::RefCell;
 
-use candid::{CandidType, Nat};
+use candid::{CandidType, Nat, Principal};
 
-use crate::types::{EnokiToken, StableNat};
+use crate::types::{EnokiToken, OrderFees, Result, StableNat, TxError};
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct TradingFees {
@@ -15,6 +15,47 @@ pub struct TradingFees {
     pub swap_fee: f64,
     // as percentage of trade quantity (ex: 0.002)
     pub swap_market_maker_reward: f64,  // as percentage of the swap fee (ex: 0.3)
+    pub fee_tiers: Option<Vec<FeeTier>>,
+    // sorted by min_volume; when empty or unset, everyone pays limit_order_taker_fee
+    pub referral_share: Option<f64>,
+    // as percentage of the fees paid by referred users (ex: 0.2)
+    pub market_maker_pool_share: Option<f64>,
+    // as percentage of the swap market maker reward, paid to designated market makers (ex: 0.5)
+    pub dynamic_swap_fee: Option<DynamicSwapFee>,
+    // when set, replaces swap_fee with a fee that follows the recent volatility of the price
+}
+
+/// The swap fee is the realized volatility of the last hour times `volatility_factor`, bounded by
+/// `min_fee` and `max_fee`.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct DynamicSwapFee {
+    pub min_fee: f64,
+    pub max_fee: f64,
+    pub volatility_factor: f64,
+}
+
+/// Limit order fees for users who traded at least `min_volume` (in token B) over the last 30 days.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct FeeTier {
+    pub min_volume: StableNat,
+    pub maker_fee: f64,
+    pub taker_fee: f64,
+}
+
+/// Accrued fees sent from a broker to the fee recipient.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct FeeSweep {
+    pub time: u64,
+    pub recipient: Principal,
+    pub token: EnokiToken,
+    pub amount: StableNat,
+    pub transfer_fee: StableNat,
+}
+
+impl TradingFees {
+    pub fn get_fee_tiers(&self) -> &[FeeTier] {
+        self.fee_tiers.as_deref().unwrap_or_default()
+    }
 }
 
 thread_local! {
@@ -29,19 +70,54 @@ pub fn import_stable_storage(data: TradingFees) {
     STATE.with(|s| s.replace(data));
 }
 
-pub fn init_fee_info(data: TradingFees) {
-    if data.limit_order_taker_fee > 0.03 || data.limit_order_taker_fee < 0.0 {
-        panic!("limit order taker fee out of range")
+const MAX_FEE: f64 = 0.03;
+
+pub fn validate_trading_fees(data: &TradingFees) -> Result<()> {
+    let out_of_range =
+        |name: &str| Err(TxError::InvalidInput(format!("{} out of range", name)).into());
+    if !(0.0..=MAX_FEE).contains(&data.limit_order_taker_fee) {
+        return out_of_range("limit order taker fee");
     }
-    if data.swap_fee > 0.03 || data.swap_fee < 0.0 {
-        panic!("swap fee out of range")
+    if !(0.0..=MAX_FEE).contains(&data.swap_fee) {
+        return out_of_range("swap fee");
     }
-    if data.swap_market_maker_reward > 1.00 || data.swap_market_maker_reward < 0.0 {
-        panic!("swap market marker reward out of range")
+    if !(0.0..=1.0).contains(&data.swap_market_maker_reward) {
+        return out_of_range("swap market maker reward");
     }
+    if !(0.0..=1.0).contains(&data.referral_share.unwrap_or_default()) {
+        return out_of_range("referral share");
+    }
+    if !(0.0..=1.0).contains(&data.market_maker_pool_share.unwrap_or_default()) {
+        return out_of_range("market maker pool share");
+    }
+    if let Some(dynamic) = data.dynamic_swap_fee.as_ref() {
+        if !(0.0..=MAX_FEE).contains(&dynamic.min_fee)
+            || !(dynamic.min_fee..=MAX_FEE).contains(&dynamic.max_fee)
+        {
+            return out_of_range("dynamic swap fee bounds");
+        }
+        if !(0.0..).contains(&dynamic.volatility_factor) {
+            return out_of_range("dynamic swap fee volatility factor");
+        }
+    }
+    for tier in data.get_fee_tiers() {
+        if !(0.0..=MAX_FEE).contains(&tier.taker_fee) {
+            return out_of_range("fee tier taker fee");
+        }
+        if !(-MAX_FEE..=MAX_FEE).contains(&tier.maker_fee) {
+            return out_of_range("fee tier maker fee");
+        }
+    }
+    Ok(())
+}
 
-
+pub fn init_fee_info(mut data: TradingFees) -> Result<()> {
+    validate_trading_fees(&data)?;
+    if let Some(fee_tiers) = data.fee_tiers.as_mut() {
+        fee_tiers.sort_by(|a, b| a.min_volume.cmp(&b.min_volume));
+    }
     STATE.with(|s| s.replace(data));
+    Ok(())
 }
 
 pub fn get_trading_fees() -> TradingFees {
@@ -59,10 +135,58 @@ pub fn get_limit_order_taker_fee() -> f64 {
     STATE.with(|s| s.borrow().limit_order_taker_fee)
 }
 
+/// The fees without tiers, where the maker receives everything the taker paid, fee included.
+pub fn get_flat_limit_order_fees() -> OrderFees {
+    let taker_fee = get_limit_order_taker_fee();
+    OrderFees {
+        maker_fee: -taker_fee / (1.0 - taker_fee),
+        taker_fee,
+    }
+}
+
+/// Returns the fees of the highest tier reached by `volume`, or of the lowest tier if none is.
+pub fn get_limit_order_fees(volume: &StableNat) -> OrderFees {
+    let tier = STATE.with(|s| {
+        let s = s.borrow();
+        let fee_tiers = s.get_fee_tiers();
+        fee_tiers
+            .iter()
+            .rev()
+            .find(|tier| tier.min_volume <= *volume)
+            .or_else(|| fee_tiers.first())
+            .cloned()
+    });
+    match tier {
+        Some(tier) => OrderFees {
+            maker_fee: tier.maker_fee,
+            taker_fee: tier.taker_fee,
+        },
+        None => get_flat_limit_order_fees(),
+    }
+}
+
 pub fn get_swap_fee() -> f64 {
     STATE.with(|s| s.borrow().swap_fee)
 }
 
+/// The swap fee for a given realized volatility: `swap_fee` unless the dynamic mode is on.
+pub fn get_effective_swap_fee(volatility: f64) -> f64 {
+    STATE.with(|s| match s.borrow().dynamic_swap_fee.as_ref() {
+        Some(dynamic) => (volatility * dynamic.volatility_factor)
+            .max(dynamic.min_fee)
+            .min(dynamic.max_fee),
+        None => s.borrow().swap_fee,
+    })
+}
+
 pub fn get_swap_market_maker_reward() -> f64 {
     STATE.with(|s| s.borrow().swap_market_maker_reward)
 }
+
+pub fn get_referral_share() -> f64 {
+    STATE.with(|s| s.borrow().referral_share.unwrap_or_default())
+}
+
+pub fn get_market_maker_pool_share() -> f64 {
+    STATE.with(|s| s.borrow().market_maker_pool_share.unwrap_or_default())
+}
@@ -32,6 +32,10 @@ pub fn assert_is_manager() -> Result<()> {
     }
 }
 
+pub fn assert_is_owner_or_manager() -> Result<()> {
+    assert_is_manager().or_else(|_| assert_is_owner())
+}
+
 thread_local! {
     static STATE: RefCell<ManagementData> = RefCell::new(ManagementData::default());
 }
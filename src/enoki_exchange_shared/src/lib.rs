@@ -4,6 +4,8 @@ pub mod is_owned;
 pub mod is_managed;
 pub mod interfaces;
 pub mod has_sharded_users;
+pub mod has_rejected_deposits;
 pub mod liquidity;
 pub mod utils;
-pub mod has_trading_fees;
\ No newline at end of file
+pub mod has_trading_fees;
+pub mod has_scheduler;
\ No newline at end of file
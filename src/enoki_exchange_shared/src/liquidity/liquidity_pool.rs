@@ -1,20 +1,37 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::ops::AddAssign;
-use std::str::FromStr;
+use std::ops::{AddAssign, Div, Mul};
 
 use candid::{CandidType, Nat, Principal};
-use lazy_static::lazy_static;
 
+use crate::has_token_info::quantity_a_to_b;
 use crate::types::*;
 
-lazy_static! {
-    //TODO: make it based on fee
-    static ref MIN_AMOUNT_TO_WITHDRAW: StableNat = Nat::from_str("30_000_000").unwrap().into();
-}
+/// Locking for this long (or longer) gives the maximum reward weight bonus.
+pub const MAX_LOCK_SECONDS: u64 = 365 * 24 * 3600;
+pub const MAX_LOCK_WEIGHT_BONUS_BPS: u64 = 10_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct LiquidityPool {
+    shares: HashMap<Principal, StableNat>,
+    total_shares: StableNat,
+    reserves: LiquidityAmount,
+    fees_earned: LiquidityAmount,
+    pending_add: Vec<PendingDeposit>,
+    pending_remove: Vec<(Principal, TokenAmount)>,
+    pending_add_locked: Vec<PendingDeposit>,
+    pending_remove_locked: Vec<(Principal, TokenAmount)>,
+    scheduled_remove: Vec<(Principal, ScheduledWithdrawal)>,
+    locks: HashMap<Principal, Vec<SharesLock>>,
+    user_net_deposits: HashMap<Principal, LiquidityTrades>,
+    // liquidity of each user from before pool shares, converted once a price is known
+    legacy_liquidity: HashMap<Principal, LiquidityAmount>,
+}
+
+/// The pool as stored before shares, when every user owned separate amounts of each token.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct LegacyLiquidityPool {
     liquidity: HashMap<Principal, LiquidityAmount>,
     pending_add: Vec<(Principal, TokenAmount)>,
     pending_remove: Vec<(Principal, TokenAmount)>,
@@ -23,11 +40,33 @@ pub struct LiquidityPool {
     user_net_deposits: HashMap<Principal, LiquidityTrades>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct PendingDeposit {
+    pub user: Principal,
+    pub amount: TokenAmount,
+    pub lock_seconds: u64,
+}
+
+/// Shares minted from a time-locked deposit, which cannot be withdrawn or transferred until
+/// `unlock_time`, and earn rewards with a higher weight until then.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct SharesLock {
+    pub shares: StableNat,
+    pub unlock_time: u64,
+    pub weight_bonus_bps: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct ScheduledWithdrawal {
+    pub amount: LiquidityAmount,
+    pub due: u64,
+}
+
 pub struct LiquidityPoolTotalBalance<'a>(&'a LiquidityPool);
 
 impl<'a> LiquidityPoolTotalBalance<'a> {
     pub fn get_total_balances(&self) -> LiquidityAmount {
-        self.0.liquidity.iter().map(|(_, val)| val.clone()).sum()
+        self.0.reserves.clone()
     }
     pub fn new(pool: &'a LiquidityPool) -> Self {
         Self(pool)
@@ -40,9 +79,226 @@ impl<'a> Debug for LiquidityPoolTotalBalance<'a> {
     }
 }
 
+/// Value of an amount of liquidity denominated in token B, at the given price.
+pub fn value_in_b(amount: &LiquidityAmount, price: u64) -> Result<StableNat> {
+    let value_a = quantity_a_to_b(amount.token_a.clone().to_nat(), price)?;
+    Ok(StableNat::from(value_a) + amount.token_b.clone())
+}
+
 impl LiquidityPool {
+    /// Moves the liquidity of every user into the reserves. Their shares are minted by
+    /// `mint_legacy_shares` once a price is known. `rounding_error` is what the pool held on top
+    /// of (or short of) the sum of the users' liquidity.
+    pub fn from_legacy(legacy: LegacyLiquidityPool, rounding_error: &LiquidityTrades) -> Self {
+        let into_deposits = |pending: Vec<(Principal, TokenAmount)>| -> Vec<PendingDeposit> {
+            pending
+                .into_iter()
+                .map(|(user, amount)| PendingDeposit {
+                    user,
+                    amount,
+                    lock_seconds: 0,
+                })
+                .collect()
+        };
+        let mut reserves: LiquidityAmount = legacy.liquidity.values().cloned().sum();
+        reserves.add_assign(rounding_error.increased.clone());
+        reserves.sub_assign_or_zero(rounding_error.decreased.clone());
+        Self {
+            reserves,
+            pending_add: into_deposits(legacy.pending_add),
+            pending_remove: legacy.pending_remove,
+            pending_add_locked: into_deposits(legacy.pending_add_locked),
+            pending_remove_locked: legacy.pending_remove_locked,
+            user_net_deposits: legacy.user_net_deposits,
+            legacy_liquidity: legacy.liquidity,
+            ..Default::default()
+        }
+    }
+    /// Mints the shares of the liquidity moved over by `from_legacy`, one share per unit of value
+    /// in token B, the same basis as the first deposit of a new pool.
+    pub fn mint_legacy_shares(&mut self, price: u64) -> Result<()> {
+        let mut minted = Vec::with_capacity(self.legacy_liquidity.len());
+        for (user, liquidity) in self.legacy_liquidity.iter() {
+            minted.push((*user, value_in_b(liquidity, price)?));
+        }
+        for (user, shares) in minted {
+            self.shares
+                .entry(user)
+                .or_default()
+                .add_assign(shares.clone());
+            self.total_shares.add_assign(shares);
+        }
+        self.legacy_liquidity.clear();
+        Ok(())
+    }
     pub fn get_user_liquidity(&self, user: Principal) -> Option<LiquidityAmount> {
-        self.liquidity.get(&user).cloned()
+        self.shares
+            .get(&user)
+            .map(|shares| self.shares_to_liquidity(shares))
+            .or_else(|| self.legacy_liquidity.get(&user).cloned())
+    }
+    pub fn get_user_shares(&self, user: Principal) -> StableNat {
+        self.shares.get(&user).cloned().unwrap_or_default()
+    }
+    pub fn get_total_shares(&self) -> StableNat {
+        self.total_shares.clone()
+    }
+    pub fn iter_shares(&self) -> impl Iterator<Item = (&Principal, &StableNat)> {
+        self.shares.iter()
+    }
+    pub fn shares_to_liquidity(&self, shares: &StableNat) -> LiquidityAmount {
+        if !self.total_shares.is_nonzero() {
+            return LiquidityAmount::default();
+        }
+        LiquidityAmount {
+            token_a: self
+                .reserves
+                .token_a
+                .clone()
+                .mul(shares.clone())
+                .div(self.total_shares.clone()),
+            token_b: self
+                .reserves
+                .token_b
+                .clone()
+                .mul(shares.clone())
+                .div(self.total_shares.clone()),
+        }
+    }
+    pub fn get_fees_earned(&self) -> &LiquidityAmount {
+        &self.fees_earned
+    }
+    /// Sum of all the user claims, which can be slightly less than the reserves due to rounding.
+    pub fn get_total_user_liquidity(&self) -> LiquidityAmount {
+        self.shares
+            .values()
+            .fold(LiquidityAmount::default(), |mut sum, shares| {
+                sum.add_assign(self.shares_to_liquidity(shares));
+                sum
+            })
+    }
+    /// Liquidity from before pool shares cannot be locked, so it is all unlocked.
+    pub fn get_user_unlocked_liquidity(&self, user: Principal) -> LiquidityAmount {
+        match self.legacy_liquidity.get(&user) {
+            Some(liquidity) => liquidity.clone(),
+            None => self.shares_to_liquidity(&self.get_unlocked_shares(user)),
+        }
+    }
+    pub fn get_user_locks(&self, user: Principal) -> Vec<SharesLock> {
+        self.locks.get(&user).cloned().unwrap_or_default()
+    }
+    pub fn get_locked_shares(&self, user: Principal) -> StableNat {
+        self.locks
+            .get(&user)
+            .map(|locks| locks.iter().map(|lock| lock.shares.clone()).sum())
+            .unwrap_or_default()
+    }
+    pub fn get_unlocked_shares(&self, user: Principal) -> StableNat {
+        (self.get_user_shares(user) - self.get_locked_shares(user)).unwrap_or_default()
+    }
+    /// Shares, plus the bonus given to the ones that are locked.
+    pub fn get_reward_weight(&self, user: Principal) -> StableNat {
+        let bonus: StableNat = self
+            .locks
+            .get(&user)
+            .map(|locks| {
+                locks
+                    .iter()
+                    .map(|lock| {
+                        lock.shares
+                            .clone()
+                            .mul(Nat::from(lock.weight_bonus_bps).into())
+                            .div(Nat::from(10_000u32).into())
+                    })
+                    .sum()
+            })
+            .unwrap_or_default();
+        self.get_user_shares(user) + bonus
+    }
+    /// Locks shares that were just minted for a time-locked deposit.
+    pub fn lock_shares(&mut self, user: Principal, shares: StableNat, lock_seconds: u64, now: u64) {
+        let weight_bonus_bps =
+            lock_seconds.min(MAX_LOCK_SECONDS) * MAX_LOCK_WEIGHT_BONUS_BPS / MAX_LOCK_SECONDS;
+        self.locks.entry(user).or_default().push(SharesLock {
+            shares,
+            unlock_time: lock_seconds
+                .checked_mul(NANOS_PER_SECOND)
+                .map_or(u64::MAX, |lock| now.saturating_add(lock)),
+            weight_bonus_bps,
+        });
+    }
+    fn release_expired_locks(&mut self, now: u64) {
+        for locks in self.locks.values_mut() {
+            locks.retain(|lock| lock.unlock_time > now);
+        }
+        self.locks.retain(|_, locks| !locks.is_empty());
+    }
+    pub fn get_pending_deposits(&self, user: Principal) -> LiquidityAmount {
+        self.pending_add
+            .iter()
+            .chain(self.pending_add_locked.iter())
+            .filter(|deposit| deposit.user == user)
+            .map(|deposit| deposit.amount.clone())
+            .collect()
+    }
+    pub fn get_pending_withdrawals(&self, user: Principal) -> LiquidityAmount {
+        self.pending_remove
+            .iter()
+            .chain(self.pending_remove_locked.iter())
+            .filter(|(principal, _)| *principal == user)
+            .map(|(_, amount)| amount.clone())
+            .collect()
+    }
+    pub fn get_scheduled_withdrawals(&self, user: Principal) -> Vec<ScheduledWithdrawal> {
+        self.scheduled_remove
+            .iter()
+            .filter(|(principal, _)| *principal == user)
+            .map(|(_, withdrawal)| withdrawal.clone())
+            .collect()
+    }
+    /// Schedules a withdrawal, as long as it and the withdrawals already scheduled do not exceed
+    /// the liquidity of the user.
+    pub fn schedule_remove_liquidity(
+        &mut self,
+        user: Principal,
+        amount: LiquidityAmount,
+        due: u64,
+        now: u64,
+    ) -> Result<()> {
+        if !amount.token_a.is_nonzero() && !amount.token_b.is_nonzero() {
+            return Err(TxError::QuantityTooLow.into());
+        }
+        if due <= now {
+            return Err(TxError::InvalidInput("withdrawal is due in the past".to_string()).into());
+        }
+        let liquidity = self.get_user_liquidity(user).unwrap_or_default();
+        let mut scheduled = amount.clone();
+        for withdrawal in self.get_scheduled_withdrawals(user) {
+            scheduled.add_assign(withdrawal.amount);
+        }
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            if scheduled.get(&token) > liquidity.get(&token) {
+                return Err(TxError::InsufficientFunds {
+                    token: token.clone(),
+                    funds: liquidity.get(&token).clone().to_nat().to_string(),
+                    needed: scheduled.get(&token).clone().to_nat().to_string(),
+                }
+                .into());
+            }
+        }
+        self.scheduled_remove
+            .push((user, ScheduledWithdrawal { amount, due }));
+        Ok(())
+    }
+    /// Drops the withdrawals of the user scheduled for `due`.
+    pub fn cancel_scheduled_removal(&mut self, user: Principal, due: u64) -> Result<()> {
+        let before = self.scheduled_remove.len();
+        self.scheduled_remove
+            .retain(|(principal, withdrawal)| *principal != user || withdrawal.due != due);
+        if self.scheduled_remove.len() == before {
+            return Err(TxError::NotFound(format!("withdrawal scheduled for {}", due)).into());
+        }
+        Ok(())
     }
     pub fn get_user_net_deposits(&self, user: Principal) -> Option<LiquidityTrades> {
         self.user_net_deposits.get(&user).cloned()
@@ -50,14 +306,19 @@ impl LiquidityPool {
     pub fn nothing_pending(&self) -> bool {
         self.pending_add.is_empty() && self.pending_remove.is_empty()
     }
-    pub fn user_add_liquidity(&mut self, user: Principal, amount: TokenAmount) {
+    pub fn user_add_liquidity(&mut self, user: Principal, amount: TokenAmount, lock_seconds: u64) {
         if amount.amount.is_nonzero() {
             ic_cdk::println!(
-                "[worker] increased user {} pending liquidity by {:?}",
+                "[worker] increased user {} pending liquidity by {:?} (locked for {}s)",
                 user,
-                amount
+                amount,
+                lock_seconds
             );
-            self.pending_add.push((user, amount));
+            self.pending_add.push(PendingDeposit {
+                user,
+                amount,
+                lock_seconds,
+            });
         }
     }
     pub fn user_remove_liquidity(
@@ -65,16 +326,17 @@ impl LiquidityPool {
         user: Principal,
         amount: LiquidityAmount,
     ) -> Result<()> {
-        let existing = self
-            .liquidity
-            .get(&user)
-            .ok_or(TxError::UserNotRegistered {
+        if self.get_user_liquidity(user).is_none() {
+            return Err(TxError::UserNotRegistered {
                 user: user.to_string(),
                 registry: ic_cdk::id().to_string(),
-            })?;
+            }
+            .into());
+        }
+        let existing = self.get_user_unlocked_liquidity(user);
         let amount_a = amount.token_a.min(existing.token_a.clone());
         let amount_b = amount.token_b.min(existing.token_b.clone());
-        if amount_a >= *MIN_AMOUNT_TO_WITHDRAW {
+        if amount_a.is_nonzero() {
             ic_cdk::println!(
                 "[worker] decreased user {} pending liquidity A by {:?}",
                 user,
@@ -88,7 +350,7 @@ impl LiquidityPool {
                 },
             ));
         }
-        if amount_b >= *MIN_AMOUNT_TO_WITHDRAW {
+        if amount_b.is_nonzero() {
             ic_cdk::println!(
                 "[worker] decreased user {} pending liquidity B by {:?}",
                 user,
@@ -104,18 +366,72 @@ impl LiquidityPool {
         }
         Ok(())
     }
-    pub fn lock_liquidity(&mut self) -> (LiquidityAmount, LiquidityAmount) {
+    /// Token in which deposits can be turned into shares before a price is known: the only token
+    /// the pool holds, or the token of the first pending deposit into an empty pool.
+    fn bootstrap_token(&self) -> Option<EnokiToken> {
+        if !self.legacy_liquidity.is_empty() {
+            return None;
+        }
+        match (
+            self.reserves.token_a.is_nonzero(),
+            self.reserves.token_b.is_nonzero(),
+        ) {
+            (true, true) => None,
+            (true, false) => Some(EnokiToken::TokenA),
+            (false, true) => Some(EnokiToken::TokenB),
+            (false, false) if self.total_shares.is_nonzero() => None,
+            (false, false) => self
+                .pending_add
+                .first()
+                .map(|deposit| deposit.amount.token.clone()),
+        }
+    }
+    /// Until a price is known, only additions in the `bootstrap_token` are locked, since shares
+    /// cannot be minted for the others, and removals of users whose shares are not minted yet
+    /// wait, since there is nothing to burn. Scheduled withdrawals are only included once due.
+    pub fn lock_liquidity(
+        &mut self,
+        price_known: bool,
+        now: u64,
+    ) -> (LiquidityAmount, LiquidityAmount) {
+        self.release_expired_locks(now);
+        let (due, scheduled): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled_remove)
+            .into_iter()
+            .partition(|(_, withdrawal)| withdrawal.due <= now);
+        self.scheduled_remove = scheduled;
+        for (user, withdrawal) in due {
+            if let Err(error) = self.user_remove_liquidity(user, withdrawal.amount) {
+                ic_cdk::println!(
+                    "[worker] could not process scheduled withdrawal for {}: {:?}",
+                    user,
+                    error
+                );
+            }
+        }
+        let to_add = if price_known {
+            std::mem::take(&mut self.pending_add)
+        } else if let Some(token) = self.bootstrap_token() {
+            let (to_add, waiting) = std::mem::take(&mut self.pending_add)
+                .into_iter()
+                .partition(|deposit| deposit.amount.token == token);
+            self.pending_add = waiting;
+            to_add
+        } else {
+            Vec::new()
+        };
         ic_cdk::println!(
             "[worker] locking {} pending add and {} pending remove",
-            self.pending_add.len(),
+            to_add.len(),
             self.pending_remove.len()
         );
-        let to_add = std::mem::take(&mut self.pending_add);
-        let to_remove = std::mem::take(&mut self.pending_remove);
+        let (to_remove, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending_remove)
+            .into_iter()
+            .partition(|(user, _)| !self.legacy_liquidity.contains_key(user));
+        self.pending_remove = waiting;
         self.pending_add_locked.extend(to_add.clone());
         self.pending_remove_locked.extend(to_remove.clone());
         (
-            to_add.into_iter().map(|(_, i)| i).collect(),
+            to_add.into_iter().map(|deposit| deposit.amount).collect(),
             to_remove.into_iter().map(|(_, i)| i).collect(),
         )
     }
@@ -134,17 +450,19 @@ impl LiquidityPool {
             })
     }
     pub fn count_locked_add_liquidity_by_principal(&self) -> HashMap<Principal, LiquidityAmount> {
-        Self::consolidate_liquidity_by_principal(&self.pending_add_locked)
+        let pending: Vec<(Principal, TokenAmount)> = self
+            .pending_add_locked
+            .iter()
+            .map(|deposit| (deposit.user, deposit.amount.clone()))
+            .collect();
+        Self::consolidate_liquidity_by_principal(&pending)
     }
     pub fn count_locked_remove_liquidity_by_principal(
         &self,
     ) -> HashMap<Principal, LiquidityAmount> {
         Self::consolidate_liquidity_by_principal(&self.pending_remove_locked)
     }
-    pub fn get_liquidity_by_principal(&self) -> &HashMap<Principal, LiquidityAmount> {
-        &self.liquidity
-    }
-    pub fn get_locked_add_item(&mut self, index: usize) -> Option<&mut (Principal, TokenAmount)> {
+    pub fn get_locked_add_item(&mut self, index: usize) -> Option<&mut PendingDeposit> {
         if index < self.pending_add_locked.len() {
             Some(&mut self.pending_add_locked[index])
         } else {
@@ -161,16 +479,127 @@ impl LiquidityPool {
             None
         }
     }
-    pub fn get_user_liquidity_mut(
+    fn pool_value_in_b(&self, price: u64) -> Result<StableNat> {
+        value_in_b(&self.reserves, price)
+    }
+    /// Value of a deposit and of the pool, in the deposited token while the pool holds nothing
+    /// else and no price is known, and in token B otherwise.
+    fn deposit_and_pool_value(
+        &self,
+        token: &EnokiToken,
+        amount: StableNat,
+        price: u64,
+    ) -> Result<(StableNat, StableNat)> {
+        if price == 0 && !self.reserves.get(&token.opposite()).is_nonzero() {
+            return Ok((amount, self.reserves.get(token).clone()));
+        }
+        let mut deposit = LiquidityAmount::default();
+        *deposit.get_mut(token) = amount;
+        Ok((value_in_b(&deposit, price)?, self.pool_value_in_b(price)?))
+    }
+    /// Adds `amount` to the pool reserves and mints shares for `user` in proportion to the value
+    /// contributed. The first deposit into the pool sets the share basis. Returns the number of
+    /// shares minted.
+    pub fn mint_shares(
         &mut self,
         user: Principal,
         token: &EnokiToken,
-    ) -> &mut StableNat {
-        self.liquidity.entry(user).or_default().get_mut(token)
+        amount: StableNat,
+        price: u64,
+    ) -> Result<StableNat> {
+        if !self.legacy_liquidity.is_empty() {
+            return Err(TxError::InvalidState(
+                "shares of the liquidity from before pool shares are not minted yet".to_string(),
+            )
+            .into());
+        }
+        let (value, pool_value) = self.deposit_and_pool_value(token, amount.clone(), price)?;
+        let minted = if !self.total_shares.is_nonzero() {
+            value
+        } else if pool_value.is_nonzero() {
+            value.mul(self.total_shares.clone()).div(pool_value)
+        } else {
+            return Err(TxError::InvalidState(
+                "cannot mint shares of a pool without value".to_string(),
+            )
+            .into());
+        };
+        self.reserves.get_mut(token).add_assign(amount);
+        self.shares
+            .entry(user)
+            .or_default()
+            .add_assign(minted.clone());
+        self.total_shares.add_assign(minted.clone());
+        Ok(minted)
+    }
+    /// Removes `amount` from the pool reserves and burns the shares of `user` worth that amount
+    /// (rounded up, in favor of the pool). Both tokens of a withdrawal are burned for at once, so
+    /// taking one does not shrink the claim on the other. Withdrawing the whole claim burns all
+    /// unlocked shares.
+    pub fn burn_shares_for(
+        &mut self,
+        user: Principal,
+        amount: &LiquidityAmount,
+        price: u64,
+    ) -> Result<StableNat> {
+        let value = value_in_b(amount, price)?;
+        let pool_value = self.pool_value_in_b(price)?;
+        let user_shares = self.get_unlocked_shares(user);
+        let claim = self.shares_to_liquidity(&user_shares);
+        let whole_claim = amount.token_a >= claim.token_a && amount.token_b >= claim.token_b;
+        let to_burn = if pool_value.is_nonzero() && !whole_claim {
+            let numerator = value.mul(self.total_shares.clone());
+            let mut burned = numerator.clone().div(pool_value.clone());
+            if burned.clone().mul(pool_value) != numerator {
+                burned.add_assign(Nat::from(1u32).into());
+            }
+            burned.min(user_shares)
+        } else {
+            user_shares
+        };
+        self.reserves.safe_sub_assign(amount.clone())?;
+        self.shares
+            .entry(user)
+            .or_default()
+            .safe_sub_assign(to_burn.clone())?;
+        self.total_shares.safe_sub_assign(to_burn.clone())?;
+        if !self.get_user_shares(user).is_nonzero() {
+            self.shares.remove(&user);
+        }
+        Ok(to_burn)
+    }
+    pub fn transfer_shares(
+        &mut self,
+        from: Principal,
+        to: Principal,
+        amount: StableNat,
+    ) -> Result<()> {
+        let available = self.get_unlocked_shares(from);
+        if available < amount {
+            return Err(TxError::InsufficientShares {
+                shares: available.to_nat().to_string(),
+                needed: amount.to_nat().to_string(),
+            }
+            .into());
+        }
+        self.shares
+            .entry(from)
+            .or_default()
+            .safe_sub_assign(amount.clone())?;
+        if !self.get_user_shares(from).is_nonzero() {
+            self.shares.remove(&from);
+        }
+        self.shares.entry(to).or_default().add_assign(amount);
+        Ok(())
+    }
+    /// Swap fees are added to the reserves, so they accrue to all share holders.
+    pub fn apply_fees(&mut self, fees: &LiquidityAmount) {
+        self.reserves.add_assign(fees.clone());
+        self.fees_earned.add_assign(fees.clone());
     }
     pub fn remove_zeros(&mut self) {
         self.pending_add_locked
-            .retain(|(_, amount)| amount.amount.is_nonzero());
+            .retain(|deposit| deposit.amount.amount.is_nonzero());
         self.pending_remove_locked
             .retain(|(_, amount)| amount.amount.is_nonzero());
         ic_cdk::println!(
@@ -211,12 +640,194 @@ impl LiquidityPool {
             deposits.decreased.get_mut(token).add_assign(amount);
         }
     }
-    pub fn apply_traded(&mut self, traded: &HashMap<Principal, LiquidityTrades>) {
-        for (user, liquidity) in self.liquidity.iter_mut() {
-            if let Some(traded) = traded.get(user) {
-                liquidity.add_assign(traded.increased.clone());
-                liquidity.safe_sub_assign(traded.decreased.clone()).unwrap();
-            }
-        }
+    /// Returns the amount that could not be taken from the reserves, if any.
+    pub fn apply_traded(&mut self, traded: &LiquidityTrades) -> LiquidityAmount {
+        self.reserves.add_assign(traded.increased.clone());
+        let shortfall = traded.decreased.sub_or_zero(&self.reserves);
+        self.reserves.sub_assign_or_zero(traded.decreased.clone());
+        shortfall
+    }
+}
+
+#[cfg(test)]
+mod liquidity_pool_tests {
+    use super::*;
+
+    fn user(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn test_mint_proportional_to_value() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(1_000), 2)
+            .unwrap();
+        pool.mint_shares(user(2), &EnokiToken::TokenA, StableNat::from(500), 2)
+            .unwrap();
+        assert_eq!(pool.get_user_shares(user(1)), pool.get_user_shares(user(2)));
+        assert_eq!(pool.get_total_shares(), StableNat::from(2_000));
+    }
+
+    #[test]
+    fn test_bootstrap_without_price() {
+        let mut pool = LiquidityPool::default();
+        let deposit = |token, amount| TokenAmount {
+            token,
+            amount: StableNat::from(amount),
+        };
+        pool.user_add_liquidity(user(1), deposit(EnokiToken::TokenA, 300), 0);
+        pool.user_add_liquidity(user(2), deposit(EnokiToken::TokenB, 100), 0);
+        pool.user_add_liquidity(user(3), deposit(EnokiToken::TokenA, 150), 0);
+        let (added, _) = pool.lock_liquidity(false, 0);
+        assert_eq!(added.token_a, StableNat::from(450));
+        assert!(!added.token_b.is_nonzero());
+
+        pool.mint_shares(user(1), &EnokiToken::TokenA, StableNat::from(300), 0)
+            .unwrap();
+        pool.mint_shares(user(3), &EnokiToken::TokenA, StableNat::from(150), 0)
+            .unwrap();
+        assert_eq!(pool.get_user_shares(user(1)), StableNat::from(300));
+        assert_eq!(pool.get_user_shares(user(3)), StableNat::from(150));
+        assert!(pool
+            .mint_shares(user(2), &EnokiToken::TokenB, StableNat::from(100), 0)
+            .is_err());
+
+        let (added, _) = pool.lock_liquidity(true, 0);
+        assert_eq!(added.token_b, StableNat::from(100));
+        pool.mint_shares(user(2), &EnokiToken::TokenB, StableNat::from(100), 2)
+            .unwrap();
+        assert_eq!(pool.get_user_shares(user(2)), StableNat::from(50));
+    }
+
+    #[test]
+    fn test_legacy_migration() {
+        let mut legacy = LegacyLiquidityPool::default();
+        let liquidity = |token_a, token_b| LiquidityAmount {
+            token_a: StableNat::from(token_a),
+            token_b: StableNat::from(token_b),
+        };
+        legacy.liquidity.insert(user(1), liquidity(100, 0));
+        legacy.liquidity.insert(user(2), liquidity(0, 200));
+        let mut rounding_error = LiquidityTrades::default();
+        rounding_error.increased.token_b = StableNat::from(3);
+        let mut pool = LiquidityPool::from_legacy(legacy, &rounding_error);
+        assert_eq!(
+            pool.get_user_liquidity(user(1)).unwrap().token_a,
+            StableNat::from(100)
+        );
+        assert_eq!(pool.reserves.token_b, StableNat::from(203));
+        assert!(pool
+            .mint_shares(user(3), &EnokiToken::TokenB, StableNat::from(10), 2)
+            .is_err());
+        pool.user_remove_liquidity(user(1), liquidity(40, 0))
+            .unwrap();
+        let (_, removed) = pool.lock_liquidity(false, 0);
+        assert!(!removed.token_a.is_nonzero());
+
+        pool.mint_legacy_shares(2).unwrap();
+        assert_eq!(pool.get_user_shares(user(1)), StableNat::from(200));
+        assert_eq!(pool.get_user_shares(user(2)), StableNat::from(200));
+        let claim = pool.get_user_liquidity(user(1)).unwrap();
+        assert_eq!(claim.token_a, StableNat::from(50));
+        assert_eq!(claim.token_b, StableNat::from(101));
+        assert!(pool.legacy_liquidity.is_empty());
+        let (_, removed) = pool.lock_liquidity(true, 0);
+        assert_eq!(removed.token_a, StableNat::from(40));
+    }
+
+    #[test]
+    fn test_claims_follow_trades() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(1_000), 1)
+            .unwrap();
+        pool.mint_shares(user(2), &EnokiToken::TokenB, StableNat::from(3_000), 1)
+            .unwrap();
+        let mut traded = LiquidityTrades::default();
+        traded.increased.token_a = StableNat::from(400);
+        traded.decreased.token_b = StableNat::from(400);
+        pool.apply_traded(&traded);
+        let claim = pool.get_user_liquidity(user(1)).unwrap();
+        assert_eq!(claim.token_a, StableNat::from(100));
+        assert_eq!(claim.token_b, StableNat::from(900));
+    }
+
+    #[test]
+    fn test_burn_rounds_in_favor_of_pool() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(10), 1)
+            .unwrap();
+        pool.mint_shares(user(2), &EnokiToken::TokenA, StableNat::from(20), 1)
+            .unwrap();
+        pool.apply_fees(&LiquidityAmount::from((0, 1)));
+        // 3 of value out of 31 is worth 90 / 31 = 2.9 of the 30 shares
+        let withdrawal = LiquidityAmount::from((3, 0));
+        let burned = pool.burn_shares_for(user(1), &withdrawal, 1).unwrap();
+        assert_eq!(burned, StableNat::from(3));
+        assert_eq!(pool.get_user_shares(user(1)), StableNat::from(7));
+        assert_eq!(pool.get_total_shares(), StableNat::from(27));
+    }
+
+    #[test]
+    fn test_transfer_shares() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(100), 1)
+            .unwrap();
+        assert!(pool
+            .transfer_shares(user(1), user(2), StableNat::from(101))
+            .is_err());
+        pool.transfer_shares(user(1), user(2), StableNat::from(100))
+            .unwrap();
+        assert_eq!(pool.get_user_shares(user(2)), StableNat::from(100));
+        assert!(pool.get_user_liquidity(user(1)).is_none());
+    }
+
+    #[test]
+    fn test_locked_shares() {
+        let mut pool = LiquidityPool::default();
+        let minted = pool
+            .mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(100), 1)
+            .unwrap();
+        pool.lock_shares(user(1), minted, MAX_LOCK_SECONDS / 2, 0);
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(100), 1)
+            .unwrap();
+        assert_eq!(pool.get_unlocked_shares(user(1)), StableNat::from(100));
+        assert_eq!(pool.get_reward_weight(user(1)), StableNat::from(250));
+        assert!(pool
+            .transfer_shares(user(1), user(2), StableNat::from(101))
+            .is_err());
+        pool.release_expired_locks(MAX_LOCK_SECONDS * NANOS_PER_SECOND);
+        assert_eq!(pool.get_reward_weight(user(1)), StableNat::from(200));
+        pool.transfer_shares(user(1), user(2), StableNat::from(200))
+            .unwrap();
+
+        pool.lock_shares(user(2), StableNat::from(10), u64::MAX, 5);
+        assert_eq!(pool.get_user_locks(user(2))[0].unlock_time, u64::MAX);
+    }
+
+    #[test]
+    fn test_schedule_remove_liquidity() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user(1), &EnokiToken::TokenB, StableNat::from(100), 1)
+            .unwrap();
+        let amount = |b| LiquidityAmount {
+            token_a: Default::default(),
+            token_b: StableNat::from(b),
+        };
+        assert!(pool
+            .schedule_remove_liquidity(user(1), amount(0), 20, 10)
+            .is_err());
+        assert!(pool
+            .schedule_remove_liquidity(user(1), amount(50), 10, 10)
+            .is_err());
+        pool.schedule_remove_liquidity(user(1), amount(60), 20, 10)
+            .unwrap();
+        assert!(pool
+            .schedule_remove_liquidity(user(1), amount(50), 30, 10)
+            .is_err());
+        pool.schedule_remove_liquidity(user(1), amount(40), 30, 10)
+            .unwrap();
+        pool.cancel_scheduled_removal(user(1), 20).unwrap();
+        assert!(pool.cancel_scheduled_removal(user(1), 20).is_err());
+        assert_eq!(pool.get_scheduled_withdrawals(user(1)).len(), 1);
     }
 }
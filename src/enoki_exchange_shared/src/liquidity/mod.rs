@@ -1,3 +1,5 @@
+use std::ops::AddAssign;
+
 use candid::CandidType;
 
 use crate::types::*;
@@ -16,10 +18,25 @@ pub struct ResponseAboutLiquidityChanges {
     pub added: LiquidityAmount,
     pub removed: LiquidityAmount,
     pub traded: LiquidityTrades,
+    /// swap fees credited to the LPs, on top of `traded`
+    pub fees_earned: LiquidityAmount,
+    /// rounding residue from price conversions during swaps, owed to the protocol reserve
+    pub rounding_error: LiquidityAmount,
+}
+
+impl AddAssign for ResponseAboutLiquidityChanges {
+    fn add_assign(&mut self, rhs: Self) {
+        self.added.add_assign(rhs.added);
+        self.removed.add_assign(rhs.removed);
+        self.traded.add_assign(rhs.traded);
+        self.fees_earned.add_assign(rhs.fees_earned);
+        self.rounding_error.add_assign(rhs.rounding_error);
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, CandidType, Clone, Debug, Default)]
 pub struct RequestForNewLiquidityTarget {
     pub target: LiquidityAmount,
     pub extra_liquidity_available: LiquidityAmount,
+    pub swap_fee: f64,
 }
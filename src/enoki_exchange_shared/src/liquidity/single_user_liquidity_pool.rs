@@ -2,6 +2,7 @@ use std::ops::{AddAssign};
 
 use candid::CandidType;
 
+use crate::liquidity::ResponseAboutLiquidityChanges;
 use crate::types::*;
 
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
@@ -76,10 +77,12 @@ impl SingleUserLiquidityPool {
         self.pending_remove_locked
             .retain(|amount| amount.amount.is_nonzero());
     }
-    pub fn apply_changes(&mut self, added: &LiquidityAmount, removed: &LiquidityAmount, traded: &LiquidityTrades) {
-        self.liquidity.add_assign(traded.increased.clone());
-        self.liquidity.safe_sub_assign(traded.decreased.clone()).unwrap();
-        self.liquidity.add_assign(added.clone());
-        self.liquidity.safe_sub_assign(removed.clone()).unwrap();
+    pub fn apply_changes(&mut self, changes: &ResponseAboutLiquidityChanges) {
+        self.liquidity.add_assign(changes.traded.increased.clone());
+        self.liquidity.add_assign(changes.fees_earned.clone());
+        self.liquidity.add_assign(changes.rounding_error.clone());
+        self.liquidity.safe_sub_assign(changes.traded.decreased.clone()).unwrap();
+        self.liquidity.add_assign(changes.added.clone());
+        self.liquidity.safe_sub_assign(changes.removed.clone()).unwrap();
     }
 }
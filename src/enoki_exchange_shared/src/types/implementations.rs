@@ -22,7 +22,19 @@ impl FromIterator<TokenAmount> for LiquidityAmount {
     }
 }
 
+impl From<(u64, u64)> for LiquidityAmount {
+    fn from((token_a, token_b): (u64, u64)) -> Self {
+        Self {
+            token_a: token_a.into(),
+            token_b: token_b.into(),
+        }
+    }
+}
+
 impl LiquidityAmount {
+    pub fn is_nonzero(&self) -> bool {
+        self.token_a.is_nonzero() || self.token_b.is_nonzero()
+    }
     pub fn get(&self, token: &EnokiToken) -> &StableNat {
         match token {
             EnokiToken::TokenA => &self.token_a,
@@ -173,6 +185,27 @@ impl EnokiToken {
     }
 }
 
+impl OrderInfo {
+    pub fn get_fees(&self) -> OrderFees {
+        self.fees
+            .clone()
+            .unwrap_or_else(crate::has_trading_fees::get_flat_limit_order_fees)
+    }
+}
+
+impl CounterpartyInfo {
+    pub fn get_maker_fee(&self) -> f64 {
+        self.maker_fee
+            .unwrap_or_else(|| crate::has_trading_fees::get_flat_limit_order_fees().maker_fee)
+    }
+}
+
+impl DrainStatus {
+    pub fn is_drained(&self) -> bool {
+        self.users_with_funds == 0 && self.open_settlements == 0 && self.failed_orders == 0
+    }
+}
+
 impl From<OrderInfo> for OrderInfoShare {
     fn from(info: OrderInfo) -> Self {
         Self {
@@ -3,7 +3,7 @@ use std::fmt::{Debug, Formatter};
 
 use candid::{CandidType, Nat, Principal};
 
-pub use result::{IntoTxError, Result, TxError};
+pub use result::{flatten_response, IntoTxError, Result, TxError, TxResult};
 pub use stable_nat::StableNat;
 
 use crate::has_token_info;
@@ -86,6 +86,27 @@ pub struct OrderInfo {
     pub limit_price: u64,
     pub quantity: StableNat,
     pub expiration_time: Option<u64>,
+    // unset for orders placed before fee tiers, which pay the flat limit order fees
+    pub fees: Option<OrderFees>,
+}
+
+/// The fees of the user placing an order, as fractions of the quantity traded. They are set by
+/// the broker from the user's fee tier when the order is created.
+#[derive(CandidType, Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct OrderFees {
+    /// A negative maker fee is a rebate.
+    pub maker_fee: f64,
+    pub taker_fee: f64,
+}
+
+/// A designated market maker's share of the market maker reward pool, from how well they met
+/// their quoting obligations over the last scoring period.
+#[derive(CandidType, Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MarketMakerScore {
+    pub broker: Principal,
+    pub user: Principal,
+    pub uptime: f64,
+    pub score: f64,
 }
 
 impl Default for OrderInfo {
@@ -99,6 +120,7 @@ impl Default for OrderInfo {
             limit_price: Default::default(),
             quantity: Default::default(),
             expiration_time: None,
+            fees: Default::default(),
         }
     }
 }
@@ -191,6 +213,8 @@ pub struct CounterpartyInfo {
     pub user: Principal,
     pub quantity: StableNat,
     pub price: u64,
+    // unset for fills from before fee tiers
+    pub maker_fee: Option<f64>,
 }
 
 #[derive(CandidType, Debug, Clone, serde::Serialize, serde::Deserialize, Default)]
@@ -218,6 +242,14 @@ pub struct BrokerAndUser {
     pub user: Principal,
 }
 
+/// What a broker being retired still holds for its users, which keeps it from being dropped.
+#[derive(CandidType, serde::Deserialize, Clone, Debug, Default)]
+pub struct DrainStatus {
+    pub users_with_funds: u64,
+    pub open_settlements: u64,
+    pub failed_orders: u64,
+}
+
 #[derive(CandidType, serde::Deserialize, serde::Serialize)]
 pub struct InitBrokerParams {
     pub other_brokers: Vec<Principal>,
@@ -1,6 +1,6 @@
 pub use anyhow::Result;
 use candid::{CandidType, Deserialize};
-use ic_cdk::api::call::RejectionCode;
+use ic_cdk::api::call::{CallResult, RejectionCode};
 use thiserror::Error;
 
 use crate::types::EnokiToken;
@@ -15,6 +15,8 @@ pub enum TxError {
     },
     #[error("Insufficient Liquidity Available")]
     InsufficientLiquidityAvailable,
+    #[error("Insufficient Shares: shares={shares} vs needed={needed}")]
+    InsufficientShares { shares: String, needed: String },
     #[error("Slippage Exceeded (limit {limit_price} vs actual {actual_price}): swap was cancelled")]
     SlippageExceeded {limit_price: u64, actual_price: u64},
     #[error("Unauthorized")]
@@ -27,6 +29,22 @@ pub enum TxError {
     IntUnderflow,
     #[error("Quantity too low")]
     QuantityTooLow,
+    #[error("Order {id} not found")]
+    OrderNotFound { id: u64 },
+    #[error("Order {id} does not belong to the caller")]
+    NotOrderOwner { id: u64 },
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Already initialized")]
+    AlreadyInitialized,
+    #[error("Not initialized: {0}")]
+    NotInitialized(String),
+    #[error("Liquidity pool not initialized")]
+    PoolNotInitialized,
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Invalid state: {0}")]
+    InvalidState(String),
     #[error("Cannot subtract a larger uint from a smaller one.")]
     UIntSubtractError,
     #[error("Parsing error: {0}")]
@@ -37,6 +55,23 @@ pub enum TxError {
     Other(String),
 }
 
+/// What endpoints return, so callers can branch on the error.
+pub type TxResult<T> = std::result::Result<T, TxError>;
+
+impl From<anyhow::Error> for TxError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<TxError>() {
+            Ok(error) => error,
+            Err(error) => TxError::Other(error.to_string()),
+        }
+    }
+}
+
+/// Unwraps the response of a call to an endpoint that returns a `TxResult`.
+pub fn flatten_response<T>(response: CallResult<(TxResult<T>,)>) -> Result<T> {
+    Ok(response.map_err(|e| e.into_tx_error())?.0?)
+}
+
 pub trait IntoTxError {
     fn into_tx_error(self) -> anyhow::Error;
 }
@@ -46,3 +81,29 @@ impl IntoTxError for (RejectionCode, String) {
         TxError::CallbackError(format!("Error in callback (code {:?}): {}", self.0, self.1)).into()
     }
 }
+
+#[cfg(test)]
+mod result_tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_keep_their_variant() {
+        let error: anyhow::Error = TxError::OrderNotFound { id: 7 }.into();
+        assert!(matches!(
+            TxError::from(error),
+            TxError::OrderNotFound { id: 7 }
+        ));
+        let error = anyhow::anyhow!("unexpected");
+        assert!(matches!(TxError::from(error), TxError::Other(message) if message == "unexpected"));
+    }
+
+    #[test]
+    fn test_flatten_response() {
+        assert_eq!(flatten_response::<u64>(Ok((Ok(1),))).unwrap(), 1);
+        let error = flatten_response::<u64>(Ok((Err(TxError::Unauthorized),))).unwrap_err();
+        assert!(matches!(TxError::from(error), TxError::Unauthorized));
+        let rejected = Err((RejectionCode::CanisterReject, "rejected".to_string()));
+        let error = flatten_response::<u64>(rejected).unwrap_err();
+        assert!(matches!(TxError::from(error), TxError::CallbackError(_)));
+    }
+}
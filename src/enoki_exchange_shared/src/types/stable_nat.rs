@@ -71,6 +71,12 @@ impl From<Nat> for StableNat {
     }
 }
 
+impl From<u64> for StableNat {
+    fn from(v: u64) -> Self {
+        Nat::from(v).into()
+    }
+}
+
 impl From<StableNat> for Nat {
     fn from(v: StableNat) -> Self {
         Self::from(BigUint::from_bytes_be(&v.0))
@@ -47,3 +47,7 @@ pub fn nat_div_float(value: Nat, divisor: f64) -> Result<Nat> {
 pub fn nat_to_u64(value: Nat) -> Result<u64> {
     value.0.to_u64().ok_or(TxError::IntOverflow.into())
 }
+
+pub fn nat_to_f64(value: Nat) -> f64 {
+    value.0.to_f64().unwrap_or(f64::MAX)
+}
@@ -8,6 +8,7 @@ use enoki_exchange_shared::has_token_info;
 use enoki_exchange_shared::has_token_info::init_token_info;
 use enoki_exchange_shared::is_managed::assert_is_manager;
 use enoki_exchange_shared::liquidity::single_user_liquidity_pool::SingleUserLiquidityPool;
+use enoki_exchange_shared::liquidity::ResponseAboutLiquidityChanges;
 use enoki_exchange_shared::types::*;
 
 use crate::worker::{assert_is_worker_contract, get_worker, init_worker_token_data};
@@ -15,9 +16,12 @@ use crate::worker::{assert_is_worker_contract, get_worker, init_worker_token_dat
 #[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
 pub struct PooledAmounts {
     worker_pool: SingleUserLiquidityPool,
-    added: LiquidityAmount,
-    removed: LiquidityAmount,
-    traded: LiquidityTrades,
+    // changes stored before swap fees were tracked apart, moved into `changes` on upgrade
+    added: Option<LiquidityAmount>,
+    removed: Option<LiquidityAmount>,
+    traded: Option<LiquidityTrades>,
+    changes: Option<ResponseAboutLiquidityChanges>,
+    last_price: Option<u64>,
 }
 
 thread_local! {
@@ -28,7 +32,16 @@ pub fn export_stable_storage() -> PooledAmounts {
     STATE.with(|b| b.take())
 }
 
-pub fn import_stable_storage(data: PooledAmounts) {
+pub fn import_stable_storage(mut data: PooledAmounts) {
+    let legacy_changes = ResponseAboutLiquidityChanges {
+        added: data.added.take().unwrap_or_default(),
+        removed: data.removed.take().unwrap_or_default(),
+        traded: data.traded.take().unwrap_or_default(),
+        ..Default::default()
+    };
+    data.changes
+        .get_or_insert_with(Default::default)
+        .add_assign(legacy_changes);
     STATE.with(|b| b.replace(data));
 }
 
@@ -41,37 +54,43 @@ pub fn lock_liquidity() -> (LiquidityAmount, LiquidityAmount) {
 
 #[update(name = "initLiquidityPool")]
 #[candid_method(update, rename = "initLiquidityPool")]
-async fn init_liquidity_pool(supply_token_info: has_token_info::TokenPairInfo) -> Principal {
-    assert_is_manager().unwrap();
+async fn init_liquidity_pool(
+    supply_token_info: has_token_info::TokenPairInfo,
+) -> TxResult<Principal> {
+    assert_is_manager()?;
     let worker = get_worker();
-    assert_ne!(worker, Principal::anonymous(), "worker not initialized");
-    init_token_info(supply_token_info).await.unwrap();
-    init_worker_token_data().await.unwrap();
-    worker
+    if worker == Principal::anonymous() {
+        return Err(TxError::NotInitialized("worker".to_string()));
+    }
+    init_token_info(supply_token_info).await?;
+    init_worker_token_data().await?;
+    Ok(worker)
 }
 
 #[update(name = "getUpdatedLiquidity")]
 #[candid_method(update, rename = "getUpdatedLiquidity")]
-fn get_updated_liquidity() -> (LiquidityAmount, LiquidityAmount) {
-    assert_is_manager().unwrap();
+fn get_updated_liquidity() -> TxResult<(LiquidityAmount, LiquidityAmount)> {
+    assert_is_manager()?;
     let (to_add, to_remove) = lock_liquidity();
     ic_cdk::println!(
         "[lp] updated liquidity: {:?} to add, {:?} to remove",
         to_add,
         to_remove
     );
-    (to_add, to_remove)
+    Ok((to_add, to_remove))
 }
 
 #[update(name = "resolveLiquidity")]
 #[candid_method(update, rename = "resolveLiquidity")]
-fn resolve_liquidity(added: LiquidityAmount, removed: LiquidityAmount, traded: LiquidityTrades) {
-    assert_is_manager().unwrap();
+fn resolve_liquidity(changes: ResponseAboutLiquidityChanges, price: Option<u64>) -> TxResult<()> {
+    assert_is_manager()?;
     ic_cdk::println!(
-        "[lp] resolved liquidity: {:?} added, {:?} removed, {:?} traded",
-        added,
-        removed,
-        traded
+        "[lp] resolved liquidity: {:?} added, {:?} removed, {:?} traded, {:?} in fees, last price {:?}",
+        changes.added,
+        changes.removed,
+        changes.traded,
+        changes.fees_earned,
+        price
     );
     STATE.with(|s| {
         ic_cdk::println!(
@@ -81,10 +100,13 @@ fn resolve_liquidity(added: LiquidityAmount, removed: LiquidityAmount, traded: L
     });
     STATE.with(|s| {
         let mut s = s.borrow_mut();
-        s.worker_pool.apply_changes(&added, &removed, &traded);
-        s.added.add_assign(added);
-        s.removed.add_assign(removed);
-        s.traded.add_assign(traded);
+        s.worker_pool.apply_changes(&changes);
+        s.changes
+            .get_or_insert_with(Default::default)
+            .add_assign(changes);
+        if price.is_some() {
+            s.last_price = price;
+        }
     });
     STATE.with(|s| {
         ic_cdk::println!(
@@ -92,6 +114,7 @@ fn resolve_liquidity(added: LiquidityAmount, removed: LiquidityAmount, traded: L
             s.borrow().worker_pool.get_liquidity()
         );
     });
+    Ok(())
 }
 
 #[update(name = "updateLiquidity")]
@@ -99,10 +122,11 @@ fn resolve_liquidity(added: LiquidityAmount, removed: LiquidityAmount, traded: L
 fn update_liquidity(
     pending_add: LiquidityAmount,
     pending_remove: LiquidityAmount,
-) -> (LiquidityAmount, LiquidityAmount, LiquidityTrades) {
-    assert_is_worker_contract().unwrap();
-    let result: Result<(LiquidityAmount, LiquidityAmount, LiquidityTrades)> = STATE.with(|s| {
+) -> TxResult<(ResponseAboutLiquidityChanges, Option<u64>, LiquidityAmount)> {
+    assert_is_worker_contract()?;
+    let result: Result<_> = STATE.with(|s| {
         let mut s = s.borrow_mut();
+        let previous_pool = s.worker_pool.clone();
         let LiquidityAmount {
             token_a: add_a,
             token_b: add_b,
@@ -115,11 +139,15 @@ fn update_liquidity(
             token: EnokiToken::TokenB,
             amount: add_b,
         });
-        s.worker_pool.user_remove_liquidity(pending_remove)?;
-        let added = std::mem::take(&mut s.added);
-        let removed = std::mem::take(&mut s.removed);
-        let traded = std::mem::take(&mut s.traded);
-        Ok((added, removed, traded))
+        if let Err(error) = s.worker_pool.user_remove_liquidity(pending_remove) {
+            s.worker_pool = previous_pool;
+            return Err(error);
+        }
+        Ok((
+            s.changes.take().unwrap_or_default(),
+            s.last_price,
+            s.worker_pool.get_liquidity().clone(),
+        ))
     });
-    result.unwrap()
+    Ok(result?)
 }
@@ -9,6 +9,8 @@ use enoki_exchange_shared::is_managed::ManagementData;
 #[allow(unused_imports)]
 use enoki_exchange_shared::is_owned::OwnershipData;
 #[allow(unused_imports)]
+use enoki_exchange_shared::liquidity::ResponseAboutLiquidityChanges;
+#[allow(unused_imports)]
 use enoki_exchange_shared::{
     has_token_info,
     has_token_info::{AssignedShards, TokenPairInfo},
@@ -35,10 +37,13 @@ fn init() {
 
 #[update(name = "finishInit")]
 #[candid_method(update, rename = "finishInit")]
-fn finish_init(exchange: Principal) {
-    is_owned::assert_is_owner().unwrap();
-    assert_eq!(is_managed::get_manager(), Principal::anonymous(), "already init");
+fn finish_init(exchange: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    if is_managed::get_manager() != Principal::anonymous() {
+        return Err(TxError::AlreadyInitialized);
+    }
     is_managed::init_manager(ManagementData { manager: exchange });
+    Ok(())
 }
 
 #[cfg(any(target_arch = "wasm32", test))]
@@ -41,24 +41,24 @@ pub fn get_worker() -> Principal {
 
 #[update(name = "initWorker")]
 #[candid_method(update, rename = "initWorker")]
-async fn init_worker(worker: Principal) {
-    is_owned::assert_is_owner().unwrap();
+async fn init_worker(worker: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
     if STATE.with(|s| s.borrow().worker_id != Principal::anonymous()) {
-        panic!("worker already init");
+        return Err(TxError::AlreadyInitialized);
     }
     STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.worker_id = worker;
     });
+    Ok(())
 }
 
 pub async fn init_worker_token_data() -> Result<()> {
     let worker = STATE.with(|s| s.borrow().worker_id);
-    let response: Result<(AssignedShards,)> =
-        ic_cdk::call(worker, "initWorker", (has_token_info::get_token_info(),))
-            .await
-            .map_err(|e| e.into_tx_error());
-    let worker_shards = response?.0;
+    let response: Result<AssignedShards> = flatten_response(
+        ic_cdk::call(worker, "initWorker", (has_token_info::get_token_info(),)).await,
+    );
+    let worker_shards = response?;
     has_sharded_users::register_user_with(
         worker,
         has_token_info::get_token_address(&EnokiToken::TokenA),
@@ -74,13 +74,12 @@ pub async fn init_worker_token_data() -> Result<()> {
 
 #[update(name = "addBroker")]
 #[candid_method(update, rename = "addBroker")]
-async fn add_broker(broker: Principal) {
-    is_managed::assert_is_manager().unwrap();
+async fn add_broker(broker: Principal) -> TxResult<()> {
+    is_managed::assert_is_manager()?;
 
-    let result: Result<()> = ic_cdk::call(get_worker(), "addBroker", (broker,))
-        .await
-        .map_err(|e| e.into_tx_error());
-    result.unwrap();
+    let result: Result<()> =
+        flatten_response(ic_cdk::call(get_worker(), "addBroker", (broker,)).await);
+    Ok(result?)
 }
 
 pub fn _get_worker_shard(token: &EnokiToken) -> Result<Principal> {
@@ -1,57 +1,41 @@
-use std::cell::RefCell;
-
 use candid::candid_method;
 use ic_cdk_macros::*;
 
-use crate::liquidity::update_liquidity_with_manager;
-
-// #[heartbeat]
-// fn tick() {
-//     ic_cdk::spawn(update_liquidity_with_manager())
-// }
+use enoki_exchange_shared::has_scheduler;
+use enoki_exchange_shared::is_managed::assert_is_owner_or_manager;
+use enoki_exchange_shared::types::*;
 
-thread_local! {
-    static STATE: RefCell<AntiSpam> = RefCell::new(Default::default());
-}
+use crate::liquidity::{has_pending_liquidity, update_liquidity_with_manager};
+use crate::withdrawals::{has_any_queued_withdrawals, retry_due_withdrawals};
 
-#[derive(Default)]
-struct AntiSpam {
-    last_update: u64,
-    locked: bool,
+#[heartbeat]
+fn tick() {
+    if has_scheduler::try_start_scheduled_run() {
+        ic_cdk::spawn(async {
+            let _ = run().await;
+        })
+    }
 }
 
-impl AntiSpam {
-    const UPDATE_INTERVAL: u64 = 1 * 1_000_000_000;
-    pub fn try_lock(&mut self) -> bool {
-        let now = ic_cdk::api::time();
-        if self.last_update < now - Self::UPDATE_INTERVAL {
-            self.locked = true;
-            self.last_update = now;
-            true
-        } else {
-            false
-        }
-    }
-    pub fn unlock(&mut self) {
-        self.locked = false;
-    }
+async fn run() -> Result<()> {
+    let result = update_liquidity_with_manager().await;
+    retry_due_withdrawals().await;
+    has_scheduler::finish_run(
+        &result,
+        has_pending_liquidity() || has_any_queued_withdrawals(),
+    );
+    result
 }
 
-// without too many users, this is probably much cheaper
+/// Runs right away for the owner or manager, unless a run started within the active interval of
+/// the scheduler, in which case the start of the last one is returned.
 #[update(name = "triggerHeartbeat")]
 #[candid_method(update, rename = "triggerHeartbeat")]
-async fn trigger_heartbeat() -> Option<u64> {
-    if let Some(too_soon) = STATE.with(|s| {
-        let mut s = s.borrow_mut();
-        if s.try_lock() {
-            None
-        } else {
-            Some(s.last_update)
-        }
-    }) {
-        return Some(too_soon);
+async fn trigger_heartbeat() -> TxResult<Option<u64>> {
+    assert_is_owner_or_manager()?;
+    if let Err(last_run) = has_scheduler::try_start_manual_run() {
+        return Ok(Some(last_run));
     }
-    update_liquidity_with_manager().await;
-    STATE.with(|s| s.borrow_mut().unlock());
-    None
+    run().await?;
+    Ok(None)
 }
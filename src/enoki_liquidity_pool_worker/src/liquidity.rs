@@ -1,17 +1,29 @@
 use std::cell::RefCell;
-use std::ops::{AddAssign, Div, Mul};
-
+use std::ops::AddAssign;
 use candid::{candid_method, CandidType, Nat, Principal};
 use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
 
 use enoki_exchange_shared::has_token_info::{
     get_assigned_shard, get_assigned_shards, AssignedShards,
 };
 use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
 use enoki_exchange_shared::is_managed::get_manager;
-use enoki_exchange_shared::liquidity::liquidity_pool::{LiquidityPool, LiquidityPoolTotalBalance};
+use enoki_exchange_shared::liquidity::liquidity_pool::{
+    value_in_b, LegacyLiquidityPool, LiquidityPool, LiquidityPoolTotalBalance, ScheduledWithdrawal,
+    MAX_LOCK_SECONDS,
+};
+use enoki_exchange_shared::liquidity::ResponseAboutLiquidityChanges;
 use enoki_exchange_shared::types::*;
-use enoki_exchange_shared::{has_sharded_users, has_token_info};
+use enoki_exchange_shared::{has_rejected_deposits, has_sharded_users, has_token_info};
+
+use crate::performance;
+use crate::reserve;
+use crate::reserve::ReserveSource;
+use crate::rewards;
+use crate::swap_withdrawal;
+use crate::withdrawals;
+use crate::withdrawals::WithdrawalFailure;
 
 thread_local! {
     static STATE: RefCell<LiquidityState> = RefCell::new(LiquidityState::default());
@@ -21,39 +33,109 @@ thread_local! {
 pub struct LiquidityState {
     locked: bool,
     pool: LiquidityPool,
+    /// failed withdrawals from before the retry queue, moved there on upgrade
     earnings_pending: Vec<(Principal, TokenAmount)>,
-    rounding_error: LiquidityTrades, //TODO: send these to the accrued fees / use fees to pay for these
+    last_price: Option<u64>,
+}
+
+/// The state as stored before pool shares.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct LegacyLiquidityState {
+    locked: bool,
+    pool: LegacyLiquidityPool,
+    earnings_pending: Vec<(Principal, TokenAmount)>,
+    rounding_error: LiquidityTrades,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct AddLiquidityOptions {
+    #[serde(default)]
+    lock_seconds: u64,
+}
+
+#[derive(CandidType)]
+pub struct LockedLiquidity {
+    pub shares: Nat,
+    pub liquidity: LiquidityAmountNat,
+    pub unlock_time: u64,
+    pub weight_bonus_bps: u64,
+}
+
+#[derive(CandidType)]
+pub struct ScheduledWithdrawalInfo {
+    pub amount: LiquidityAmountNat,
+    pub due: u64,
+}
+
+#[derive(CandidType)]
+pub struct PendingLiquidity {
+    pub deposits: LiquidityAmountNat,
+    pub withdrawals: LiquidityAmountNat,
+    pub scheduled_withdrawals: Vec<ScheduledWithdrawalInfo>,
+}
+
+#[derive(CandidType)]
+pub struct SharePrice {
+    pub total_shares: Nat,
+    pub total_liquidity: LiquidityAmountNat,
+    pub value_in_b_per_share: f64,
 }
 
-pub async fn update_liquidity_with_manager() {
+pub async fn update_liquidity_with_manager() -> Result<()> {
     if STATE.with(|s| {
         let s = s.borrow();
         s.locked
     }) {
-        return;
+        return Ok(());
     }
     let (pending_add, pending_remove) = STATE.with(|s| {
         let mut s = s.borrow_mut();
         s.locked = true;
-        s.pool.lock_liquidity()
+        let price_known = s.last_price.is_some();
+        s.pool.lock_liquidity(price_known, ic_cdk::api::time())
     });
-    let response: Result<(LiquidityAmount, LiquidityAmount, LiquidityTrades)> = ic_cdk::call(
-        get_manager(),
-        "updateLiquidity",
-        (pending_add, pending_remove),
-    )
-    .await
-    .map_err(|e| e.into_tx_error());
+    let response: Result<(ResponseAboutLiquidityChanges, Option<u64>, LiquidityAmount)> =
+        flatten_response(
+            ic_cdk::call(
+                get_manager(),
+                "updateLiquidity",
+                (pending_add, pending_remove),
+            )
+            .await,
+        );
     let final_result: Result<Vec<(Principal, TokenAmount)>> = match response {
-        Ok((added, removed, traded)) => STATE.with(|s| {
+        Ok((changes, price, pool_total)) => STATE.with(|s| {
             let mut s = s.borrow_mut();
             s.locked = false;
-            let rounding_error = apply_traded(traded, &mut s.pool);
-            s.rounding_error.add_assign(rounding_error);
+            if price.is_some() {
+                s.last_price = price;
+            }
+            if let Some(price) = s.last_price {
+                if let Err(error) = s.pool.mint_legacy_shares(price) {
+                    ic_cdk::print(format!("[worker] cannot mint legacy shares: {:?}", error));
+                }
+            }
+            let ResponseAboutLiquidityChanges {
+                added,
+                removed,
+                traded,
+                fees_earned,
+                rounding_error,
+            } = changes;
+            reserve::credit(ReserveSource::SwapRounding, rounding_error);
+            apply_traded(traded, &mut s.pool);
+            performance::attribute_fees(&s.pool, &fees_earned);
+            performance::record_fees(&s.pool, &fees_earned, s.last_price);
+            s.pool.apply_fees(&fees_earned);
 
-            apply_new_liquidity(added, &mut s.pool);
-            let withdrawals = calculate_withdrawals(removed, &mut s.pool);
+            // additions are only locked once a price is known, so shares are never minted at 0
+            let price = s.last_price.unwrap_or_default();
+            apply_new_liquidity(added, &mut s.pool, price);
+            let withdrawals = calculate_withdrawals(removed, &mut s.pool, price);
             s.pool.remove_zeros();
+            performance::record_share_snapshots(&s.pool);
+            rewards::update_weights(&s.pool);
+            reserve::check_invariant(&s.pool, pool_total);
             Ok(withdrawals)
         }),
         Err(err) => {
@@ -67,136 +149,86 @@ pub async fn update_liquidity_with_manager() {
     match final_result {
         Ok(withdrawals) => {
             ic_cdk::spawn(distribute_withdrawals(withdrawals));
+            Ok(())
         }
         Err(error) => {
             ic_cdk::print(format!(
                 "error updating liquidity with manager: {:?}",
                 error
             ));
+            Err(error)
         }
     }
 }
 
-fn apply_traded(traded: LiquidityTrades, pool: &mut LiquidityPool) -> LiquidityTrades {
-    ic_cdk::println!("[worker] resolved: applying traded: {:?}", traded);
-    let total = LiquidityPoolTotalBalance::new(pool).get_total_balances();
-    ic_cdk::println!("[worker] liquidity before applying traded: {:?}", total);
-    let total_a = total.token_a;
-    let total_b = total.token_b;
-    let balances = pool.get_liquidity_by_principal();
-
-    let changes_per_user = balances
-        .into_iter()
-        .map(|(&id, balances)| {
-            let plus_a;
-            let minus_a;
-            let plus_b;
-            let minus_b;
-            if total_a.is_nonzero() {
-                minus_a = balances
-                    .token_a
-                    .clone()
-                    .mul(traded.decreased.token_a.clone())
-                    .div(total_a.clone());
-                plus_b = balances
-                    .token_a
-                    .clone()
-                    .mul(traded.increased.token_b.clone())
-                    .div(total_a.clone());
-            } else {
-                minus_a = Default::default();
-                plus_b = Default::default();
-            }
-            if total_b.is_nonzero() {
-                minus_b = balances
-                    .token_b
-                    .clone()
-                    .mul(traded.decreased.token_b.clone())
-                    .div(total_b.clone());
-                plus_a = balances
-                    .token_b
-                    .clone()
-                    .mul(traded.increased.token_a.clone())
-                    .div(total_b.clone());
-            } else {
-                minus_b = Default::default();
-                plus_a = Default::default();
-            }
-            (
-                id,
-                LiquidityTrades {
-                    increased: LiquidityAmount {
-                        token_a: plus_a,
-                        token_b: plus_b,
-                    },
-                    decreased: LiquidityAmount {
-                        token_a: minus_a,
-                        token_b: minus_b,
-                    },
-                },
-            )
-        })
-        .collect();
-
-    pool.apply_traded(&changes_per_user);
+pub fn has_pending_liquidity() -> bool {
+    STATE.with(|s| !s.borrow().pool.nothing_pending())
+}
 
-    let aggr_changes_for_users = changes_per_user.into_iter().map(|(_, val)| val).fold(
-        LiquidityTrades::default(),
-        |mut sum, next| {
-            sum.add_assign(next);
-            sum
-        },
+fn apply_traded(traded: LiquidityTrades, pool: &mut LiquidityPool) {
+    ic_cdk::println!("[worker] resolved: applying traded: {:?}", traded);
+    ic_cdk::println!(
+        "[worker] liquidity before applying traded: {:?}",
+        LiquidityPoolTotalBalance::new(pool)
     );
-
+    let shortfall = pool.apply_traded(&traded);
+    reserve::debit(ReserveSource::TradedShortfall, shortfall);
     ic_cdk::println!(
         "[worker] liquidity after applying traded: {:?}",
         LiquidityPoolTotalBalance::new(pool)
     );
-
-    let mut rounding_error = traded;
-    rounding_error
-        .safe_sub_assign(aggr_changes_for_users)
-        .unwrap();
-
-    if rounding_error.decreased.token_a.is_nonzero()
-        || rounding_error.decreased.token_b.is_nonzero()
-        || rounding_error.increased.token_a.is_nonzero()
-        || rounding_error.increased.token_b.is_nonzero()
-    {
-        ic_cdk::println!(
-            "[worker] liquidity after applying traded there is a rounding error: {:?}",
-            rounding_error
-        );
-    }
-
-    rounding_error
 }
 
-fn apply_new_liquidity(mut amount: LiquidityAmount, pool: &mut LiquidityPool) {
+fn apply_new_liquidity(mut amount: LiquidityAmount, pool: &mut LiquidityPool, price: u64) {
     let mut i = 0;
     ic_cdk::println!(
         "[worker] resolved: adding more total liquidity: {:?}",
         amount
     );
     while amount.token_a.is_nonzero() || amount.token_b.is_nonzero() {
-        let item = pool
-            .get_locked_add_item(i)
-            .expect("inconsistent state between pool and worker");
-        let token = item.1.token.clone();
+        let item = match pool.get_locked_add_item(i) {
+            Some(item) => item,
+            None => {
+                reserve::credit(ReserveSource::UnassignedDeposit, amount);
+                break;
+            }
+        };
+        let token = item.amount.token.clone();
         let amount_left = amount.get_mut(&token);
         if amount_left.is_nonzero() {
-            let diff = amount_left.clone().min(item.1.amount.clone());
+            let diff = amount_left.clone().min(item.amount.amount.clone());
             amount_left.safe_sub_assign(diff.clone()).unwrap();
-            item.1.amount.safe_sub_assign(diff.clone()).unwrap();
-            let addr = item.0;
+            item.amount.amount.safe_sub_assign(diff.clone()).unwrap();
+            let addr = item.user;
+            let lock_seconds = item.lock_seconds;
+            let minted = match pool.mint_shares(addr, &token, diff.clone(), price) {
+                Ok(minted) => minted,
+                Err(error) => {
+                    ic_cdk::println!(
+                        "[worker] could not mint shares for {}, keeping {:?} {:?} in the reserve: {:?}",
+                        addr,
+                        diff,
+                        token,
+                        error
+                    );
+                    let mut unassigned = LiquidityAmount::default();
+                    *unassigned.get_mut(&token) = diff;
+                    reserve::credit(ReserveSource::UnassignedDeposit, unassigned);
+                    i += 1;
+                    continue;
+                }
+            };
+            if lock_seconds > 0 {
+                pool.lock_shares(addr, minted.clone(), lock_seconds, ic_cdk::api::time());
+            }
             ic_cdk::println!(
-                "[worker] liquidity for user {} was successfully added: {:?} {:?}",
+                "[worker] liquidity for user {} was successfully added: {:?} {:?} ({:?} shares)",
                 addr,
                 diff,
-                token
+                token,
+                minted
             );
-            pool.get_user_liquidity_mut(addr, &token)
-                .add_assign(diff.clone());
+            performance::record_deposit(addr, value_of(&token, diff.clone(), price));
             pool.update_user_net_deposits(addr, &token, true, diff);
         }
         i += 1;
@@ -206,28 +238,44 @@ fn apply_new_liquidity(mut amount: LiquidityAmount, pool: &mut LiquidityPool) {
 fn calculate_withdrawals(
     mut amount: LiquidityAmount,
     pool: &mut LiquidityPool,
+    price: u64,
 ) -> Vec<(Principal, TokenAmount)> {
     let mut amounts_to_distribute: Vec<(Principal, TokenAmount)> = Default::default();
+    // claims are capped as of the start of the round, and each user's shares are burned once for
+    // both tokens at the end, so withdrawing one token does not shrink the claim on the other
+    let mut withdrawn: Vec<(Principal, LiquidityAmount)> = Default::default();
     let mut i = 0;
     ic_cdk::println!("[worker] resolved: removing total liquidity: {:?}", amount);
     while amount.token_a.is_nonzero() || amount.token_b.is_nonzero() {
-        let item = pool
-            .get_locked_remove_item(i)
-            .expect("inconsistent state between pool and worker");
+        let item = match pool.get_locked_remove_item(i) {
+            Some(item) => item,
+            None => {
+                reserve::credit_held(ReserveSource::UnassignedWithdrawal, amount);
+                break;
+            }
+        };
         let token = item.1.token.clone();
         let amount_left = amount.get_mut(&token);
         if amount_left.is_nonzero() {
             let addr = item.0;
-            let amount_in_lp = pool.get_user_liquidity_mut(addr, &token).clone();
+            let index = match withdrawn.iter().position(|(user, _)| *user == addr) {
+                Some(index) => index,
+                None => {
+                    withdrawn.push((addr, LiquidityAmount::default()));
+                    withdrawn.len() - 1
+                }
+            };
+            let amount_in_lp = pool
+                .get_user_unlocked_liquidity(addr)
+                .sub_or_zero(&withdrawn[index].1)
+                .get(&token)
+                .clone();
             let item = pool.get_locked_remove_item(i).unwrap();
-            item.1.amount = item.1.amount.clone().min(amount_in_lp.clone());
+            item.1.amount = item.1.amount.clone().min(amount_in_lp);
             let diff = amount_left.clone().min(item.1.amount.clone());
             amount_left.safe_sub_assign(diff.clone()).unwrap();
             item.1.amount.safe_sub_assign(diff.clone()).unwrap();
-            pool.get_user_liquidity_mut(addr, &token)
-                .safe_sub_assign(diff.clone())
-                .unwrap();
-            pool.update_user_net_deposits(addr, &token, false, diff.clone());
+            withdrawn[index].1.get_mut(&token).add_assign(diff.clone());
             ic_cdk::println!(
                 "[worker] liquidity for user {} is successfully being removed: {:?} {:?}",
                 addr,
@@ -245,29 +293,91 @@ fn calculate_withdrawals(
 
         i += 1;
     }
+    for (addr, total) in withdrawn {
+        pool.burn_shares_for(addr, &total, price).unwrap();
+        performance::record_withdrawal(addr, value_in_b(&total, price).unwrap());
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            pool.update_user_net_deposits(addr, &token, false, total.get(&token).clone());
+        }
+    }
     amounts_to_distribute
 }
 
-async fn distribute_withdrawals(mut withdrawals: Vec<(Principal, TokenAmount)>) {
-    let mut past_pending = STATE.with(|s| std::mem::take(&mut s.borrow_mut().earnings_pending));
-    withdrawals.append(&mut past_pending);
+fn value_of(token: &EnokiToken, amount: StableNat, price: u64) -> StableNat {
+    let mut liquidity = LiquidityAmount::default();
+    *liquidity.get_mut(token) = amount;
+    value_in_b(&liquidity, price).unwrap()
+}
+
+pub fn with_pool<F: FnOnce(&LiquidityPool, Option<u64>) -> R, R>(f: F) -> R {
+    STATE.with(|s| {
+        let s = s.borrow();
+        f(&s.pool, s.last_price)
+    })
+}
+
+async fn distribute_withdrawals(withdrawals: Vec<(Principal, TokenAmount)>) {
+    let withdrawals = withdrawals::batch_withdrawals(withdrawals).await;
+    send_withdrawals(withdrawals).await;
+}
+
+pub async fn send_withdrawals(withdrawals: Vec<(Principal, TokenAmount)>) {
     let results = futures::future::join_all(
         withdrawals
             .into_iter()
-            .map(|(user, withdrawal)| withdraw_for_user(user, withdrawal)),
+            .map(|(user, withdrawal)| send_withdrawal(user, withdrawal)),
     )
     .await;
+    withdrawals::queue_failures(results.into_iter().flatten().collect());
+    swap_withdrawal::clear_completed_requests();
+}
+
+/// Sends a withdrawal to the user, swapping it first if they asked for a single token.
+pub async fn send_withdrawal(
+    user: Principal,
+    withdrawal: TokenAmount,
+) -> Option<WithdrawalFailure> {
+    match swap_withdrawal::get_requested_token(user) {
+        Some(token) if token != withdrawal.token => {
+            swap_withdrawal::swap_and_withdraw_for_user(user, withdrawal).await
+        }
+        _ => withdraw_for_user(user, withdrawal).await,
+    }
+}
+
+pub fn take_legacy_pending_withdrawals() -> Vec<(Principal, TokenAmount)> {
+    STATE.with(|s| std::mem::take(&mut s.borrow_mut().earnings_pending))
+}
+
+/// Whether the user still has liquidity in the pool or a withdrawal waiting to be distributed.
+pub fn user_has_liquidity(user: Principal) -> bool {
     STATE.with(|s| {
-        s.borrow_mut()
-            .earnings_pending
-            .extend(results.into_iter().filter_map(|failed| failed))
+        let s = s.borrow();
+        s.pool.get_user_liquidity(user).is_some()
+    }) || withdrawals::has_queued_withdrawals(user)
+}
+
+pub fn remove_all_unlocked_liquidity(user: Principal) -> Result<()> {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let liquidity = s.pool.get_user_unlocked_liquidity(user);
+        s.pool.user_remove_liquidity(user, liquidity)
+    })
+}
+
+/// Records a withdrawal swapped by the broker: the user gave up `sold` and received `bought`.
+pub fn record_swap_in_net_deposits(user: Principal, sold: TokenAmount, bought: TokenAmount) {
+    STATE.with(|s| {
+        let pool = &mut s.borrow_mut().pool;
+        pool.update_user_net_deposits(user, &sold.token, true, sold.amount);
+        pool.update_user_net_deposits(user, &bought.token, false, bought.amount);
     });
 }
 
-async fn withdraw_for_user(
+pub async fn withdraw_for_user(
     user: Principal,
     withdrawal: TokenAmount,
-) -> Option<(Principal, TokenAmount)> {
+) -> Option<WithdrawalFailure> {
     let error;
     match has_sharded_users::get_user_shard(
         user,
@@ -311,7 +421,11 @@ async fn withdraw_for_user(
         }
     }
     ic_cdk::api::print(format!("failed to remove liquidity: {:?}", error));
-    Some((user, withdrawal))
+    Some(WithdrawalFailure {
+        user,
+        withdrawal,
+        error: format!("{:?}", error),
+    })
 }
 
 #[query(name = "getLiquidity")]
@@ -323,6 +437,45 @@ fn get_liquidity(user: Principal) -> LiquidityAmountNat {
         .into()
 }
 
+#[query(name = "getShares")]
+#[candid_method(query, rename = "getShares")]
+fn get_shares(user: Principal) -> Nat {
+    STATE.with(|s| s.borrow().pool.get_user_shares(user).to_nat())
+}
+
+#[query(name = "getSharePrice")]
+#[candid_method(query, rename = "getSharePrice")]
+fn get_share_price() -> TxResult<SharePrice> {
+    STATE.with(|s| {
+        let s = s.borrow();
+        let total_shares = s.pool.get_total_shares().to_nat();
+        let total_liquidity = LiquidityPoolTotalBalance::new(&s.pool).get_total_balances();
+        let value_in_b_per_share = if total_shares > 0u32 {
+            let value = value_in_b(&total_liquidity, s.last_price.unwrap_or_default())?.to_nat();
+            value.0.to_f64().unwrap() / total_shares.0.to_f64().unwrap()
+        } else {
+            0f64
+        };
+        Ok(SharePrice {
+            total_shares,
+            total_liquidity: total_liquidity.into(),
+            value_in_b_per_share,
+        })
+    })
+}
+
+#[update(name = "transferShares")]
+#[candid_method(update, rename = "transferShares")]
+fn transfer_shares(to: Principal, amount: Nat) -> TxResult<()> {
+    let from = ic_cdk::caller();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.pool.transfer_shares(from, to, amount.into())?;
+        rewards::update_weights(&s.pool);
+        Ok(())
+    })
+}
+
 #[query(name = "getNetDeposits")]
 #[candid_method(query, rename = "getNetDeposits")]
 fn get_net_deposits(user: Principal) -> LiquidityTradesNat {
@@ -356,49 +509,148 @@ pub fn is_user_registered(user: Principal) -> bool {
 
 #[update(name = "register")]
 #[candid_method(update)]
-async fn register(user: Principal) {
-    has_sharded_users::register_user(user).await.unwrap();
+async fn register(user: Principal) -> TxResult<()> {
+    Ok(has_sharded_users::register_user(user).await?)
 }
 
+/// Called by the token shards through `shardTransferAndCall`, which expect text back. A rejected
+/// deposit is refunded and answered with text starting with `REJECTED_PREFIX`.
 #[update(name = "addLiquidity")]
 #[candid_method(update, rename = "addLiquidity")]
 async fn add_liquidity(notification: ShardedTransferNotification) -> String {
-    assert_eq!(notification.to, ic_cdk::id());
-    let token = has_token_info::parse_from().unwrap();
+    let token = match has_rejected_deposits::parse_notification(&notification) {
+        Ok(token) => token,
+        Err(error) => {
+            return has_rejected_deposits::reject_without_refund("addLiquidity", error.into())
+        }
+    };
     let from = notification.from;
-    if !is_user_registered(from) {
-        panic!(
-            "{:?}",
-            TxError::UserNotRegistered {
-                user: from.to_string(),
-                registry: ic_cdk::id().to_string()
-            }
-        );
-    }
+    let options = match parse_add_liquidity_options(&notification) {
+        Ok(options) => options,
+        Err(error) => {
+            let transfer_fee = withdrawals::get_fee_for_transfer(&token)
+                .await
+                .map(|fee| fee.into());
+            let refundable = notification.value.clone();
+            return has_rejected_deposits::refund_rejected_deposit(
+                notification,
+                token,
+                "addLiquidity",
+                error,
+                refundable,
+                transfer_fee,
+            )
+            .await;
+        }
+    };
     let amount = TokenAmount {
         token,
         amount: notification.value.into(),
     };
-    STATE.with(|s| s.borrow_mut().pool.user_add_liquidity(from, amount));
+    STATE.with(|s| {
+        s.borrow_mut()
+            .pool
+            .user_add_liquidity(from, amount, options.lock_seconds)
+    });
     "OK".to_string()
 }
 
+fn parse_add_liquidity_options(
+    notification: &ShardedTransferNotification,
+) -> TxResult<AddLiquidityOptions> {
+    if !is_user_registered(notification.from) {
+        return Err(TxError::UserNotRegistered {
+            user: notification.from.to_string(),
+            registry: ic_cdk::id().to_string(),
+        });
+    }
+    if notification.data.is_empty() {
+        return Ok(Default::default());
+    }
+    let options: AddLiquidityOptions = serde_json::from_str(&notification.data)
+        .map_err(|e| TxError::ParsingError(e.to_string()))?;
+    if options.lock_seconds > MAX_LOCK_SECONDS {
+        return Err(TxError::InvalidInput(format!(
+            "liquidity can be locked for at most {} seconds",
+            MAX_LOCK_SECONDS
+        )));
+    }
+    Ok(options)
+}
+
 #[update(name = "removeLiquidity")]
 #[candid_method(update, rename = "removeLiquidity")]
-async fn remove_liquidity(amount: LiquidityAmountNat) {
+async fn remove_liquidity(amount: LiquidityAmountNat) -> TxResult<()> {
     let from = ic_cdk::caller();
 
-    STATE
-        .with(|s| s.borrow_mut().pool.user_remove_liquidity(from, amount.into()))
-        .unwrap();
+    STATE.with(|s| s.borrow_mut().pool.user_remove_liquidity(from, amount.into()))?;
+    Ok(())
+}
+
+#[update(name = "scheduleRemoveLiquidity")]
+#[candid_method(update, rename = "scheduleRemoveLiquidity")]
+fn schedule_remove_liquidity(amount: LiquidityAmountNat, due: u64) -> TxResult<()> {
+    let from = ic_cdk::caller();
+    STATE.with(|s| {
+        s.borrow_mut()
+            .pool
+            .schedule_remove_liquidity(from, amount.into(), due, ic_cdk::api::time())
+    })?;
+    Ok(())
+}
+
+#[update(name = "cancelScheduledRemoval")]
+#[candid_method(update, rename = "cancelScheduledRemoval")]
+fn cancel_scheduled_removal(due: u64) -> TxResult<()> {
+    let from = ic_cdk::caller();
+    STATE.with(|s| s.borrow_mut().pool.cancel_scheduled_removal(from, due))?;
+    Ok(())
+}
+
+#[query(name = "getLockedLiquidity")]
+#[candid_method(query, rename = "getLockedLiquidity")]
+fn get_locked_liquidity(user: Principal) -> Vec<LockedLiquidity> {
+    STATE.with(|s| {
+        let pool = &s.borrow().pool;
+        pool.get_user_locks(user)
+            .into_iter()
+            .map(|lock| LockedLiquidity {
+                liquidity: pool.shares_to_liquidity(&lock.shares).into(),
+                shares: lock.shares.into(),
+                unlock_time: lock.unlock_time,
+                weight_bonus_bps: lock.weight_bonus_bps,
+            })
+            .collect()
+    })
+}
+
+#[query(name = "getPendingLiquidity")]
+#[candid_method(query, rename = "getPendingLiquidity")]
+fn get_pending_liquidity(user: Principal) -> PendingLiquidity {
+    STATE.with(|s| {
+        let pool = &s.borrow().pool;
+        PendingLiquidity {
+            deposits: pool.get_pending_deposits(user).into(),
+            withdrawals: pool.get_pending_withdrawals(user).into(),
+            scheduled_withdrawals: pool
+                .get_scheduled_withdrawals(user)
+                .into_iter()
+                .map(|ScheduledWithdrawal { amount, due }| ScheduledWithdrawalInfo {
+                    amount: amount.into(),
+                    due,
+                })
+                .collect(),
+        }
+    })
 }
 
 #[update(name = "removeAllLiquidity")]
 #[candid_method(update, rename = "removeAllLiquidity")]
-async fn remove_all_liquidity() {
+async fn remove_all_liquidity() -> TxResult<()> {
     if let Some(liquidity) = STATE.with(|s| s.borrow().pool.get_user_liquidity(ic_cdk::caller())) {
-        remove_liquidity(liquidity.into()).await;
+        remove_liquidity(liquidity.into()).await?;
     }
+    Ok(())
 }
 
 pub fn export_stable_storage() -> LiquidityState {
@@ -408,3 +660,56 @@ pub fn export_stable_storage() -> LiquidityState {
 pub fn import_stable_storage(data: LiquidityState) {
     STATE.with(|s| s.replace(data));
 }
+
+pub fn import_legacy_stable_storage(data: LegacyLiquidityState) {
+    let LegacyLiquidityState {
+        pool,
+        earnings_pending,
+        rounding_error,
+        ..
+    } = data;
+    let pool = LiquidityPool::from_legacy(pool, &rounding_error);
+    STATE.with(|s| {
+        s.replace(LiquidityState {
+            locked: false,
+            pool,
+            earnings_pending,
+            last_price: None,
+        })
+    });
+}
+
+#[cfg(test)]
+mod liquidity_tests {
+    use super::*;
+
+    #[test]
+    fn test_full_exit_burns_all_shares() {
+        let exiting = Principal::from_slice(&[1]);
+        let staying = Principal::from_slice(&[2]);
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(exiting, &EnokiToken::TokenA, StableNat::from(100), 1)
+            .unwrap();
+        pool.mint_shares(staying, &EnokiToken::TokenB, StableNat::from(100), 1)
+            .unwrap();
+        let claim = pool.get_user_unlocked_liquidity(exiting);
+        assert_eq!(claim.token_a, StableNat::from(50));
+        assert_eq!(claim.token_b, StableNat::from(50));
+
+        pool.user_remove_liquidity(exiting, claim).unwrap();
+        let (_, removed) = pool.lock_liquidity(true, 0);
+        let withdrawals = calculate_withdrawals(removed, &mut pool, 1);
+        let withdrawn: LiquidityAmount = withdrawals
+            .into_iter()
+            .map(|(user, amount)| {
+                assert_eq!(user, exiting);
+                amount
+            })
+            .collect();
+        assert_eq!(withdrawn.token_a, StableNat::from(50));
+        assert_eq!(withdrawn.token_b, StableNat::from(50));
+        assert!(!pool.get_user_shares(exiting).is_nonzero());
+        assert_eq!(pool.get_total_shares(), StableNat::from(100));
+        assert_eq!(pool.get_user_shares(staying), StableNat::from(100));
+    }
+}
@@ -1,9 +1,15 @@
+#[allow(unused_imports)]
+use candid::Nat;
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
 #[allow(unused_imports)]
 use enoki_exchange_shared::{has_token_info, types::*};
 #[allow(unused_imports)]
+use enoki_exchange_shared::has_rejected_deposits::RejectedDeposit;
+#[allow(unused_imports)]
+use enoki_exchange_shared::has_scheduler::{SchedulerSettings, SchedulerStatus};
+#[allow(unused_imports)]
 use enoki_exchange_shared::has_token_info::{AssignedShards, TokenPairInfo};
 #[allow(unused_imports)]
 use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
@@ -13,9 +19,24 @@ use enoki_exchange_shared::is_managed::ManagementData;
 use enoki_exchange_shared::is_owned;
 #[allow(unused_imports)]
 use enoki_exchange_shared::is_owned::OwnershipData;
+#[allow(unused_imports)]
+use liquidity::{LockedLiquidity, PendingLiquidity, SharePrice};
+#[allow(unused_imports)]
+use performance::{FeeApr, LiquidityPerformance};
+#[allow(unused_imports)]
+use reserve::ProtocolReserveInfo;
+#[allow(unused_imports)]
+use rewards::RewardsInfo;
+#[allow(unused_imports)]
+use withdrawals::QueuedWithdrawal;
 
 mod main_pool;
 mod liquidity;
+mod performance;
+mod reserve;
+mod rewards;
+mod swap_withdrawal;
+mod withdrawals;
 mod heartbeat;
 mod upgrade;
 mod shared_candid_methods;
@@ -31,10 +52,13 @@ fn init() {
 
 #[update(name = "finishInit")]
 #[candid_method(update, rename = "finishInit")]
-fn finish_init(main_pool: Principal) {
-    is_owned::assert_is_owner().unwrap();
-    assert_eq!(is_managed::get_manager(), Principal::anonymous(), "already init");
+fn finish_init(main_pool: Principal) -> TxResult<()> {
+    is_owned::assert_is_owner()?;
+    if is_managed::get_manager() != Principal::anonymous() {
+        return Err(TxError::AlreadyInitialized);
+    }
     is_managed::init_manager(ManagementData { manager: main_pool });
+    Ok(())
 }
 
 #[cfg(any(target_arch = "wasm32", test))]
@@ -0,0 +1,379 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::ops::{AddAssign, Div, Mul};
+
+use candid::{candid_method, CandidType, Int, Nat, Principal};
+use ic_cdk_macros::*;
+use num_traits::ToPrimitive;
+
+use enoki_exchange_shared::liquidity::liquidity_pool::{
+    value_in_b, LiquidityPool, LiquidityPoolTotalBalance,
+};
+use enoki_exchange_shared::types::*;
+
+use crate::liquidity::with_pool;
+
+const MAX_SHARE_HISTORY: usize = 500;
+const FEE_BUCKET_DURATION: u64 = 3_600 * 1_000_000_000;
+const FEE_APR_WINDOW: u64 = 30 * 24 * FEE_BUCKET_DURATION;
+const YEAR: u64 = 365 * 24 * 3_600 * 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<PerformanceState> = RefCell::new(PerformanceState::default());
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct PerformanceState {
+    users: HashMap<Principal, UserPerformance>,
+    fee_history: VecDeque<FeeBucket>,
+}
+
+/// Fees earned during one bucket of time, valued in B at the price of the round they were earned.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+struct FeeBucket {
+    start: u64,
+    fees_in_b: StableNat,
+    pool_value_in_b: StableNat,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+struct UserPerformance {
+    fees_earned: LiquidityAmount,
+    value_deposited_in_b: StableNat,
+    value_withdrawn_in_b: StableNat,
+    share_history: VecDeque<ShareSnapshot>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+struct ShareSnapshot {
+    time: u64,
+    shares: StableNat,
+    total_shares: StableNat,
+}
+
+#[derive(CandidType)]
+pub struct PoolShareRecord {
+    pub time: u64,
+    pub shares: Nat,
+    pub total_shares: Nat,
+    pub pool_share: f64,
+}
+
+#[derive(CandidType)]
+pub struct FeeApr {
+    pub apr: f64,
+    pub window_seconds: u64,
+    pub fees_in_b: Nat,
+    pub average_pool_value_in_b: Nat,
+}
+
+#[derive(CandidType)]
+pub struct LiquidityPerformance {
+    pub net_deposits: LiquidityTradesNat,
+    pub current_holdings: LiquidityAmountNat,
+    pub fees_earned: LiquidityAmountNat,
+    pub price: Option<u64>,
+    pub current_value_in_b: Nat,
+    /// value now of the net deposits, had they been held instead of provided as liquidity
+    pub hold_value_in_b: Int,
+    pub fees_value_in_b: Nat,
+    /// current value minus the value of the net deposits at the time they were made
+    pub pnl_in_b: Int,
+    /// current value (excluding fees) minus the hold value
+    pub impermanent_loss_in_b: Int,
+    pub share_history: Vec<PoolShareRecord>,
+}
+
+/// Splits the fees earned during the last round between existing share holders.
+pub fn attribute_fees(pool: &LiquidityPool, fees: &LiquidityAmount) {
+    let total_shares = pool.get_total_shares();
+    if !total_shares.is_nonzero() || !(fees.token_a.is_nonzero() || fees.token_b.is_nonzero()) {
+        return;
+    }
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for (user, shares) in pool.iter_shares() {
+            let user_fees = LiquidityAmount {
+                token_a: fees
+                    .token_a
+                    .clone()
+                    .mul(shares.clone())
+                    .div(total_shares.clone()),
+                token_b: fees
+                    .token_b
+                    .clone()
+                    .mul(shares.clone())
+                    .div(total_shares.clone()),
+            };
+            s.users
+                .entry(*user)
+                .or_default()
+                .fees_earned
+                .add_assign(user_fees);
+        }
+    });
+}
+
+/// Adds the fees of the last round to the current bucket of the fee history, used for the fee APR.
+pub fn record_fees(pool: &LiquidityPool, fees: &LiquidityAmount, price: Option<u64>) {
+    let price = match price {
+        Some(price) => price,
+        None => return,
+    };
+    let now = ic_cdk::api::time();
+    let fees_in_b = value_in_b(fees, price).unwrap();
+    let pool_value_in_b = value_in_b(
+        &LiquidityPoolTotalBalance::new(pool).get_total_balances(),
+        price,
+    )
+    .unwrap();
+    STATE.with(|s| {
+        let history = &mut s.borrow_mut().fee_history;
+        match history.back_mut() {
+            Some(bucket) if bucket.start + FEE_BUCKET_DURATION > now => {
+                bucket.fees_in_b.add_assign(fees_in_b);
+                bucket.pool_value_in_b = pool_value_in_b;
+            }
+            _ => history.push_back(FeeBucket {
+                start: now - now % FEE_BUCKET_DURATION,
+                fees_in_b,
+                pool_value_in_b,
+            }),
+        }
+        while history
+            .front()
+            .map(|bucket| bucket.start + FEE_APR_WINDOW < now)
+            .unwrap_or_default()
+        {
+            history.pop_front();
+        }
+    });
+}
+
+pub fn record_deposit(user: Principal, value_in_b: StableNat) {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .users
+            .entry(user)
+            .or_default()
+            .value_deposited_in_b
+            .add_assign(value_in_b)
+    });
+}
+
+pub fn record_withdrawal(user: Principal, value_in_b: StableNat) {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .users
+            .entry(user)
+            .or_default()
+            .value_withdrawn_in_b
+            .add_assign(value_in_b)
+    });
+}
+
+/// Appends an entry to the share history of every user whose pool share changed this round.
+pub fn record_share_snapshots(pool: &LiquidityPool) {
+    let time = ic_cdk::api::time();
+    let total_shares = pool.get_total_shares();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for (user, _) in pool.iter_shares() {
+            s.users.entry(*user).or_default();
+        }
+        for (user, performance) in s.users.iter_mut() {
+            let shares = pool.get_user_shares(*user);
+            let changed = match performance.share_history.back() {
+                Some(last) => last.shares != shares || last.total_shares != total_shares,
+                None => shares.is_nonzero(),
+            };
+            if changed {
+                performance.share_history.push_back(ShareSnapshot {
+                    time,
+                    shares,
+                    total_shares: total_shares.clone(),
+                });
+                if performance.share_history.len() > MAX_SHARE_HISTORY {
+                    performance.share_history.pop_front();
+                }
+            }
+        }
+    });
+}
+
+fn to_int(value: StableNat) -> Int {
+    value.to_nat().into()
+}
+
+#[query(name = "getFeesEarned")]
+#[candid_method(query, rename = "getFeesEarned")]
+fn get_fees_earned(user: Principal) -> LiquidityAmountNat {
+    STATE
+        .with(|s| s.borrow().users.get(&user).map(|u| u.fees_earned.clone()))
+        .unwrap_or_default()
+        .into()
+}
+
+#[query(name = "getTotalFeesEarned")]
+#[candid_method(query, rename = "getTotalFeesEarned")]
+fn get_total_fees_earned() -> LiquidityAmountNat {
+    with_pool(|pool, _| pool.get_fees_earned().clone()).into()
+}
+
+#[query(name = "getFeeApr")]
+#[candid_method(query, rename = "getFeeApr")]
+fn get_fee_apr() -> FeeApr {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let history = &s.borrow().fee_history;
+        let window = history
+            .front()
+            .map(|bucket| now.saturating_sub(bucket.start))
+            .unwrap_or_default()
+            .max(FEE_BUCKET_DURATION);
+        let fees_in_b: StableNat = history.iter().map(|b| b.fees_in_b.clone()).sum();
+        let average_pool_value_in_b = if history.is_empty() {
+            StableNat::default()
+        } else {
+            history
+                .iter()
+                .map(|b| b.pool_value_in_b.clone())
+                .sum::<StableNat>()
+                .div(Nat::from(history.len()).into())
+        };
+        let apr = if average_pool_value_in_b.is_nonzero() {
+            fees_in_b.clone().to_nat().0.to_f64().unwrap()
+                / average_pool_value_in_b.clone().to_nat().0.to_f64().unwrap()
+                * (YEAR as f64 / window as f64)
+        } else {
+            0f64
+        };
+        FeeApr {
+            apr,
+            window_seconds: window / 1_000_000_000,
+            fees_in_b: fees_in_b.into(),
+            average_pool_value_in_b: average_pool_value_in_b.into(),
+        }
+    })
+}
+
+#[query(name = "getLiquidityPerformance")]
+#[candid_method(query, rename = "getLiquidityPerformance")]
+fn get_liquidity_performance(user: Principal) -> TxResult<LiquidityPerformance> {
+    with_pool(|pool, price| performance_of(user, pool, price))
+}
+
+fn performance_of(
+    user: Principal,
+    pool: &LiquidityPool,
+    price: Option<u64>,
+) -> TxResult<LiquidityPerformance> {
+    let current_holdings = pool.get_user_liquidity(user).unwrap_or_default();
+    let net_deposits = pool.get_user_net_deposits(user).unwrap_or_default();
+    let performance = STATE.with(|s| s.borrow().users.get(&user).cloned().unwrap_or_default());
+    let value = |amount: &LiquidityAmount| value_in_b(amount, price.unwrap_or_default());
+
+    let current_value_in_b = value(&current_holdings)?;
+    let fees_value_in_b = value(&performance.fees_earned)?;
+    let hold_value_in_b =
+        to_int(value(&net_deposits.increased)?) - to_int(value(&net_deposits.decreased)?);
+    let cost_basis_in_b =
+        to_int(performance.value_deposited_in_b) - to_int(performance.value_withdrawn_in_b);
+    let pnl_in_b = to_int(current_value_in_b.clone()) - cost_basis_in_b;
+    let impermanent_loss_in_b = to_int(current_value_in_b.clone())
+        - to_int(fees_value_in_b.clone())
+        - hold_value_in_b.clone();
+
+    Ok(LiquidityPerformance {
+        net_deposits: net_deposits.into(),
+        current_holdings: current_holdings.into(),
+        fees_earned: performance.fees_earned.into(),
+        price,
+        current_value_in_b: current_value_in_b.into(),
+        hold_value_in_b,
+        fees_value_in_b: fees_value_in_b.into(),
+        pnl_in_b,
+        impermanent_loss_in_b,
+        share_history: performance
+            .share_history
+            .into_iter()
+            .map(|snapshot| {
+                let shares = snapshot.shares.to_nat();
+                let total_shares = snapshot.total_shares.to_nat();
+                let pool_share = if total_shares > 0u32 {
+                    shares.0.to_f64().unwrap() / total_shares.0.to_f64().unwrap()
+                } else {
+                    0f64
+                };
+                PoolShareRecord {
+                    time: snapshot.time,
+                    shares,
+                    total_shares,
+                    pool_share,
+                }
+            })
+            .collect(),
+    })
+}
+
+pub fn export_stable_storage() -> PerformanceState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: PerformanceState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod performance_tests {
+    use super::*;
+
+    #[test]
+    fn test_pnl_and_impermanent_loss_after_price_move() {
+        let user = Principal::from_slice(&[1]);
+        let mut pool = LiquidityPool::default();
+        for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+            pool.mint_shares(user, &token, StableNat::from(100), 1)
+                .unwrap();
+            pool.update_user_net_deposits(user, &token, true, StableNat::from(100));
+        }
+        record_deposit(user, StableNat::from(200));
+        // 100 B buy 50 A from the pool, moving the price from 1 to 4
+        let mut traded = LiquidityTrades::default();
+        traded.increased.token_b = StableNat::from(100);
+        traded.decreased.token_a = StableNat::from(50);
+        let _ = pool.apply_traded(&traded);
+
+        let performance = performance_of(user, &pool, Some(4)).unwrap();
+        assert_eq!(performance.current_value_in_b, Nat::from(400u32));
+        assert_eq!(performance.hold_value_in_b, Int::from(500));
+        assert_eq!(performance.pnl_in_b, Int::from(200));
+        assert_eq!(performance.impermanent_loss_in_b, Int::from(-100));
+    }
+
+    #[test]
+    fn test_fees_split_by_shares() {
+        let user_1 = Principal::from_slice(&[1]);
+        let user_2 = Principal::from_slice(&[2]);
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(user_1, &EnokiToken::TokenB, StableNat::from(1_000), 1)
+            .unwrap();
+        pool.mint_shares(user_2, &EnokiToken::TokenB, StableNat::from(3_000), 1)
+            .unwrap();
+        let fees = LiquidityAmount {
+            token_a: StableNat::from(8),
+            token_b: StableNat::from(40),
+        };
+        attribute_fees(&pool, &fees);
+        pool.apply_fees(&fees);
+
+        let earned = get_fees_earned(user_1);
+        assert_eq!(earned.token_a, Nat::from(2u32));
+        assert_eq!(earned.token_b, Nat::from(10u32));
+        assert_eq!(get_fees_earned(user_2).token_b, Nat::from(30u32));
+        assert_eq!(pool.get_fees_earned().token_b, StableNat::from(40));
+        let claim = pool.get_user_liquidity(user_1).unwrap();
+        assert_eq!(claim.token_a, StableNat::from(2));
+        assert_eq!(claim.token_b, StableNat::from(1_010));
+    }
+}
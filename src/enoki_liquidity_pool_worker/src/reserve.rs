@@ -0,0 +1,279 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::AddAssign;
+
+use candid::{candid_method, CandidType, Int};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::is_owned::assert_is_owner;
+use enoki_exchange_shared::liquidity::liquidity_pool::{LiquidityPool, LiquidityPoolTotalBalance};
+use enoki_exchange_shared::types::*;
+
+const MAX_RESERVE_ENTRIES: usize = 200;
+
+thread_local! {
+    static STATE: RefCell<ProtocolReserve> = RefCell::new(ProtocolReserve::default());
+}
+
+/// Ledger of the tokens owned by the protocol rather than by the LPs.
+/// Its balance (`credited - debited`) can go negative when rounding works against the pool.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct ProtocolReserve {
+    credited: LiquidityAmount,
+    debited: LiquidityAmount,
+    /// part of the balance that was removed from the pool and is held by this worker
+    held_by_worker: LiquidityAmount,
+    entries: VecDeque<ReserveEntry>,
+    last_check: Option<InvariantCheck>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub enum ReserveSource {
+    SwapRounding,
+    TradedShortfall,
+    UnassignedDeposit,
+    UnassignedWithdrawal,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct ReserveEntry {
+    time: u64,
+    source: ReserveSource,
+    credit: bool,
+    amount: LiquidityAmount,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+struct InvariantCheck {
+    time: u64,
+    pool_total: LiquidityAmount,
+    lp_reserves: LiquidityAmount,
+    user_liquidity: LiquidityAmount,
+}
+
+#[derive(CandidType, Clone, Debug, Default)]
+pub struct SignedLiquidityAmount {
+    pub token_a: Int,
+    pub token_b: Int,
+}
+
+#[derive(CandidType)]
+pub struct InvariantCheckInfo {
+    pub time: u64,
+    pub pool_total: LiquidityAmountNat,
+    pub lp_reserves: LiquidityAmountNat,
+    pub user_liquidity: LiquidityAmountNat,
+    /// pool total minus the LP reserves and the part of the protocol reserve kept in the pool
+    pub discrepancy: SignedLiquidityAmount,
+}
+
+#[derive(CandidType)]
+pub struct ProtocolReserveInfo {
+    pub credited: LiquidityAmountNat,
+    pub debited: LiquidityAmountNat,
+    pub balance: SignedLiquidityAmount,
+    pub held_by_worker: LiquidityAmountNat,
+    pub last_check: Option<InvariantCheckInfo>,
+    pub recent_entries: Vec<ReserveEntry>,
+}
+
+fn signed(value: &LiquidityAmount) -> SignedLiquidityAmount {
+    SignedLiquidityAmount {
+        token_a: value.token_a.clone().to_nat().into(),
+        token_b: value.token_b.clone().to_nat().into(),
+    }
+}
+
+fn signed_sub(lhs: SignedLiquidityAmount, rhs: SignedLiquidityAmount) -> SignedLiquidityAmount {
+    SignedLiquidityAmount {
+        token_a: lhs.token_a - rhs.token_a,
+        token_b: lhs.token_b - rhs.token_b,
+    }
+}
+
+fn is_negative(value: &SignedLiquidityAmount) -> bool {
+    value.token_a < 0 || value.token_b < 0
+}
+
+fn is_nonzero(value: &SignedLiquidityAmount) -> bool {
+    value.token_a != 0 || value.token_b != 0
+}
+
+impl ProtocolReserve {
+    fn balance(&self) -> SignedLiquidityAmount {
+        signed_sub(signed(&self.credited), signed(&self.debited))
+    }
+    fn discrepancy(&self, check: &InvariantCheck) -> SignedLiquidityAmount {
+        let in_pool = signed_sub(self.balance(), signed(&self.held_by_worker));
+        signed_sub(
+            signed_sub(signed(&check.pool_total), signed(&check.lp_reserves)),
+            in_pool,
+        )
+    }
+    fn record(&mut self, source: ReserveSource, credit: bool, amount: LiquidityAmount) {
+        ic_cdk::println!(
+            "[worker] protocol reserve {} {:?} ({:?})",
+            if credit { "credited" } else { "debited" },
+            amount,
+            source
+        );
+        if credit {
+            self.credited.add_assign(amount.clone());
+        } else {
+            self.debited.add_assign(amount.clone());
+        }
+        self.entries.push_back(ReserveEntry {
+            time: ic_cdk::api::time(),
+            source,
+            credit,
+            amount,
+        });
+        if self.entries.len() > MAX_RESERVE_ENTRIES {
+            self.entries.pop_front();
+        }
+        let balance = self.balance();
+        if is_negative(&balance) {
+            ic_cdk::println!(
+                "[worker] ALERT: protocol reserve is negative: {:?}",
+                balance
+            );
+        }
+    }
+}
+
+fn is_empty(amount: &LiquidityAmount) -> bool {
+    !amount.token_a.is_nonzero() && !amount.token_b.is_nonzero()
+}
+
+pub fn credit(source: ReserveSource, amount: LiquidityAmount) {
+    if !is_empty(&amount) {
+        STATE.with(|s| s.borrow_mut().record(source, true, amount));
+    }
+}
+
+/// Credits tokens that were taken out of the pool and are now held by the worker.
+pub fn credit_held(source: ReserveSource, amount: LiquidityAmount) {
+    if !is_empty(&amount) {
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            s.held_by_worker.add_assign(amount.clone());
+            s.record(source, true, amount);
+        });
+    }
+}
+
+pub fn debit(source: ReserveSource, amount: LiquidityAmount) {
+    if !is_empty(&amount) {
+        STATE.with(|s| s.borrow_mut().record(source, false, amount));
+    }
+}
+
+/// Checks that the total liquidity of the pool equals the LP reserves plus the protocol reserve.
+pub fn check_invariant(pool: &LiquidityPool, pool_total: LiquidityAmount) {
+    let check = InvariantCheck {
+        time: ic_cdk::api::time(),
+        pool_total,
+        lp_reserves: LiquidityPoolTotalBalance::new(pool).get_total_balances(),
+        user_liquidity: pool.get_total_user_liquidity(),
+    };
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        let discrepancy = s.discrepancy(&check);
+        if is_nonzero(&discrepancy) {
+            ic_cdk::println!(
+                "[worker] ALERT: pool liquidity does not match LP and protocol reserves: {:?} vs {:?} + {:?}. discrepancy: {:?}",
+                check.pool_total,
+                check.lp_reserves,
+                s.balance(),
+                discrepancy
+            );
+        }
+        s.last_check = Some(check);
+    });
+}
+
+#[query(name = "getProtocolReserve")]
+#[candid_method(query, rename = "getProtocolReserve")]
+fn get_protocol_reserve() -> TxResult<ProtocolReserveInfo> {
+    assert_is_owner()?;
+    Ok(STATE.with(|s| {
+        let s = s.borrow();
+        ProtocolReserveInfo {
+            credited: s.credited.clone().into(),
+            debited: s.debited.clone().into(),
+            balance: s.balance(),
+            held_by_worker: s.held_by_worker.clone().into(),
+            last_check: s.last_check.as_ref().map(|check| InvariantCheckInfo {
+                time: check.time,
+                pool_total: check.pool_total.clone().into(),
+                lp_reserves: check.lp_reserves.clone().into(),
+                user_liquidity: check.user_liquidity.clone().into(),
+                discrepancy: s.discrepancy(check),
+            }),
+            recent_entries: s.entries.iter().cloned().collect(),
+        }
+    }))
+}
+
+pub fn export_stable_storage() -> ProtocolReserve {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: ProtocolReserve) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod reserve_tests {
+    use candid::{Nat, Principal};
+
+    use super::*;
+
+    fn check(pool: &LiquidityPool, pool_total: LiquidityAmount) -> InvariantCheck {
+        InvariantCheck {
+            time: 0,
+            pool_total,
+            lp_reserves: LiquidityPoolTotalBalance::new(pool).get_total_balances(),
+            user_liquidity: pool.get_total_user_liquidity(),
+        }
+    }
+
+    #[test]
+    fn test_residue_and_shortfall_keep_pool_balanced() {
+        let mut pool = LiquidityPool::default();
+        pool.mint_shares(
+            Principal::from_slice(&[1]),
+            &EnokiToken::TokenB,
+            StableNat::from(100),
+            1,
+        )
+        .unwrap();
+        let mut reserve = ProtocolReserve::default();
+
+        // the swaps' rounding residue stays in the pool, owned by the protocol
+        let traded = LiquidityTrades {
+            increased: LiquidityAmount::from((50, 0)),
+            decreased: LiquidityAmount::from((0, 45)),
+        };
+        assert!(is_empty(&pool.apply_traded(&traded)));
+        reserve.credited.add_assign(LiquidityAmount::from((3, 4)));
+        let round = check(&pool, LiquidityAmount::from((53, 59)));
+        assert!(!is_nonzero(&reserve.discrepancy(&round)));
+
+        // paying out more than the LPs hold takes the difference from the residue
+        let traded = LiquidityTrades {
+            increased: LiquidityAmount::from((0, 0)),
+            decreased: LiquidityAmount::from((0, 58)),
+        };
+        let shortfall = pool.apply_traded(&traded);
+        assert_eq!(shortfall.token_b, StableNat::from(Nat::from(3u32)));
+        reserve.debited.add_assign(shortfall);
+        let round = check(&pool, LiquidityAmount::from((53, 1)));
+        assert!(!is_nonzero(&reserve.discrepancy(&round)));
+        assert!(!is_negative(&reserve.balance()));
+
+        // a discrepancy is reported when the pool holds less than both reserves
+        let round = check(&pool, LiquidityAmount::from((52, 1)));
+        assert!(is_negative(&reserve.discrepancy(&round)));
+    }
+}
@@ -0,0 +1,356 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::{AddAssign, Div, Mul};
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_token_info::get_assigned_shard;
+use enoki_exchange_shared::interfaces::enoki_wrapped_token::ShardedTransferNotification;
+use enoki_exchange_shared::is_owned::assert_is_owner;
+use enoki_exchange_shared::liquidity::liquidity_pool::LiquidityPool;
+use enoki_exchange_shared::types::*;
+use enoki_exchange_shared::{has_rejected_deposits, has_sharded_users, has_token_info};
+
+use crate::withdrawals;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<RewardsState> = RefCell::new(RewardsState::default());
+}
+
+fn accumulator_precision() -> StableNat {
+    Nat::from(10u64.pow(18)).into()
+}
+
+/// Liquidity mining rewards, emitted per second and split between LPs by reward weight,
+/// using an accumulator of rewards per unit of weight.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct RewardsState {
+    schedule: Option<RewardSchedule>,
+    acc_reward_per_weight: StableNat,
+    last_update: u64,
+    total_weight: StableNat,
+    users: HashMap<Principal, UserRewards>,
+    funded: StableNat,
+    accrued: StableNat,
+    claimed: StableNat,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+struct RewardSchedule {
+    token: EnokiToken,
+    rate_per_second: StableNat,
+    start: u64,
+    end: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+struct UserRewards {
+    weight: StableNat,
+    reward_debt: StableNat,
+    pending: StableNat,
+}
+
+#[derive(CandidType)]
+pub struct RewardsInfo {
+    pub token: Option<EnokiToken>,
+    pub rate_per_second: Nat,
+    pub start: u64,
+    pub end: u64,
+    pub funded: Nat,
+    pub accrued: Nat,
+    pub claimed: Nat,
+    pub total_weight: Nat,
+}
+
+impl RewardsState {
+    fn emitted_until(&self, now: u64) -> StableNat {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule,
+            None => return StableNat::zero(),
+        };
+        let from = self.last_update.max(schedule.start);
+        let to = now.min(schedule.end);
+        if to <= from || !self.total_weight.is_nonzero() {
+            return StableNat::zero();
+        }
+        let seconds: StableNat = Nat::from((to - from) / NANOS_PER_SECOND).into();
+        let emitted = schedule.rate_per_second.clone().mul(seconds);
+        let available = (self.funded.clone() - self.accrued.clone()).unwrap_or_default();
+        emitted.min(available)
+    }
+    fn acc_reward_per_weight_at(&self, now: u64) -> StableNat {
+        let emitted = self.emitted_until(now);
+        if !emitted.is_nonzero() {
+            return self.acc_reward_per_weight.clone();
+        }
+        self.acc_reward_per_weight.clone()
+            + emitted
+                .mul(accumulator_precision())
+                .div(self.total_weight.clone())
+    }
+    fn update(&mut self, now: u64) {
+        let emitted = self.emitted_until(now);
+        if emitted.is_nonzero() {
+            self.acc_reward_per_weight = self.acc_reward_per_weight_at(now);
+            self.accrued.add_assign(emitted);
+        }
+        // emission only advances by whole seconds, so the remainder is carried over
+        if now > self.last_update {
+            self.last_update = now - (now - self.last_update) % NANOS_PER_SECOND;
+        }
+    }
+    fn settle_user(&mut self, user: Principal) -> &mut UserRewards {
+        let acc = self.acc_reward_per_weight.clone();
+        let rewards = self.users.entry(user).or_default();
+        let earned = rewards.weight.clone().mul(acc).div(accumulator_precision());
+        rewards
+            .pending
+            .add_assign((earned.clone() - rewards.reward_debt.clone()).unwrap_or_default());
+        rewards.reward_debt = earned;
+        rewards
+    }
+    fn set_weight(&mut self, user: Principal, weight: StableNat) {
+        let acc = self.acc_reward_per_weight.clone();
+        let rewards = self.settle_user(user);
+        let previous = std::mem::replace(&mut rewards.weight, weight.clone());
+        rewards.reward_debt = weight.clone().mul(acc).div(accumulator_precision());
+        self.total_weight = (self.total_weight.clone() - previous).unwrap_or_default() + weight;
+    }
+    fn pending_at(&self, user: Principal, now: u64) -> StableNat {
+        let rewards = match self.users.get(&user) {
+            Some(rewards) => rewards,
+            None => return StableNat::zero(),
+        };
+        let earned = rewards
+            .weight
+            .clone()
+            .mul(self.acc_reward_per_weight_at(now))
+            .div(accumulator_precision());
+        rewards.pending.clone() + (earned - rewards.reward_debt.clone()).unwrap_or_default()
+    }
+}
+
+/// Brings the reward weight of every LP up to date with their current shares and locks.
+/// Must be called in the same message as any change to the shares.
+pub fn update_weights(pool: &LiquidityPool) {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.update(now);
+        let mut users: Vec<Principal> = pool.iter_shares().map(|(user, _)| *user).collect();
+        users.extend(
+            s.users
+                .iter()
+                .filter(|(_, rewards)| rewards.weight.is_nonzero())
+                .map(|(user, _)| *user),
+        );
+        users.sort();
+        users.dedup();
+        for user in users {
+            s.set_weight(user, pool.get_reward_weight(user));
+        }
+    });
+}
+
+#[update(name = "setRewardSchedule")]
+#[candid_method(update, rename = "setRewardSchedule")]
+fn set_reward_schedule(
+    token: EnokiToken,
+    rate_per_second: Nat,
+    start: u64,
+    end: u64,
+) -> TxResult<()> {
+    assert_is_owner()?;
+    if start >= end {
+        return Err(TxError::InvalidInput(
+            "reward schedule must end after it starts".to_string(),
+        ));
+    }
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        if let Some(existing) = &s.schedule {
+            if existing.token != token && s.funded.is_nonzero() {
+                return Err(TxError::InvalidState(
+                    "cannot change the reward token of a funded schedule".to_string(),
+                ));
+            }
+        }
+        s.update(now);
+        s.schedule = Some(RewardSchedule {
+            token,
+            rate_per_second: rate_per_second.into(),
+            start,
+            end,
+        });
+        Ok(())
+    })
+}
+
+/// Called by the token shards through `shardTransferAndCall`. Tokens other than the current
+/// reward token are refunded.
+#[update(name = "fundRewards")]
+#[candid_method(update, rename = "fundRewards")]
+async fn fund_rewards(notification: ShardedTransferNotification) -> String {
+    let token = match has_rejected_deposits::parse_notification(&notification) {
+        Ok(token) => token,
+        Err(error) => {
+            return has_rejected_deposits::reject_without_refund("fundRewards", error.into())
+        }
+    };
+    let funded = STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        match &s.schedule {
+            Some(schedule) if schedule.token == token => {
+                s.funded.add_assign(notification.value.clone().into());
+                true
+            }
+            _ => false,
+        }
+    });
+    if funded {
+        return "OK".to_string();
+    }
+    let transfer_fee = withdrawals::get_fee_for_transfer(&token)
+        .await
+        .map(|fee| fee.into());
+    let refundable = notification.value.clone();
+    has_rejected_deposits::refund_rejected_deposit(
+        notification,
+        token,
+        "fundRewards",
+        TxError::InvalidInput("token is not the current reward token".to_string()),
+        refundable,
+        transfer_fee,
+    )
+    .await
+}
+
+#[query(name = "getRewardsInfo")]
+#[candid_method(query, rename = "getRewardsInfo")]
+fn get_rewards_info() -> RewardsInfo {
+    STATE.with(|s| {
+        let s = s.borrow();
+        let schedule = s.schedule.clone();
+        RewardsInfo {
+            token: schedule.as_ref().map(|schedule| schedule.token.clone()),
+            rate_per_second: schedule
+                .as_ref()
+                .map(|schedule| schedule.rate_per_second.clone().to_nat())
+                .unwrap_or_default(),
+            start: schedule
+                .as_ref()
+                .map(|schedule| schedule.start)
+                .unwrap_or_default(),
+            end: schedule
+                .as_ref()
+                .map(|schedule| schedule.end)
+                .unwrap_or_default(),
+            funded: s.funded.clone().into(),
+            accrued: s.accrued.clone().into(),
+            claimed: s.claimed.clone().into(),
+            total_weight: s.total_weight.clone().into(),
+        }
+    })
+}
+
+#[query(name = "getPendingRewards")]
+#[candid_method(query, rename = "getPendingRewards")]
+fn get_pending_rewards(user: Principal) -> Nat {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| s.borrow().pending_at(user, now).to_nat())
+}
+
+#[update(name = "claimRewards")]
+#[candid_method(update, rename = "claimRewards")]
+async fn claim_rewards() -> TxResult<Nat> {
+    let user = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let (token, amount) = STATE.with(|s| -> TxResult<_> {
+        let mut s = s.borrow_mut();
+        let token = s
+            .schedule
+            .as_ref()
+            .map(|schedule| schedule.token.clone())
+            .ok_or_else(|| TxError::NotInitialized("reward schedule".to_string()))?;
+        s.update(now);
+        let amount = std::mem::take(&mut s.settle_user(user).pending);
+        s.claimed.add_assign(amount.clone());
+        Ok((token, amount))
+    })?;
+    if !amount.is_nonzero() {
+        return Ok(Nat::default());
+    }
+    let amount: Nat = amount.into();
+    let result: Result<()> = async {
+        let user_shard =
+            has_sharded_users::get_user_shard(user, has_token_info::get_token_address(&token))?;
+        ic_cdk::call(
+            get_assigned_shard(&token),
+            "shardTransfer",
+            (user_shard, user, amount.clone()),
+        )
+        .await
+        .map_err(|e| e.into_tx_error())
+    }
+    .await;
+    if let Err(error) = result {
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            s.claimed.safe_sub_assign(amount.clone().into()).unwrap();
+            s.users
+                .entry(user)
+                .or_default()
+                .pending
+                .add_assign(amount.into());
+        });
+        ic_cdk::print(format!("failed to claim rewards: {:?}", error));
+        return Err(error.into());
+    }
+    ic_cdk::println!("[worker] user {} claimed {} rewards", user, amount);
+    Ok(amount)
+}
+
+pub fn export_stable_storage() -> RewardsState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: RewardsState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod rewards_tests {
+    use super::*;
+
+    #[test]
+    fn test_rewards_split_by_weight_over_time() {
+        let user_1 = Principal::from_slice(&[1]);
+        let user_2 = Principal::from_slice(&[2]);
+        let mut state = RewardsState {
+            schedule: Some(RewardSchedule {
+                token: EnokiToken::TokenB,
+                rate_per_second: StableNat::from(100),
+                start: 0,
+                end: 100 * NANOS_PER_SECOND,
+            }),
+            funded: StableNat::from(1_000_000),
+            ..Default::default()
+        };
+        state.set_weight(user_1, StableNat::from(1));
+        state.update(10 * NANOS_PER_SECOND);
+        state.set_weight(user_2, StableNat::from(3));
+        let now = 20 * NANOS_PER_SECOND;
+        assert_eq!(state.pending_at(user_1, now), StableNat::from(1_000 + 250));
+        assert_eq!(state.pending_at(user_2, now), StableNat::from(750));
+        let now = 200 * NANOS_PER_SECOND;
+        assert_eq!(
+            state.pending_at(user_1, now),
+            StableNat::from(1_000 + 2_250)
+        );
+        assert_eq!(state.pending_at(user_2, now), StableNat::from(6_750));
+    }
+}
@@ -1,8 +1,13 @@
 use candid::{candid_method, Principal};
 use ic_cdk_macros::*;
 
-use enoki_exchange_shared::{has_token_info, is_managed, is_owned};
+use enoki_exchange_shared::{
+    has_rejected_deposits, has_scheduler, has_token_info, is_managed, is_owned,
+};
+use enoki_exchange_shared::has_rejected_deposits::RejectedDeposit;
+use enoki_exchange_shared::has_scheduler::{SchedulerSettings, SchedulerStatus};
 use enoki_exchange_shared::has_token_info::{AssignedShards, TokenPairInfo};
+use enoki_exchange_shared::types::TxResult;
 
 #[query(name = "getOwner")]
 #[candid_method(query, rename = "getOwner")]
@@ -12,8 +17,8 @@ fn get_owner() -> Principal {
 
 #[update(name = "setOwner")]
 #[candid_method(update, rename = "setOwner")]
-fn set_owner(new_owner: Principal) {
-    is_owned::set_owner(new_owner).unwrap()
+fn set_owner(new_owner: Principal) -> TxResult<()> {
+    Ok(is_owned::set_owner(new_owner)?)
 }
 
 #[query(name = "getTokenInfo")]
@@ -48,6 +53,24 @@ fn get_manager() -> Principal {
 
 #[update(name = "setManager")]
 #[candid_method(update, rename = "setManager")]
-fn set_manager(new_manager: Principal) {
-    is_managed::set_manager(new_manager).unwrap()
+fn set_manager(new_manager: Principal) -> TxResult<()> {
+    Ok(is_managed::set_manager(new_manager)?)
+}
+
+#[query(name = "getRejectedDeposits")]
+#[candid_method(query, rename = "getRejectedDeposits")]
+fn get_rejected_deposits(user: Principal) -> Vec<RejectedDeposit> {
+    has_rejected_deposits::get_rejected_deposits(user)
+}
+
+#[query(name = "getSchedulerStatus")]
+#[candid_method(query, rename = "getSchedulerStatus")]
+fn get_scheduler_status() -> SchedulerStatus {
+    has_scheduler::get_status()
+}
+
+#[update(name = "setSchedulerSettings")]
+#[candid_method(update, rename = "setSchedulerSettings")]
+fn set_scheduler_settings(settings: SchedulerSettings) -> TxResult<()> {
+    Ok(has_scheduler::set_settings(settings)?)
 }
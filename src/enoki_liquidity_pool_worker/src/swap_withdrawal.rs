@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_rejected_deposits::REJECTED_PREFIX;
+use enoki_exchange_shared::has_token_info::{
+    get_assigned_shard, price_in_b_u64_to_float, AssignedShards,
+};
+use enoki_exchange_shared::is_owned::assert_is_owner;
+use enoki_exchange_shared::types::*;
+
+use crate::liquidity::{
+    record_swap_in_net_deposits, remove_all_unlocked_liquidity, user_has_liquidity, with_pool,
+    withdraw_for_user,
+};
+use crate::withdrawals::WithdrawalFailure;
+
+thread_local! {
+    static STATE: RefCell<SwapWithdrawalState> = RefCell::new(SwapWithdrawalState::default());
+}
+
+/// Lets LPs exit in a single token: the other side of their withdrawal is swapped through the
+/// broker this worker is registered with.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct SwapWithdrawalState {
+    broker: Option<Principal>,
+    broker_shards: Option<AssignedShards>,
+    requests: HashMap<Principal, SingleTokenRequest>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+struct SingleTokenRequest {
+    token: EnokiToken,
+    max_slippage: f64,
+}
+
+#[derive(serde::Serialize)]
+struct SwapInput {
+    allow_taker: bool,
+    limit_price_in_b: f64,
+}
+
+#[update(name = "initSwapRouting")]
+#[candid_method(update, rename = "initSwapRouting")]
+async fn init_swap_routing(exchange: Principal) -> TxResult<Principal> {
+    assert_is_owner()?;
+    let response: Result<Principal> =
+        flatten_response(ic_cdk::call(exchange, "register", (ic_cdk::id(), None::<String>)).await);
+    let broker = response?;
+    let response: Result<(AssignedShards,)> = ic_cdk::call(broker, "getAssignedShards", ())
+        .await
+        .map_err(|e| e.into_tx_error());
+    let shards = response?.0;
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        s.broker = Some(broker);
+        s.broker_shards = Some(shards);
+    });
+    Ok(broker)
+}
+
+#[query(name = "getSwapBroker")]
+#[candid_method(query, rename = "getSwapBroker")]
+fn get_swap_broker() -> Option<Principal> {
+    STATE.with(|s| s.borrow().broker)
+}
+
+/// Removes all of the caller's unlocked liquidity, paying it out only in `token`.
+/// `max_slippage` is the fraction the swap price may deviate from the last exchange price.
+#[update(name = "removeAllLiquidityInToken")]
+#[candid_method(update, rename = "removeAllLiquidityInToken")]
+fn remove_all_liquidity_in_token(token: EnokiToken, max_slippage: f64) -> TxResult<()> {
+    if !(0f64..1f64).contains(&max_slippage) {
+        return Err(TxError::InvalidInput(
+            "slippage must be between 0 and 1".to_string(),
+        ));
+    }
+    if STATE.with(|s| s.borrow().broker.is_none()) {
+        return Err(TxError::NotInitialized("swap routing".to_string()));
+    }
+    let user = ic_cdk::caller();
+    remove_all_unlocked_liquidity(user)?;
+    STATE.with(|s| {
+        s.borrow_mut().requests.insert(
+            user,
+            SingleTokenRequest {
+                token,
+                max_slippage,
+            },
+        )
+    });
+    Ok(())
+}
+
+pub fn get_requested_token(user: Principal) -> Option<EnokiToken> {
+    STATE.with(|s| s.borrow().requests.get(&user).map(|r| r.token.clone()))
+}
+
+/// Forgets the requests of users that have nothing left to withdraw.
+pub fn clear_completed_requests() {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .requests
+            .retain(|&user, _| user_has_liquidity(user))
+    });
+}
+
+/// Swaps a withdrawal into the token requested by the user and sends it to them.
+/// If the swap fails, the withdrawal is sent as is.
+pub async fn swap_and_withdraw_for_user(
+    user: Principal,
+    withdrawal: TokenAmount,
+) -> Option<WithdrawalFailure> {
+    match swap_for_user(user, &withdrawal).await {
+        Ok(received) => withdraw_for_user(user, received).await,
+        Err(error) => {
+            ic_cdk::print(format!(
+                "[worker] could not swap withdrawal for {}: {:?}",
+                user, error
+            ));
+            withdraw_for_user(user, withdrawal).await
+        }
+    }
+}
+
+/// Worst price accepted when swapping away `token`: selling A for less, or buying A for more,
+/// than the last exchange price by at most `max_slippage`.
+fn limit_price_in_b(token: &EnokiToken, price: u64, max_slippage: f64) -> f64 {
+    let price = price_in_b_u64_to_float(price);
+    match token {
+        EnokiToken::TokenA => price * (1.0 - max_slippage),
+        EnokiToken::TokenB => price * (1.0 + max_slippage),
+    }
+}
+
+async fn swap_for_user(user: Principal, withdrawal: &TokenAmount) -> Result<TokenAmount> {
+    let (broker, broker_shards, request) = STATE.with(|s| {
+        let s = s.borrow();
+        (
+            s.broker,
+            s.broker_shards.clone(),
+            s.requests.get(&user).cloned(),
+        )
+    });
+    let (broker, broker_shards, request) = match (broker, broker_shards, request) {
+        (Some(broker), Some(shards), Some(request)) => (broker, shards, request),
+        _ => return Err(TxError::Other("no swap route for withdrawal".to_string()).into()),
+    };
+    let price = with_pool(|_, price| price)
+        .ok_or_else(|| TxError::Other("no exchange price known yet".to_string()))?;
+    let data = serde_json::to_string(&SwapInput {
+        allow_taker: true,
+        limit_price_in_b: limit_price_in_b(&withdrawal.token, price, request.max_slippage),
+    })
+    .map_err(|e| TxError::ParsingError(e.to_string()))?;
+    let broker_shard = match &withdrawal.token {
+        EnokiToken::TokenA => broker_shards.token_a,
+        EnokiToken::TokenB => broker_shards.token_b,
+    };
+    let amount: Nat = withdrawal.amount.clone().into();
+    let response: Result<(String,)> = ic_cdk::call(
+        get_assigned_shard(&withdrawal.token),
+        "shardTransferAndCall",
+        (broker_shard, broker, amount, broker, "swap", data),
+    )
+    .await
+    .map_err(|e| e.into_tx_error());
+    let response = response?.0;
+    if let Some(reason) = response.strip_prefix(REJECTED_PREFIX) {
+        return Err(TxError::Other(format!("swap rejected: {}", reason)).into());
+    }
+    let received = Nat::from_str(&response).map_err(|e| TxError::ParsingError(e.to_string()))?;
+    let received = TokenAmount {
+        token: request.token,
+        amount: received.into(),
+    };
+    ic_cdk::println!(
+        "[worker] swapped withdrawal for {}: {:?} -> {:?}",
+        user,
+        withdrawal,
+        received
+    );
+    record_swap_in_net_deposits(user, withdrawal.clone(), received.clone());
+    Ok(received)
+}
+
+pub fn export_stable_storage() -> SwapWithdrawalState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: SwapWithdrawalState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod swap_withdrawal_tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_price_allows_slippage_against_the_user() {
+        let sell_a = limit_price_in_b(&EnokiToken::TokenA, 600, 0.05);
+        let buy_a = limit_price_in_b(&EnokiToken::TokenB, 600, 0.05);
+        assert!((sell_a - 570.0).abs() < 1e-9);
+        assert!((buy_a - 630.0).abs() < 1e-9);
+        assert_eq!(limit_price_in_b(&EnokiToken::TokenA, 600, 0.0), 600.0);
+    }
+}
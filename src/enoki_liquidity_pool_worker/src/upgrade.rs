@@ -2,15 +2,27 @@ use candid::{CandidType, Deserialize};
 use ic_cdk_macros::*;
 
 use enoki_exchange_shared::{
-    has_sharded_users, has_token_info, is_managed, is_owned,
+    has_rejected_deposits, has_scheduler, has_sharded_users, has_token_info, is_managed, is_owned,
 };
+use enoki_exchange_shared::has_rejected_deposits::RejectedDepositsState;
+use enoki_exchange_shared::has_scheduler::SchedulerState;
 use enoki_exchange_shared::has_sharded_users::ShardedUserState;
 use enoki_exchange_shared::has_token_info::TokenInfoState;
 use enoki_exchange_shared::is_managed::ManagementData;
 use enoki_exchange_shared::is_owned::OwnershipData;
 
 use crate::liquidity;
-use crate::liquidity::LiquidityState;
+use crate::liquidity::{LegacyLiquidityState, LiquidityState};
+use crate::performance;
+use crate::performance::PerformanceState;
+use crate::reserve;
+use crate::reserve::ProtocolReserve;
+use crate::rewards;
+use crate::rewards::RewardsState;
+use crate::swap_withdrawal;
+use crate::swap_withdrawal::SwapWithdrawalState;
+use crate::withdrawals;
+use crate::withdrawals::WithdrawalBatchingState;
 
 #[derive(Deserialize, CandidType)]
 struct UpgradePayload {
@@ -18,7 +30,16 @@ struct UpgradePayload {
     token_info: TokenInfoState,
     manager: ManagementData,
     owner: OwnershipData,
-    liquidity: LiquidityState,
+    // only set when upgrading from before pool shares
+    liquidity: Option<LegacyLiquidityState>,
+    liquidity_shares: Option<LiquidityState>,
+    performance: Option<PerformanceState>,
+    reserve: Option<ProtocolReserve>,
+    rewards: Option<RewardsState>,
+    swap_withdrawal: Option<SwapWithdrawalState>,
+    withdrawals: Option<WithdrawalBatchingState>,
+    rejected_deposits: Option<RejectedDepositsState>,
+    scheduler: Option<SchedulerState>,
 }
 
 #[pre_upgrade]
@@ -27,13 +48,28 @@ fn pre_upgrade() {
     let token_info = has_token_info::export_stable_storage();
     let manager = is_managed::export_stable_storage();
     let owner = is_owned::export_stable_storage();
-    let liquidity = liquidity::export_stable_storage();
+    let liquidity_shares = liquidity::export_stable_storage();
+    let performance = performance::export_stable_storage();
+    let reserve = reserve::export_stable_storage();
+    let rewards = rewards::export_stable_storage();
+    let swap_withdrawal = swap_withdrawal::export_stable_storage();
+    let withdrawals = withdrawals::export_stable_storage();
+    let rejected_deposits = has_rejected_deposits::export_stable_storage();
+    let scheduler = has_scheduler::export_stable_storage();
     let payload = UpgradePayload {
         sharded_users,
         token_info,
         manager,
         owner,
-        liquidity,
+        liquidity: None,
+        liquidity_shares: Some(liquidity_shares),
+        performance: Some(performance),
+        reserve: Some(reserve),
+        rewards: Some(rewards),
+        swap_withdrawal: Some(swap_withdrawal),
+        withdrawals: Some(withdrawals),
+        rejected_deposits: Some(rejected_deposits),
+        scheduler: Some(scheduler),
     };
     ic_cdk::storage::stable_save((payload, )).expect("failed to save to stable storage");
 }
@@ -47,12 +83,48 @@ fn post_upgrade() {
         sharded_users,
         token_info,
         manager,
-        owner, liquidity,
+        owner,
+        liquidity,
+        liquidity_shares,
+        performance,
+        reserve,
+        rewards,
+        swap_withdrawal,
+        withdrawals,
+        rejected_deposits,
+        scheduler,
     } = payload;
 
     has_sharded_users::import_stable_storage(sharded_users);
     has_token_info::import_stable_storage(token_info);
     is_managed::import_stable_storage(manager);
     is_owned::import_stable_storage(owner);
-    liquidity::import_stable_storage(liquidity);
+    if let Some(liquidity) = liquidity {
+        liquidity::import_legacy_stable_storage(liquidity);
+    }
+    if let Some(liquidity_shares) = liquidity_shares {
+        liquidity::import_stable_storage(liquidity_shares);
+    }
+    if let Some(performance) = performance {
+        performance::import_stable_storage(performance);
+    }
+    if let Some(reserve) = reserve {
+        reserve::import_stable_storage(reserve);
+    }
+    if let Some(rewards) = rewards {
+        rewards::import_stable_storage(rewards);
+    }
+    if let Some(swap_withdrawal) = swap_withdrawal {
+        swap_withdrawal::import_stable_storage(swap_withdrawal);
+    }
+    if let Some(withdrawals) = withdrawals {
+        withdrawals::import_stable_storage(withdrawals);
+    }
+    if let Some(rejected_deposits) = rejected_deposits {
+        has_rejected_deposits::import_stable_storage(rejected_deposits);
+    }
+    if let Some(scheduler) = scheduler {
+        has_scheduler::import_stable_storage(scheduler);
+    }
+    withdrawals::queue_legacy_withdrawals(liquidity::take_legacy_pending_withdrawals());
 }
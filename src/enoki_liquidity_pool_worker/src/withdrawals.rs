@@ -0,0 +1,420 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::{AddAssign, Mul};
+
+use candid::{candid_method, CandidType, Nat, Principal};
+use ic_cdk_macros::*;
+
+use enoki_exchange_shared::has_token_info;
+use enoki_exchange_shared::is_owned::assert_is_owner;
+use enoki_exchange_shared::types::*;
+
+use crate::liquidity::{send_withdrawal, send_withdrawals};
+
+/// Withdrawals are only sent once they are worth this many transfer fees of their token.
+const MIN_WITHDRAWAL_IN_TRANSFER_FEES: u64 = 10;
+const RETRY_BASE_DELAY: u64 = 60 * 1_000_000_000;
+const RETRY_MAX_DELAY: u64 = 24 * 3_600 * 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<WithdrawalBatchingState> = RefCell::new(WithdrawalBatchingState::default());
+}
+
+/// Withdrawals too small to be worth a transfer are accumulated per user until they cross the
+/// minimum of their token, or until the user claims them.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug, Default)]
+pub struct WithdrawalBatchingState {
+    token_a_transfer_fee: Option<StableNat>,
+    token_b_transfer_fee: Option<StableNat>,
+    claimable: HashMap<Principal, LiquidityAmount>,
+    retry_queue: BTreeMap<u64, QueuedWithdrawal>,
+    next_retry_id: u64,
+}
+
+/// A withdrawal that could not be sent, retried with exponential backoff.
+#[derive(serde::Serialize, serde::Deserialize, CandidType, Clone, Debug)]
+pub struct QueuedWithdrawal {
+    id: u64,
+    user: Principal,
+    withdrawal: TokenAmount,
+    attempts: u32,
+    last_error: String,
+    last_attempt: u64,
+    next_attempt: u64,
+}
+
+pub struct WithdrawalFailure {
+    pub user: Principal,
+    pub withdrawal: TokenAmount,
+    pub error: String,
+}
+
+fn retry_delay(attempts: u32) -> u64 {
+    RETRY_BASE_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+        .min(RETRY_MAX_DELAY)
+}
+
+impl WithdrawalBatchingState {
+    fn get_token_fee(&self, token: &EnokiToken) -> Option<StableNat> {
+        match token {
+            EnokiToken::TokenA => self.token_a_transfer_fee.clone(),
+            EnokiToken::TokenB => self.token_b_transfer_fee.clone(),
+        }
+    }
+    fn get_token_fee_mut(&mut self, token: &EnokiToken) -> &mut Option<StableNat> {
+        match token {
+            EnokiToken::TokenA => &mut self.token_a_transfer_fee,
+            EnokiToken::TokenB => &mut self.token_b_transfer_fee,
+        }
+    }
+    fn add_claimable(&mut self, user: Principal, withdrawal: TokenAmount) {
+        self.claimable
+            .entry(user)
+            .or_default()
+            .get_mut(&withdrawal.token)
+            .add_assign(withdrawal.amount);
+    }
+    /// Takes every claimable balance of `token` that is at least `minimum`.
+    fn take_claimable_above(
+        &mut self,
+        token: &EnokiToken,
+        minimum: &StableNat,
+    ) -> Vec<(Principal, TokenAmount)> {
+        let mut ready = vec![];
+        for (user, claimable) in self.claimable.iter_mut() {
+            if claimable.get(token) >= minimum && claimable.get(token).is_nonzero() {
+                ready.push((
+                    *user,
+                    TokenAmount {
+                        token: token.clone(),
+                        amount: std::mem::take(claimable.get_mut(token)),
+                    },
+                ));
+            }
+        }
+        self.claimable.retain(|_, claimable| {
+            claimable.token_a.is_nonzero() || claimable.token_b.is_nonzero()
+        });
+        ready
+    }
+    /// Queues a failed withdrawal. `previous` is the queue entry it was retried from, if any.
+    fn queue_failure(
+        &mut self,
+        previous: Option<QueuedWithdrawal>,
+        failure: WithdrawalFailure,
+        now: u64,
+    ) {
+        let (id, attempts) = match previous {
+            Some(previous) => (previous.id, previous.attempts + 1),
+            None => {
+                self.next_retry_id += 1;
+                (self.next_retry_id, 1)
+            }
+        };
+        self.retry_queue.insert(
+            id,
+            QueuedWithdrawal {
+                id,
+                user: failure.user,
+                withdrawal: failure.withdrawal,
+                attempts,
+                last_error: failure.error,
+                last_attempt: now,
+                next_attempt: now + retry_delay(attempts),
+            },
+        );
+    }
+    fn take_due(&mut self, now: u64) -> Vec<QueuedWithdrawal> {
+        let due: Vec<u64> = self
+            .retry_queue
+            .values()
+            .filter(|queued| queued.next_attempt <= now)
+            .map(|queued| queued.id)
+            .collect();
+        due.into_iter()
+            .filter_map(|id| self.retry_queue.remove(&id))
+            .collect()
+    }
+}
+
+fn minimum_for_fee(fee: StableNat) -> StableNat {
+    fee.mul(Nat::from(MIN_WITHDRAWAL_IN_TRANSFER_FEES).into())
+}
+
+async fn update_upstream_token_fee(token: &EnokiToken) -> Result<()> {
+    let result: Result<(Nat,)> =
+        ic_cdk::call(has_token_info::get_token_address(token), "getFee", ())
+            .await
+            .map_err(|e| e.into_tx_error());
+    let fee = result?.0;
+    STATE.with(|s| *s.borrow_mut().get_token_fee_mut(token) = Some(fee.into()));
+    Ok(())
+}
+
+pub async fn get_fee_for_transfer(token: &EnokiToken) -> Result<StableNat> {
+    if let Some(fee) = STATE.with(|s| s.borrow().get_token_fee(token)) {
+        return Ok(fee);
+    }
+    update_upstream_token_fee(token).await?;
+    STATE
+        .with(|s| s.borrow().get_token_fee(token))
+        .ok_or_else(|| {
+            TxError::Other("cannot calculate upstream token transfer fee".to_string()).into()
+        })
+}
+
+/// Adds the withdrawals to the claimable balances, and returns the balances that are now large
+/// enough to be sent.
+pub async fn batch_withdrawals(
+    withdrawals: Vec<(Principal, TokenAmount)>,
+) -> Vec<(Principal, TokenAmount)> {
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for (user, withdrawal) in withdrawals {
+            s.add_claimable(user, withdrawal);
+        }
+    });
+    let mut ready = vec![];
+    for token in [EnokiToken::TokenA, EnokiToken::TokenB] {
+        match get_fee_for_transfer(&token).await {
+            Ok(fee) => ready.extend(STATE.with(|s| {
+                s.borrow_mut()
+                    .take_claimable_above(&token, &minimum_for_fee(fee))
+            })),
+            Err(error) => ic_cdk::print(format!(
+                "[worker] holding withdrawals of {:?}, transfer fee unknown: {:?}",
+                token, error
+            )),
+        }
+    }
+    ready
+}
+
+pub fn queue_failures(failures: Vec<WithdrawalFailure>) {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for failure in failures {
+            s.queue_failure(None, failure, now);
+        }
+    });
+}
+
+pub fn queue_legacy_withdrawals(withdrawals: Vec<(Principal, TokenAmount)>) {
+    queue_failures(
+        withdrawals
+            .into_iter()
+            .map(|(user, withdrawal)| WithdrawalFailure {
+                user,
+                withdrawal,
+                error: "failed before the retry queue existed".to_string(),
+            })
+            .collect(),
+    );
+}
+
+pub fn has_queued_withdrawals(user: Principal) -> bool {
+    STATE.with(|s| {
+        s.borrow()
+            .retry_queue
+            .values()
+            .any(|queued| queued.user == user)
+    })
+}
+
+async fn retry(queued: Vec<QueuedWithdrawal>) -> Vec<u64> {
+    let results = futures::future::join_all(
+        queued
+            .iter()
+            .map(|queued| send_withdrawal(queued.user, queued.withdrawal.clone())),
+    )
+    .await;
+    let now = ic_cdk::api::time();
+    let mut sent = vec![];
+    STATE.with(|s| {
+        let mut s = s.borrow_mut();
+        for (queued, result) in queued.into_iter().zip(results) {
+            match result {
+                Some(failure) => s.queue_failure(Some(queued), failure, now),
+                None => sent.push(queued.id),
+            }
+        }
+    });
+    sent
+}
+
+/// Retries every queued withdrawal whose backoff has elapsed.
+pub async fn retry_due_withdrawals() {
+    let due = STATE.with(|s| s.borrow_mut().take_due(ic_cdk::api::time()));
+    if !due.is_empty() {
+        retry(due).await;
+    }
+}
+
+pub fn has_any_queued_withdrawals() -> bool {
+    STATE.with(|s| !s.borrow().retry_queue.is_empty())
+}
+
+fn take_queued(id: u64) -> TxResult<QueuedWithdrawal> {
+    STATE
+        .with(|s| s.borrow_mut().retry_queue.remove(&id))
+        .ok_or_else(|| TxError::NotFound(format!("queued withdrawal {}", id)))
+}
+
+#[query(name = "getPendingWithdrawals")]
+#[candid_method(query, rename = "getPendingWithdrawals")]
+fn get_pending_withdrawals(user: Principal) -> Vec<QueuedWithdrawal> {
+    STATE.with(|s| {
+        s.borrow()
+            .retry_queue
+            .values()
+            .filter(|queued| queued.user == user)
+            .cloned()
+            .collect()
+    })
+}
+
+/// Retries a queued withdrawal right away, ignoring its backoff. Returns whether it was sent.
+#[update(name = "retryWithdrawal")]
+#[candid_method(update, rename = "retryWithdrawal")]
+async fn retry_withdrawal(id: u64) -> TxResult<bool> {
+    assert_is_owner()?;
+    Ok(!retry(vec![take_queued(id)?]).await.is_empty())
+}
+
+/// Sends a queued withdrawal to another principal, for instance when the user cannot receive it.
+#[update(name = "redirectWithdrawal")]
+#[candid_method(update, rename = "redirectWithdrawal")]
+async fn redirect_withdrawal(id: u64, to: Principal) -> TxResult<bool> {
+    assert_is_owner()?;
+    let mut queued = take_queued(id)?;
+    ic_cdk::println!(
+        "[worker] redirecting withdrawal {} from {} to {}",
+        id,
+        queued.user,
+        to
+    );
+    queued.user = to;
+    Ok(!retry(vec![queued]).await.is_empty())
+}
+
+#[query(name = "getClaimableWithdrawals")]
+#[candid_method(query, rename = "getClaimableWithdrawals")]
+fn get_claimable_withdrawals(user: Principal) -> LiquidityAmountNat {
+    STATE
+        .with(|s| s.borrow().claimable.get(&user).cloned())
+        .unwrap_or_default()
+        .into()
+}
+
+#[query(name = "getMinimumWithdrawals")]
+#[candid_method(query, rename = "getMinimumWithdrawals")]
+fn get_minimum_withdrawals() -> LiquidityAmountNat {
+    STATE.with(|s| {
+        let s = s.borrow();
+        LiquidityAmount {
+            token_a: minimum_for_fee(s.token_a_transfer_fee.clone().unwrap_or_default()),
+            token_b: minimum_for_fee(s.token_b_transfer_fee.clone().unwrap_or_default()),
+        }
+        .into()
+    })
+}
+
+/// Sends the caller's claimable balances right away, as long as they cover the transfer fee.
+#[update(name = "claimWithdrawals")]
+#[candid_method(update, rename = "claimWithdrawals")]
+async fn claim_withdrawals() -> TxResult<LiquidityAmountNat> {
+    let user = ic_cdk::caller();
+    let fees = vec![
+        get_fee_for_transfer(&EnokiToken::TokenA).await?,
+        get_fee_for_transfer(&EnokiToken::TokenB).await?,
+    ];
+    let mut claimed = LiquidityAmount::default();
+    let mut withdrawals = vec![];
+    for (token, fee) in vec![EnokiToken::TokenA, EnokiToken::TokenB]
+        .into_iter()
+        .zip(fees)
+    {
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            if let Some(claimable) = s.claimable.get_mut(&user) {
+                if claimable.get(&token) > &fee {
+                    let amount = std::mem::take(claimable.get_mut(&token));
+                    claimed.get_mut(&token).add_assign(amount.clone());
+                    withdrawals.push((user, TokenAmount { token, amount }));
+                }
+            }
+        });
+    }
+    if !withdrawals.is_empty() {
+        send_withdrawals(withdrawals).await;
+    }
+    Ok(claimed.into())
+}
+
+#[update(name = "updateWithdrawalFees")]
+#[candid_method(update, rename = "updateWithdrawalFees")]
+async fn update_withdrawal_fees() -> TxResult<()> {
+    assert_is_owner()?;
+    update_upstream_token_fee(&EnokiToken::TokenA).await?;
+    update_upstream_token_fee(&EnokiToken::TokenB).await?;
+    Ok(())
+}
+
+pub fn export_stable_storage() -> WithdrawalBatchingState {
+    STATE.with(|s| s.take())
+}
+
+pub fn import_stable_storage(data: WithdrawalBatchingState) {
+    STATE.with(|s| s.replace(data));
+}
+
+#[cfg(test)]
+mod withdrawals_tests {
+    use super::*;
+
+    #[test]
+    fn test_small_withdrawals_accumulate_until_minimum() {
+        let user = Principal::from_slice(&[1]);
+        let minimum = minimum_for_fee(StableNat::from(1_000));
+        let mut state = WithdrawalBatchingState::default();
+        let withdrawal = TokenAmount {
+            token: EnokiToken::TokenA,
+            amount: StableNat::from(6_000),
+        };
+        state.add_claimable(user, withdrawal.clone());
+        assert!(state
+            .take_claimable_above(&EnokiToken::TokenA, &minimum)
+            .is_empty());
+        state.add_claimable(user, withdrawal);
+        let ready = state.take_claimable_above(&EnokiToken::TokenA, &minimum);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1.amount, StableNat::from(12_000));
+        assert!(state.claimable.is_empty());
+    }
+
+    #[test]
+    fn test_failed_withdrawals_back_off() {
+        let user = Principal::from_slice(&[1]);
+        let failure = || WithdrawalFailure {
+            user,
+            withdrawal: TokenAmount {
+                token: EnokiToken::TokenB,
+                amount: StableNat::from(100),
+            },
+            error: "error".to_string(),
+        };
+        let mut state = WithdrawalBatchingState::default();
+        state.queue_failure(None, failure(), 0);
+        assert!(state.take_due(RETRY_BASE_DELAY - 1).is_empty());
+        let mut due = state.take_due(RETRY_BASE_DELAY);
+        assert_eq!(due.len(), 1);
+        let now = RETRY_BASE_DELAY;
+        state.queue_failure(due.pop(), failure(), now);
+        let queued = &state.retry_queue[&1];
+        assert_eq!(queued.attempts, 2);
+        assert_eq!(queued.next_attempt, now + 2 * RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(30), RETRY_MAX_DELAY);
+    }
+}
@@ -22,6 +22,10 @@ fn main() {
         limit_order_taker_fee: 0.0,
         swap_fee: 0.003,
         swap_market_maker_reward: 0.45,
+        fee_tiers: None,
+        referral_share: Some(0.2),
+        market_maker_pool_share: Some(0.5),
+        dynamic_swap_fee: None,
     };
 
     let json = serde_json::to_string(&fees).unwrap();